@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use crate::{Cmd, KvsError, Result};
+
+//口令哈希的格式为 pbkdf2-sha256$<迭代次数>$<salt hex>$<hash hex>
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const HASH_ROUNDS: u32 = 10_000;
+const HASH_LEN: usize = 32;
+
+/// A group of commands a user can be allowed to run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    /// get, scan, watch and the subscribe commands
+    Read,
+    /// set, remove and publish
+    Write,
+    /// info, dbsize, config, slowlog, monitor, cdc, sync and replicaof
+    Admin,
+    /// vget, vset and vdel
+    Vector,
+}
+
+impl Category {
+    /// Returns the category of `cmd`, `None` for commands everybody may run.
+    pub fn of(cmd: &Cmd) -> Option<Category> {
+        match cmd {
+            Cmd::Ping(_) | Cmd::Auth(_) => None,
+            Cmd::Get(_) | Cmd::Scan(_) | Cmd::Watch(_) | Cmd::ClusterSlots(_) => Some(Category::Read),
+            Cmd::Subscribe(_) | Cmd::Unsubscribe(_) | Cmd::PSubscribe(_) | Cmd::PUnsubscribe(_) => Some(Category::Read),
+            Cmd::Set(_) | Cmd::Remove(_) | Cmd::Publish(_) => Some(Category::Write),
+            Cmd::VGet(_) | Cmd::VSet(_) | Cmd::VDel(_) => Some(Category::Vector),
+            Cmd::Info(_) | Cmd::DbSize(_) | Cmd::ConfigGet(_) | Cmd::ConfigSet(_) => Some(Category::Admin),
+            Cmd::SlowlogGet(_) | Cmd::SlowlogReset(_) | Cmd::Monitor(_) | Cmd::Cdc(_) => Some(Category::Admin),
+            Cmd::Sync(_) | Cmd::ReplicaOf(_) => Some(Category::Admin),
+            Cmd::ClusterSetSlot(_) | Cmd::ClusterMigrate(_) | Cmd::Backup(_) => Some(Category::Admin),
+        }
+    }
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Category::Read => write!(f, "read"),
+            Category::Write => write!(f, "write"),
+            Category::Admin => write!(f, "admin"),
+            Category::Vector => write!(f, "vector"),
+        }
+    }
+}
+
+/// A user defined in the `[[users]]` tables of the server config.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub name: String,
+    /// The password hash printed by `kvs-server --hash-password`
+    pub password: String,
+    /// The command categories the user may run
+    #[serde(default)]
+    pub commands: Vec<Category>,
+    /// The key prefixes the user may access, empty means all keys
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+impl User {
+    /// Checks that the user may run `cmd`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NoPermission` if the category of the command is not
+    /// granted or a key is outside the allowed prefixes. Scans and watches must
+    /// stay within a single allowed prefix.
+    pub fn check(&self, cmd: &Cmd) -> Result<()> {
+        let Some(category) = Category::of(cmd) else {
+            return Ok(());
+        };
+        if !self.commands.contains(&category) {
+            return Err(KvsError::NoPermission(format!(
+                "user '{}' cannot run {} commands",
+                self.name, category
+            )));
+        }
+        let allowed = match cmd {
+            Cmd::Get(c) => self.allows(&c.key, &c.key),
+            Cmd::Set(c) => self.allows(&c.key, &c.key),
+            Cmd::Remove(c) => self.allows(&c.key, &c.key),
+            Cmd::VGet(c) => self.allows(&c.key, &c.key),
+            Cmd::VSet(c) => self.allows(&c.key, &c.key),
+            Cmd::VDel(c) => self.allows(&c.key, &c.key),
+            Cmd::Scan(c) => self.allows(&c.start, &c.end),
+            Cmd::Watch(c) => self.allows(&c.prefix, &c.prefix),
+            // monitor, cdc and sync export every key
+            Cmd::Monitor(_) | Cmd::Cdc(_) | Cmd::Sync(_) => self.keys.is_empty(),
+            _ => true,
+        };
+        if !allowed {
+            return Err(KvsError::NoPermission(format!(
+                "user '{}' cannot access these keys",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+
+    // whether both keys start with the same allowed prefix
+    fn allows(&self, first: &str, last: &str) -> bool {
+        self.keys.is_empty()
+            || self.keys.iter().any(|p| first.starts_with(p.as_str()) && last.starts_with(p.as_str()))
+    }
+}
+
+/// The users of a server, authentication is disabled if there are none.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+    users: Arc<BTreeMap<String, User>>,
+}
+
+impl Acl {
+    pub fn new(users: Vec<User>) -> Acl {
+        Acl {
+            users: Arc::new(users.into_iter().map(|u| (u.name.clone(), u)).collect()),
+        }
+    }
+
+    /// Returns whether clients have to authenticate.
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// Returns the user if `password` matches.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AuthFailed` for an unknown user or a wrong password.
+    pub fn authenticate(&self, name: &str, password: &str) -> Result<&User> {
+        match self.users.get(name) {
+            Some(user) if verify_password(&user.password, password) => Ok(user),
+            Some(_) => Err(KvsError::AuthFailed),
+            None => {
+                // 未知用户也计算一次哈希,响应时间不暴露用户是否存在
+                std::hint::black_box(derive(password, &[0; 16], HASH_ROUNDS));
+                Err(KvsError::AuthFailed)
+            }
+        }
+    }
+}
+
+/// Hashes a password with a random salt for the server config.
+pub fn hash_password(password: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    let hash = derive(password, &salt, HASH_ROUNDS);
+    format!("{}${}${}${}", HASH_SCHEME, HASH_ROUNDS, to_hex(&salt), to_hex(&hash))
+}
+
+/// Checks that `hash` has the format produced by `hash_password`.
+pub fn validate_hash(hash: &str) -> Result<()> {
+    parse_hash(hash).map(|_| ()).ok_or_else(|| {
+        KvsError::Config(format!("password hash '{}' is invalid, create one with kvs-server --hash-password", hash))
+    })
+}
+
+/// Returns whether `password` matches `hash`.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    let Some((rounds, salt, expected)) = parse_hash(hash) else {
+        return false;
+    };
+    let actual = derive(password, &salt, rounds);
+    // 逐字节比较全部内容,耗时与不匹配的位置无关
+    actual.len() == expected.len() && actual.iter().zip(expected.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn derive(password: &str, salt: &[u8], rounds: u32) -> [u8; HASH_LEN] {
+    let mut out = [0u8; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut out);
+    out
+}
+
+fn parse_hash(hash: &str) -> Option<(u32, Vec<u8>, Vec<u8>)> {
+    let mut parts = hash.split('$');
+    if parts.next()? != HASH_SCHEME {
+        return None;
+    }
+    let rounds = parts.next()?.parse().ok().filter(|r| *r > 0)?;
+    let salt = from_hex(parts.next()?)?;
+    let hash = from_hex(parts.next()?)?;
+    if parts.next().is_some() || hash.len() != HASH_LEN {
+        return None;
+    }
+    Some((rounds, salt, hash))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
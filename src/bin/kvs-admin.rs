@@ -0,0 +1,264 @@
+use clap::{Parser, Subcommand};
+use kvs::dump::{dump, load, DumpFormat};
+use kvs::engines::{restore, BackupManifest, ChangeOp, LogReader, StoreMeta};
+use kvs::{KVEngine, KvStore, KvsError, Result, SledStore};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+#[derive(Parser, Debug)]
+#[command(name = "kvs-admin", version, author, about = "Offline maintenance of key value store data directories")]
+struct KvsAdmin{
+    #[command(subcommand)]
+    command:Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command{
+    /// Validates a backup written by the BACKUP command and installs it into a data directory.
+    /// The server must be stopped
+    Restore{
+        /// The backup directory
+        backup:PathBuf,
+
+        /// The data directory of the server, the backup becomes its kvs or sled store
+        #[arg(short,long,default_value="./data")]
+        data:PathBuf,
+
+        /// Replace an existing store, it is kept next to the restored one as <store>.<time>.old
+        #[arg(long)]
+        force:bool,
+    },
+
+    /// Writes every key with its value and TTL to a dump file in key order. The server must be stopped
+    Dump{
+        /// The dump file
+        output:PathBuf,
+
+        /// The data directory of the server
+        #[arg(short,long,default_value="./data")]
+        data:PathBuf,
+
+        /// The format of the dump, json (JSON lines) or binary
+        #[arg(short,long,default_value="json",value_parser=parse_format)]
+        format:DumpFormat,
+
+        /// Continue an interrupted dump after its last complete record
+        #[arg(long)]
+        resume:bool,
+    },
+
+    /// Sets every key of a dump file in a store, creating it if needed. The server must be stopped
+    Load{
+        /// The dump file, json or binary
+        input:PathBuf,
+
+        /// The data directory of the server
+        #[arg(short,long,default_value="./data")]
+        data:PathBuf,
+
+        /// The engine of a new store [default: the engine of the existing store or kvs]
+        #[arg(short,long,value_parser=["kvs","sled"])]
+        engine:Option<String>,
+
+        /// Skip the records applied before the checkpoint of an interrupted load
+        #[arg(long)]
+        resume:bool,
+    },
+
+    /// Lists the generation logs of a kvs store with their stale bytes
+    List{
+        /// The data directory of the server
+        #[arg(short,long,default_value="./data")]
+        data:PathBuf,
+    },
+
+    /// Prints the records of a generation log: offset, type, key, size, TTL and compression of the value
+    Decode{
+        /// The log file, <data>/kvs/N.log
+        log:PathBuf,
+
+        /// Print the values of set records too
+        #[arg(long)]
+        values:bool,
+    },
+
+    /// Checks that every generation log of a kvs store can be read to its end, exits with 1 otherwise
+    Verify{
+        /// The data directory of the server
+        #[arg(short,long,default_value="./data")]
+        data:PathBuf,
+    },
+
+    /// Rewrites the readable records of a kvs store into a fresh generation, dropping unreadable ones.
+    /// The server must be stopped
+    Repair{
+        /// The data directory of the server
+        #[arg(short,long,default_value="./data")]
+        data:PathBuf,
+
+        /// Only report what would be salvaged
+        #[arg(long)]
+        dry_run:bool,
+    },
+}
+
+fn parse_format(s:&str)->std::result::Result<DumpFormat,String>{
+    s.parse()
+}
+
+fn main(){
+    let args=KvsAdmin::parse();
+    let res=match args.command{
+        Command::Restore{backup,data,force}=>run_restore(&backup,&data,force),
+        Command::Dump{output,data,format,resume}=>run_dump(&output,&data,format,resume),
+        Command::Load{input,data,engine,resume}=>run_load(&input,&data,engine,resume),
+        Command::List{data}=>run_list(&data),
+        Command::Decode{log,values}=>run_decode(&log,values),
+        Command::Verify{data}=>run_verify(&data),
+        Command::Repair{data,dry_run}=>run_repair(&data,dry_run),
+    };
+    if let Err(e)=res{
+        eprintln!("{}",e);
+        exit(1);
+    }
+}
+
+fn run_restore(backup:&Path,data:&Path,force:bool)->Result<()>{
+    let manifest=BackupManifest::load(backup)?;
+    // 数据目录中不能同时存在两种引擎的存储
+    for engine in ["kvs","sled"]{
+        if engine!=manifest.engine && StoreMeta::load(&data.join(engine))?.is_some(){
+            return Err(KvsError::StringError(format!("{} contains a {} store, move it away before restoring a {} backup",data.display(),engine,manifest.engine)));
+        }
+    }
+    let dest=data.join(&manifest.engine);
+    let manifest=restore(backup,&dest,force)?;
+    println!("Restored {} keys of store {} backed up at {} into {}",manifest.keys,manifest.store_id,manifest.created_at,dest.display());
+    Ok(())
+}
+
+//数据目录中存储使用的引擎,没有存储时返回None
+fn store_engine(data:&Path)->Result<Option<String>>{
+    let mut found=Vec::new();
+    for engine in ["kvs","sled"]{
+        if StoreMeta::load(&data.join(engine))?.is_some(){
+            found.push(engine.to_string());
+        }
+    }
+    match found.len(){
+        0=>Ok(None),
+        1=>Ok(found.pop()),
+        _=>Err(KvsError::StringError(format!("data directory {} contains both kvs and sled stores",data.display()))),
+    }
+}
+
+//在同一行刷新进度
+fn report(action:&str)->impl FnMut(u64){
+    move |count|{
+        eprint!("\r{} {} records",action,count);
+        io::stderr().flush().ok();
+    }
+}
+
+fn run_dump(output:&Path,data:&Path,format:DumpFormat,resume:bool)->Result<()>{
+    let engine=store_engine(data)?.ok_or_else(||KvsError::StringError(format!("no store in {}",data.display())))?;
+    let dir=data.join(&engine);
+    let count=match engine.as_str(){
+        "kvs"=>dump(&KvStore::open(dir)?,output,format,resume,report("Dumped"))?,
+        _=>dump(&SledStore::open(dir)?,output,format,resume,report("Dumped"))?,
+    };
+    eprintln!();
+    println!("Dumped {} records of the {} store to {}",count,engine,output.display());
+    Ok(())
+}
+
+fn run_load(input:&Path,data:&Path,engine:Option<String>,resume:bool)->Result<()>{
+    let current=store_engine(data)?;
+    let engine=match (engine,current){
+        (Some(engine),Some(current)) if engine!=current=>{
+            return Err(KvsError::EngineMismatch{expected:engine,found:current});
+        }
+        (Some(engine),_)=>engine,
+        (None,current)=>current.unwrap_or_else(||"kvs".to_string()),
+    };
+    let dir=data.join(&engine);
+    let (count,keys)=match engine.as_str(){
+        "kvs"=>load_into(KvStore::open(dir)?,input,resume)?,
+        _=>load_into(SledStore::open(dir)?,input,resume)?,
+    };
+    eprintln!();
+    println!("Loaded {} records into the {} store, it has {} keys",count,engine,keys);
+    Ok(())
+}
+
+fn load_into<E:KVEngine>(store:E,input:&Path,resume:bool)->Result<(u64,u64)>{
+    let count=load(&store,input,resume,report("Loaded"))?;
+    Ok((count,store.stats()?.keys))
+}
+
+//kvs存储的目录,日志损坏时存储无法打开,所以只检查目录是否存在
+fn kvs_dir(data:&Path)->Result<PathBuf>{
+    let dir=data.join("kvs");
+    if !dir.is_dir(){
+        return Err(KvsError::StringError(format!("no kvs store in {}",data.display())));
+    }
+    Ok(dir)
+}
+
+fn run_list(data:&Path)->Result<()>{
+    let reports=KvStore::inspect(&kvs_dir(data)?)?;
+    println!("{:>8} {:>9} {:>12} {:>9} {:>12} {:>7}  status","gen","kind","bytes","records","live bytes","stale");
+    for report in &reports{
+        let kind=if report.is_compacted(){"compacted"}else{"writes"};
+        let status=match &report.error{
+            Some(e)=>format!("unreadable at {}",e.offset),
+            None=>"ok".to_string(),
+        };
+        println!("{:>8} {:>9} {:>12} {:>9} {:>12} {:>6.1}%  {}",report.generation,kind,report.bytes,report.records,report.live_bytes,report.stale_ratio()*100.0,status);
+    }
+    let bytes:u64=reports.iter().map(|r|r.bytes).sum();
+    let live:u64=reports.iter().map(|r|r.live_bytes).sum();
+    println!("{} generations, {} bytes, {} live bytes",reports.len(),bytes,live);
+    Ok(())
+}
+
+fn run_decode(log:&Path,values:bool)->Result<()>{
+    let mut reader=LogReader::open(log)?;
+    println!("{:>12} {:>6} {:>8} {:>8} {:>11}  key","offset","type","size","ttl","compression");
+    for record in &mut reader{
+        let op=if record.op==ChangeOp::Remove{"remove"}else{"set"};
+        match record.value{
+            Some(value) if values=>println!("{:>12} {:>6} {:>8} {:>8} {:>11}  {:?} = {:?}",record.offset,op,record.len,record.ttl,record.compression,record.key,value),
+            _=>println!("{:>12} {:>6} {:>8} {:>8} {:>11}  {:?}",record.offset,op,record.len,record.ttl,record.compression,record.key),
+        }
+    }
+    if let Some(e)=reader.error(){
+        return Err(KvsError::StringError(format!("unreadable record at offset {}: {}",e.offset,e.message)));
+    }
+    Ok(())
+}
+
+fn run_verify(data:&Path)->Result<()>{
+    let reports=KvStore::inspect(&kvs_dir(data)?)?;
+    let broken:Vec<_>=reports.iter().filter_map(|r|r.error.as_ref().map(|e|(r.generation,e))).collect();
+    for (r#gen,e) in &broken{
+        println!("{}.log: unreadable record at offset {}: {}",r#gen,e.offset,e.message);
+    }
+    if !broken.is_empty(){
+        return Err(KvsError::StringError(format!("{} of {} generation logs are damaged, run kvs-admin repair",broken.len(),reports.len())));
+    }
+    println!("{} generation logs are intact",reports.len());
+    Ok(())
+}
+
+fn run_repair(data:&Path,dry_run:bool)->Result<()>{
+    let report=KvStore::repair(&kvs_dir(data)?,dry_run)?;
+    match &report.old_logs{
+        Some(old)=>println!("Salvaged {} keys from {} records into generation {}, dropped {} unreadable bytes, the old logs were moved to {}",
+            report.keys,report.records,report.generation,report.lost_bytes,old.display()),
+        None=>println!("Would salvage {} keys from {} records into generation {} and drop {} unreadable bytes",
+            report.keys,report.records,report.generation,report.lost_bytes),
+    }
+    Ok(())
+}
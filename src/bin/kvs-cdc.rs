@@ -0,0 +1,136 @@
+use clap::Parser;
+use kvs::{init_logger, tls, CdcRecord, KvClient, KvsError, Result, ServerAddr};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tokio::signal;
+use log::info;
+
+#[derive(Parser, Debug)]
+#[command(name = "kvs-cdc", version, author, about = "Exports the mutations of a key value store as JSON lines")]
+struct KvsCdc{
+    /// The server address, host:port or unix:///path/to/socket
+    #[arg(short,long,default_value=DEFAULT_ADDRESS,value_parser=parse_addr)]
+    addr:ServerAddr,
+
+    /// Export the mutations after this sequence number, 0 exports all mutations the server still has.
+    /// Defaults to the last sequence number in the output file, or 0
+    #[arg(short,long)]
+    from:Option<u64>,
+
+    /// Append the mutations to this file instead of writing them to stdout
+    #[arg(short,long)]
+    output:Option<PathBuf>,
+
+    /// The log directory to store the client log file
+    #[arg(short,long, default_value = "./log")]
+    log: String,
+
+    /// Authenticate as this user, it needs the admin permission on all keys
+    #[arg(short,long,requires="password")]
+    user: Option<String>,
+
+    /// The password of the user
+    #[arg(short,long,requires="user")]
+    password: Option<String>,
+
+    /// Connect over TLS trusting the server certificates signed by this PEM CA
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM client certificate for servers requiring one, requires --tls-key
+    #[arg(long,requires_all=["tls_key","tls_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[arg(long,requires="tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// The name the server certificate must match [default: the ip of --addr]
+    #[arg(long,requires="tls_ca")]
+    tls_server_name: Option<String>,
+}
+
+const DEFAULT_ADDRESS:&str="127.0.0.1:4001";
+
+fn parse_addr(s:&str)->std::result::Result<ServerAddr,String>{
+    s.parse()
+}
+
+//输出文件中最后一条变更的序号,文件不存在或为空时返回0
+fn last_seq(path:&Path)->Result<u64>{
+    let file=match File::open(path){
+        Ok(file)=>file,
+        Err(e) if e.kind()==io::ErrorKind::NotFound=>return Ok(0),
+        Err(e)=>return Err(e.into()),
+    };
+    let mut last=None;
+    for line in BufReader::new(file).lines(){
+        let line=line?;
+        if !line.trim().is_empty(){
+            last=Some(line);
+        }
+    }
+    match last{
+        Some(line)=>{
+            let record:CdcRecord=serde_json::from_str(&line)?;
+            Ok(record.seq)
+        },
+        None=>Ok(0),
+    }
+}
+
+#[tokio::main]
+async fn main()->Result<()>{
+    let args=KvsCdc::parse();
+    init_logger(&args.log,true)?;
+
+    let from=match (args.from,&args.output){
+        (Some(from),_)=>from,
+        (None,Some(path))=>last_seq(path)?,
+        (None,None)=>0,
+    };
+    let mut out:Box<dyn Write>=match &args.output{
+        Some(path)=>Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        None=>Box::new(io::stdout()),
+    };
+
+    info!("Exporting mutations after {} from {}",from,args.addr);
+    let mut client=match &args.tls_ca{
+        Some(ca)=>{
+            let ServerAddr::Tcp(addr)=args.addr else {
+                return Err(KvsError::StringError("TLS is only supported over TCP".to_string()));
+            };
+            let identity=args.tls_cert.as_deref().zip(args.tls_key.as_deref());
+            let config=tls::client_config(ca,identity)?;
+            let name=args.tls_server_name.clone().unwrap_or_else(||addr.ip().to_string());
+            KvClient::new_tls(addr,&name,config).await?
+        },
+        None=>KvClient::connect(&args.addr).await?,
+    };
+    if let (Some(user),Some(password))=(&args.user,&args.password){
+        client.auth(user,password).await?;
+    }
+    let mut stream=client.cdc(from).await?;
+    loop{
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                info!("Export stopped by Ctrl+C");
+                break;
+            }
+            record = stream.next() => {
+                match record?{
+                    Some(record)=>{
+                        writeln!(out,"{}",serde_json::to_string(&record)?)?;
+                        out.flush()?;
+                    },
+                    None=>{
+                        eprintln!("Connection closed by server");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
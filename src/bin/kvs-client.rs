@@ -1,7 +1,8 @@
 use clap::Parser;
-use kvs::common::{GetCmd,SetCmd,RemoveCmd,ScanCmd,DelVector, GetVector, SetVector,PingCmd};
-use kvs::{init_logger, validate_vector, Cmd, KvClient, KvsError, Result};
-use std::net::SocketAddr;
+use kvs::common::{AuthCmd,GetCmd,SetCmd,RemoveCmd,ScanCmd,DelVector, GetVector, SetVector,PingCmd,InfoCmd,DbSizeCmd,ConfigGetCmd,ConfigSetCmd,SlowlogGetCmd,SlowlogResetCmd,PublishCmd,ReplicaOfCmd,ClusterSlotsCmd,ClusterSetSlotCmd,ClusterMigrateCmd,BackupCmd};
+use kvs::cluster::{parse_slots,SlotMap,SlotState};
+use kvs::{init_logger, BackupManifest, ClusterClient, tls, validate_vector, Cmd, KvClient, KvsError, Result, ServerAddr, ServerInfo, SlowlogEntry};
+use std::path::PathBuf;
 use tokio::signal;
 use std::io::{self,Write};
 use log::{warn,info};
@@ -10,23 +11,70 @@ use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 #[derive(Parser, Debug)]
 #[command(name = "kvs-client", version, author, about = "A key value store client")]
 struct KvsClient{
+    /// The server address, host:port or unix:///path/to/socket
     #[arg(short,long,default_value=DEFAULT_ADDRESS,value_parser=parse_addr)]
-    addr:SocketAddr,
+    addr:ServerAddr,
 
     /// The log directory to store the client log file
     #[arg(short,long, default_value = "./log")]
     log: String,
+
+    /// Authenticate as this user
+    #[arg(short,long,requires="password")]
+    user: Option<String>,
+
+    /// The password of the user
+    #[arg(short,long,requires="user")]
+    password: Option<String>,
+
+    /// Connect over TLS trusting the server certificates signed by this PEM CA
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM client certificate for servers requiring one, requires --tls-key
+    #[arg(long,requires_all=["tls_key","tls_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[arg(long,requires="tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// The name the server certificate must match [default: the ip of --addr]
+    #[arg(long,requires="tls_ca")]
+    tls_server_name: Option<String>,
+}
+
+impl KvsClient{
+    //连接服务端,指定了CA时使用TLS,指定了用户时先认证
+    async fn connect(&self)->Result<KvClient>{
+        let mut client=match &self.tls_ca{
+            Some(ca)=>{
+                let ServerAddr::Tcp(addr)=self.addr else {
+                    return Err(KvsError::StringError("TLS is only supported over TCP".to_string()));
+                };
+                let identity=self.tls_cert.as_deref().zip(self.tls_key.as_deref());
+                let config=tls::client_config(ca,identity)?;
+                let name=self.tls_server_name.clone().unwrap_or_else(||addr.ip().to_string());
+                KvClient::new_tls(addr,&name,config).await?
+            },
+            None=>KvClient::connect(&self.addr).await?,
+        };
+        if let (Some(user),Some(password))=(&self.user,&self.password){
+            client.auth(user,password).await?;
+        }
+        Ok(client)
+    }
 }
 
 const DEFAULT_ADDRESS:&str="127.0.0.1:4001";
 
-fn parse_addr(s:&str)->std::result::Result<SocketAddr,String>{
-    s.parse::<SocketAddr>().map_err(|e|format!("Invalid address '{}': {}", s, e))
+fn parse_addr(s:&str)->std::result::Result<ServerAddr,String>{
+    s.parse()
 }
 
-async fn parse_cmd(cmd:&str)->Result<Cmd>{
+async fn parse_cmd(line:&str)->Result<Cmd>{
     //处理ping命令
-    let mut iter=cmd.split_whitespace();
+    let mut iter=line.split_whitespace();
     let cmd=iter.next().ok_or(KvsError::InvalidCommand)?;
     if cmd.eq_ignore_ascii_case("ping"){
         let mut message=String::from("");
@@ -39,7 +87,17 @@ async fn parse_cmd(cmd:&str)->Result<Cmd>{
         }
         return Ok(Cmd::Ping(PingCmd { message}));
     }
-    let parts:Vec<&str>=cmd.splitn(2, ' ').collect();
+    //处理无参数的管理命令
+    if cmd.eq_ignore_ascii_case("info") || cmd.eq_ignore_ascii_case("dbsize"){
+        if iter.next().is_some(){
+            return Err(KvsError::InvalidCommand);
+        }
+        if cmd.eq_ignore_ascii_case("info"){
+            return Ok(Cmd::Info(InfoCmd));
+        }
+        return Ok(Cmd::DbSize(DbSizeCmd));
+    }
+    let parts:Vec<&str>=line.trim().splitn(2, ' ').collect();
     if parts.len()<2{
         return Err(KvsError::InvalidCommand);
     }
@@ -123,6 +181,101 @@ async fn parse_cmd(cmd:&str)->Result<Cmd>{
             }
             Cmd::VDel(DelVector { key: key.to_string()})
         }
+        "config"=>{
+            let mut iter=remain.split_whitespace();
+            let sub=iter.next().ok_or(KvsError::InvalidCommand)?;
+            if sub.eq_ignore_ascii_case("get"){
+                let pattern=iter.next().ok_or(KvsError::InvalidCommand)?;
+                if iter.next().is_some(){
+                    return Err(KvsError::InvalidCommand);
+                }
+                Cmd::ConfigGet(ConfigGetCmd { pattern: pattern.to_string()})
+            }else if sub.eq_ignore_ascii_case("set"){
+                let key=iter.next().ok_or(KvsError::InvalidCommand)?;
+                let value=iter.next().ok_or(KvsError::InvalidCommand)?;
+                if iter.next().is_some(){
+                    return Err(KvsError::InvalidCommand);
+                }
+                Cmd::ConfigSet(ConfigSetCmd { key: key.to_string(), value: value.to_string()})
+            }else{
+                return Err(KvsError::InvalidCommand);
+            }
+        }
+        "auth"=>{
+            let mut iter=remain.split_whitespace();
+            let user=iter.next().ok_or(KvsError::InvalidCommand)?;
+            let password=iter.next().ok_or(KvsError::InvalidCommand)?;
+            if iter.next().is_some(){
+                return Err(KvsError::InvalidCommand);
+            }
+            Cmd::Auth(AuthCmd { user: user.to_string(), password: password.to_string()})
+        }
+        "publish"=>{
+            //消息为channel之后的全部内容,可以包含空格
+            let parts:Vec<&str>=remain.splitn(2, ' ').collect();
+            if parts.len()!=2{
+                return Err(KvsError::InvalidCommand);
+            }
+            Cmd::Publish(PublishCmd { channel: parts[0].to_string(), message: parts[1].trim().to_string()})
+        }
+        "slowlog"=>{
+            let mut iter=remain.split_whitespace();
+            let sub=iter.next().ok_or(KvsError::InvalidCommand)?;
+            if sub.eq_ignore_ascii_case("get"){
+                //默认返回最新的10条
+                let count=match iter.next(){
+                    Some(n)=>n.parse().map_err(|_|KvsError::StringError("count invalid".to_string()))?,
+                    None=>10,
+                };
+                if iter.next().is_some(){
+                    return Err(KvsError::InvalidCommand);
+                }
+                Cmd::SlowlogGet(SlowlogGetCmd { count })
+            }else if sub.eq_ignore_ascii_case("reset"){
+                if iter.next().is_some(){
+                    return Err(KvsError::InvalidCommand);
+                }
+                Cmd::SlowlogReset(SlowlogResetCmd)
+            }else{
+                return Err(KvsError::InvalidCommand);
+            }
+        }
+        "replicaof"=>{
+            //replicaof no one 将副本提升为leader
+            let args:Vec<&str>=remain.split_whitespace().collect();
+            let leader=match args.as_slice(){
+                [no,one] if no.eq_ignore_ascii_case("no")&&one.eq_ignore_ascii_case("one")=>String::new(),
+                [addr]=>addr.parse::<ServerAddr>().map_err(KvsError::StringError)?.to_string(),
+                _=>return Err(KvsError::InvalidCommand),
+            };
+            Cmd::ReplicaOf(ReplicaOfCmd { leader })
+        }
+        "cluster"=>{
+            let args:Vec<&str>=remain.split_whitespace().collect();
+            match args.as_slice(){
+                [sub] if sub.eq_ignore_ascii_case("slots")=>Cmd::ClusterSlots(ClusterSlotsCmd),
+                //cluster setslot <slots> <state> [addr]
+                [sub,slots,state,addr @ ..] if sub.eq_ignore_ascii_case("setslot") && addr.len()<=1=>{
+                    let slots=parse_slots(slots)?;
+                    let state=SlotState::from_parts(state,addr.first().copied().unwrap_or(""))?;
+                    let (state,addr)=state.parts();
+                    Cmd::ClusterSetSlot(ClusterSetSlotCmd { start: *slots.start(), end: *slots.end(), state: state.to_string(), addr: addr.to_string()})
+                }
+                [sub,slot] if sub.eq_ignore_ascii_case("migrate")=>{
+                    let slot=slot.parse().map_err(|_|KvsError::StringError("slot invalid".to_string()))?;
+                    Cmd::ClusterMigrate(ClusterMigrateCmd { slot })
+                }
+                _=>return Err(KvsError::InvalidCommand),
+            }
+        }
+        "backup"=>{
+            //备份目录在服务端上
+            let dir=remain.trim();
+            if dir.is_empty(){
+                return Err(KvsError::InvalidCommand);
+            }
+            Cmd::Backup(BackupCmd { dir: dir.to_string() })
+        }
         _=>{
             return Err(KvsError::InvalidCommand);
         }
@@ -138,14 +291,37 @@ async fn handle_request(client:&mut KvClient,cmd:&str)->Result<()>{
             if let Cmd::Get(_)=cmd{
                 println!("{}",response);
             } else if let Cmd::Scan(_)=cmd{
-                let v:Vec<&str>=response.split_whitespace().collect();
-                for s in v{
-                    println!("{}",s);
+                let pairs:Vec<(String,String)>=serde_json::from_str(&response)?;
+                for (k,v) in pairs{
+                    println!("{} {}",k,v);
                 }
             }else if let Cmd::VGet(_)=cmd{
                 println!("{}",response);
             }else if let Cmd::Ping(_)=cmd{
                 println!("{}",response);
+            }else if let Cmd::Info(_)=cmd{
+                let info:ServerInfo=serde_json::from_str(&response)?;
+                print_info(&info);
+            }else if let Cmd::DbSize(_)=cmd{
+                println!("{}",response);
+            }else if let Cmd::Publish(_)=cmd{
+                println!("{}",response);
+            }else if let Cmd::ConfigGet(_)=cmd{
+                let pairs:Vec<(String,String)>=serde_json::from_str(&response)?;
+                for (k,v) in pairs{
+                    println!("{} {}",k,v);
+                }
+            }else if let Cmd::ClusterSlots(_)=cmd{
+                let map:SlotMap=serde_json::from_str(&response)?;
+                print_slots(&map);
+            }else if let Cmd::ClusterMigrate(_)=cmd{
+                println!("{}",response);
+            }else if let Cmd::Backup(c)=&cmd{
+                let manifest:BackupManifest=serde_json::from_str(&response)?;
+                println!("Backup of {} keys in {} files ({} bytes) written to {}",manifest.keys,manifest.files.len(),manifest.bytes(),c.dir);
+            }else if let Cmd::SlowlogGet(_)=cmd{
+                let entries:Vec<SlowlogEntry>=serde_json::from_str(&response)?;
+                print_slowlog(&entries);
             }else{
                 println!("Ok");
             }
@@ -161,6 +337,153 @@ async fn handle_request(client:&mut KvClient,cmd:&str)->Result<()>{
     Ok(())
 }
 
+//把槽位及其中的key移动到to节点,并通知集群中的全部节点
+async fn run_move(kvs:&KvsClient,slot:&str,to:&str)->Result<()>{
+    let slot:u16=slot.parse().map_err(|_|KvsError::StringError("slot invalid".to_string()))?;
+    let to=to.parse::<ServerAddr>().map_err(KvsError::StringError)?.to_string();
+    let auth=kvs.user.as_deref().zip(kvs.password.as_deref());
+    let mut cluster=ClusterClient::connect(std::slice::from_ref(&kvs.addr),auth).await?;
+    let moved=cluster.migrate_slot(slot,&to).await?;
+    println!("Moved slot {} with {} keys to {}",slot,moved,to);
+    Ok(())
+}
+
+//使用单独的连接打印服务端处理的每条命令,直到Ctrl+C或服务端关闭连接
+async fn run_monitor(kvs:&KvsClient)->Result<()>{
+    let mut monitor=kvs.connect().await?.monitor().await?;
+    println!("OK, press Ctrl+C to stop");
+    loop{
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                info!("Monitor stopped by Ctrl+C");
+                break;
+            }
+            event = monitor.next() => {
+                match event?{
+                    Some(event)=>println!("{}",event),
+                    None=>{
+                        println!("Connection closed by server");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//使用单独的连接打印前缀为prefix的key的变更,直到Ctrl+C或服务端关闭连接
+async fn run_watch(kvs:&KvsClient,prefix:&str)->Result<()>{
+    let mut watch=kvs.connect().await?.watch(prefix).await?;
+    println!("Watching keys with prefix '{}', press Ctrl+C to stop",prefix);
+    loop{
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                info!("Watch stopped by Ctrl+C");
+                break;
+            }
+            event = watch.next() => {
+                match event?{
+                    Some(e)=>println!("{:?} {} (version {})",e.op,e.key,e.version),
+                    None=>{
+                        println!("Connection closed by server");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//使用单独的连接订阅频道或模式并打印收到的消息,直到Ctrl+C或服务端关闭连接
+async fn run_subscribe(kvs:&KvsClient,pattern:bool,names:&[&str])->Result<()>{
+    if names.is_empty(){
+        return Err(KvsError::InvalidCommand);
+    }
+    let client=kvs.connect().await?;
+    let mut sub=if pattern{
+        client.psubscribe(names).await?
+    }else{
+        client.subscribe(names).await?
+    };
+    println!("Subscribed to {}, press Ctrl+C to stop",names.join(" "));
+    loop{
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                info!("Subscription stopped by Ctrl+C");
+                break;
+            }
+            message = sub.next() => {
+                match message?{
+                    Some(m)=>match m.pattern{
+                        Some(p)=>println!("[{}] {}: {}",p,m.channel,m.payload),
+                        None=>println!("{}: {}",m.channel,m.payload),
+                    },
+                    None=>{
+                        println!("Connection closed by server");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_info(info:&ServerInfo){
+    println!("version:{}",info.version);
+    println!("uptime_secs:{}",info.uptime_secs);
+    println!("engine:{}",info.engine.engine);
+    println!("keys:{}",info.engine.keys);
+    println!("live_bytes:{}",info.engine.live_bytes);
+    println!("stale_bytes:{}",info.engine.stale_bytes);
+    println!("disk_bytes:{}",info.engine.disk_bytes);
+    println!("compactions:{}",info.engine.compactions);
+    println!("active_connections:{}",info.connections.active_connections);
+    println!("total_connections:{}",info.connections.total_connections);
+    println!("rejected_connections:{}",info.connections.rejected_connections);
+    println!("commands_processed:{}",info.commands_processed);
+    let replication=&info.replication;
+    println!("role:{}",replication.role);
+    if let Some(leader)=&replication.leader{
+        println!("leader:{}",leader);
+        println!("link_up:{}",replication.link_up);
+        println!("applied_seq:{}",replication.applied_seq);
+        match replication.lag_secs{
+            Some(lag)=>println!("lag_secs:{}",lag),
+            None=>println!("lag_secs:unknown"),
+        }
+        println!("full_syncs:{}",replication.full_syncs);
+    }
+    println!("replicas:{}",replication.replicas);
+}
+
+fn print_slots(map:&SlotMap){
+    println!("myself:{}",map.myself);
+    for range in &map.slots{
+        println!("{}-{} {}",range.start,range.end,range.addr);
+    }
+    for (slot,addr) in &map.migrating{
+        println!("{} migrating to {}",slot,addr);
+    }
+    for (slot,addr) in &map.importing{
+        println!("{} importing from {}",slot,addr);
+    }
+}
+
+fn print_slowlog(entries:&[SlowlogEntry]){
+    if entries.is_empty(){
+        println!("(empty)");
+    }
+    for entry in entries{
+        let time=chrono::DateTime::from_timestamp(entry.timestamp as i64,0)
+            .map(|t|t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        println!("{} {} {} {}us {}",entry.id,time,entry.client,entry.duration_micros,entry.command.join(" "));
+    }
+}
+
 fn print_welcome() -> Result<()> {
     let mut stdout = StandardStream::stdout(ColorChoice::Always);
 
@@ -204,7 +527,7 @@ async fn main()->Result<()>{
     //初始化日志
     init_logger(&kvs.log,true)?;
     
-    let mut client=KvClient::new(kvs.addr).await?;
+    let mut client=kvs.connect().await?;
 
     print_welcome()?;
     loop {
@@ -234,6 +557,40 @@ async fn main()->Result<()>{
                             info!("Received exit command, exit normal");
                             break;
                         }
+                        let mut words=line.split_whitespace();
+                        let first=words.next().unwrap_or("");
+                        if first.eq_ignore_ascii_case("subscribe") || first.eq_ignore_ascii_case("psubscribe") {
+                            let names:Vec<&str>=words.collect();
+                            if let Err(e)=run_subscribe(&kvs,first.eq_ignore_ascii_case("psubscribe"),&names).await {
+                                println!("{}", e);
+                            }
+                            continue;
+                        }
+                        if first.eq_ignore_ascii_case("watch") {
+                            let prefix=words.next().unwrap_or("");
+                            if let Err(e)=run_watch(&kvs,prefix).await {
+                                println!("{}", e);
+                            }
+                            continue;
+                        }
+                        //cluster move <slot> <addr>
+                        if first.eq_ignore_ascii_case("cluster") && words.next().is_some_and(|w|w.eq_ignore_ascii_case("move")) {
+                            let args:Vec<&str>=words.collect();
+                            let res=match args.as_slice(){
+                                [slot,to]=>run_move(&kvs,slot,to).await,
+                                _=>Err(KvsError::InvalidCommand),
+                            };
+                            if let Err(e)=res {
+                                println!("{}", e);
+                            }
+                            continue;
+                        }
+                        if line.eq_ignore_ascii_case("monitor") {
+                            if let Err(e)=run_monitor(&kvs).await {
+                                println!("{}", e);
+                            }
+                            continue;
+                        }
                        
                         // 发送请求并打印响应
                         match handle_request(&mut client,line).await {
@@ -0,0 +1,92 @@
+use clap::Parser;
+use kvs::acl::Acl;
+use kvs::{init_logger, Config, Proxy, ProxyOptions, Result, ServerAddr};
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+use tokio::signal;
+use log::info;
+
+#[derive(Parser, Debug)]
+#[command(name = "kvs-proxy", version, author, about = "Shards keys over several key value servers by consistent hashing")]
+struct KvsProxy{
+    /// The address the proxy listens on
+    #[arg(short,long,default_value=DEFAULT_ADDRESS)]
+    addr:SocketAddr,
+
+    /// A backend server, host:port or unix:///path/to/socket, repeat it for every backend
+    #[arg(short,long,required=true,value_parser=parse_addr)]
+    backend:Vec<ServerAddr>,
+
+    /// Points of every backend on the hash ring
+    #[arg(long,default_value_t=160)]
+    vnodes:usize,
+
+    /// Milliseconds between two health checks of a backend
+    #[arg(long,default_value_t=1000)]
+    health_interval:u64,
+
+    /// Failed health checks in a row after which a backend is down
+    #[arg(long,default_value_t=3)]
+    failure_limit:u32,
+
+    /// Move the keys of down backends to the next backend on the ring instead of failing them
+    #[arg(long)]
+    eject:bool,
+
+    /// Milliseconds to wait for a backend to connect or respond
+    #[arg(long,default_value_t=5000)]
+    timeout:u64,
+
+    /// Authenticate with the backends as this user
+    #[arg(short,long,requires="password")]
+    user: Option<String>,
+
+    /// The password of the user
+    #[arg(short,long,requires="user")]
+    password: Option<String>,
+
+    /// A config file whose [[users]] tables are the users clients authenticate as, required with --user
+    #[arg(long)]
+    users: Option<String>,
+
+    /// The log directory to store the proxy log file
+    #[arg(short,long, default_value = "./log/proxy")]
+    log: String,
+}
+
+const DEFAULT_ADDRESS:&str="127.0.0.1:4000";
+
+fn parse_addr(s:&str)->std::result::Result<ServerAddr,String>{
+    s.parse()
+}
+
+#[tokio::main]
+async fn main()->Result<()>{
+    let args=KvsProxy::parse();
+    init_logger(&args.log,false)?;
+
+    let options=ProxyOptions{
+        backends:args.backend,
+        vnodes:args.vnodes,
+        health_interval:Duration::from_millis(args.health_interval),
+        failure_limit:args.failure_limit,
+        eject:args.eject,
+        timeout:Duration::from_millis(args.timeout),
+        auth:args.user.zip(args.password),
+        users:match &args.users{
+            Some(path)=>Acl::new(Config::load(path)?.users),
+            None=>Acl::default(),
+        },
+    };
+    let shut_down=Arc::new(AtomicBool::new(false));
+    let proxy=Proxy::bind(args.addr,options,shut_down.clone()).await?;
+    // Ctrl+C后设置关闭标志,run在下一次检查时退出
+    tokio::spawn(async move{
+        if signal::ctrl_c().await.is_ok(){
+            info!("Proxy stopped by Ctrl+C");
+            shut_down.store(true,Ordering::SeqCst);
+        }
+    });
+    proxy.run().await
+}
@@ -1,35 +1,187 @@
 use clap::Parser;
-use kvs::{KvServer,Result,KvStore,SledStore,ThreadPool,ShardThreadPool,init_logger};
+use kvs::raft::{RaftNode,Role,TcpTransport};
+use kvs::{KvServer,ServerHandle,Config,KvsError,StoreMeta,Result,KvStore,SledStore,KVEngine,ThreadPool,ShardThreadPool,init_logger,set_log_level,ServerAddr};
 use log::{info, error, warn};
-use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
-use std::path::Path;
+use std::path::{Path,PathBuf};
+use std::thread;
+use std::time::Duration;
 
+//连接其他Raft节点和等待回复的超时
+const RAFT_TIMEOUT:Duration=Duration::from_millis(500);
 
-const DEFAULT_ADDRESS:&str="127.0.0.1:4001";
-
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(name = "kvs-server", version, author, about = "A key value store server")]
 struct KvsServer{
-    /// The address to bind the server
-    #[clap(short,long, default_value = DEFAULT_ADDRESS, value_parser = parse_addr)]
-    addr: SocketAddr,
+    /// The TOML config file, command line options override its settings
+    #[clap(short,long)]
+    config: Option<PathBuf>,
+
+    /// The address to bind the server [default: 127.0.0.1:4001]
+    #[clap(short,long, value_parser = parse_addr)]
+    addr: Option<SocketAddr>,
 
-    /// The data directory to store the key-value pairs
-    #[clap(short,long, default_value = "./data")]
-    data: String,
+    /// The data directory to store the key-value pairs [default: ./data]
+    #[clap(short,long)]
+    data: Option<String>,
 
-    /// The log directory to store the log file
-    #[clap(short,long, default_value = "./log")]
-    log: String,
+    /// The log directory to store the log file [default: ./log]
+    #[clap(short,long)]
+    log: Option<String>,
 
-    /// The storage engine to use
-    #[clap(short,long, default_value = "kvs")]
+    /// The storage engine to use [default: the previous engine or kvs]
+    #[clap(short,long)]
     engine: Option<Engine>,
+
+    /// Seconds to wait for in-flight requests on shutdown before forcing exit [default: 30]
+    #[clap(long)]
+    shutdown_timeout: Option<u64>,
+
+    /// Maximum number of concurrent client connections, 0 means unlimited [default: 1024]
+    #[clap(long)]
+    max_connections: Option<usize>,
+
+    /// Seconds after which an idle connection is closed, 0 disables it [default: 0]
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+
+    /// Seconds allowed to receive a whole request frame, 0 disables it [default: 0]
+    #[clap(long)]
+    read_timeout: Option<u64>,
+
+    /// Seconds allowed to write a response, 0 disables it [default: 0]
+    #[clap(long)]
+    write_timeout: Option<u64>,
+
+    /// The address to serve Prometheus metrics on, e.g. 127.0.0.1:9001 [default: disabled]
+    #[clap(long, value_parser = parse_addr)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// The address to serve the Redis protocol on, e.g. 127.0.0.1:6379 [default: disabled]
+    #[clap(long, value_parser = parse_addr)]
+    resp_addr: Option<SocketAddr>,
+
+    /// The address to serve the HTTP/JSON gateway on, e.g. 127.0.0.1:8080 [default: disabled]
+    #[clap(long, value_parser = parse_addr)]
+    http_addr: Option<SocketAddr>,
+
+    /// Commands slower than this many milliseconds are recorded in the slowlog, 0 disables it [default: 10]
+    #[clap(long)]
+    slowlog_threshold: Option<u64>,
+
+    /// Maximum number of entries kept in the slowlog [default: 128]
+    #[clap(long)]
+    slowlog_max_len: Option<usize>,
+
+    /// Also listen on this Unix socket [default: disabled]
+    #[clap(long)]
+    unix_socket: Option<String>,
+
+    /// Octal permissions of the Unix socket file, e.g. 660 [default: the umask]
+    #[clap(long, value_parser = parse_mode, requires = "unix_socket")]
+    unix_socket_mode: Option<u32>,
+
+    /// Only listen on the Unix socket, not on --addr
+    #[clap(long, requires = "unix_socket")]
+    no_tcp: bool,
+
+    /// PEM certificate chain to serve clients over TLS, requires --tls-key
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// PEM private key of the TLS certificate
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// PEM CA certificates, clients have to present a certificate signed by one of them
+    #[clap(long)]
+    tls_client_ca: Option<String>,
+
+    /// Start as a replica of this leader, host:port or unix:///path [default: disabled]
+    #[clap(long, value_name = "LEADER")]
+    replica_of: Option<ServerAddr>,
+
+    /// Print the hash of a password for the users in the config file and exit
+    #[clap(long, value_name = "PASSWORD")]
+    hash_password: Option<String>,
 }
 
+impl KvsServer{
+    //读取配置文件,并用命令行参数覆盖
+    fn load_config(&self)->Result<Config>{
+        let mut config=match &self.config{
+            Some(path)=>Config::load(path)?,
+            None=>Config::default(),
+        };
+        if let Some(addr)=self.addr{
+            config.server.addr=addr.to_string();
+        }
+        if let Some(data)=&self.data{
+            config.engine.data=data.clone();
+        }
+        if let Some(log)=&self.log{
+            config.log.dir=log.clone();
+        }
+        if let Some(engine)=self.engine{
+            config.engine.name=Some(engine.to_string());
+        }
+        if let Some(t)=self.shutdown_timeout{
+            config.server.shutdown_timeout=t;
+        }
+        if let Some(n)=self.max_connections{
+            config.server.max_connections=n;
+        }
+        if let Some(t)=self.idle_timeout{
+            config.server.idle_timeout=t;
+        }
+        if let Some(t)=self.read_timeout{
+            config.server.read_timeout=t;
+        }
+        if let Some(t)=self.write_timeout{
+            config.server.write_timeout=t;
+        }
+        if let Some(addr)=self.metrics_addr{
+            config.server.metrics_addr=Some(addr.to_string());
+        }
+        if let Some(addr)=self.resp_addr{
+            config.server.resp_addr=Some(addr.to_string());
+        }
+        if let Some(addr)=self.http_addr{
+            config.server.http_addr=Some(addr.to_string());
+        }
+        if let Some(ms)=self.slowlog_threshold{
+            config.server.slowlog_threshold=ms;
+        }
+        if let Some(n)=self.slowlog_max_len{
+            config.server.slowlog_max_len=n;
+        }
+        if let Some(path)=&self.unix_socket{
+            config.server.unix_socket=Some(path.clone());
+        }
+        if let Some(mode)=self.unix_socket_mode{
+            config.server.unix_socket_mode=Some(mode);
+        }
+        if self.no_tcp{
+            config.server.tcp=false;
+        }
+        if let Some(cert)=&self.tls_cert{
+            config.server.tls_cert=Some(cert.clone());
+        }
+        if let Some(key)=&self.tls_key{
+            config.server.tls_key=Some(key.clone());
+        }
+        if let Some(ca)=&self.tls_client_ca{
+            config.server.tls_client_ca=Some(ca.clone());
+        }
+        if let Some(leader)=&self.replica_of{
+            config.replication.replica_of=Some(leader.to_string());
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
 
 #[derive(Clone,Copy,Debug,PartialEq,Eq)]
 enum Engine {
@@ -50,6 +202,15 @@ impl clap::ValueEnum for Engine {
     }
 }
 
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
+        }
+    }
+}
+
 impl std::str::FromStr for Engine {
     type Err = String;
 
@@ -66,13 +227,72 @@ fn parse_addr(s:&str)->std::result::Result<SocketAddr,String>{
     s.parse::<SocketAddr>().map_err(|e|format!("Invalid address '{}': {}", s, e))
 }
 
+fn parse_mode(s:&str)->std::result::Result<u32,String>{
+    let digits=s.strip_prefix("0o").unwrap_or(s);
+    u32::from_str_radix(digits,8).map_err(|e|format!("Invalid mode '{}': {}", s, e))
+}
+
+//按配置监听TCP和/或Unix socket,以及Redis协议和HTTP端口,配置了主节点时作为副本启动
+fn build_server<E:KVEngine>(store:E,config:&Config,shutdown:Arc<AtomicBool>,pool:ShardThreadPool,tls:Option<Arc<rustls::ServerConfig>>)->Result<KvServer<E,ShardThreadPool>>{
+    let server=match (&config.server.unix_socket,config.server.tcp){
+        #[cfg(unix)]
+        (Some(path),false)=>KvServer::unix(store,path,config.server.unix_socket_mode,shutdown,pool)?,
+        #[cfg(unix)]
+        (Some(path),true)=>KvServer::new(store,config.addr()?,shutdown,pool)?.with_unix_socket(path,config.server.unix_socket_mode)?,
+        _=>KvServer::new(store,config.addr()?,shutdown,pool)?,
+    };
+    let server=match config.resp_addr()?{
+        Some(addr)=>server.with_resp(addr)?,
+        None=>server,
+    };
+    let server=match config.http_addr()?{
+        Some(addr)=>server.with_http(addr)?,
+        None=>server,
+    };
+    let server=server.with_options(config.server_options());
+    let server=match (&config.replication.user,&config.replication.password){
+        (Some(user),Some(password))=>server.with_replication_auth(user,password),
+        _=>server,
+    };
+    let server=match config.replica_of()?{
+        Some(leader)=>server.with_replica_of(leader)?,
+        None=>server,
+    };
+    let mut server=match config.cluster()?{
+        Some((myself,slots))=>server.with_cluster(&myself,&slots)?,
+        None=>server,
+    };
+    for node in &config.cluster.nodes{
+        if let Some(resp_addr)=&node.resp_addr{
+            server=server.with_cluster_resp_addr(&node.addr,resp_addr);
+        }
+    }
+    Ok(match tls{
+        Some(tls)=>server.with_tls(tls),
+        None=>server,
+    })
+}
+
 fn main(){
     //命令行参数解析
     let args=KvsServer::parse();
+    if let Some(password)=&args.hash_password{
+        println!("{}",kvs::acl::hash_password(password));
+        return;
+    }
+    let config=match args.load_config(){
+        Ok(config)=>config,
+        Err(e)=>{
+            eprintln!("{}",e);
+            std::process::exit(1);
+        }
+    };
     //初始化日志
-    init_logger(&args.log,false).unwrap();
+    init_logger(&config.log.dir,false).unwrap();
+    set_log_level(config.log_level().unwrap());
     
-    info!("Server starting with Address: {}",args.addr);
+    let addr=config.addr().unwrap();
+    info!("Server starting with Address: {}",addr);
     
     //engine规则
     /*
@@ -84,11 +304,14 @@ fn main(){
             非第一次启动得以上一次启动的engine作为默认引擎
      */
 
-    let res=get_current_engine().and_then(move |cur_engine|{
-        if let Some(engine)=args.engine{
+    let configured=config.engine.name.as_deref().map(|name|name.parse::<Engine>().unwrap());
+    let data_path=config.engine.data.clone();
+    let res=get_current_engine(Path::new(&data_path)).and_then(|cur_engine|{
+        if let Some(engine)=configured{
             if let Some(cur_engine)=cur_engine{
                 if engine!=cur_engine{
                     error!("Cannot specify engine '{:?}' because the current engine is '{:?}'",engine,cur_engine);
+                    eprintln!("Cannot specify engine '{}' because the data directory {} uses '{}'",engine,data_path,cur_engine);
                     std::process::exit(1);
                 }
             }
@@ -100,55 +323,183 @@ fn main(){
 
     if let Err(e)=res{
         error!("{}",e);
+        eprintln!("{}",e);
         std::process::exit(1);
     }
     let engine =res.unwrap();
     info!("Storage Engine:{:?}",engine);
 
-    let path=current_dir().unwrap().join("engine");
-    fs::write(path, format!("{:?}", engine)).unwrap();
-
     let shutdown = Arc::new(AtomicBool::new(false));
     let shutdown_clone = shutdown.clone();
 
-    // 捕获 Ctrl+C 信号
+    // 捕获 Ctrl+C(SIGINT) 和 SIGTERM 信号
     ctrlc::set_handler(move || {
         shutdown_clone.store(true, Ordering::SeqCst);
     }).expect("Error setting Ctrl+C handler");
     
 
-    let pool=ShardThreadPool::new(4).unwrap();
-    let data_path=args.data;
+    let tls=match config.tls(){
+        Ok(tls)=>tls,
+        Err(e)=>{
+            error!("{}",e);
+            eprintln!("{}",e);
+            std::process::exit(1);
+        }
+    };
+    if tls.is_some(){
+        info!("TLS enabled, client certificates {}",if config.server.tls_client_ca.is_some(){"required"}else{"not required"});
+    }
+
+    if let Some(path)=&config.server.unix_socket{
+        info!("Listening on unix socket {}{}",path,if config.server.tcp{""}else{" only"});
+    }
+
+    let pool=ShardThreadPool::new(config.server.threads).unwrap();
     if engine==Engine::Sled{
         let path=Path::new(&data_path).join("sled");
         let store=SledStore::open(path).unwrap();
-
-        let mut server = KvServer::new(store, args.addr, shutdown,pool).unwrap();
-        server.run().unwrap();
-        server.shut_down();
+        start(store,&args,&config,shutdown,pool,tls);
     }else{
         let path=Path::new(&data_path).join("kvs");
-        let store=KvStore::open(path).unwrap();
-        
-        let mut server = KvServer::new(store, args.addr, shutdown,pool).unwrap();
-        server.run().unwrap();
-        server.shut_down();
+        let store=KvStore::open_with_options(path,config.store_options()).unwrap();
+        start(store,&args,&config,shutdown,pool,tls);
     }
     
     info!("Server shut down gracefully");
 }
 
-fn get_current_engine()->Result<Option<Engine>>{
-    let path=current_dir()?.join("engine");
-    if !path.exists(){//第一次启动
-        return Ok(None);
+//配置了raft时以RaftNode包装存储引擎,写入经过多数节点确认后才应用
+fn start<E:KVEngine>(store:E,args:&KvsServer,config:&Config,shutdown:Arc<AtomicBool>,pool:ShardThreadPool,tls:Option<Arc<rustls::ServerConfig>>){
+    let Some(options)=config.raft_options() else {
+        run(store,args,config,shutdown,pool,tls,|_:&Config|());
+        return;
+    };
+    let id=options.id;
+    let dir=Path::new(&config.engine.data).join("raft");
+    let mut transport=TcpTransport::new(RAFT_TIMEOUT);
+    if let Some(secret)=&options.secret{
+        transport=transport.with_secret(secret.clone());
     }
+    let transport=Arc::new(transport);
+    let node=match RaftNode::open(store,dir,options,transport).and_then(|node|{
+        node.serve(config.raft_addr()?.unwrap())?;
+        Ok(node)
+    }){
+        Ok(node)=>node,
+        Err(e)=>{
+            error!("Failed to start raft node {}: {}",id,e);
+            eprintln!("Failed to start raft node {}: {}",id,e);
+            std::process::exit(1);
+        }
+    };
+    info!("Raft node {} started, members: {:?}",id,node.info().members.keys().collect::<Vec<_>>());
+    let raft=node.clone();
+    run(node.clone(),args,config,shutdown,pool,tls,move|config:&Config|reload_members(&raft,config));
+    node.shut_down();
+}
 
-    match fs::read_to_string(path)?.parse(){
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
+//重新加载配置时由leader把集群成员变更为raft.members
+fn reload_members<E:KVEngine>(node:&RaftNode<E>,config:&Config){
+    let Some(options)=config.raft_options() else {
+        return;
+    };
+    let info=node.info();
+    if options.members.is_empty() || options.members==info.members{
+        return;
+    }
+    if info.role!=Role::Leader{
+        warn!("raft.members changed, reload the config of the leader to change the members");
+        return;
+    }
+    match node.set_members(&options.members){
+        Ok(())=>info!("Raft members changed to {:?}",options.members.keys().collect::<Vec<_>>()),
+        Err(e)=>error!("Failed to change the raft members: {}",e),
+    }
+}
+
+fn run<E:KVEngine>(store:E,args:&KvsServer,config:&Config,shutdown:Arc<AtomicBool>,pool:ShardThreadPool,tls:Option<Arc<rustls::ServerConfig>>,on_reload:impl Fn(&Config)+Send+'static){
+    let server = match build_server(store,config,shutdown,pool,tls){
+        Ok(server)=>server,
+        Err(e)=>{
+            error!("{}",e);
+            eprintln!("{}",e);
+            std::process::exit(1);
+        }
+    };
+    watch_reload(args,config.clone(),server.handle(),on_reload);
+    serve(server,config);
+}
+
+//收到 SIGHUP 时重新加载配置文件,只应用可以在线修改的配置(日志级别、连接限制、超时、slowlog、用户、raft成员)
+#[cfg(unix)]
+fn watch_reload(args:&KvsServer,config:Config,handle:ServerHandle,on_reload:impl Fn(&Config)+Send+'static){
+    use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+    let mut signals=Signals::new([SIGHUP]).expect("Error setting SIGHUP handler");
+    let args=args.clone();
+    thread::spawn(move||{
+        let mut current=config;
+        for _ in signals.forever(){
+            info!("Received SIGHUP, reloading config");
+            let new=match args.load_config(){
+                Ok(new)=>new,
+                Err(e)=>{
+                    error!("Reload failed, keeping the current config: {}",e);
+                    continue;
+                }
+            };
+            for name in new.restart_required(&current){
+                warn!("Config '{}' changed, it takes effect after restart",name);
+            }
+            set_log_level(new.log_level().unwrap());
+            handle.set_options(new.server_options());
+            on_reload(&new);
+            info!("Config reloaded, log level: {}",new.log.level);
+            current=new;
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn watch_reload(_args:&KvsServer,_config:Config,_handle:ServerHandle,_on_reload:impl Fn(&Config)+Send+'static){}
+
+//运行server直到收到关闭信号,超时未关闭完成则强制退出
+fn serve<E:KVEngine,P:ThreadPool>(mut server:KvServer<E,P>,config:&Config){
+    if let Some(addr)=config.metrics_addr().unwrap(){
+        if let Err(e)=server.serve_metrics(addr){
+            error!("Failed to serve metrics on {}: {}",addr,e);
+            std::process::exit(1);
         }
     }
-}
\ No newline at end of file
+    let timeout=Duration::from_secs(config.server.shutdown_timeout);
+    if let Err(e)=server.run(){
+        error!("Server error: {}",e);
+    }
+    info!("Draining in-flight requests, timeout {:?}",timeout);
+    if let Err(e)=server.shut_down(timeout){
+        error!("Force exit: {}",e);
+        std::process::exit(1);
+    }
+}
+
+//根据数据目录中的存储目录及其元数据判断上一次启动使用的engine
+fn get_current_engine(data:&Path)->Result<Option<Engine>>{
+    let mut found=Vec::new();
+    for engine in [Engine::Kvs,Engine::Sled]{
+        let dir=data.join(engine.to_string());
+        if let Some(meta)=StoreMeta::load(&dir)?{
+            if meta.engine!=engine.to_string(){
+                return Err(KvsError::EngineMismatch{expected:engine.to_string(),found:meta.engine});
+            }
+            found.push(engine);
+        }else if dir.is_dir() && fs::read_dir(&dir)?.next().is_some(){
+            //没有元数据的旧版本存储目录
+            found.push(engine);
+        }
+    }
+    match found.as_slice(){
+        []=>Ok(None),//第一次启动
+        [engine]=>Ok(Some(*engine)),
+        _=>Err(KvsError::StringError(format!("data directory {} contains both kvs and sled stores",data.display()))),
+    }
+}
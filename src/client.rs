@@ -1,39 +1,143 @@
+use std::fmt;
 use std::net::SocketAddr;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{tcp::{OwnedReadHalf,OwnedWriteHalf},TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use rustls::ClientConfig;
+use rustls::pki_types::ServerName;
 use tokio::time::{self,Duration};
-use crate::{Result,parse_response, Cmd};
+use crate::{Result,KvsError,parse_response, BackupManifest, CdcRecord, ChangeEvent, Cmd, ServerInfo, SlowlogEntry};
+use crate::cluster::{SlotMap,SlotState};
+use crate::common::{BackupCmd,ClusterSlotsCmd,ClusterSetSlotCmd,ClusterMigrateCmd,SyncCmd,SyncFrame,ReplicaOfCmd,GetCmd,SetCmd,RemoveCmd,ScanCmd,GetVector,SetVector,DelVector,PingCmd,InfoCmd,DbSizeCmd,ConfigGetCmd,ConfigSetCmd,SlowlogGetCmd,SlowlogResetCmd,MonitorCmd,PublishCmd,SubscribeCmd,UnsubscribeCmd,PSubscribeCmd,PUnsubscribeCmd,WatchCmd,CdcCmd,AuthCmd};
+use crate::pubsub::PubSubFrame;
+use std::collections::VecDeque;
 use log::{error,info, warn};
 
+/// The address of a server, `host:port` for TCP or `unix:///path` for a Unix socket.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum ServerAddr{
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ServerAddr{
+    type Err=String;
+
+    fn from_str(s:&str)->std::result::Result<Self,Self::Err>{
+        if let Some(path)=s.strip_prefix("unix://"){
+            if path.is_empty(){
+                return Err(format!("Invalid address '{}': missing socket path",s));
+            }
+            return Ok(ServerAddr::Unix(PathBuf::from(path)));
+        }
+        s.parse().map(ServerAddr::Tcp).map_err(|e|format!("Invalid address '{}': {}", s, e))
+    }
+}
+
+impl fmt::Display for ServerAddr{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            ServerAddr::Tcp(addr)=>write!(f,"{}",addr),
+            ServerAddr::Unix(path)=>write!(f,"unix://{}",path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for ServerAddr{
+    fn from(addr:SocketAddr)->Self{
+        ServerAddr::Tcp(addr)
+    }
+}
+
+//连接重试
+async fn connect_with_retry<T,F,Fut>(addr:impl fmt::Display,connect:F)->Result<T>
+where
+    F:Fn()->Fut,
+    Fut:std::future::Future<Output=std::io::Result<T>>,
+{
+    let mut attempts = 0;
+    loop{
+        match connect().await{
+            Ok(stream)=>{
+                info!("Connected to server:{} success",addr);
+                return Ok(stream);
+            },
+            Err(e)=>{
+                attempts += 1;
+                if attempts >= 5 {
+                    error!("Failed to connect to server at {}: {}", addr, e);
+                    return Err(e.into());
+                }
+                warn!("Failed to connect to server at {}: {}. Retrying ({}/5)...", addr, e, attempts);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn connect(addr:SocketAddr)->Result<TcpStream>{
+    connect_with_retry(addr,||TcpStream::connect(addr)).await
+}
+
 pub struct KvClient{
-    reader: BufReader<OwnedReadHalf>,
-    writer: OwnedWriteHalf,
+    reader: BufReader<ReadHalf>,
+    writer: WriteHalf,
 }
 
+//明文和TLS连接的读写两端
+type ReadHalf=Box<dyn AsyncRead+Send+Unpin>;
+type WriteHalf=Box<dyn AsyncWrite+Send+Unpin>;
+
 impl KvClient{
     pub async fn new(addr:SocketAddr)->Result<Self>{
-        let mut attempts = 0;
-        //连接重试
-        loop{
-            match TcpStream::connect(addr).await{
-                Ok(stream)=>{
-                    info!("Connected to server:{} success",addr);
-                    let (reader, writer) = stream.into_split();
-                    return Ok(KvClient { reader: BufReader::new(reader), writer });
-                },
-                Err(e)=>{
-                    attempts += 1;
-                    if attempts >= 5 {
-                        error!("Failed to connect to server at {}: {}", addr, e);
-                        return Err(e.into());
-                    }
-                    warn!("Failed to connect to server at {}: {}. Retrying ({}/5)...", addr, e, attempts);
-                    time::sleep(Duration::from_secs(1)).await;
-                }
-            }
+        let (reader, writer) = connect(addr).await?.into_split();
+        Ok(KvClient { reader: BufReader::new(Box::new(reader)), writer: Box::new(writer) })
+    }
+
+    /// Connects over TCP or a Unix socket depending on `addr`.
+    pub async fn connect(addr:&ServerAddr)->Result<Self>{
+        match addr{
+            ServerAddr::Tcp(addr)=>Self::new(*addr).await,
+            ServerAddr::Unix(path)=>Self::new_unix(path).await,
         }
     }
 
+    /// Connects to the Unix socket at `path`.
+    #[cfg(unix)]
+    pub async fn new_unix(path:impl AsRef<std::path::Path>)->Result<Self>{
+        let path=path.as_ref();
+        let stream=connect_with_retry(path.display(),||tokio::net::UnixStream::connect(path)).await?;
+        let (reader, writer) = stream.into_split();
+        Ok(KvClient { reader: BufReader::new(Box::new(reader)), writer: Box::new(writer) })
+    }
+
+    #[cfg(not(unix))]
+    pub async fn new_unix(path:impl AsRef<std::path::Path>)->Result<Self>{
+        Err(KvsError::StringError(format!("unix sockets are not supported, cannot connect to {}",path.as_ref().display())))
+    }
+
+    /// Connects over TLS, `server_name` has to match the server certificate.
+    ///
+    /// Build `config` with `tls::client_config`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Tls` if the handshake fails, e.g. because the server
+    /// certificate is not trusted or the server requires a client certificate.
+    pub async fn new_tls(addr:SocketAddr,server_name:&str,config:Arc<ClientConfig>)->Result<Self>{
+        let name=ServerName::try_from(server_name.to_string())
+            .map_err(|e|KvsError::Tls(format!("invalid server name '{}': {}",server_name,e)))?;
+        let stream=connect(addr).await?;
+        let stream=TlsConnector::from(config).connect(name,stream).await
+            .map_err(|e|KvsError::Tls(e.to_string()))?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(KvClient { reader: BufReader::new(Box::new(reader)), writer: Box::new(writer) })
+    }
+
     pub async fn send_request(&mut self,cmd:Cmd)->Result<String>{
         let buf=cmd.encode();
         self.writer.write_all(buf.as_slice()).await?;
@@ -42,9 +146,451 @@ impl KvClient{
 
         //读取响应
         let mut response=String::new();
-        self.reader.read_line(&mut response).await?;
+        if self.reader.read_line(&mut response).await?==0{
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,"connection closed by server").into());
+        }
         let res=parse_response(response).await?;
         
         Ok(res)
     }
-}
\ No newline at end of file
+
+    /// Gets the value of `key`, returns `None` if the key does not exist.
+    pub async fn get(&mut self,key:&str)->Result<Option<String>>{
+        let cmd=Cmd::Get(GetCmd{key:key.to_string()});
+        match self.send_request(cmd).await{
+            Ok(v)=>Ok(Some(v)),
+            Err(KvsError::KeyNotFound)=>Ok(None),
+            Err(e)=>Err(e),
+        }
+    }
+
+    /// Sets `key` to `value`, the key expires after `ttl` if it is given.
+    ///
+    /// The ttl is sent with second precision and rounded up, so a ttl below one
+    /// second still expires the key.
+    pub async fn set(&mut self,key:&str,value:&str,ttl:Option<Duration>)->Result<()>{
+        let cmd=Cmd::Set(SetCmd{key:key.to_string(),value:value.to_string(),expire:expire_secs(ttl)?});
+        self.send_request(cmd).await?;
+        Ok(())
+    }
+
+    /// Removes `key`, returns `KvsError::KeyNotFound` if the key does not exist.
+    pub async fn remove(&mut self,key:&str)->Result<()>{
+        let cmd=Cmd::Remove(RemoveCmd{key:key.to_string()});
+        self.send_request(cmd).await?;
+        Ok(())
+    }
+
+    /// Returns all key value pairs with `range.start() <= key <= range.end()`, ordered by key.
+    pub async fn scan(&mut self,range:RangeInclusive<&str>)->Result<Vec<(String,String)>>{
+        let cmd=Cmd::Scan(ScanCmd{start:range.start().to_string(),end:range.end().to_string()});
+        let res=self.send_request(cmd).await?;
+        parse_pairs(&res)
+    }
+
+    /// Authenticates the connection as `user`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AuthFailed` for an unknown user or a wrong password.
+    pub async fn auth(&mut self,user:&str,password:&str)->Result<()>{
+        self.send_request(Cmd::Auth(AuthCmd{user:user.to_string(),password:password.to_string()})).await?;
+        Ok(())
+    }
+
+    /// Pings the server, returns `PONG` or the echoed `message`.
+    pub async fn ping(&mut self,message:Option<&str>)->Result<String>{
+        let cmd=Cmd::Ping(PingCmd{message:message.unwrap_or("").to_string()});
+        self.send_request(cmd).await
+    }
+
+    /// Returns version, uptime, engine and connection statistics of the server.
+    pub async fn info(&mut self)->Result<ServerInfo>{
+        let res=self.send_request(Cmd::Info(InfoCmd)).await?;
+        Ok(serde_json::from_str(&res)?)
+    }
+
+    /// Returns the number of keys stored on the server.
+    pub async fn dbsize(&mut self)->Result<u64>{
+        let res=self.send_request(Cmd::DbSize(DbSizeCmd)).await?;
+        res.parse().map_err(|_|KvsError::StringError(format!("malformed dbsize response: {}",res)))
+    }
+
+    /// Returns the runtime configs matching `pattern`, `*` matches all of them.
+    pub async fn config_get(&mut self,pattern:&str)->Result<Vec<(String,String)>>{
+        let res=self.send_request(Cmd::ConfigGet(ConfigGetCmd{pattern:pattern.to_string()})).await?;
+        parse_pairs(&res)
+    }
+
+    /// Changes a runtime config of the server.
+    pub async fn config_set(&mut self,key:&str,value:&str)->Result<()>{
+        self.send_request(Cmd::ConfigSet(ConfigSetCmd{key:key.to_string(),value:value.to_string()})).await?;
+        Ok(())
+    }
+
+    /// Returns up to `count` of the newest slowlog entries, all of them if `count` is 0.
+    pub async fn slowlog_get(&mut self,count:u32)->Result<Vec<SlowlogEntry>>{
+        let res=self.send_request(Cmd::SlowlogGet(SlowlogGetCmd{count})).await?;
+        Ok(serde_json::from_str(&res)?)
+    }
+
+    /// Switches the connection to monitor mode, the server then streams every
+    /// command processed for other clients until the connection is dropped.
+    pub async fn monitor(mut self)->Result<Monitor>{
+        self.send_request(Cmd::Monitor(MonitorCmd)).await?;
+        Ok(Monitor{reader:self.reader,_writer:self.writer})
+    }
+
+    /// Switches the connection to watch mode, the server then streams every
+    /// change of the keys starting with `prefix` until the connection is dropped.
+    pub async fn watch(mut self,prefix:&str)->Result<Watch>{
+        self.send_request(Cmd::Watch(WatchCmd{prefix:prefix.to_string()})).await?;
+        Ok(Watch{reader:self.reader,_writer:self.writer})
+    }
+
+    /// Switches the connection to change data capture mode, the server then streams
+    /// every mutation after the sequence number `after` in order, 0 starts from the
+    /// oldest mutation the store still has.
+    ///
+    /// It returns an error if the mutations after `after` were compacted away.
+    pub async fn cdc(mut self,after:u64)->Result<CdcStream>{
+        self.send_request(Cmd::Cdc(CdcCmd{after})).await?;
+        Ok(CdcStream{reader:self.reader,_writer:self.writer})
+    }
+
+    /// Switches the connection to replication mode, the server first sends a snapshot
+    /// unless it can resume after `after` for the leader `id`, then every change.
+    pub(crate) async fn sync(mut self,id:&str,after:u64)->Result<SyncStream>{
+        let res=self.send_request(Cmd::Sync(SyncCmd{id:id.to_string(),after})).await?;
+        let first=serde_json::from_str(&res)?;
+        Ok(SyncStream{first:Some(first),reader:self.reader,_writer:self.writer})
+    }
+
+    /// Makes the server a replica of `leader`, `None` promotes a replica to a leader.
+    ///
+    /// A server that becomes a replica replaces its keys with those of the leader.
+    pub async fn replica_of(&mut self,leader:Option<&ServerAddr>)->Result<()>{
+        let leader=leader.map(|addr|addr.to_string()).unwrap_or_default();
+        self.send_request(Cmd::ReplicaOf(ReplicaOfCmd{leader})).await?;
+        Ok(())
+    }
+
+    /// Returns the slot assignment as seen by the server.
+    pub async fn cluster_slots(&mut self)->Result<SlotMap>{
+        let res=self.send_request(Cmd::ClusterSlots(ClusterSlotsCmd)).await?;
+        Ok(serde_json::from_str(&res)?)
+    }
+
+    /// Changes the owner or the migration state of `slots` on the server.
+    ///
+    /// The change only applies to this server, see `ClusterClient::migrate_slot`
+    /// to move a slot between nodes.
+    pub async fn cluster_set_slot(&mut self,slots:RangeInclusive<u16>,state:&SlotState)->Result<()>{
+        let (name,addr)=state.parts();
+        let cmd=Cmd::ClusterSetSlot(ClusterSetSlotCmd{start:*slots.start(),end:*slots.end(),state:name.to_string(),addr:addr.to_string()});
+        self.send_request(cmd).await?;
+        Ok(())
+    }
+
+    /// Moves the keys of a migrating `slot` to its target, returns the number of keys moved.
+    pub async fn cluster_migrate(&mut self,slot:u16)->Result<u64>{
+        let res=self.send_request(Cmd::ClusterMigrate(ClusterMigrateCmd{slot})).await?;
+        res.parse().map_err(|_|KvsError::StringError(format!("malformed migrate response: {}",res)))
+    }
+
+    /// Writes a consistent backup of the store to `dir` on the server while it keeps serving.
+    ///
+    /// `dir` must be missing or empty, relative paths are relative to the working
+    /// directory of the server. Restore the backup with `kvs-admin restore`.
+    pub async fn backup(&mut self,dir:&str)->Result<BackupManifest>{
+        let res=self.send_request(Cmd::Backup(BackupCmd{dir:dir.to_string()})).await?;
+        Ok(serde_json::from_str(&res)?)
+    }
+
+    /// Publishes `message` to `channel`, returns the number of subscribers that received it.
+    pub async fn publish(&mut self,channel:&str,message:&str)->Result<u64>{
+        let cmd=Cmd::Publish(PublishCmd{channel:channel.to_string(),message:message.to_string()});
+        let res=self.send_request(cmd).await?;
+        res.parse().map_err(|_|KvsError::StringError(format!("malformed publish response: {}",res)))
+    }
+
+    /// Switches the connection to subscriber mode and subscribes to `channels`.
+    pub async fn subscribe(self,channels:&[&str])->Result<Subscription>{
+        let mut sub=Subscription::new(self);
+        sub.subscribe(channels).await?;
+        Ok(sub)
+    }
+
+    /// Switches the connection to subscriber mode and subscribes to the glob `patterns`.
+    pub async fn psubscribe(self,patterns:&[&str])->Result<Subscription>{
+        let mut sub=Subscription::new(self);
+        sub.psubscribe(patterns).await?;
+        Ok(sub)
+    }
+
+    /// Clears the slowlog of the server.
+    pub async fn slowlog_reset(&mut self)->Result<()>{
+        self.send_request(Cmd::SlowlogReset(SlowlogResetCmd)).await?;
+        Ok(())
+    }
+
+    /// Gets the vector stored at `key`, returns `None` if the key does not exist.
+    pub async fn vget(&mut self,key:&str)->Result<Option<Vec<f32>>>{
+        let cmd=Cmd::VGet(GetVector{key:key.to_string()});
+        match self.send_request(cmd).await{
+            Ok(v)=>Ok(Some(parse_vector(&v)?)),
+            Err(KvsError::KeyNotFound)=>Ok(None),
+            Err(e)=>Err(e),
+        }
+    }
+
+    /// Stores `value` as a vector at `key`, the key expires after `ttl` if it is given.
+    pub async fn vset(&mut self,key:&str,value:&[f32],ttl:Option<Duration>)->Result<()>{
+        let cmd=Cmd::VSet(SetVector{key:key.to_string(),value:format_vector(value)?,expire:expire_secs(ttl)?});
+        self.send_request(cmd).await?;
+        Ok(())
+    }
+
+    /// Removes the vector stored at `key`.
+    pub async fn vdel(&mut self,key:&str)->Result<()>{
+        let cmd=Cmd::VDel(DelVector{key:key.to_string()});
+        self.send_request(cmd).await?;
+        Ok(())
+    }
+}
+
+/// A connection in monitor mode, created by `KvClient::monitor`.
+pub struct Monitor{
+    reader: BufReader<ReadHalf>,
+    //保留写端,drop时才关闭连接
+    _writer: WriteHalf,
+}
+
+impl Monitor{
+    /// Waits for the next command event, returns `None` once the server closes the connection.
+    ///
+    /// Each event is formatted as `<unix time> [<client addr>] "name" "arg"...`.
+    pub async fn next(&mut self)->Result<Option<String>>{
+        let mut line=String::new();
+        if self.reader.read_line(&mut line).await?==0{
+            return Ok(None);
+        }
+        parse_response(line).await.map(Some)
+    }
+}
+
+/// A connection in watch mode, created by `KvClient::watch`.
+pub struct Watch{
+    reader: BufReader<ReadHalf>,
+    _writer: WriteHalf,
+}
+
+impl Watch{
+    /// Waits for the next change, returns `None` once the server closes the connection.
+    ///
+    /// It returns an error if the server dropped changes because they were not read fast enough.
+    pub async fn next(&mut self)->Result<Option<ChangeEvent>>{
+        let mut line=String::new();
+        if self.reader.read_line(&mut line).await?==0{
+            return Ok(None);
+        }
+        let res=parse_response(line).await?;
+        Ok(Some(serde_json::from_str(&res)?))
+    }
+}
+
+/// A connection in change data capture mode, created by `KvClient::cdc`.
+pub struct CdcStream{
+    reader: BufReader<ReadHalf>,
+    _writer: WriteHalf,
+}
+
+impl CdcStream{
+    /// Waits for the next mutation, returns `None` once the server closes the connection.
+    ///
+    /// It returns an error if the server can no longer read the mutations, the
+    /// consumer then has to resume from 0.
+    pub async fn next(&mut self)->Result<Option<CdcRecord>>{
+        let mut line=String::new();
+        if self.reader.read_line(&mut line).await?==0{
+            return Ok(None);
+        }
+        let res=parse_response(line).await?;
+        Ok(Some(serde_json::from_str(&res)?))
+    }
+}
+
+/// A connection in replication mode, created by `KvClient::sync`.
+pub(crate) struct SyncStream{
+    // the response to the sync command
+    first: Option<SyncFrame>,
+    reader: BufReader<ReadHalf>,
+    _writer: WriteHalf,
+}
+
+impl SyncStream{
+    /// Waits for the next frame, returns `None` once the leader closes the connection.
+    pub(crate) async fn next(&mut self)->Result<Option<SyncFrame>>{
+        if let Some(frame)=self.first.take(){
+            return Ok(Some(frame));
+        }
+        let mut line=String::new();
+        if self.reader.read_line(&mut line).await?==0{
+            return Ok(None);
+        }
+        let res=parse_response(line).await?;
+        Ok(Some(serde_json::from_str(&res)?))
+    }
+}
+
+/// A message received on a subscribed channel.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Message{
+    pub channel:String,
+    /// The pattern that matched the channel, `None` for channel subscriptions.
+    pub pattern:Option<String>,
+    pub payload:String,
+}
+
+/// A connection in subscriber mode, created by `KvClient::subscribe` or `KvClient::psubscribe`.
+pub struct Subscription{
+    client:KvClient,
+    //等待(取消)订阅确认时收到的消息
+    pending:VecDeque<Message>,
+    count:usize,
+}
+
+impl Subscription{
+    fn new(client:KvClient)->Self{
+        Subscription{client,pending:VecDeque::new(),count:0}
+    }
+
+    /// Number of subscribed channels and patterns.
+    pub fn count(&self)->usize{
+        self.count
+    }
+
+    /// Returns the connection in normal mode once nothing is subscribed any more,
+    /// otherwise the subscription itself.
+    pub fn into_client(self)->std::result::Result<KvClient,Subscription>{
+        if self.count==0 && self.pending.is_empty(){
+            Ok(self.client)
+        }else{
+            Err(self)
+        }
+    }
+
+    pub async fn subscribe(&mut self,channels:&[&str])->Result<()>{
+        self.request(Cmd::Subscribe(SubscribeCmd{channels:to_strings(channels)})).await
+    }
+
+    pub async fn psubscribe(&mut self,patterns:&[&str])->Result<()>{
+        self.request(Cmd::PSubscribe(PSubscribeCmd{patterns:to_strings(patterns)})).await
+    }
+
+    /// Unsubscribes from `channels`, or from all channels if it is empty.
+    pub async fn unsubscribe(&mut self,channels:&[&str])->Result<()>{
+        self.request(Cmd::Unsubscribe(UnsubscribeCmd{channels:to_strings(channels)})).await
+    }
+
+    /// Unsubscribes from `patterns`, or from all patterns if it is empty.
+    pub async fn punsubscribe(&mut self,patterns:&[&str])->Result<()>{
+        self.request(Cmd::PUnsubscribe(PUnsubscribeCmd{patterns:to_strings(patterns)})).await
+    }
+
+    /// Waits for the next message, returns `None` once nothing is subscribed
+    /// any more or the server closes the connection.
+    pub async fn next(&mut self)->Result<Option<Message>>{
+        loop{
+            if let Some(message)=self.pending.pop_front(){
+                return Ok(Some(message));
+            }
+            if self.count==0{
+                return Ok(None);
+            }
+            match self.read_frame().await?{
+                Some(PubSubFrame::Message{channel,payload})=>{
+                    return Ok(Some(Message{channel,pattern:None,payload}));
+                }
+                Some(PubSubFrame::Pmessage{pattern,channel,payload})=>{
+                    return Ok(Some(Message{channel,pattern:Some(pattern),payload}));
+                }
+                Some(_)=>continue,
+                None=>return Ok(None),
+            }
+        }
+    }
+
+    //发送(取消)订阅命令并等待确认,期间收到的消息缓存到pending
+    async fn request(&mut self,cmd:Cmd)->Result<()>{
+        let buf=cmd.encode();
+        self.client.writer.write_all(buf.as_slice()).await?;
+        self.client.writer.flush().await?;
+        loop{
+            match self.read_frame().await?{
+                Some(PubSubFrame::Message{channel,payload})=>{
+                    self.pending.push_back(Message{channel,pattern:None,payload});
+                }
+                Some(PubSubFrame::Pmessage{pattern,channel,payload})=>{
+                    self.pending.push_back(Message{channel,pattern:Some(pattern),payload});
+                }
+                Some(PubSubFrame::Subscribe{count,..})
+                | Some(PubSubFrame::Unsubscribe{count,..})
+                | Some(PubSubFrame::Psubscribe{count,..})
+                | Some(PubSubFrame::Punsubscribe{count,..})=>{
+                    self.count=count;
+                    return Ok(());
+                }
+                None=>return Err(KvsError::StringError("Connection closed by server".to_string())),
+            }
+        }
+    }
+
+    async fn read_frame(&mut self)->Result<Option<PubSubFrame>>{
+        let mut line=String::new();
+        if self.client.reader.read_line(&mut line).await?==0{
+            return Ok(None);
+        }
+        let res=parse_response(line).await?;
+        Ok(Some(serde_json::from_str(&res)?))
+    }
+}
+
+fn to_strings(items:&[&str])->Vec<String>{
+    items.iter().map(|s|s.to_string()).collect()
+}
+
+fn expire_secs(ttl:Option<Duration>)->Result<u32>{
+    match ttl{
+        //向上取整,非零的ttl不能变成表示永不过期的0
+        Some(d)=>u32::try_from(d.as_nanos().div_ceil(1_000_000_000)).map_err(|_|KvsError::StringError("expire time invalid".to_string())),
+        None=>Ok(0),
+    }
+}
+
+//scan和config get的响应是[[key,value],..]形式的JSON数组
+pub(crate) fn parse_pairs(s:&str)->Result<Vec<(String,String)>>{
+    serde_json::from_str(s).map_err(|_|KvsError::StringError(format!("malformed scan response: {}",s)))
+}
+
+fn format_vector(v:&[f32])->Result<String>{
+    if v.is_empty(){
+        return Err(KvsError::StringError("Vector must have at least 1 dimension".to_string()));
+    }
+    if v.iter().any(|x|x.is_nan()){
+        return Err(KvsError::StringError("NAN not allowed in vector".to_string()));
+    }
+    if v.iter().any(|x|x.is_infinite()){
+        return Err(KvsError::StringError("Inf not allowed in vector".to_string()));
+    }
+    let items:Vec<String>=v.iter().map(|x|x.to_string()).collect();
+    Ok(format!("[{}]",items.join(",")))
+}
+
+fn parse_vector(s:&str)->Result<Vec<f32>>{
+    let s=s.trim().trim_start_matches('[').trim_end_matches(']');
+    s.split(',')
+        .map(|x|x.trim().parse::<f32>().map_err(|_|KvsError::StringError(format!("Invalid vector value: {}",x))))
+        .collect()
+}
+
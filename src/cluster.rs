@@ -0,0 +1,358 @@
+//! Hash-slot sharding of the keyspace across several servers.
+//!
+//! Every key belongs to one of `SLOTS` hash slots, the CRC16 of the key modulo
+//! `SLOTS`. If the key contains `{tag}` with a non-empty tag only the tag is hashed,
+//! so keys sharing a tag stay on the same node. Each slot is served by one node,
+//! other nodes answer `KvsError::Moved` with the address of the owner.
+//!
+//! A slot moves between nodes online: the target marks it importing, the source
+//! marks it migrating and copies its keys over one by one. Meanwhile the source
+//! answers `KvsError::Ask` for keys it no longer has, and the client retries once on
+//! the target. When all keys have been copied, every node is told the new owner.
+//! `ClusterClient::migrate_slot` runs all these steps.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use crate::{KvClient, KvsError, Result, ServerAddr};
+
+/// The number of hash slots.
+pub const SLOTS: u16 = 16384;
+
+//跟随MOVED和ASK重定向的最大次数
+const MAX_REDIRECTS: usize = 5;
+
+/// Returns the hash slot of `key`.
+pub fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    // {tag}中的tag非空时只计算tag
+    let hashed = match key.iter().position(|&b| b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|&b| b == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(hashed) % SLOTS
+}
+
+// CRC16-XMODEM,和Redis Cluster相同
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Parses a slot `100` or an inclusive range of slots `0-8191`.
+pub fn parse_slots(s: &str) -> Result<RangeInclusive<u16>> {
+    let invalid = || KvsError::StringError(format!("invalid slots '{}', must be <slot> or <start>-<end> below {}", s, SLOTS));
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+    let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+    if start > end || end >= SLOTS {
+        return Err(invalid());
+    }
+    Ok(start..=end)
+}
+
+/// Consecutive slots served by the node at `addr`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotRange {
+    pub start: u16,
+    /// The last slot of the range, inclusive
+    pub end: u16,
+    pub addr: String,
+}
+
+/// The slot assignment as seen by one node, returned by `ClusterSlots`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotMap {
+    /// The address of the node that sent the map
+    pub myself: String,
+    pub slots: Vec<SlotRange>,
+    /// Slots this node is moving away, with the address of the target
+    #[serde(default)]
+    pub migrating: BTreeMap<u16, String>,
+    /// Slots this node is receiving, with the address of the source
+    #[serde(default)]
+    pub importing: BTreeMap<u16, String>,
+}
+
+impl SlotMap {
+    /// Returns the address of the node serving every slot, `None` for unassigned slots.
+    pub fn owners(&self) -> Vec<Option<String>> {
+        let mut owners = vec![None; SLOTS as usize];
+        for range in &self.slots {
+            for slot in range.start..=range.end.min(SLOTS - 1) {
+                owners[slot as usize] = Some(range.addr.clone());
+            }
+        }
+        owners
+    }
+
+    /// Returns the address of the node serving `slot`.
+    pub fn owner(&self, slot: u16) -> Option<&str> {
+        self.slots.iter().find(|r| r.start <= slot && slot <= r.end).map(|r| r.addr.as_str())
+    }
+
+    /// Merges the owner of every slot into ranges.
+    pub fn ranges(owners: &[Option<String>]) -> Vec<SlotRange> {
+        let mut ranges: Vec<SlotRange> = Vec::new();
+        for (slot, owner) in owners.iter().enumerate() {
+            let Some(addr) = owner else { continue };
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some(last) if last.end + 1 == slot && last.addr == *addr => last.end = slot,
+                _ => ranges.push(SlotRange { start: slot, end: slot, addr: addr.clone() }),
+            }
+        }
+        ranges
+    }
+}
+
+/// The state `ClusterSetSlot` puts slots into.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SlotState {
+    /// The slots are served by the node at the address
+    Node(String),
+    /// The slots are moving from this node to the node at the address
+    Migrating(String),
+    /// The slots are moving from the node at the address to this node
+    Importing(String),
+    /// Cancels migrating and importing
+    Stable,
+}
+
+impl SlotState {
+    /// Builds the state from the `state` and `addr` fields of `ClusterSetSlot`.
+    pub fn from_parts(state: &str, addr: &str) -> Result<SlotState> {
+        let addr = || -> Result<String> {
+            if addr.is_empty() {
+                return Err(KvsError::StringError(format!("slot state '{}' requires an address", state)));
+            }
+            Ok(addr.to_string())
+        };
+        match state.to_lowercase().as_str() {
+            "node" => Ok(SlotState::Node(addr()?)),
+            "migrating" => Ok(SlotState::Migrating(addr()?)),
+            "importing" => Ok(SlotState::Importing(addr()?)),
+            "stable" => Ok(SlotState::Stable),
+            _ => Err(KvsError::StringError(format!(
+                "unknown slot state '{}', must be one of node, migrating, importing, stable",
+                state
+            ))),
+        }
+    }
+
+    /// Returns the `state` and `addr` fields of `ClusterSetSlot`.
+    pub fn parts(&self) -> (&str, &str) {
+        match self {
+            SlotState::Node(addr) => ("node", addr),
+            SlotState::Migrating(addr) => ("migrating", addr),
+            SlotState::Importing(addr) => ("importing", addr),
+            SlotState::Stable => ("stable", ""),
+        }
+    }
+}
+
+impl fmt::Display for SlotState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.parts() {
+            (state, "") => write!(f, "{}", state),
+            (state, addr) => write!(f, "{} {}", state, addr),
+        }
+    }
+}
+
+/// A client of a sharded cluster.
+///
+/// It caches which node serves every slot and sends each command to the owner of
+/// its key, following `Moved` and `Ask` redirects. Scans are sent to every node
+/// and the results merged.
+pub struct ClusterClient {
+    seeds: Vec<ServerAddr>,
+    auth: Option<(String, String)>,
+    owners: Vec<Option<String>>,
+    clients: HashMap<String, KvClient>,
+}
+
+impl ClusterClient {
+    /// Loads the slot map from the first of `seeds` that answers.
+    ///
+    /// Connections to the nodes authenticate as `auth` if it is given.
+    pub async fn connect(seeds: &[ServerAddr], auth: Option<(&str, &str)>) -> Result<ClusterClient> {
+        let mut client = ClusterClient {
+            seeds: seeds.to_vec(),
+            auth: auth.map(|(user, password)| (user.to_string(), password.to_string())),
+            owners: vec![None; SLOTS as usize],
+            clients: HashMap::new(),
+        };
+        client.refresh().await?;
+        Ok(client)
+    }
+
+    /// Reloads the slot map from a known node or a seed.
+    pub async fn refresh(&mut self) -> Result<()> {
+        let mut candidates = self.nodes();
+        candidates.extend(self.seeds.iter().map(|s| s.to_string()).filter(|s| !candidates.contains(s)).collect::<Vec<_>>());
+        let mut last = None;
+        for addr in candidates {
+            let res = match self.client(&addr).await {
+                Ok(client) => client.cluster_slots().await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(map) => {
+                    self.owners = map.owners();
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Failed to load the slot map from {}: {}", addr, e);
+                    self.clients.remove(&addr);
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| KvsError::StringError("no cluster node to connect to".to_string())))
+    }
+
+    /// Returns the address of the node serving `slot` in the cached map.
+    pub fn owner(&self, slot: u16) -> Option<&str> {
+        self.owners[slot as usize].as_deref()
+    }
+
+    /// Returns the addresses of all nodes serving slots, in order.
+    pub fn nodes(&self) -> Vec<String> {
+        let nodes: BTreeSet<&String> = self.owners.iter().flatten().collect();
+        nodes.into_iter().cloned().collect()
+    }
+
+    /// Gets the value of `key`, returns `None` if the key does not exist.
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.route(key, async |c| c.get(key).await).await
+    }
+
+    /// Sets `key` to `value`, the key expires after `ttl` if it is given.
+    pub async fn set(&mut self, key: &str, value: &str, ttl: Option<Duration>) -> Result<()> {
+        self.route(key, async |c| c.set(key, value, ttl).await).await
+    }
+
+    /// Removes `key`, returns `KvsError::KeyNotFound` if the key does not exist.
+    pub async fn remove(&mut self, key: &str) -> Result<()> {
+        self.route(key, async |c| c.remove(key).await).await
+    }
+
+    /// Gets the vector stored at `key`, returns `None` if the key does not exist.
+    pub async fn vget(&mut self, key: &str) -> Result<Option<Vec<f32>>> {
+        self.route(key, async |c| c.vget(key).await).await
+    }
+
+    /// Stores `value` as a vector at `key`, the key expires after `ttl` if it is given.
+    pub async fn vset(&mut self, key: &str, value: &[f32], ttl: Option<Duration>) -> Result<()> {
+        self.route(key, async |c| c.vset(key, value, ttl).await).await
+    }
+
+    /// Removes the vector stored at `key`.
+    pub async fn vdel(&mut self, key: &str) -> Result<()> {
+        self.route(key, async |c| c.vdel(key).await).await
+    }
+
+    /// Returns all key value pairs with `range.start() <= key <= range.end()` on
+    /// any node, ordered by key.
+    ///
+    /// The slot map is reloaded first so nodes added since are scanned too.
+    pub async fn scan(&mut self, range: RangeInclusive<&str>) -> Result<Vec<(String, String)>> {
+        self.refresh().await?;
+        // 迁移过程中key可能短暂地同时存在于两个节点,按key去重
+        let mut pairs = BTreeMap::new();
+        for addr in self.nodes() {
+            let client = self.client(&addr).await?;
+            match client.scan(range.clone()).await {
+                Ok(res) => pairs.extend(res),
+                Err(e) => {
+                    self.clients.remove(&addr);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(pairs.into_iter().collect())
+    }
+
+    /// Moves `slot` with its keys to the node at `to`, returns the number of keys moved.
+    ///
+    /// The keys stay readable and writable while they move. Every node serving
+    /// slots is told the new owner, `to` can be a node that serves no slots yet.
+    /// Keys lose their expiration when they move.
+    pub async fn migrate_slot(&mut self, slot: u16, to: &str) -> Result<u64> {
+        if slot >= SLOTS {
+            return Err(KvsError::StringError(format!("slot {} is out of range", slot)));
+        }
+        self.refresh().await?;
+        let from = self.owner(slot).ok_or_else(|| KvsError::StringError(format!("slot {} is not served by any node", slot)))?.to_string();
+        if from == to {
+            return Ok(0);
+        }
+        let range = slot..=slot;
+        self.client(to).await?.cluster_set_slot(range.clone(), &SlotState::Importing(from.clone())).await?;
+        self.client(&from).await?.cluster_set_slot(range.clone(), &SlotState::Migrating(to.to_string())).await?;
+        let moved = self.client(&from).await?.cluster_migrate(slot).await?;
+        // 先通知目标和源节点,之后其他节点的重定向都指向目标节点
+        let mut nodes = vec![to.to_string(), from];
+        nodes.extend(self.nodes().into_iter().filter(|n| !nodes.contains(n)).collect::<Vec<_>>());
+        for node in nodes {
+            self.client(&node).await?.cluster_set_slot(range.clone(), &SlotState::Node(to.to_string())).await?;
+        }
+        self.owners[slot as usize] = Some(to.to_string());
+        info!("Moved slot {} with {} keys to {}", slot, moved, to);
+        Ok(moved)
+    }
+
+    // 把命令发给key所在的节点,收到MOVED时更新缓存的槽位表,收到ASK时只重试这一次
+    async fn route<T>(&mut self, key: &str, mut op: impl AsyncFnMut(&mut KvClient) -> Result<T>) -> Result<T> {
+        let slot = key_slot(key);
+        let mut addr = match self.owner(slot) {
+            Some(addr) => addr.to_string(),
+            // 不知道归属时任选一个节点,由它重定向
+            None => self.nodes().into_iter().next().or_else(|| self.seeds.first().map(|s| s.to_string())).ok_or_else(|| {
+                KvsError::StringError("no cluster node to connect to".to_string())
+            })?,
+        };
+        for _ in 0..MAX_REDIRECTS {
+            let res = op(self.client(&addr).await?).await;
+            match res {
+                Err(KvsError::Moved(slot, to)) => {
+                    self.owners[slot as usize] = Some(to.clone());
+                    addr = to;
+                }
+                Err(KvsError::Ask(_, to)) => addr = to,
+                Err(KvsError::Io(e)) => {
+                    self.clients.remove(&addr);
+                    return Err(KvsError::Io(e));
+                }
+                res => return res,
+            }
+        }
+        Err(KvsError::StringError(format!("too many redirects for key '{}'", key)))
+    }
+
+    // 返回到addr的连接,没有时新建
+    async fn client(&mut self, addr: &str) -> Result<&mut KvClient> {
+        if !self.clients.contains_key(addr) {
+            let server: ServerAddr = addr.parse().map_err(KvsError::StringError)?;
+            let mut client = KvClient::connect(&server).await?;
+            if let Some((user, password)) = &self.auth {
+                client.auth(user, password).await?;
+            }
+            self.clients.insert(addr.to_string(), client);
+        }
+        Ok(self.clients.get_mut(addr).unwrap())
+    }
+}
@@ -2,7 +2,8 @@ use std::fs::{self,OpenOptions};
 use std::path::{Path, PathBuf};
 use env_logger::Builder;
 use std::io::Write;
-use crate::{Result,KvsError};
+use crate::{Result,KvsError,CdcRecord,EngineStats,ReplicationInfo,ServerStats};
+use serde::{Deserialize, Serialize};
 use regex::Regex;
 //请求协议格式
 /* 
@@ -23,6 +24,47 @@ pub enum Cmd{
 
     //ping
     Ping(PingCmd),
+
+    //以下是管理命令
+    Info(InfoCmd),
+    DbSize(DbSizeCmd),
+    ConfigGet(ConfigGetCmd),
+    ConfigSet(ConfigSetCmd),
+    SlowlogGet(SlowlogGetCmd),
+    SlowlogReset(SlowlogResetCmd),
+    Monitor(MonitorCmd),
+
+    //以下是发布订阅命令
+    Publish(PublishCmd),
+    Subscribe(SubscribeCmd),
+    Unsubscribe(UnsubscribeCmd),
+    PSubscribe(PSubscribeCmd),
+    PUnsubscribe(PUnsubscribeCmd),
+
+    //订阅前缀为prefix的key的变更
+    Watch(WatchCmd),
+    //按顺序读取after之后的全部变更
+    Cdc(CdcCmd),
+
+    //以用户身份认证当前连接
+    Auth(AuthCmd),
+
+    //以下是复制命令
+    //副本从主节点全量或增量同步
+    Sync(SyncCmd),
+    //成为leader的副本,leader为空表示提升为主节点
+    ReplicaOf(ReplicaOfCmd),
+
+    //以下是集群命令
+    //返回本节点看到的槽位分配
+    ClusterSlots(ClusterSlotsCmd),
+    //修改槽位的归属或迁移状态
+    ClusterSetSlot(ClusterSetSlotCmd),
+    //把迁出中的槽位的key移动到目标节点
+    ClusterMigrate(ClusterMigrateCmd),
+
+    //在服务端的dir目录中写入存储的一致备份
+    Backup(BackupCmd),
 }
 
 #[derive(Clone,Debug,PartialEq,Eq)]
@@ -70,6 +112,155 @@ pub struct PingCmd{
     pub message:String,
 }
 
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct InfoCmd;
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct DbSizeCmd;
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ConfigGetCmd{
+    //配置名, * 表示全部
+    pub pattern:String,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ConfigSetCmd{
+    pub key:String,
+    pub value:String,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct SlowlogGetCmd{
+    //返回最新的count条,0表示全部
+    pub count:u32,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct SlowlogResetCmd;
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct MonitorCmd;
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct PublishCmd{
+    pub channel:String,
+    pub message:String,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct SubscribeCmd{
+    pub channels:Vec<String>,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct UnsubscribeCmd{
+    //为空表示取消全部订阅
+    pub channels:Vec<String>,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct PSubscribeCmd{
+    //glob模式,支持* ? [abc]
+    pub patterns:Vec<String>,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct WatchCmd{
+    pub prefix:String,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct CdcCmd{
+    //从该序号之后开始,0表示从保留的最早变更开始
+    pub after:u64,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct SyncCmd{
+    //上次同步的主节点复制id,与主节点不同时全量同步
+    pub id:String,
+    //已应用的变更序号,0表示全量同步
+    pub after:u64,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ReplicaOfCmd{
+    //主节点地址,为空表示提升为主节点
+    pub leader:String,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ClusterSlotsCmd;
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ClusterSetSlotCmd{
+    //槽位范围,包含end
+    pub start:u16,
+    pub end:u16,
+    //node、migrating、importing或stable
+    pub state:String,
+    //stable时为空
+    pub addr:String,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ClusterMigrateCmd{
+    pub slot:u16,
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct BackupCmd{
+    //服务端上的目录,必须不存在或为空
+    pub dir:String,
+}
+
+#[derive(Clone,PartialEq,Eq)]
+pub struct AuthCmd{
+    pub user:String,
+    pub password:String,
+}
+
+//日志中不打印口令
+impl std::fmt::Debug for AuthCmd{
+    fn fmt(&self,f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        f.debug_struct("AuthCmd").field("user",&self.user).field("password",&"(redacted)").finish()
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct PUnsubscribeCmd{
+    //为空表示取消全部模式订阅
+    pub patterns:Vec<String>,
+}
+
+/// Response of the `Info` command, sent as a single line of JSON.
+#[derive(Clone,Debug,Default,PartialEq,Eq,Serialize,Deserialize)]
+pub struct ServerInfo{
+    pub version:String,
+    pub uptime_secs:u64,
+    pub engine:EngineStats,
+    pub connections:ServerStats,
+    pub commands_processed:u64,
+    #[serde(default)]
+    pub replication:ReplicationInfo,
+}
+
+/// A line of the `Sync` stream from the leader to a replica.
+#[derive(Clone,Debug,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(tag="type",rename_all="snake_case")]
+pub(crate) enum SyncFrame{
+    /// A full sync: `keys` entries follow, then the changes after `seq`.
+    Snapshot{id:String,seq:u64,keys:u64},
+    /// The changes after `seq` follow.
+    Resume{id:String,seq:u64},
+    /// A key of the snapshot with its remaining TTL in seconds, 0 if it never expires.
+    Entry{key:String,value:String,#[serde(default)] ttl:u32},
+    Change(CdcRecord),
+    /// Sent while the replica has every change of the leader.
+    Heartbeat,
+}
+
 impl Cmd{
     pub fn to_string(&self)->String{
         match self{
@@ -81,6 +272,137 @@ impl Cmd{
             Cmd::VSet(_)=>"VSet".to_string(),
             Cmd::VDel(_)=>"VDel".to_string(),
             Cmd::Ping(_)=>"Ping".to_string(),
+            Cmd::Info(_)=>"Info".to_string(),
+            Cmd::DbSize(_)=>"DbSize".to_string(),
+            Cmd::ConfigGet(_)=>"ConfigGet".to_string(),
+            Cmd::ConfigSet(_)=>"ConfigSet".to_string(),
+            Cmd::SlowlogGet(_)=>"SlowlogGet".to_string(),
+            Cmd::SlowlogReset(_)=>"SlowlogReset".to_string(),
+            Cmd::Monitor(_)=>"Monitor".to_string(),
+            Cmd::Publish(_)=>"Publish".to_string(),
+            Cmd::Subscribe(_)=>"Subscribe".to_string(),
+            Cmd::Unsubscribe(_)=>"Unsubscribe".to_string(),
+            Cmd::PSubscribe(_)=>"PSubscribe".to_string(),
+            Cmd::PUnsubscribe(_)=>"PUnsubscribe".to_string(),
+            Cmd::Watch(_)=>"Watch".to_string(),
+            Cmd::Cdc(_)=>"Cdc".to_string(),
+            Cmd::Auth(_)=>"Auth".to_string(),
+            Cmd::Sync(_)=>"Sync".to_string(),
+            Cmd::ReplicaOf(_)=>"ReplicaOf".to_string(),
+            Cmd::ClusterSlots(_)=>"ClusterSlots".to_string(),
+            Cmd::ClusterSetSlot(_)=>"ClusterSetSlot".to_string(),
+            Cmd::ClusterMigrate(_)=>"ClusterMigrate".to_string(),
+            Cmd::Backup(_)=>"Backup".to_string(),
+        }
+    }
+
+    /// The lowercase name of the command, used as its label in metrics.
+    pub fn name(&self)->&'static str{
+        match self{
+            Cmd::Get(_)=>"get",
+            Cmd::Set(_)=>"set",
+            Cmd::Remove(_)=>"remove",
+            Cmd::Scan(_)=>"scan",
+            Cmd::VGet(_)=>"vget",
+            Cmd::VSet(_)=>"vset",
+            Cmd::VDel(_)=>"vdel",
+            Cmd::Ping(_)=>"ping",
+            Cmd::Info(_)=>"info",
+            Cmd::DbSize(_)=>"dbsize",
+            Cmd::ConfigGet(_)=>"configget",
+            Cmd::ConfigSet(_)=>"configset",
+            Cmd::SlowlogGet(_)=>"slowlogget",
+            Cmd::SlowlogReset(_)=>"slowlogreset",
+            Cmd::Monitor(_)=>"monitor",
+            Cmd::Publish(_)=>"publish",
+            Cmd::Subscribe(_)=>"subscribe",
+            Cmd::Unsubscribe(_)=>"unsubscribe",
+            Cmd::PSubscribe(_)=>"psubscribe",
+            Cmd::PUnsubscribe(_)=>"punsubscribe",
+            Cmd::Watch(_)=>"watch",
+            Cmd::Cdc(_)=>"cdc",
+            Cmd::Auth(_)=>"auth",
+            Cmd::Sync(_)=>"sync",
+            Cmd::ReplicaOf(_)=>"replicaof",
+            Cmd::ClusterSlots(_)=>"clusterslots",
+            Cmd::ClusterSetSlot(_)=>"clustersetslot",
+            Cmd::ClusterMigrate(_)=>"clustermigrate",
+            Cmd::Backup(_)=>"backup",
+        }
+    }
+
+    /// Returns the key of a command reading or writing a single key.
+    pub fn key(&self)->Option<&str>{
+        match self{
+            Cmd::Get(c)=>Some(&c.key),
+            Cmd::Set(c)=>Some(&c.key),
+            Cmd::Remove(c)=>Some(&c.key),
+            Cmd::VGet(c)=>Some(&c.key),
+            Cmd::VSet(c)=>Some(&c.key),
+            Cmd::VDel(c)=>Some(&c.key),
+            _=>None,
+        }
+    }
+
+    /// Returns the command as it is typed in `kvs-client`, the name followed by its arguments.
+    pub fn args(&self)->Vec<String>{
+        match self{
+            Cmd::Get(c)=>vec!["get".to_string(),c.key.clone()],
+            Cmd::Set(c)=>{
+                let mut args=vec!["set".to_string(),c.key.clone(),c.value.clone()];
+                if c.expire>0{
+                    args.push("EX".to_string());
+                    args.push(c.expire.to_string());
+                }
+                args
+            }
+            Cmd::Remove(c)=>vec!["remove".to_string(),c.key.clone()],
+            Cmd::Scan(c)=>vec!["scan".to_string(),c.start.clone(),c.end.clone()],
+            Cmd::VGet(c)=>vec!["vget".to_string(),c.key.clone()],
+            Cmd::VSet(c)=>vec!["vset".to_string(),c.key.clone(),c.value.clone()],
+            Cmd::VDel(c)=>vec!["vdel".to_string(),c.key.clone()],
+            Cmd::Ping(c)=>{
+                let mut args=vec!["ping".to_string()];
+                if !c.message.is_empty(){
+                    args.push(c.message.clone());
+                }
+                args
+            }
+            Cmd::Info(_)=>vec!["info".to_string()],
+            Cmd::DbSize(_)=>vec!["dbsize".to_string()],
+            Cmd::ConfigGet(c)=>vec!["config".to_string(),"get".to_string(),c.pattern.clone()],
+            Cmd::ConfigSet(c)=>vec!["config".to_string(),"set".to_string(),c.key.clone(),c.value.clone()],
+            Cmd::SlowlogGet(c)=>vec!["slowlog".to_string(),"get".to_string(),c.count.to_string()],
+            Cmd::SlowlogReset(_)=>vec!["slowlog".to_string(),"reset".to_string()],
+            Cmd::Monitor(_)=>vec!["monitor".to_string()],
+            Cmd::Publish(c)=>vec!["publish".to_string(),c.channel.clone(),c.message.clone()],
+            Cmd::Subscribe(c)=>[vec!["subscribe".to_string()],c.channels.clone()].concat(),
+            Cmd::Unsubscribe(c)=>[vec!["unsubscribe".to_string()],c.channels.clone()].concat(),
+            Cmd::PSubscribe(c)=>[vec!["psubscribe".to_string()],c.patterns.clone()].concat(),
+            Cmd::PUnsubscribe(c)=>[vec!["punsubscribe".to_string()],c.patterns.clone()].concat(),
+            Cmd::Watch(c)=>vec!["watch".to_string(),c.prefix.clone()],
+            Cmd::Cdc(c)=>vec!["cdc".to_string(),c.after.to_string()],
+            //口令不出现在monitor和slowlog中
+            Cmd::Auth(c)=>vec!["auth".to_string(),c.user.clone(),"(redacted)".to_string()],
+            Cmd::Sync(c)=>vec!["sync".to_string(),c.id.clone(),c.after.to_string()],
+            Cmd::ReplicaOf(c)=>{
+                if c.leader.is_empty(){
+                    vec!["replicaof".to_string(),"no".to_string(),"one".to_string()]
+                }else{
+                    vec!["replicaof".to_string(),c.leader.clone()]
+                }
+            }
+            Cmd::ClusterSlots(_)=>vec!["cluster".to_string(),"slots".to_string()],
+            Cmd::ClusterSetSlot(c)=>{
+                let slots=if c.start==c.end { c.start.to_string() } else { format!("{}-{}",c.start,c.end) };
+                let mut args=vec!["cluster".to_string(),"setslot".to_string(),slots,c.state.clone()];
+                if !c.addr.is_empty(){
+                    args.push(c.addr.clone());
+                }
+                args
+            }
+            Cmd::ClusterMigrate(c)=>vec!["cluster".to_string(),"migrate".to_string(),c.slot.to_string()],
+            Cmd::Backup(c)=>vec!["backup".to_string(),c.dir.clone()],
         }
     }
 
@@ -156,13 +478,112 @@ impl Cmd{
                 res.extend(u32::to_be_bytes(c.message.len() as u32));
                 res.extend_from_slice(c.message.as_bytes());
             },
+            Cmd::Info(_)=>{
+                res.push(9 as u8);
+            },
+            Cmd::DbSize(_)=>{
+                res.push(10 as u8);
+            },
+            Cmd::ConfigGet(c)=>{
+                res.push(11 as u8);
+                len+=4;
+                len+=c.pattern.len() as u32;
+                res.extend(u32::to_be_bytes(c.pattern.len() as u32));
+                res.extend_from_slice(c.pattern.as_bytes());
+            },
+            Cmd::ConfigSet(c)=>{
+                res.push(12 as u8);
+                len+=8;
+                len+=c.key.len() as u32;
+                len+=c.value.len() as u32;
+                res.extend(u32::to_be_bytes(c.key.len() as u32));
+                res.extend_from_slice(c.key.as_bytes());
+                res.extend(u32::to_be_bytes(c.value.len() as u32));
+                res.extend_from_slice(c.value.as_bytes());
+            },
+            Cmd::SlowlogGet(c)=>{
+                res.push(13 as u8);
+                len+=4;
+                res.extend(u32::to_be_bytes(c.count));
+            },
+            Cmd::SlowlogReset(_)=>{
+                res.push(14 as u8);
+            },
+            Cmd::Monitor(_)=>{
+                res.push(15 as u8);
+            },
+            Cmd::Publish(c)=>{
+                res.push(16 as u8);
+                len+=encode_string(&mut res,&c.channel);
+                len+=encode_string(&mut res,&c.message);
+            },
+            Cmd::Subscribe(c)=>{
+                res.push(17 as u8);
+                len+=encode_strings(&mut res,&c.channels);
+            },
+            Cmd::Unsubscribe(c)=>{
+                res.push(18 as u8);
+                len+=encode_strings(&mut res,&c.channels);
+            },
+            Cmd::PSubscribe(c)=>{
+                res.push(19 as u8);
+                len+=encode_strings(&mut res,&c.patterns);
+            },
+            Cmd::PUnsubscribe(c)=>{
+                res.push(20 as u8);
+                len+=encode_strings(&mut res,&c.patterns);
+            },
+            Cmd::Watch(c)=>{
+                res.push(21 as u8);
+                len+=encode_string(&mut res,&c.prefix);
+            },
+            Cmd::Cdc(c)=>{
+                res.push(22 as u8);
+                res.extend(u64::to_be_bytes(c.after));
+                len+=8;
+            },
+            Cmd::Auth(c)=>{
+                res.push(23 as u8);
+                len+=encode_string(&mut res,&c.user);
+                len+=encode_string(&mut res,&c.password);
+            },
+            Cmd::Sync(c)=>{
+                res.push(24 as u8);
+                len+=encode_string(&mut res,&c.id);
+                res.extend(u64::to_be_bytes(c.after));
+                len+=8;
+            },
+            Cmd::ReplicaOf(c)=>{
+                res.push(25 as u8);
+                len+=encode_string(&mut res,&c.leader);
+            },
+            Cmd::ClusterSlots(_)=>{
+                res.push(26 as u8);
+            },
+            Cmd::ClusterSetSlot(c)=>{
+                res.push(27 as u8);
+                res.extend(u16::to_be_bytes(c.start));
+                res.extend(u16::to_be_bytes(c.end));
+                len+=4;
+                len+=encode_string(&mut res,&c.state);
+                len+=encode_string(&mut res,&c.addr);
+            },
+            Cmd::ClusterMigrate(c)=>{
+                res.push(28 as u8);
+                res.extend(u16::to_be_bytes(c.slot));
+                len+=2;
+            },
+            Cmd::Backup(c)=>{
+                res.push(29 as u8);
+                len+=encode_string(&mut res,&c.dir);
+            },
         }
         fres.extend(u32::to_be_bytes(len));
         fres.extend_from_slice(res.as_slice());
         fres
     }
 
-    pub fn decode(len:u32,s:Vec<u8>)->Result<Self>{
+    pub fn decode(len:u32,s:&[u8])->Result<Self>{
         if len> s.len() as u32{
             return Err(KvsError::DecodeError);
         }
@@ -241,6 +662,89 @@ impl Cmd{
                 let message=String::from_utf8(s[5..5+message_len as usize].to_vec()).unwrap();
                 return Ok(Cmd::Ping(PingCmd{message:message}));
             }
+            9=>{
+                return Ok(Cmd::Info(InfoCmd));
+            }
+            10=>{
+                return Ok(Cmd::DbSize(DbSizeCmd));
+            }
+            11=>{
+                let (pattern,_)=decode_string(s,1)?;
+                return Ok(Cmd::ConfigGet(ConfigGetCmd{pattern}));
+            }
+            12=>{
+                let (key,st)=decode_string(s,1)?;
+                let (value,_)=decode_string(s,st)?;
+                return Ok(Cmd::ConfigSet(ConfigSetCmd{key,value}));
+            }
+            13=>{
+                let bytes:[u8;4]=s.get(1..5).ok_or(KvsError::DecodeError)?.try_into().unwrap();
+                return Ok(Cmd::SlowlogGet(SlowlogGetCmd{count:u32::from_be_bytes(bytes)}));
+            }
+            14=>{
+                return Ok(Cmd::SlowlogReset(SlowlogResetCmd));
+            }
+            15=>{
+                return Ok(Cmd::Monitor(MonitorCmd));
+            }
+            16=>{
+                let (channel,st)=decode_string(s,1)?;
+                let (message,_)=decode_string(s,st)?;
+                return Ok(Cmd::Publish(PublishCmd{channel,message}));
+            }
+            17=>{
+                return Ok(Cmd::Subscribe(SubscribeCmd{channels:decode_strings(s,1)?}));
+            }
+            18=>{
+                return Ok(Cmd::Unsubscribe(UnsubscribeCmd{channels:decode_strings(s,1)?}));
+            }
+            19=>{
+                return Ok(Cmd::PSubscribe(PSubscribeCmd{patterns:decode_strings(s,1)?}));
+            }
+            20=>{
+                return Ok(Cmd::PUnsubscribe(PUnsubscribeCmd{patterns:decode_strings(s,1)?}));
+            }
+            21=>{
+                let (prefix,_)=decode_string(s,1)?;
+                return Ok(Cmd::Watch(WatchCmd{prefix}));
+            }
+            22=>{
+                let bytes:[u8;8]=s.get(1..9).ok_or(KvsError::DecodeError)?.try_into().unwrap();
+                return Ok(Cmd::Cdc(CdcCmd{after:u64::from_be_bytes(bytes)}));
+            }
+            23=>{
+                let (user,st)=decode_string(s,1)?;
+                let (password,_)=decode_string(s,st)?;
+                return Ok(Cmd::Auth(AuthCmd{user,password}));
+            }
+            24=>{
+                let (id,st)=decode_string(s,1)?;
+                let bytes:[u8;8]=s.get(st..st+8).ok_or(KvsError::DecodeError)?.try_into().unwrap();
+                return Ok(Cmd::Sync(SyncCmd{id,after:u64::from_be_bytes(bytes)}));
+            }
+            25=>{
+                let (leader,_)=decode_string(s,1)?;
+                return Ok(Cmd::ReplicaOf(ReplicaOfCmd{leader}));
+            }
+            26=>{
+                return Ok(Cmd::ClusterSlots(ClusterSlotsCmd));
+            }
+            27=>{
+                let bytes:[u8;4]=s.get(1..5).ok_or(KvsError::DecodeError)?.try_into().unwrap();
+                let (state,st)=decode_string(s,5)?;
+                let (addr,_)=decode_string(s,st)?;
+                let start=u16::from_be_bytes([bytes[0],bytes[1]]);
+                let end=u16::from_be_bytes([bytes[2],bytes[3]]);
+                return Ok(Cmd::ClusterSetSlot(ClusterSetSlotCmd{start,end,state,addr}));
+            }
+            28=>{
+                let bytes:[u8;2]=s.get(1..3).ok_or(KvsError::DecodeError)?.try_into().unwrap();
+                return Ok(Cmd::ClusterMigrate(ClusterMigrateCmd{slot:u16::from_be_bytes(bytes)}));
+            }
+            29=>{
+                let (dir,_)=decode_string(s,1)?;
+                return Ok(Cmd::Backup(BackupCmd{dir}));
+            }
             _=>{
                 Err(KvsError::DecodeError)
             }
@@ -248,11 +752,54 @@ impl Cmd{
     }
 }
 
+//从st处解析<len><string>,返回字符串和下一个字段的起始位置
+fn decode_string(s:&[u8],st:usize)->Result<(String,usize)>{
+    let bytes:[u8;4]=s.get(st..st+4).ok_or(KvsError::DecodeError)?.try_into().unwrap();
+    let len=u32::from_be_bytes(bytes) as usize;
+    let bytes=s.get(st+4..st+4+len).ok_or(KvsError::DecodeError)?;
+    Ok((String::from_utf8(bytes.to_vec())?,st+4+len))
+}
+
+//追加<len><string>,返回追加的字节数
+fn encode_string(res:&mut Vec<u8>,s:&str)->u32{
+    res.extend(u32::to_be_bytes(s.len() as u32));
+    res.extend_from_slice(s.as_bytes());
+    4+s.len() as u32
+}
+
+//追加<count><len><string>..,返回追加的字节数
+fn encode_strings(res:&mut Vec<u8>,items:&[String])->u32{
+    res.extend(u32::to_be_bytes(items.len() as u32));
+    4+items.iter().map(|item|encode_string(res,item)).sum::<u32>()
+}
+
+//从st处解析<count><len><string>..
+fn decode_strings(s:&[u8],st:usize)->Result<Vec<String>>{
+    let bytes:[u8;4]=s.get(st..st+4).ok_or(KvsError::DecodeError)?.try_into().unwrap();
+    let count=u32::from_be_bytes(bytes) as usize;
+    let mut items=Vec::new();
+    let mut st=st+4;
+    for _ in 0..count{
+        let (item,next)=decode_string(s,st)?;
+        items.push(item);
+        st=next;
+    }
+    Ok(items)
+}
 
 //响应协议格式
 /*
-成功：OK[value]..[value]\n//只有Get响应有value,scan可能会有多个以空格间隔的value
-失败：Error<message>\n
+成功：OK[value]..[value]\n//只有Get响应有value,scan响应为[[key,value],..]形式的json数组
+                          //info响应为一行json,config get响应为[[name,value],..]形式的json数组
+                          //slowlog get响应为一行json数组,cluster slots响应为一行json格式的槽位表
+                          //cluster migrate响应为移动的key数,backup响应为一行json格式的备份清单
+失败：Error<message>\n //集群模式下key属于其他节点时为ErrorMOVED <slot> <addr>,
+                      //key已从迁出中的槽位移走时为ErrorASK <slot> <addr>
+流式响应：monitor成功后服务端持续发送OK<event>\n,每行是其他客户端执行的一条命令,
+        watch成功后每行是一个json格式的key变更事件,
+        cdc成功后每行是一个json格式的变更记录,按序号顺序发送且不会丢弃,
+        sync成功后每行是一个json格式的复制帧:全量快照或续传的起点、快照中的key、变更记录和心跳,
+        直到连接断开或服务端关闭(发送Error<message>\n)
 */
 
 pub async fn parse_response(s:String)->Result<String>{
@@ -264,9 +811,33 @@ pub async fn parse_response(s:String)->Result<String>{
         return Ok("".to_string());
     }else{
         let message=s[5..].to_string();
-        if message.trim()=="Key not found"{
+        let message_trim=message.trim();
+        if message_trim=="Key not found"{
             return Err(KvsError::KeyNotFound);
         }
+        if message_trim=="Invalid username or password"{
+            return Err(KvsError::AuthFailed);
+        }
+        if let Some(reason)=message_trim.strip_prefix("No permission: "){
+            return Err(KvsError::NoPermission(reason.to_string()));
+        }
+        if let Some(leader)=message_trim.strip_prefix("Read-only replica, write to the leader at "){
+            return Err(KvsError::ReadOnlyReplica(leader.to_string()));
+        }
+        if let Some(leader)=message_trim.strip_prefix("Replica is loading a full sync from ").and_then(|r|r.strip_suffix(", retry later")){
+            return Err(KvsError::ReplicaLoading(leader.to_string()));
+        }
+        if let Some(leader)=message_trim.strip_prefix("Not the leader, the leader is "){
+            return Err(KvsError::NotLeader(leader.to_string()));
+        }
+        //MOVED <slot> <addr>和ASK <slot> <addr>
+        for (prefix,ask) in [("MOVED ",false),("ASK ",true)]{
+            if let Some((slot,addr))=message_trim.strip_prefix(prefix).and_then(|r|r.split_once(' '))
+                && let Ok(slot)=slot.parse::<u16>(){
+                let addr=addr.to_string();
+                return Err(if ask { KvsError::Ask(slot,addr) } else { KvsError::Moved(slot,addr) });
+            }
+        }
         return Err(KvsError::StringError(message));
     }
 }
@@ -288,9 +859,9 @@ pub fn init_logger(log_dir: &str,is_client:bool) -> Result<()> {
     .create(true)
     .open(log_path)?;
 
-    // 配置 env_logger
+    // 配置 env_logger,实际的日志级别由 log::max_level 控制,可在运行时调整
     Builder::new()
-        .filter_level(log::LevelFilter::Info) // 设置日志级别
+        .filter_level(log::LevelFilter::Trace)
         .target(env_logger::Target::Pipe(Box::new(log_file))) // 输出到文件
         .format(|buf, record| {
             // 自定义日志格式
@@ -304,10 +875,16 @@ pub fn init_logger(log_dir: &str,is_client:bool) -> Result<()> {
             )
         })
         .init();
+    set_log_level(log::LevelFilter::Info);
 
     Ok(())
 }
 
+/// Changes the level of the logger installed by `init_logger`.
+pub fn set_log_level(level: log::LevelFilter) {
+    log::set_max_level(level);
+}
+
 //向量校验
 pub fn validate_vector(s:&str)->Result<String>{
     let s=s.trim();
@@ -0,0 +1,559 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use log::LevelFilter;
+use serde::Deserialize;
+use crate::acl::{self,Acl,User};
+use crate::cluster::{self,SlotRange,SLOTS};
+use crate::raft::{Member, RaftOptions};
+use crate::tls;
+use crate::{Compression, KvsError, KvStoreOptions, Result, ServerAddr, ServerOptions};
+
+//配置文件格式(TOML),所有字段均可省略
+/*
+[server]
+addr = "127.0.0.1:4001"
+tcp = true                   # 为false时只监听unix_socket
+unix_socket = "/tmp/kvs.sock"
+unix_socket_mode = 0o660
+threads = 4
+shutdown_timeout = 30
+max_connections = 1024
+idle_timeout = 0
+read_timeout = 0
+write_timeout = 0
+metrics_addr = "127.0.0.1:9001"
+resp_addr = "127.0.0.1:6379"   # Redis协议的监听地址
+http_addr = "127.0.0.1:8080"   # HTTP/JSON网关的监听地址
+slowlog_threshold = 10
+slowlog_max_len = 128
+tls_cert = "server.pem"      # 同时配置tls_cert和tls_key时启用TLS
+tls_key = "server-key.pem"
+tls_client_ca = "ca.pem"     # 要求客户端提供该CA签发的证书
+
+[engine]
+name = "kvs"
+data = "./data"
+compaction_threshold = 1048576
+compression = "none"         # value的压缩方式: none、lz4或zstd,只影响kvs引擎新写入的记录
+compression_threshold = 1024 # 小于该字节数的value不压缩
+
+[log]
+dir = "./log"
+level = "info"
+
+[replication]
+replica_of = "127.0.0.1:4001"   # 作为该主节点的副本启动,也可以是unix:///path
+user = "replica"                # 连接主节点或迁移槽位时认证的用户,需要admin权限,迁移槽位还需要write权限
+password = "..."
+
+[raft]
+id = 1
+addr = "127.0.0.1:7001"        # 接收其他节点Raft消息的地址
+secret = "..."                 # 节点之间互相认证的共享密钥,所有成员必须相同
+# 集群的初始成员,加入已有集群的节点省略,由leader添加;修改后向leader发送SIGHUP变更成员
+members = [
+    { id = 1, addr = "127.0.0.1:7001", client_addr = "127.0.0.1:4001" },
+    { id = 2, addr = "127.0.0.1:7002", client_addr = "127.0.0.1:4002" },
+    { id = 3, addr = "127.0.0.1:7003", client_addr = "127.0.0.1:4003" },
+]
+
+[cluster]
+addr = "127.0.0.1:4001"        # 本节点在nodes中的地址,省略时为server.addr
+# 每个节点负责的槽位(0-16383)和Redis协议的地址,迁移槽位后需要同步修改各节点的配置
+nodes = [
+    { addr = "127.0.0.1:4001", resp_addr = "127.0.0.1:6379", slots = ["0-8191"] },
+    { addr = "127.0.0.1:4002", resp_addr = "127.0.0.1:6380", slots = ["8192-16383"] },
+]
+
+# 每个用户一个[[users]]表,未配置用户时不需要认证
+[[users]]
+name = "app"
+password = "pbkdf2-sha256$10000$..."  # kvs-server --hash-password <password> 生成
+commands = ["read", "write"]          # read, write, admin, vector
+keys = ["app:"]                       # 允许访问的key前缀,省略表示全部key
+*/
+
+/// Settings of `kvs-server`, usually loaded from a TOML file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub engine: EngineConfig,
+    pub log: LogConfig,
+    pub replication: ReplicationConfig,
+    pub raft: RaftConfig,
+    pub cluster: ClusterConfig,
+    /// Users allowed to connect, authentication is disabled if there are none
+    pub users: Vec<User>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The address to bind the server
+    pub addr: String,
+    /// Whether to listen on `addr`, it can only be disabled with a Unix socket
+    pub tcp: bool,
+    /// Path of a Unix socket to listen on in addition to TCP
+    pub unix_socket: Option<String>,
+    /// Permissions of the Unix socket file, e.g. 0o660; they follow the umask if not set
+    pub unix_socket_mode: Option<u32>,
+    /// Number of worker threads serving connections
+    pub threads: u32,
+    /// Seconds to wait for in-flight requests on shutdown
+    pub shutdown_timeout: u64,
+    /// Maximum number of concurrent connections, 0 means unlimited
+    pub max_connections: usize,
+    /// Idle connection timeout in seconds, 0 disables it
+    pub idle_timeout: u64,
+    /// Per-frame read timeout in seconds, 0 disables it
+    pub read_timeout: u64,
+    /// Response write timeout in seconds, 0 disables it
+    pub write_timeout: u64,
+    /// Address of the Prometheus metrics endpoint, disabled if not set
+    pub metrics_addr: Option<String>,
+    /// Address serving the Redis protocol, disabled if not set
+    pub resp_addr: Option<String>,
+    /// Address serving the HTTP/JSON gateway, disabled if not set
+    pub http_addr: Option<String>,
+    /// Commands slower than this many milliseconds are recorded in the slowlog, 0 disables it
+    pub slowlog_threshold: u64,
+    /// Maximum number of entries kept in the slowlog
+    pub slowlog_max_len: usize,
+    /// PEM certificate chain of the server, TLS is enabled together with `tls_key`
+    pub tls_cert: Option<String>,
+    /// PEM private key of the server
+    pub tls_key: Option<String>,
+    /// PEM CA certificates, clients have to present a certificate signed by one of them
+    pub tls_client_ca: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: "127.0.0.1:4001".to_string(),
+            tcp: true,
+            unix_socket: None,
+            unix_socket_mode: None,
+            threads: 4,
+            shutdown_timeout: 30,
+            max_connections: 1024,
+            idle_timeout: 0,
+            read_timeout: 0,
+            write_timeout: 0,
+            metrics_addr: None,
+            resp_addr: None,
+            http_addr: None,
+            slowlog_threshold: 10,
+            slowlog_max_len: 128,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    /// The storage engine, `kvs` or `sled`; the previous engine is used if not set
+    pub name: Option<String>,
+    /// The data directory
+    pub data: String,
+    /// Bytes of stale log entries that trigger a `KvStore` compaction
+    pub compaction_threshold: u64,
+    /// Compression of the values written by a `KvStore`: none, lz4 or zstd
+    pub compression: Compression,
+    /// Values shorter than this many bytes are stored raw
+    pub compression_threshold: usize,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            name: None,
+            data: "./data".to_string(),
+            compaction_threshold: KvStoreOptions::default().compaction_threshold,
+            compression: KvStoreOptions::default().compression,
+            compression_threshold: KvStoreOptions::default().compression_threshold,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// The log directory
+    pub dir: String,
+    /// One of off, error, warn, info, debug, trace
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            dir: "./log".to_string(),
+            level: "info".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// The leader to replicate, the server is a leader if not set
+    pub replica_of: Option<String>,
+    /// The user to authenticate as with the leader, together with `password`
+    pub user: Option<String>,
+    /// The plain text password of `user`
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RaftConfig {
+    /// The id of this node, Raft is disabled if not set
+    pub id: Option<u64>,
+    /// The address other nodes send Raft messages to
+    pub addr: Option<String>,
+    /// The secret shared by all members, required with Raft
+    pub secret: Option<String>,
+    /// The initial members of a new cluster, empty when joining an existing one;
+    /// reloading a changed list on the leader adds and removes nodes
+    pub members: Vec<RaftMemberConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RaftMemberConfig {
+    pub id: u64,
+    /// The Raft address of the member
+    pub addr: String,
+    /// The address clients reach the member on, returned when redirecting writes
+    pub client_addr: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// The address of this node in `nodes`, `server.addr` if not set
+    pub addr: Option<String>,
+    /// The slots of every node, cluster mode is disabled if there are none
+    pub nodes: Vec<ClusterNodeConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterNodeConfig {
+    /// The address clients reach the node on
+    pub addr: String,
+    /// The address Redis protocol clients reach the node on, required on every
+    /// node if `server.resp_addr` is set
+    pub resp_addr: Option<String>,
+    /// Slots `100` or ranges of slots `0-8191` served by the node
+    pub slots: Vec<String>,
+}
+
+impl Config {
+    /// Loads and validates the config file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Config` naming the file if it cannot be read,
+    /// is not valid TOML, has unknown fields or invalid values.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| KvsError::Config(format!("cannot read {}: {}", path.display(), e)))?;
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| KvsError::Config(format!("{}: {}", path.display(), e.message())))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that all settings have valid values.
+    pub fn validate(&self) -> Result<()> {
+        self.addr()?;
+        self.metrics_addr()?;
+        self.resp_addr()?;
+        self.http_addr()?;
+        self.replica_of()?;
+        self.raft_addr()?;
+        self.cluster()?;
+        self.log_level()?;
+        if !self.server.tcp && self.server.unix_socket.is_none() {
+            return Err(KvsError::Config("server.tcp can only be disabled with server.unix_socket".to_string()));
+        }
+        if cfg!(not(unix)) && self.server.unix_socket.is_some() {
+            return Err(KvsError::Config("server.unix_socket is not supported on this platform".to_string()));
+        }
+        if let Some(mode) = self.server.unix_socket_mode
+            && mode > 0o777
+        {
+            return Err(KvsError::Config(format!("server.unix_socket_mode {:o} is invalid, must be at most 0o777", mode)));
+        }
+        match (&self.server.tls_cert, &self.server.tls_key) {
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(KvsError::Config("server.tls_cert and server.tls_key must be set together".to_string()));
+            }
+            (None, None) if self.server.tls_client_ca.is_some() => {
+                return Err(KvsError::Config("server.tls_client_ca requires server.tls_cert and server.tls_key".to_string()));
+            }
+            _ => (),
+        }
+        if self.replication.user.is_some() != self.replication.password.is_some() {
+            return Err(KvsError::Config("replication.user and replication.password must be set together".to_string()));
+        }
+        if self.raft.id.is_some() != self.raft.addr.is_some() {
+            return Err(KvsError::Config("raft.id and raft.addr must be set together".to_string()));
+        }
+        if self.raft.id.is_some() && self.raft.secret.as_deref().is_none_or(str::is_empty) {
+            return Err(KvsError::Config("raft.secret must be set to authenticate the other members".to_string()));
+        }
+        if self.raft.id.is_some() && self.replication.replica_of.is_some() {
+            return Err(KvsError::Config("raft and replication.replica_of cannot be used together".to_string()));
+        }
+        for (i, member) in self.raft.members.iter().enumerate() {
+            if self.raft.members[..i].iter().any(|m| m.id == member.id) {
+                return Err(KvsError::Config(format!("raft member {} is defined twice", member.id)));
+            }
+        }
+        if self.server.threads == 0 {
+            return Err(KvsError::Config("server.threads must be greater than 0".to_string()));
+        }
+        if let Some(name) = &self.engine.name
+            && name != "kvs" && name != "sled"
+        {
+            return Err(KvsError::Config(format!("engine.name '{}' is invalid, must be 'kvs' or 'sled'", name)));
+        }
+        if self.engine.data.is_empty() {
+            return Err(KvsError::Config("engine.data must not be empty".to_string()));
+        }
+        if self.engine.compaction_threshold == 0 {
+            return Err(KvsError::Config("engine.compaction_threshold must be greater than 0".to_string()));
+        }
+        for (i, user) in self.users.iter().enumerate() {
+            if user.name.is_empty() || user.name.contains(char::is_whitespace) {
+                return Err(KvsError::Config(format!("users.name '{}' is invalid", user.name)));
+            }
+            if self.users[..i].iter().any(|u| u.name == user.name) {
+                return Err(KvsError::Config(format!("user '{}' is defined twice", user.name)));
+            }
+            acl::validate_hash(&user.password)?;
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> Result<SocketAddr> {
+        self.server.addr.parse().map_err(|e| {
+            KvsError::Config(format!("server.addr '{}' is invalid: {}", self.server.addr, e))
+        })
+    }
+
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>> {
+        match &self.server.metrics_addr {
+            Some(addr) => addr.parse().map(Some).map_err(|e| {
+                KvsError::Config(format!("server.metrics_addr '{}' is invalid: {}", addr, e))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn resp_addr(&self) -> Result<Option<SocketAddr>> {
+        match &self.server.resp_addr {
+            Some(addr) => addr.parse().map(Some).map_err(|e| {
+                KvsError::Config(format!("server.resp_addr '{}' is invalid: {}", addr, e))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn http_addr(&self) -> Result<Option<SocketAddr>> {
+        match &self.server.http_addr {
+            Some(addr) => addr.parse().map(Some).map_err(|e| {
+                KvsError::Config(format!("server.http_addr '{}' is invalid: {}", addr, e))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn replica_of(&self) -> Result<Option<ServerAddr>> {
+        match &self.replication.replica_of {
+            Some(addr) => addr.parse().map(Some).map_err(|e| {
+                KvsError::Config(format!("replication.replica_of '{}' is invalid: {}", addr, e))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn raft_addr(&self) -> Result<Option<SocketAddr>> {
+        match &self.raft.addr {
+            Some(addr) => addr.parse().map(Some).map_err(|e| {
+                KvsError::Config(format!("raft.addr '{}' is invalid: {}", addr, e))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// The options of the Raft node, `None` if Raft is disabled.
+    pub fn raft_options(&self) -> Option<RaftOptions> {
+        let id = self.raft.id?;
+        let members: BTreeMap<u64, Member> = self
+            .raft
+            .members
+            .iter()
+            .map(|m| (m.id, Member { addr: m.addr.clone(), client_addr: m.client_addr.clone() }))
+            .collect();
+        Some(RaftOptions { members, secret: self.raft.secret.clone(), ..RaftOptions::new(id) })
+    }
+
+    /// This node's address and the slots of every node, `None` if cluster mode is disabled.
+    pub fn cluster(&self) -> Result<Option<(String, Vec<SlotRange>)>> {
+        if self.cluster.nodes.is_empty() {
+            return Ok(None);
+        }
+        let mut ranges = Vec::new();
+        let mut assigned = vec![false; SLOTS as usize];
+        for node in &self.cluster.nodes {
+            node.addr.parse::<ServerAddr>().map_err(|e| {
+                KvsError::Config(format!("cluster.nodes.addr '{}' is invalid: {}", node.addr, e))
+            })?;
+            match &node.resp_addr {
+                Some(addr) => {
+                    addr.parse::<SocketAddr>().map_err(|e| {
+                        KvsError::Config(format!("cluster.nodes.resp_addr '{}' is invalid: {}", addr, e))
+                    })?;
+                }
+                // Redis协议的客户端需要重定向到对方的Redis协议地址
+                None if self.server.resp_addr.is_some() => {
+                    return Err(KvsError::Config(format!("cluster.nodes.resp_addr of {} must be set with server.resp_addr", node.addr)));
+                }
+                None => (),
+            }
+            for slots in &node.slots {
+                let slots = cluster::parse_slots(slots).map_err(|e| KvsError::Config(format!("cluster.nodes.slots: {}", e)))?;
+                if let Some(slot) = slots.clone().find(|&s| assigned[s as usize]) {
+                    return Err(KvsError::Config(format!("cluster slot {} is assigned twice", slot)));
+                }
+                slots.clone().for_each(|s| assigned[s as usize] = true);
+                ranges.push(SlotRange { start: *slots.start(), end: *slots.end(), addr: node.addr.clone() });
+            }
+        }
+        let myself = self.cluster.addr.clone().unwrap_or_else(|| self.server.addr.clone());
+        Ok(Some((myself, ranges)))
+    }
+
+    /// Loads the TLS config of the listener, `None` if TLS is disabled.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Tls` if the certificates or the key cannot be loaded.
+    pub fn tls(&self) -> Result<Option<Arc<rustls::ServerConfig>>> {
+        match (&self.server.tls_cert, &self.server.tls_key) {
+            (Some(cert), Some(key)) => tls::server_config(
+                Path::new(cert),
+                Path::new(key),
+                self.server.tls_client_ca.as_deref().map(Path::new),
+            )
+            .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter> {
+        self.log.level.parse().map_err(|_| {
+            KvsError::Config(format!(
+                "log.level '{}' is invalid, must be one of off, error, warn, info, debug, trace",
+                self.log.level
+            ))
+        })
+    }
+
+    pub fn server_options(&self) -> ServerOptions {
+        ServerOptions {
+            max_connections: self.server.max_connections,
+            idle_timeout: secs(self.server.idle_timeout),
+            read_timeout: secs(self.server.read_timeout),
+            write_timeout: secs(self.server.write_timeout),
+            slowlog_threshold: match self.server.slowlog_threshold {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            slowlog_max_len: self.server.slowlog_max_len,
+            acl: Acl::new(self.users.clone()),
+        }
+    }
+
+    pub fn store_options(&self) -> KvStoreOptions {
+        KvStoreOptions {
+            compaction_threshold: self.engine.compaction_threshold,
+            compression: self.engine.compression,
+            compression_threshold: self.engine.compression_threshold,
+        }
+    }
+
+    /// Returns the settings that differ from `other` but only take effect after a restart.
+    ///
+    /// Log level, connection limits, timeouts, slowlog settings and users can change while the server is running.
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut res = Vec::new();
+        if self.server.addr != other.server.addr {
+            res.push("server.addr");
+        }
+        if self.server.tcp != other.server.tcp {
+            res.push("server.tcp");
+        }
+        if self.server.unix_socket != other.server.unix_socket
+            || self.server.unix_socket_mode != other.server.unix_socket_mode
+        {
+            res.push("server.unix_socket");
+        }
+        if self.server.threads != other.server.threads {
+            res.push("server.threads");
+        }
+        if self.server.shutdown_timeout != other.server.shutdown_timeout {
+            res.push("server.shutdown_timeout");
+        }
+        if self.server.metrics_addr != other.server.metrics_addr {
+            res.push("server.metrics_addr");
+        }
+        if self.server.resp_addr != other.server.resp_addr {
+            res.push("server.resp_addr");
+        }
+        if self.server.http_addr != other.server.http_addr {
+            res.push("server.http_addr");
+        }
+        if self.server.tls_cert != other.server.tls_cert
+            || self.server.tls_key != other.server.tls_key
+            || self.server.tls_client_ca != other.server.tls_client_ca
+        {
+            res.push("server.tls");
+        }
+        if self.replication != other.replication {
+            res.push("replication");
+        }
+        //成员列表由leader在重新加载时变更
+        if self.raft.id != other.raft.id || self.raft.addr != other.raft.addr || self.raft.secret != other.raft.secret {
+            res.push("raft");
+        }
+        if self.cluster != other.cluster {
+            res.push("cluster");
+        }
+        if self.engine != other.engine {
+            res.push("engine");
+        }
+        if self.log.dir != other.log.dir {
+            res.push("log.dir");
+        }
+        res
+    }
+}
+
+//0表示不限制
+fn secs(s: u64) -> Option<Duration> {
+    if s == 0 { None } else { Some(Duration::from_secs(s)) }
+}
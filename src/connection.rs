@@ -0,0 +1,231 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+#[cfg(unix)]
+use socket2::SockRef;
+
+type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// The address of a connected client, Unix socket clients are numbered.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Peer {
+    Tcp(SocketAddr),
+    Unix(u64),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(id) => write!(f, "unix:{}", id),
+        }
+    }
+}
+
+/// A client connection accepted by the server, plain TCP, TLS or a Unix socket.
+///
+/// Clones share the same connection, so a handler can buffer reads and writes
+/// separately like with `TcpStream::try_clone`.
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    // 原始socket用于设置超时,TLS状态由所有clone共享
+    Tls(TcpStream, Arc<Mutex<TlsStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    /// Starts a TLS session on `stream`, the handshake happens with the first read.
+    pub fn tls(stream: TcpStream, config: Arc<ServerConfig>) -> io::Result<Connection> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        let socket = stream.try_clone()?;
+        Ok(Connection::Tls(socket, Arc::new(Mutex::new(StreamOwned::new(conn, stream)))))
+    }
+
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        Ok(match self {
+            Connection::Tcp(s) => Connection::Tcp(s.try_clone()?),
+            Connection::Tls(s, tls) => Connection::Tls(s.try_clone()?, Arc::clone(tls)),
+            #[cfg(unix)]
+            Connection::Unix(s) => Connection::Unix(s.try_clone()?),
+        })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) | Connection::Tls(s, _) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) | Connection::Tls(s, _) => s.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) | Connection::Tls(s, _) => s.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Returns whether an error message can be written without a handshake.
+    pub fn is_plain(&self) -> bool {
+        !matches!(self, Connection::Tls(..))
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) | Connection::Tls(s, _) => s.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.shutdown(Shutdown::Both),
+        }
+    }
+
+    /// Waits until data can be read like `TcpStream::peek`, returns 0 once the peer closed.
+    ///
+    /// It fails with a timeout error after the read timeout.
+    pub fn peek_ready(&self) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => {
+                let mut probe = [0u8; 1];
+                s.peek(&mut probe)
+            }
+            Connection::Tls(_, tls) => tls_ready(&mut lock(tls)),
+            #[cfg(unix)]
+            Connection::Unix(s) => {
+                // std的UnixStream::peek还不稳定
+                let mut probe = [MaybeUninit::<u8>::uninit(); 1];
+                SockRef::from(s).peek(&mut probe)
+            }
+        }
+    }
+
+    /// Checks without blocking whether data can be read, returns `None` once the peer closed.
+    pub fn poll_readable(&self) -> io::Result<Option<bool>> {
+        self.set_nonblocking(true)?;
+        let res = self.peek_ready();
+        self.set_nonblocking(false)?;
+        match res {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(true)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Some(false)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Connection::Tcp(s) | Connection::Tls(s, _) => s.as_raw_fd(),
+            Connection::Unix(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+/// Reads a request from a connection within one deadline for the whole request.
+///
+/// A plain read timeout restarts with every read, so a client sending a frame
+/// byte by byte never times out; here every read only waits for the time left.
+pub(crate) struct DeadlineReader {
+    conn: Connection,
+    deadline: Option<Instant>,
+}
+
+impl DeadlineReader {
+    pub fn new(conn: Connection) -> DeadlineReader {
+        DeadlineReader { conn, deadline: None }
+    }
+
+    /// Gives the following reads `timeout` in total, with `None` they block without a limit.
+    pub fn set_deadline(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.deadline = timeout.map(|t| Instant::now() + t);
+        if self.deadline.is_none() {
+            self.conn.set_read_timeout(None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "read deadline exceeded"));
+            }
+            self.conn.set_read_timeout(Some(left))?;
+        }
+        self.conn.read(buf)
+    }
+}
+
+fn lock(tls: &Mutex<TlsStream>) -> MutexGuard<'_, TlsStream> {
+    tls.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// 处理已到达的TLS记录(包括握手),直到有明文可读或对端关闭
+fn tls_ready(tls: &mut TlsStream) -> io::Result<usize> {
+    loop {
+        let state = tls.conn.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        while tls.conn.wants_write() {
+            tls.conn.write_tls(&mut tls.sock)?;
+        }
+        if state.plaintext_bytes_to_read() > 0 {
+            return Ok(state.plaintext_bytes_to_read());
+        }
+        if state.peer_has_closed() {
+            return Ok(0);
+        }
+        if tls.conn.read_tls(&mut tls.sock)? == 0 {
+            return Ok(0);
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.read(buf),
+            Connection::Tls(_, tls) => lock(tls).read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.write(buf),
+            Connection::Tls(_, tls) => lock(tls).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.flush(),
+            Connection::Tls(_, tls) => lock(tls).flush(),
+        }
+    }
+}
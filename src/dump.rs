@@ -0,0 +1,354 @@
+//! Logical dumps of the entries of a store, independent of its engine.
+//!
+//! A dump is either JSON lines, one `{"key":..,"value":..,"ttl":..}` object per
+//! line, or a compact binary format: the magic `KVSDUMP1` followed by records
+//! `<keylen u32><key><valuelen u32><value><ttl u32>` in big endian. Vectors are
+//! stored in their text form like every other value. Entries are written in key
+//! order, so an interrupted `dump` resumes after the last complete record and
+//! `load` resumes from the checkpoint it writes next to the dump.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use crate::{KVEngine, KvsError, Result};
+
+/// The first bytes of a binary dump.
+pub const DUMP_MAGIC: &[u8; 8] = b"KVSDUMP1";
+
+// 每写入这么多条记录报告一次进度,load同时保存一次检查点
+const PROGRESS_INTERVAL: u64 = 10_000;
+// 扫描全部key时作为上界的最大字符
+const KEY_MAX: char = char::MAX;
+
+/// The format of a dump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Json,
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(format!("Invalid dump format '{}': must be 'json' or 'binary'", s)),
+        }
+    }
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpFormat::Json => write!(f, "json"),
+            DumpFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// An entry of a dump.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpRecord {
+    pub key: String,
+    pub value: String,
+    /// Remaining seconds before the key expires, 0 if it never expires
+    #[serde(default)]
+    pub ttl: u32,
+}
+
+/// Writes records in a dump format.
+pub struct DumpWriter<W: Write> {
+    writer: W,
+    format: DumpFormat,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Starts a new dump, writing the header of the format.
+    pub fn new(mut writer: W, format: DumpFormat) -> Result<Self> {
+        if format == DumpFormat::Binary {
+            writer.write_all(DUMP_MAGIC)?;
+        }
+        Ok(DumpWriter { writer, format })
+    }
+
+    /// Continues a dump whose header is already written.
+    pub fn append(writer: W, format: DumpFormat) -> Self {
+        DumpWriter { writer, format }
+    }
+
+    pub fn write(&mut self, record: &DumpRecord) -> Result<()> {
+        match self.format {
+            DumpFormat::Json => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                self.writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                for field in [record.key.as_bytes(), record.value.as_bytes()] {
+                    self.writer.write_all(&(field.len() as u32).to_be_bytes())?;
+                    self.writer.write_all(field)?;
+                }
+                self.writer.write_all(&record.ttl.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the records of a dump, detecting its format.
+pub struct DumpReader<R: BufRead> {
+    reader: R,
+    format: DumpFormat,
+    // 最后一条完整记录的结尾
+    pos: u64,
+}
+
+enum Next {
+    Record(DumpRecord),
+    End,
+    // 文件在记录中间结束,dump被中断
+    Truncated,
+}
+
+enum Field {
+    Bytes(Vec<u8>),
+    End,
+    Truncated,
+}
+
+impl DumpReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        DumpReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> DumpReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let binary = reader.fill_buf()?.starts_with(DUMP_MAGIC);
+        if binary {
+            reader.consume(DUMP_MAGIC.len());
+            return Ok(DumpReader { reader, format: DumpFormat::Binary, pos: DUMP_MAGIC.len() as u64 });
+        }
+        Ok(DumpReader { reader, format: DumpFormat::Json, pos: 0 })
+    }
+
+    pub fn format(&self) -> DumpFormat {
+        self.format
+    }
+
+    /// The offset after the last record read.
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    fn read(&mut self) -> Result<Next> {
+        match self.format {
+            DumpFormat::Json => loop {
+                let mut line = Vec::new();
+                let n = self.reader.read_until(b'\n', &mut line)?;
+                if n == 0 {
+                    return Ok(Next::End);
+                }
+                if line.last() != Some(&b'\n') {
+                    return Ok(Next::Truncated);
+                }
+                let start = self.pos;
+                self.pos += n as u64;
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let record = serde_json::from_slice(&line)
+                    .map_err(|e| KvsError::StringError(format!("invalid record at offset {}: {}", start, e)))?;
+                return Ok(Next::Record(record));
+            },
+            DumpFormat::Binary => {
+                let key = match self.read_field()? {
+                    Field::Bytes(key) => key,
+                    Field::End => return Ok(Next::End),
+                    Field::Truncated => return Ok(Next::Truncated),
+                };
+                let Field::Bytes(value) = self.read_field()? else {
+                    return Ok(Next::Truncated);
+                };
+                let mut ttl = [0u8; 4];
+                if read_full(&mut self.reader, &mut ttl)? < ttl.len() {
+                    return Ok(Next::Truncated);
+                }
+                let start = self.pos;
+                self.pos += (4 + key.len() + 4 + value.len() + 4) as u64;
+                let utf8 = |field: Vec<u8>| {
+                    String::from_utf8(field).map_err(|_| KvsError::StringError(format!("invalid record at offset {}: not UTF-8", start)))
+                };
+                Ok(Next::Record(DumpRecord { key: utf8(key)?, value: utf8(value)?, ttl: u32::from_be_bytes(ttl) }))
+            }
+        }
+    }
+
+    // 读取<len><bytes>
+    fn read_field(&mut self) -> Result<Field> {
+        let mut len = [0u8; 4];
+        match read_full(&mut self.reader, &mut len)? {
+            0 => return Ok(Field::End),
+            n if n < len.len() => return Ok(Field::Truncated),
+            _ => (),
+        }
+        let len = u32::from_be_bytes(len) as u64;
+        let mut field = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut field)?;
+        if (field.len() as u64) < len {
+            return Ok(Field::Truncated);
+        }
+        Ok(Field::Bytes(field))
+    }
+}
+
+impl<R: BufRead> Iterator for DumpReader<R> {
+    type Item = Result<DumpRecord>;
+
+    /// Returns the next record, a dump ending in the middle of a record is an error.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read() {
+            Ok(Next::Record(record)) => Some(Ok(record)),
+            Ok(Next::End) => None,
+            Ok(Next::Truncated) => Some(Err(KvsError::StringError(format!("dump is truncated after offset {}", self.pos)))),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// 读满buf或者到文件结尾,返回读取的字节数
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Writes every entry of `engine` to `path` in key order, returns the number of records in the dump.
+///
+/// With `resume` an existing dump at `path` is continued after its last
+/// complete record, in the format of that dump. `progress` is called with the
+/// number of records every 10000 records.
+pub fn dump<E: KVEngine>(engine: &E, path: &Path, format: DumpFormat, resume: bool, mut progress: impl FnMut(u64)) -> Result<u64> {
+    let (mut writer, after, mut count) = match resume_point(path, resume)? {
+        Some(point) => {
+            if point.format != format {
+                return Err(KvsError::StringError(format!("{} is a {} dump, cannot resume it as {}", path.display(), point.format, format)));
+            }
+            let file = OpenOptions::new().write(true).open(path)?;
+            // 丢弃中断时写了一半的记录
+            file.set_len(point.pos)?;
+            let file = OpenOptions::new().append(true).open(path)?;
+            (DumpWriter::append(BufWriter::new(file), format), point.last, point.count)
+        }
+        None => (DumpWriter::new(BufWriter::new(File::create(path)?), format)?, None, 0),
+    };
+    for (key, value) in engine.scan(String::new(), KEY_MAX.to_string())? {
+        if after.as_ref().is_some_and(|after| key <= *after) {
+            continue;
+        }
+        // 已过期的key不导出
+        let Some(ttl) = engine.ttl(key.clone())? else {
+            continue;
+        };
+        writer.write(&DumpRecord { key, value, ttl })?;
+        count += 1;
+        if count.is_multiple_of(PROGRESS_INTERVAL) {
+            writer.flush()?;
+            progress(count);
+        }
+    }
+    writer.flush()?;
+    progress(count);
+    Ok(count)
+}
+
+// 中断的dump继续写入的位置
+struct ResumePoint {
+    format: DumpFormat,
+    // 最后一条完整记录的结尾
+    pos: u64,
+    last: Option<String>,
+    count: u64,
+}
+
+// 不存在或不续传时返回None
+fn resume_point(path: &Path, resume: bool) -> Result<Option<ResumePoint>> {
+    if !resume {
+        return Ok(None);
+    }
+    let mut reader = match DumpReader::open(path) {
+        Ok(reader) => reader,
+        Err(KvsError::Io(e)) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if fs::metadata(path)?.len() == 0 {
+        return Ok(None);
+    }
+    let (mut last, mut count) = (None, 0);
+    while let Next::Record(record) = reader.read()? {
+        last = Some(record.key);
+        count += 1;
+    }
+    Ok(Some(ResumePoint { format: reader.format(), pos: reader.pos(), last, count }))
+}
+
+/// Sets every record of the dump at `path` in `engine`, returns the number of records in the dump.
+///
+/// A checkpoint `<path>.progress` with the number of records applied is saved
+/// every 10000 records and removed when the load finishes; with `resume` the
+/// records before the checkpoint are skipped. `progress` is called with the
+/// number of records applied.
+pub fn load<E: KVEngine>(engine: &E, path: &Path, resume: bool, mut progress: impl FnMut(u64)) -> Result<u64> {
+    let checkpoint = checkpoint_path(path);
+    let skip = match fs::read_to_string(&checkpoint) {
+        Ok(s) if resume => s.trim().parse::<u64>().map_err(|_| KvsError::StringError(format!("invalid checkpoint {}", checkpoint.display())))?,
+        Ok(_) => 0,
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    let mut count = 0;
+    for record in DumpReader::open(path)? {
+        let record = record?;
+        count += 1;
+        if count <= skip {
+            continue;
+        }
+        engine.set(record.key, record.value, record.ttl)?;
+        if count.is_multiple_of(PROGRESS_INTERVAL) {
+            // 先落盘再保存检查点,续传时最多重复写入一批
+            engine.flush()?;
+            let tmp = checkpoint.with_extension("progress.tmp");
+            fs::write(&tmp, count.to_string())?;
+            fs::rename(&tmp, &checkpoint)?;
+            progress(count);
+        }
+    }
+    engine.flush()?;
+    if checkpoint.exists() {
+        fs::remove_file(&checkpoint)?;
+    }
+    progress(count);
+    Ok(count)
+}
+
+fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".progress");
+    PathBuf::from(name)
+}
@@ -0,0 +1,212 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use super::{KVEngine, KvStore, SledStore, StoreMeta, META_FILE};
+use crate::{KvsError, Result};
+
+/// Name of the manifest file written last into a backup directory.
+pub const BACKUP_MANIFEST: &str = "backup.json";
+
+/// Describes a complete backup written by `KVEngine::backup`.
+///
+/// A directory without a manifest is an interrupted backup and cannot be restored.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// The engine of the backed up store, `kvs` or `sled`
+    pub engine: String,
+    /// The id of the backed up store
+    pub store_id: String,
+    /// Time the backup finished in RFC 3339 format
+    pub created_at: String,
+    /// Number of keys in the backup, including expired keys that were not removed yet
+    pub keys: u64,
+    /// The files of the backup with their lengths, paths are relative to the backup directory
+    pub files: Vec<BackupFile>,
+}
+
+/// A file of a backup.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub name: String,
+    pub len: u64,
+}
+
+impl BackupManifest {
+    /// Reads the manifest of the backup at `dir`.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `dir` is not a complete backup.
+    pub fn load(dir: &Path) -> Result<BackupManifest> {
+        match fs::read(dir.join(BACKUP_MANIFEST)) {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(KvsError::StringError(format!("{} is not a complete backup, {} is missing", dir.display(), BACKUP_MANIFEST)))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Total length of the files of the backup.
+    pub fn bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.len).sum()
+    }
+}
+
+/// Creates the directory of a new backup, it must be missing or empty.
+pub(super) fn create_dir(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::StringError(format!("backup directory {} is not empty", dest.display())));
+    }
+    Ok(())
+}
+
+/// A file opened while the store is locked and copied into a backup after it is unlocked.
+///
+/// The open file stays readable even if the store removes it meanwhile.
+pub(super) struct PendingCopy {
+    file: File,
+    src: PathBuf,
+    dest: PathBuf,
+    len: u64,
+}
+
+impl PendingCopy {
+    /// Opens `src` to copy its first `len` bytes to `dest`, all of it if `len` is `None`.
+    pub(super) fn open(src: &Path, dest: &Path, len: Option<u64>) -> Result<PendingCopy> {
+        let file = File::open(src)?;
+        let len = match len {
+            Some(len) => len,
+            None => file.metadata()?.len(),
+        };
+        Ok(PendingCopy { file, src: src.to_path_buf(), dest: dest.to_path_buf(), len })
+    }
+
+    pub(super) fn copy(self) -> Result<()> {
+        let mut out = File::create(&self.dest)?;
+        let copied = io::copy(&mut (&self.file).take(self.len), &mut out)?;
+        if copied != self.len {
+            return Err(KvsError::StringError(format!("{} is shorter than {} bytes", self.src.display(), self.len)));
+        }
+        out.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Hard-links `src` to `dest`, or opens it to be copied later if they are on different file systems.
+pub(super) fn link_or_open(src: &Path, dest: &Path) -> Result<Option<PendingCopy>> {
+    if fs::hard_link(src, dest).is_ok() {
+        return Ok(None);
+    }
+    PendingCopy::open(src, dest, None).map(Some)
+}
+
+/// Writes the manifest of the backup at `dest` listing its files, this completes the backup.
+pub(super) fn finish(dest: &Path, engine: &str, keys: u64) -> Result<BackupManifest> {
+    let meta = StoreMeta::load(dest)?
+        .ok_or_else(|| KvsError::StringError(format!("backup {} has no {}", dest.display(), META_FILE)))?;
+    let mut files = Vec::new();
+    list_files(dest, "", &mut files)?;
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    let manifest = BackupManifest {
+        engine: engine.to_string(),
+        store_id: meta.store_id,
+        created_at: chrono::Local::now().to_rfc3339(),
+        keys,
+        files,
+    };
+    // 先写临时文件再改名,中断的备份没有manifest
+    let tmp = dest.join(format!("{}.tmp", BACKUP_MANIFEST));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dest.join(BACKUP_MANIFEST))?;
+    Ok(manifest)
+}
+
+fn list_files(dir: &Path, prefix: &str, files: &mut Vec<BackupFile>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &format!("{}/", name), files)?;
+        } else if name != BACKUP_MANIFEST {
+            files.push(BackupFile { name, len: entry.metadata()?.len() });
+        }
+    }
+    Ok(())
+}
+
+/// Validates the backup at `backup` and installs it as the store directory `dest`.
+///
+/// The files are copied next to `dest` and opened with the engine of the backup
+/// before they replace `dest`, so a broken backup never replaces a store. An
+/// existing store at `dest` is only replaced with `force`, it is kept as
+/// `<dest>.<time>.old`. The server must not be running on `dest`.
+///
+/// # Errors
+///
+/// It fails if the backup is incomplete, has missing or truncated files, cannot
+/// be opened or has another number of keys than its manifest.
+pub fn restore(backup: &Path, dest: &Path, force: bool) -> Result<BackupManifest> {
+    let manifest = BackupManifest::load(backup)?;
+    match StoreMeta::load(backup)? {
+        Some(meta) if meta.engine == manifest.engine => (),
+        Some(meta) => {
+            return Err(KvsError::EngineMismatch { expected: manifest.engine, found: meta.engine });
+        }
+        None => return Err(KvsError::StringError(format!("backup {} has no {}", backup.display(), META_FILE))),
+    }
+    for file in &manifest.files {
+        let len = match fs::metadata(backup.join(&file.name)) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(KvsError::StringError(format!("backup file {} is missing", file.name)));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if len != file.len {
+            return Err(KvsError::StringError(format!("backup file {} has {} bytes, expected {}", file.name, len, file.len)));
+        }
+    }
+    let exists = dest.exists() && fs::read_dir(dest)?.next().is_some();
+    if exists && !force {
+        return Err(KvsError::StringError(format!("{} already contains a store, use --force to replace it", dest.display())));
+    }
+
+    let tmp = sibling(dest, "restoring");
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    for file in &manifest.files {
+        let target = tmp.join(&file.name);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(backup.join(&file.name), target)?;
+    }
+    // 用引擎打开一次,能完整回放并且key数一致才替换
+    let keys = match manifest.engine.as_str() {
+        "kvs" => KvStore::open(&tmp)?.stats()?.keys,
+        "sled" => SledStore::open(&tmp)?.stats()?.keys,
+        engine => return Err(KvsError::StringError(format!("unknown engine {} in backup", engine))),
+    };
+    if keys != manifest.keys {
+        return Err(KvsError::StringError(format!("backup has {} keys, expected {}", keys, manifest.keys)));
+    }
+    if exists {
+        let old = sibling(dest, &format!("{}.old", chrono::Local::now().format("%Y%m%d%H%M%S")));
+        fs::rename(dest, &old)?;
+    } else if dest.exists() {
+        fs::remove_dir(dest)?;
+    }
+    fs::rename(&tmp, dest)?;
+    Ok(manifest)
+}
+
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let name = dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    dir.with_file_name(format!("{}.{}", name, suffix))
+}
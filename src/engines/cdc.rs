@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+use super::ChangeOp;
+use crate::Result;
+
+/// A mutation captured from the write log of a store.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CdcRecord {
+    /// Sequence number of the mutation, it increases with every mutation and
+    /// equals the `version` of the matching `ChangeEvent`.
+    pub seq: u64,
+    /// `set` or `remove`, keys removed because their ttl passed are recorded as `remove`.
+    pub op: ChangeOp,
+    pub key: String,
+    /// The new value of a `set`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// The ttl of a `set` in seconds, 0 means the key never expires.
+    #[serde(default)]
+    pub ttl: u32,
+}
+
+/// Reads the mutations of a store in order, starting after a sequence number.
+pub trait CdcCursor: Send {
+    /// Returns the next mutation, or `None` if there is none yet.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CdcUnavailable` if the mutations after the position of
+    /// the cursor were compacted or trimmed away before the cursor read them.
+    fn next(&mut self) -> Result<Option<CdcRecord>>;
+}
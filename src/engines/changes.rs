@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+//每个watcher最多缓存的事件数,缓存满时断开该watcher,避免调用方错过事件而不自知
+const WATCHER_BUFFER: usize = 1024;
+
+/// The kind of change made to a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Set,
+    Remove,
+    /// The key was removed because its ttl passed.
+    Expire,
+}
+
+/// A change made to a key of a store.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub key: String,
+    pub op: ChangeOp,
+    /// Version of the store after the change, it increases with every change.
+    pub version: u64,
+}
+
+//监听的key前缀和事件发送端
+type Watcher = (String, Sender<ChangeEvent>);
+
+/// Broadcasts the changes of a store to the watchers of key prefixes.
+#[derive(Clone, Default)]
+pub struct ChangeFeed {
+    watchers: Arc<Mutex<Vec<Watcher>>>,
+    active: Arc<AtomicUsize>,
+}
+
+impl ChangeFeed {
+    /// Returns the changes of keys starting with `prefix`.
+    ///
+    /// The receiver is disconnected if it falls too far behind.
+    pub fn watch(&self, prefix: &str) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel::bounded(WATCHER_BUFFER);
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.push((prefix.to_string(), sender));
+        self.active.store(watchers.len(), Ordering::SeqCst);
+        receiver
+    }
+
+    /// Sends a change to the matching watchers, it does nothing if nobody watches.
+    pub fn emit(&self, key: &str, op: ChangeOp, version: u64) {
+        if self.active.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|(prefix, sender)| {
+            if !key.starts_with(prefix.as_str()) {
+                return true;
+            }
+            let event = ChangeEvent { key: key.to_string(), op, version };
+            match sender.try_send(event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Watcher of prefix '{}' is too slow, disconnecting it", prefix);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        self.active.store(watchers.len(), Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use bincode::error::DecodeError;
+use super::kvs::{log_path, sorted_gen_list, BufReaderWithPos, Command};
+use super::{ChangeOp, Compression, KvStore};
+use crate::{KvsError, Result};
+
+// 损坏的长度字段不能让解码分配过多的内存
+const DECODE_LIMIT: usize = 1 << 30;
+
+/// A record decoded from a generation log of a `KvStore`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Offset of the record in the log
+    pub offset: u64,
+    /// Encoded length of the record
+    pub len: u64,
+    /// `Set` or `Remove`
+    pub op: ChangeOp,
+    pub key: String,
+    /// The value of a `Set`
+    pub value: Option<String>,
+    /// The TTL in seconds, the remaining one if the record holds an expiration
+    /// time, 0 if it never expires
+    pub ttl: u32,
+    /// The compression of the value in the log
+    pub compression: Compression,
+}
+
+/// The first record of a log that cannot be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogError {
+    pub offset: u64,
+    pub message: String,
+}
+
+/// Reads the records of a generation log until its end or its first unreadable record.
+pub struct LogReader {
+    reader: BufReaderWithPos<File>,
+    len: u64,
+    error: Option<LogError>,
+}
+
+impl LogReader {
+    pub fn open(path: &Path) -> Result<LogReader> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(LogReader { reader: BufReaderWithPos::new(file)?, len, error: None })
+    }
+
+    /// The end of the last record read.
+    pub fn pos(&self) -> u64 {
+        self.error.as_ref().map_or(self.reader.pos, |e| e.offset)
+    }
+
+    /// The unreadable record that stopped the reader.
+    pub fn error(&self) -> Option<&LogError> {
+        self.error.as_ref()
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = LogRecord;
+
+    fn next(&mut self) -> Option<LogRecord> {
+        let offset = self.reader.pos;
+        if self.error.is_some() || offset >= self.len {
+            return None;
+        }
+        let config = bincode::config::standard().with_limit::<DECODE_LIMIT>();
+        let message = match bincode::decode_from_reader(&mut self.reader, config) {
+            Ok(cmd) => {
+                let len = self.reader.pos - offset;
+                let compression = Command::compression(&cmd);
+                match cmd.into_parts() {
+                    Ok((key, value, ttl)) => {
+                        let op = if value.is_some() { ChangeOp::Set } else { ChangeOp::Remove };
+                        return Some(LogRecord { offset, len, op, key, value, ttl, compression });
+                    }
+                    Err(e) => e.to_string(),
+                }
+            }
+            Err(DecodeError::Io { inner, .. }) if inner.kind() == ErrorKind::UnexpectedEof => {
+                format!("truncated record, the log ends {} bytes after it", self.len - offset)
+            }
+            Err(e) => e.to_string(),
+        };
+        self.error = Some(LogError { offset, message });
+        None
+    }
+}
+
+/// Statistics of a generation log, see `KvStore::inspect`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GenerationReport {
+    pub generation: u64,
+    /// Length of the log
+    pub bytes: u64,
+    /// Number of readable records
+    pub records: u64,
+    pub sets: u64,
+    pub removes: u64,
+    /// Bytes of the records holding the latest value of a key
+    pub live_bytes: u64,
+    /// The first unreadable record, the rest of the log is not read
+    pub error: Option<LogError>,
+}
+
+impl GenerationReport {
+    /// Whether the log was written by a compaction.
+    pub fn is_compacted(&self) -> bool {
+        self.generation.is_multiple_of(2)
+    }
+
+    /// Share of the log a compaction can reclaim.
+    pub fn stale_ratio(&self) -> f64 {
+        if self.bytes == 0 {
+            return 0.0;
+        }
+        (self.bytes - self.live_bytes) as f64 / self.bytes as f64
+    }
+}
+
+/// Result of `KvStore::repair`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of keys in the repaired store
+    pub keys: u64,
+    /// Number of readable records
+    pub records: u64,
+    /// Bytes after the first unreadable record of every log, they are dropped
+    pub lost_bytes: u64,
+    /// The generation holding the salvaged keys
+    pub generation: u64,
+    /// The directory the old logs were moved to, `None` for a dry run
+    pub old_logs: Option<PathBuf>,
+}
+
+impl KvStore {
+    /// Reads every generation log in `dir` without opening the store.
+    ///
+    /// The logs are replayed like `open` does to find the live bytes of every
+    /// generation; reading a log stops at its first unreadable record.
+    pub fn inspect(dir: &Path) -> Result<Vec<GenerationReport>> {
+        let mut reports = Vec::new();
+        // 每个key最新的set所在的代和长度
+        let mut latest: HashMap<String, (usize, u64)> = HashMap::new();
+        for r#gen in sorted_gen_list(dir)? {
+            let mut reader = LogReader::open(&log_path(dir, r#gen))?;
+            let mut report = GenerationReport {
+                generation: r#gen,
+                bytes: reader.len,
+                records: 0,
+                sets: 0,
+                removes: 0,
+                live_bytes: 0,
+                error: None,
+            };
+            for record in &mut reader {
+                report.records += 1;
+                match record.op {
+                    ChangeOp::Remove => {
+                        report.removes += 1;
+                        latest.remove(&record.key);
+                    }
+                    _ => {
+                        report.sets += 1;
+                        latest.insert(record.key, (reports.len(), record.len));
+                    }
+                }
+            }
+            report.error = reader.error().cloned();
+            reports.push(report);
+        }
+        for (index, len) in latest.into_values() {
+            reports[index].live_bytes += len;
+        }
+        Ok(reports)
+    }
+
+    /// Rewrites the readable records of the logs in `dir` into a fresh generation.
+    ///
+    /// The latest value of every key is written to a new compacted generation
+    /// with the compression it had, the records after the first unreadable one
+    /// of a log are lost. The old logs
+    /// are moved to `repair-<time>` inside `dir`. With `dry_run` nothing is
+    /// written. The store must not be open.
+    pub fn repair(dir: &Path, dry_run: bool) -> Result<RepairReport> {
+        let gens = sorted_gen_list(dir)?;
+        let Some(&last) = gens.last() else {
+            return Err(KvsError::StringError(format!("no generation logs in {}", dir.display())));
+        };
+        let mut keys = BTreeMap::new();
+        let (mut records, mut lost_bytes) = (0, 0);
+        for &r#gen in &gens {
+            let mut reader = LogReader::open(&log_path(dir, r#gen))?;
+            for record in &mut reader {
+                records += 1;
+                match record.value {
+                    Some(value) => keys.insert(record.key, (value, record.ttl, record.compression)),
+                    None => keys.remove(&record.key),
+                };
+            }
+            lost_bytes += reader.len - reader.pos();
+        }
+        // 与压缩相同,快照使用偶数代,之后的写入使用下一个奇数代
+        let snapshot = last / 2 * 2 + 2;
+        let mut report = RepairReport { keys: keys.len() as u64, records, lost_bytes, generation: snapshot, old_logs: None };
+        if dry_run {
+            return Ok(report);
+        }
+
+        let tmp = dir.join(format!("{}.log.tmp", snapshot));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for (key, (value, ttl, compression)) in keys {
+            let cmd = Command::compressed(key, value, ttl, compression)?;
+            bincode::encode_into_std_write(cmd, &mut writer, bincode::config::standard())?;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, log_path(dir, snapshot))?;
+        File::create(log_path(dir, snapshot + 1))?.sync_all()?;
+
+        // 新的快照已经包含全部key,旧日志移走后不再回放
+        let old = dir.join(format!("repair-{}", chrono::Local::now().format("%Y%m%d%H%M%S")));
+        fs::create_dir_all(&old)?;
+        for r#gen in gens {
+            fs::rename(log_path(dir, r#gen), log_path(&old, r#gen))?;
+        }
+        report.old_logs = Some(old);
+        Ok(report)
+    }
+}
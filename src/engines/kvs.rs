@@ -10,11 +10,75 @@ use std::sync::{atomic::{AtomicU64, Ordering},Arc, Mutex};
 use std::cell::RefCell;
 use std::ffi::OsStr;
 use std::result::Result as stdResult;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::str::FromStr;
+use std::fmt;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
+use super::{backup,BackupManifest,CdcCursor,CdcRecord,ChangeEvent,ChangeFeed,ChangeOp,EngineStats,StoreMeta,META_FILE};
+use crossbeam::channel::Receiver;
 use crate::{Result,KvsError,KVEngine};
 
-const COMPACTION_THRESHOLD: u64 = 2 * 1024;//2GB
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;//1MB
+const COMPRESSION_THRESHOLD: usize = 1024;
+const ZSTD_LEVEL: i32 = 3;
+// versions and sequence numbers keep the generation above the position in its log
+const POS_BITS: u64 = 40;
+
+/// Options of a `KvStore`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KvStoreOptions {
+    /// Compact the log once this many bytes of stale commands have accumulated.
+    pub compaction_threshold: u64,
+    /// Compression of the values of new records.
+    pub compression: Compression,
+    /// Values shorter than this many bytes are stored raw.
+    pub compression_threshold: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compression: Compression::None,
+            compression_threshold: COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+/// Compression of the values in the log of a `KvStore`.
+///
+/// Every record keeps the compression it was written with, so a store can be
+/// reopened with another compression and compactions copy records unchanged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> stdResult<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Invalid compression '{}': must be 'none', 'lz4' or 'zstd'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct KvStore {
@@ -26,6 +90,8 @@ pub struct KvStore {
     writer: Arc<Mutex<KvStoreWriter>>,
     // the generation number of the current log.
     index: Arc<SkipMap<String, CommandPos>>,
+    // watchers of key changes, the writer emits the changes
+    changes: ChangeFeed,
 }
 
 impl KvStore{
@@ -37,8 +103,14 @@ impl KvStore{
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
+        StoreMeta::open(&path, "kvs")?;
 
         let mut readers = BTreeMap::new();
         let index = Arc::new(SkipMap::new());
@@ -68,6 +140,7 @@ impl KvStore{
             readers: RefCell::new(readers),
         };
 
+        let changes = ChangeFeed::default();
         let writer = KvStoreWriter {
             reader: reader.clone(),
             writer,
@@ -75,6 +148,13 @@ impl KvStore{
             uncompacted,
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            compaction_threshold: options.compaction_threshold,
+            compression: options.compression,
+            compression_threshold: options.compression_threshold,
+            compactions: 0,
+            compaction_millis: 0,
+            last_compaction_millis: 0,
+            changes: changes.clone(),
         };
        
         Ok(KvStore {
@@ -82,6 +162,7 @@ impl KvStore{
             reader,
             writer:Arc::new(Mutex::new(writer)),
             index:index,
+            changes,
         })
     }
 }
@@ -101,7 +182,8 @@ impl KVEngine for KvStore {
 
     /// Gets the string value of a given string key.
     ///
-    /// Returns `None` if the given key does not exist.
+    /// Returns `None` if the given key does not exist or has expired, an expired
+    /// key is removed by the read.
     ///
     /// # Errors
     ///
@@ -111,8 +193,12 @@ impl KVEngine for KvStore {
             //检查超时
             if cmd_pos.value().ttl>0 && now()>cmd_pos.value().ttl{
                 info!("key {} expired,remove it",key);
-                self.remove(key)?;
-                return Ok(Some("key expired".to_string()));
+                let mut writer=self.writer.lock().unwrap();
+                // 加锁后重新检查,其他读取可能已经删除了这个key
+                if self.index.get(&key).is_some_and(|e|e.value().ttl>0 && now()>e.value().ttl){
+                    writer.remove(key,ChangeOp::Expire)?;
+                }
+                return Ok(None);
             }
             if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
                 Ok(Some(value))
@@ -124,17 +210,36 @@ impl KVEngine for KvStore {
         }
     }
 
-    fn scan(&self, start: String,end:String) -> Result<Vec<String>> {
+    fn scan(&self, start: String,end:String) -> Result<Vec<(String,String)>> {
         let mut res=Vec::new();
         for entry in self.index.range(start..=end){
-            if let Command::Set { value, .. } = self.reader.read_command(*entry.value())? {
-                res.push(value);
+            if let Command::Set { key,value, .. } = self.reader.read_command(*entry.value())? {
+                res.push((key,value));
             } else {
                 return Err(KvsError::UnexpectedCommandType);
             }
         }
         Ok(res)
     }
+
+    fn scan_keys(&self, start: String,end:String,skip:usize,limit:usize) -> Result<Vec<String>> {
+        Ok(self.index.range(start..=end).skip(skip).take(limit).map(|entry|entry.key().clone()).collect())
+    }
+
+    /// Expiration times are stored in the log. Keys written with a TTL by a store
+    /// older than format version 2 never expire after a reopen.
+    fn ttl(&self, key: String) -> Result<Option<u32>> {
+        let Some(cmd_pos) = self.index.get(&key) else {
+            return Ok(None);
+        };
+        let (ttl, now) = (cmd_pos.value().ttl, now());
+        match ttl {
+            0 => Ok(Some(0)),
+            ttl if now > ttl => Ok(None),
+            ttl => Ok(Some((ttl - now).max(1) as u32)),
+        }
+    }
+
     /// Removes a given key.
     ///
     /// # Errors
@@ -143,7 +248,93 @@ impl KVEngine for KvStore {
     ///
     /// It propagates I/O or serialization errors during writing the log.
     fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+        self.writer.lock().unwrap().remove(key,ChangeOp::Remove)
+    }
+
+    /// Flushes the current log and syncs it to disk.
+    fn flush(&self) -> Result<()> {
+        let mut writer=self.writer.lock().unwrap();
+        writer.writer.flush()?;
+        writer.writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let (stale_bytes,compactions,compaction_millis,last_compaction_millis)={
+            let writer=self.writer.lock().unwrap();
+            (writer.uncompacted,writer.compactions,writer.compaction_millis,writer.last_compaction_millis)
+        };
+        let gen_list=sorted_gen_list(&self.path)?;
+        let mut disk_bytes=0;
+        for &r#gen in &gen_list{
+            disk_bytes+=fs::metadata(log_path(&self.path, r#gen))?.len();
+        }
+        Ok(EngineStats{
+            engine:"kvs".to_string(),
+            keys:self.index.len() as u64,
+            live_bytes:self.index.iter().map(|e|e.value().len).sum(),
+            stale_bytes,
+            disk_bytes,
+            generations:gen_list.len() as u64,
+            compactions,
+            compaction_millis,
+            last_compaction_millis,
+        })
+    }
+
+    /// Expired keys count until a read or a compaction removes them.
+    fn key_count(&self) -> Result<u64> {
+        Ok(self.index.len() as u64)
+    }
+
+    fn watch(&self, prefix: &str) -> Receiver<ChangeEvent> {
+        self.changes.watch(prefix)
+    }
+
+    /// Tails the generation logs, the sequence number of a mutation is its version.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CdcUnavailable` if the log containing `after` was compacted.
+    fn cdc(&self, after: u64) -> Result<Box<dyn CdcCursor>> {
+        Ok(Box::new(KvStoreCursor::open(Arc::clone(&self.path), after)?))
+    }
+
+    fn cdc_seq(&self) -> Result<u64> {
+        Ok(self.writer.lock().unwrap().version())
+    }
+
+    /// Hard-links the generation logs and copies the current log up to its end
+    /// at the time of the call.
+    ///
+    /// Writes are only blocked while the logs are linked or opened, nothing is
+    /// copied meanwhile. Logs other than the current one are never appended to;
+    /// the current log and logs that cannot be linked, e.g. on another file
+    /// system, are copied afterwards from the opened files, which stay readable
+    /// even if a compaction removes them.
+    fn backup(&self, dest: &Path) -> Result<BackupManifest> {
+        backup::create_dir(dest)?;
+        fs::copy(self.path.join(META_FILE), dest.join(META_FILE))?;
+        let (copies, keys) = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.writer.flush()?;
+            let mut copies = Vec::new();
+            for r#gen in sorted_gen_list(&self.path)? {
+                if r#gen < writer.current_gen {
+                    copies.extend(backup::link_or_open(&log_path(&self.path, r#gen), &log_path(dest, r#gen))?);
+                }
+            }
+            // 当前日志之后追加的内容不属于备份
+            let (current, len) = (writer.current_gen, writer.writer.pos);
+            copies.push(backup::PendingCopy::open(&log_path(&self.path, current), &log_path(dest, current), Some(len))?);
+            (copies, self.index.len() as u64)
+        };
+        for copy in copies {
+            copy.copy()?;
+        }
+        let manifest = backup::finish(dest, "kvs", keys)?;
+        info!("Backup of {} keys written to {}", keys, dest.display());
+        Ok(manifest)
     }
 }
 
@@ -204,7 +395,7 @@ impl KvStoreReader {
             let mut buf=vec![0u8;cmd_pos.len as usize];
             cmd_reader.read_exact(&mut buf)?;
             let res:(Command,usize)=bincode::decode_from_slice(buf.as_slice(), bincode::config::standard())?;
-            Ok(res.0)
+            res.0.decompress()
         })
     }
 }
@@ -230,6 +421,15 @@ struct KvStoreWriter {
     uncompacted: u64,
     path: Arc<PathBuf>,
     index: Arc<SkipMap<String, CommandPos>>,
+    compaction_threshold: u64,
+    compression: Compression,
+    compression_threshold: usize,
+    // the number of compactions since the store was opened
+    compactions: u64,
+    // total and latest compaction durations in milliseconds
+    compaction_millis: u64,
+    last_compaction_millis: u64,
+    changes: ChangeFeed,
 }
 
 fn now() -> u64 {
@@ -241,30 +441,37 @@ fn now() -> u64 {
 
 impl KvStoreWriter {
     fn set(&mut self, key: String, value: String,ttl:u32) -> Result<()> {
-        let cmd = Command::set(key, value,ttl);
+        let compression = if value.len() >= self.compression_threshold { self.compression } else { Compression::None };
+        let cmd = Command::compressed(key, value, ttl, compression)?;
         let pos = self.writer.pos;
         bincode::encode_into_writer(&cmd, &mut self.writer, bincode::config::standard())?;
         self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
+        let expires_at = cmd.expires_at();
+        if let Command::Set { key, .. } | Command::SetCompressed { key, .. } | Command::SetExpiring { key, .. } = cmd {
             if let Some(old_cmd) = self.index.get(&key) {
                 self.uncompacted += old_cmd.value().len;
             }
             let mut cmd_pos:CommandPos=(self.current_gen, pos..self.writer.pos).into();
-            if ttl>0{
-                cmd_pos.ttl=now()+ttl as u64;
-            }
+            cmd_pos.ttl=expires_at;
             
             self.index
                 .insert(key.clone(), cmd_pos);
+            self.changes.emit(&key, ChangeOp::Set, self.version());
         }
         //info!("uncompacted: {}", self.uncompacted);
-        if self.uncompacted > COMPACTION_THRESHOLD {
+        if self.uncompacted > self.compaction_threshold {
             self.compact()?;
         }
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
+    // the end of the latest entry in the log, it increases with every write and
+    // compaction because generations only grow
+    fn version(&self) -> u64 {
+        (self.current_gen << POS_BITS) | self.writer.pos
+    }
+
+    fn remove(&mut self, key: String, op: ChangeOp) -> Result<()> {
         if self.index.contains_key(&key) {
             let cmd = Command::remove(key);
             let pos = self.writer.pos;
@@ -276,9 +483,10 @@ impl KvStoreWriter {
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
                 self.uncompacted += self.writer.pos - pos;
+                self.changes.emit(&key, op, self.version());
             }
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
+            if self.uncompacted > self.compaction_threshold {
                 self.compact()?;
             }
             Ok(())
@@ -290,6 +498,7 @@ impl KvStoreWriter {
     /// Clears stale entries in the log.
     fn compact(&mut self) -> Result<()> {
         info!("Compacting log ...");
+        let start = Instant::now();
         // increase current gen by 2. current_gen + 1 is for the compaction file
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
@@ -298,16 +507,24 @@ impl KvStoreWriter {
         let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
 
         let mut new_pos = 0; // pos in the new log file
+        let now=now();
         for entry in &mut self.index.iter() {
             let cmd_pos=entry.value();
+            // 过期的key不写入快照
+            if cmd_pos.ttl>0 && now>cmd_pos.ttl{
+                self.index.remove(entry.key());
+                self.changes.emit(entry.key(), ChangeOp::Expire, self.version());
+                continue;
+            }
             let len=self.reader.read_and(*cmd_pos, |reader|{
-                let mut buf:Vec<u8>=Vec::with_capacity(cmd_pos.len as usize);
-                reader.reader.read_exact(buf.as_mut_slice())?;
-                reader.pos+=cmd_pos.len;
-                let len=<BufWriterWithPos<File> as std::io::Write>::write(&mut compaction_writer,buf.as_slice())?;
-                Ok(len)
+                let mut buf:Vec<u8>=vec![0u8;cmd_pos.len as usize];
+                reader.read_exact(buf.as_mut_slice())?;
+                <BufWriterWithPos<File> as std::io::Write>::write_all(&mut compaction_writer,buf.as_slice())?;
+                Ok(buf.len())
             })?;
-            self.index.insert(entry.key().clone(), (compaction_gen, new_pos..new_pos + len as u64).into());
+            let mut new_cmd_pos:CommandPos=(compaction_gen, new_pos..new_pos + len as u64).into();
+            new_cmd_pos.ttl=cmd_pos.ttl;
+            self.index.insert(entry.key().clone(), new_cmd_pos);
             new_pos += len as u64;
         }
 
@@ -333,11 +550,115 @@ impl KvStoreWriter {
             fs::remove_file(&file_path)?;
         }
         self.uncompacted = 0;
+        self.compactions += 1;
+        self.last_compaction_millis = start.elapsed().as_millis() as u64;
+        self.compaction_millis += self.last_compaction_millis;
+        info!("Compaction finished in {} ms", self.last_compaction_millis);
 
         Ok(())
     }
 }
 
+/// Tails the generation logs of a `KvStore`.
+///
+/// Writers use odd generations and compactions even ones: the compaction of writer
+/// `g` moves the writes to `g + 2` before it writes the snapshot `g + 1`. So the
+/// cursor follows the writers and only reads a snapshot if it starts from the
+/// beginning and the oldest log left is one.
+struct KvStoreCursor {
+    path: Arc<PathBuf>,
+    r#gen: u64,
+    reader: BufReaderWithPos<File>,
+    // the end of the latest entry read
+    pos: u64,
+}
+
+impl KvStoreCursor {
+    fn open(path: Arc<PathBuf>, after: u64) -> Result<KvStoreCursor> {
+        if after == 0 {
+            // the oldest log may be deleted by a compaction before it is opened
+            loop {
+                let r#gen = sorted_gen_list(&path)?.first().cloned().unwrap_or(1);
+                match File::open(log_path(&path, r#gen)) {
+                    Ok(file) => {
+                        let reader = BufReaderWithPos::new(file)?;
+                        return Ok(KvStoreCursor { path, r#gen, reader, pos: 0 });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        let r#gen = after >> POS_BITS;
+        let pos = after & ((1 << POS_BITS) - 1);
+        let file = match File::open(log_path(&path, r#gen)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(KvsError::CdcUnavailable(after)),
+            Err(e) => return Err(e.into()),
+        };
+        if file.metadata()?.len() < pos {
+            return Err(KvsError::CdcUnavailable(after));
+        }
+        let mut reader = BufReaderWithPos::new(file)?;
+        reader.seek(SeekFrom::Start(pos))?;
+        Ok(KvStoreCursor { path, r#gen, reader, pos })
+    }
+
+    fn seq(&self) -> u64 {
+        (self.r#gen << POS_BITS) | self.pos
+    }
+
+    // Reads the next entry of the current log.
+    fn read(&mut self) -> Result<Option<CdcRecord>> {
+        match bincode::decode_from_reader(&mut self.reader, bincode::config::standard()) {
+            Ok(cmd) => {
+                self.pos = self.reader.pos;
+                let (key, value, ttl) = Command::into_parts(cmd)?;
+                let op = if value.is_some() { ChangeOp::Set } else { ChangeOp::Remove };
+                Ok(Some(CdcRecord { seq: self.seq(), op, key, value, ttl }))
+            }
+            Err(bincode::error::DecodeError::Io { inner, .. }) if inner.kind() == io::ErrorKind::UnexpectedEof => {
+                // the writer may be in the middle of the entry, read it again next time
+                if self.reader.pos != self.pos {
+                    self.reader.seek(SeekFrom::Start(self.pos))?;
+                }
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl CdcCursor for KvStoreCursor {
+    fn next(&mut self) -> Result<Option<CdcRecord>> {
+        loop {
+            if let Some(record) = self.read()? {
+                return Ok(Some(record));
+            }
+            let next_gen = if self.r#gen.is_multiple_of(2) { self.r#gen + 1 } else { self.r#gen + 2 };
+            let next_path = log_path(&self.path, next_gen);
+            if !next_path.exists() {
+                return Ok(None);
+            }
+            // nothing is appended to a log once the next writer log exists, but the
+            // last entries may have been written after the read above
+            if let Some(record) = self.read()? {
+                return Ok(Some(record));
+            }
+            // the open handle keeps reading a compacted log, the next one may be gone
+            // already if another compaction finished in the meantime
+            let file = match File::open(&next_path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(KvsError::CdcUnavailable(self.seq())),
+                Err(e) => return Err(e.into()),
+            };
+            self.reader = BufReaderWithPos::new(file)?;
+            self.r#gen = next_gen;
+            self.pos = 0;
+        }
+    }
+}
+
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
 /// Returns the writer to the log.
@@ -359,7 +680,7 @@ fn new_log_file(
 }
 
 /// Returns sorted generation numbers in the given directory.
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+pub(super) fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
@@ -391,12 +712,16 @@ fn load(
     
     for cmd_result in command_iter{
         let (cmd,new_pos ) = cmd_result?;
+        // 过期时间写在记录中,重新打开后继续生效;只记录TTL的旧记录不会过期
+        let expires_at = cmd.expires_at();
         match cmd {
-            Command::Set { key, .. } => {
+            Command::Set { key, .. } | Command::SetCompressed { key, .. } | Command::SetExpiring { key, .. } => {
                 if let Some(old_cmd) = index.get(&key) {
                     uncompacted += old_cmd.value().len;
                 }
-                index.insert(key, (r#gen, pos..new_pos).into());
+                let mut cmd_pos: CommandPos = (r#gen, pos..new_pos).into();
+                cmd_pos.ttl = expires_at;
+                index.insert(key, cmd_pos);
             }
             Command::Remove { key } => {
                 if let Some(old_cmd) = index.remove(&key) {
@@ -412,15 +737,20 @@ fn load(
     Ok(uncompacted)
 }
 
-fn log_path(dir: &Path, r#gen: u64) -> PathBuf {
+pub(super) fn log_path(dir: &Path, r#gen: u64) -> PathBuf {
     dir.join(format!("{}.log", r#gen))
 }
 
 /// Struct representing a command.
 #[derive(Serialize, Deserialize, Encode,Decode,Debug)]
-enum Command {
+pub(super) enum Command {
     Set { key: String, value: String,ttl:u32 },
     Remove { key: String },
+    /// A set whose value is compressed, it is read as a `Set`.
+    SetCompressed { key: String, value: Vec<u8>, ttl: u32, compression: Compression },
+    /// A set expiring at `expires_at` seconds since the Unix epoch, so the TTL
+    /// survives a reopen. It is read as a `Set`, the value is compressed with `compression`.
+    SetExpiring { key: String, value: Vec<u8>, expires_at: u64, compression: Compression },
 }
 
 impl Command {
@@ -431,6 +761,77 @@ impl Command {
     fn remove(key: String) -> Command {
         Command::Remove { key }
     }
+
+    /// A set with the value compressed, or stored raw if compression does not make it shorter.
+    ///
+    /// A key with a TTL is written with its expiration time.
+    pub(super) fn compressed(key: String, value: String, ttl: u32, compression: Compression) -> Result<Command> {
+        let data = match compression {
+            Compression::None => None,
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(value.as_bytes())),
+            Compression::Zstd => Some(zstd::encode_all(value.as_bytes(), ZSTD_LEVEL)?),
+        };
+        let data = data.filter(|data| data.len() < value.len());
+        if ttl > 0 {
+            let expires_at = now() + ttl as u64;
+            let (value, compression) = match data {
+                Some(data) => (data, compression),
+                None => (value.into_bytes(), Compression::None),
+            };
+            return Ok(Command::SetExpiring { key, value, expires_at, compression });
+        }
+        Ok(match data {
+            Some(data) => Command::SetCompressed { key, value: data, ttl, compression },
+            None => Command::set(key, value, ttl),
+        })
+    }
+
+    /// The compression of the value of the record.
+    pub(super) fn compression(&self) -> Compression {
+        match self {
+            Command::SetCompressed { compression, .. } | Command::SetExpiring { compression, .. } => *compression,
+            _ => Compression::None,
+        }
+    }
+
+    /// The expiration time of a set in seconds since the Unix epoch, 0 if it never
+    /// expires or the record only holds its TTL.
+    fn expires_at(&self) -> u64 {
+        match self {
+            Command::SetExpiring { expires_at, .. } => *expires_at,
+            _ => 0,
+        }
+    }
+
+    /// Returns the key, the value of a set and its TTL.
+    ///
+    /// The TTL of a record with an expiration time is the remaining one, at least a second.
+    pub(super) fn into_parts(self) -> Result<(String, Option<String>, u32)> {
+        let (key, value, ttl, compression) = match self {
+            Command::Set { key, value, ttl } => return Ok((key, Some(value), ttl)),
+            Command::Remove { key } => return Ok((key, None, 0)),
+            Command::SetCompressed { key, value, ttl, compression } => (key, value, ttl, compression),
+            Command::SetExpiring { key, value, expires_at, compression } => {
+                (key, value, expires_at.saturating_sub(now()).max(1) as u32, compression)
+            }
+        };
+        let value = match compression {
+            Compression::None => value,
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&value)
+                .map_err(|e| KvsError::StringError(format!("invalid lz4 value of key {}: {}", key, e)))?,
+            Compression::Zstd => zstd::decode_all(value.as_slice())
+                .map_err(|e| KvsError::StringError(format!("invalid zstd value of key {}: {}", key, e)))?,
+        };
+        Ok((key, Some(String::from_utf8(value)?), ttl))
+    }
+
+    /// Turns a compressed set into a `Set`.
+    fn decompress(self) -> Result<Command> {
+        match self.into_parts()? {
+            (key, Some(value), ttl) => Ok(Command::set(key, value, ttl)),
+            (key, None, _) => Ok(Command::remove(key)),
+        }
+    }
 }
 
 /// Represents the position and length of a json-serialized command in the log.
@@ -453,14 +854,14 @@ impl From<(u64, Range<u64>)> for CommandPos {
     }
 }
 
-struct BufReaderWithPos<R: Read + Seek> {
+pub(super) struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
-    pos: u64,
+    pub(super) pos: u64,
     len:u64,
 }
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
+    pub(super) fn new(mut inner: R) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
@@ -526,7 +927,8 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        // log files are opened in append mode, writes go to the end of a reused file
+        let pos = inner.seek(SeekFrom::End(0))?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
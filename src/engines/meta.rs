@@ -0,0 +1,132 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::{KvsError, Result};
+
+/// Name of the metadata file inside a store directory.
+pub const META_FILE: &str = "meta.json";
+
+/// Version of the on-disk format written by this build.
+///
+/// Version 2 adds compressed values and expiration times to the kvs logs.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Metadata identifying the engine and format of a store directory.
+///
+/// It is written when a store is created and checked every time it is opened,
+/// so a directory can never be opened by the wrong engine.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreMeta {
+    /// The engine that owns the directory, `kvs` or `sled`
+    pub engine: String,
+    /// Version of the on-disk format
+    pub format_version: u32,
+    /// Creation time of the store in RFC 3339 format
+    pub created_at: String,
+    /// A random id that identifies the store
+    pub store_id: String,
+}
+
+impl StoreMeta {
+    /// Reads the metadata of the store at `dir`, returns `None` if there is none.
+    pub fn load(dir: &Path) -> Result<Option<StoreMeta>> {
+        match fs::read(meta_path(dir)) {
+            Ok(content) => {
+                let meta = serde_json::from_slice(&content).map_err(|e| {
+                    KvsError::StringError(format!("corrupted store metadata {}: {}", meta_path(dir).display(), e))
+                })?;
+                Ok(Some(meta))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Checks that the store at `dir` belongs to `engine`, creating the metadata
+    /// for a new or pre-metadata store.
+    ///
+    /// The metadata of a store written by an older version is upgraded to
+    /// `FORMAT_VERSION`, the store may now get records older versions cannot read.
+    ///
+    /// The engine of a pre-metadata store is told by its files, generation logs
+    /// for `kvs` and the `conf` and `db` files for `sled`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::EngineMismatch` if the directory belongs to another
+    /// engine and `KvsError::UnsupportedFormat` if it was written by a newer version.
+    pub fn open(dir: &Path, engine: &str) -> Result<StoreMeta> {
+        if let Some(meta) = StoreMeta::load(dir)? {
+            if meta.engine != engine {
+                return Err(KvsError::EngineMismatch {
+                    expected: engine.to_string(),
+                    found: meta.engine,
+                });
+            }
+            if meta.format_version > FORMAT_VERSION {
+                return Err(KvsError::UnsupportedFormat(meta.format_version));
+            }
+            if meta.format_version < FORMAT_VERSION {
+                // 旧版本读到新的记录时会解码失败,升级后它在版本检查时就拒绝打开
+                let meta = StoreMeta { format_version: FORMAT_VERSION, ..meta };
+                meta.write(dir)?;
+                return Ok(meta);
+            }
+            return Ok(meta);
+        }
+        if let Some(found) = detect_engine(dir)?
+            && found != engine
+        {
+            return Err(KvsError::EngineMismatch {
+                expected: engine.to_string(),
+                found: found.to_string(),
+            });
+        }
+
+        let meta = StoreMeta {
+            engine: engine.to_string(),
+            format_version: FORMAT_VERSION,
+            created_at: chrono::Local::now().to_rfc3339(),
+            store_id: uuid::Uuid::new_v4().to_string(),
+        };
+        meta.write(dir)?;
+        Ok(meta)
+    }
+
+    fn write(&self, dir: &Path) -> Result<()> {
+        // write to a temporary file first so a crash never leaves a partial metadata file
+        let tmp = dir.join(format!("{}.tmp", META_FILE));
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, meta_path(dir))?;
+        Ok(())
+    }
+}
+
+// 没有元数据的旧目录按文件判断引擎,kvs写<gen>.log,sled写conf和db
+fn detect_engine(dir: &Path) -> Result<Option<&'static str>> {
+    let mut found = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        let engine = if name.strip_suffix(".log").is_some_and(|r#gen| r#gen.parse::<u64>().is_ok()) {
+            "kvs"
+        } else if name == "conf" || name == "db" {
+            "sled"
+        } else {
+            continue;
+        };
+        match found {
+            Some(other) if other != engine => {
+                return Err(KvsError::StringError(format!("{} contains files of both kvs and sled", dir.display())));
+            }
+            _ => found = Some(engine),
+        }
+    }
+    Ok(found)
+}
+
+fn meta_path(dir: &Path) -> PathBuf {
+    dir.join(META_FILE)
+}
@@ -1,4 +1,30 @@
 use crate::Result;
+use crossbeam::channel::Receiver;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+/// Statistics reported by a storage engine.
+#[derive(Clone,Debug,Default,PartialEq,Eq,Serialize,Deserialize)]
+pub struct EngineStats{
+    /// name of the engine, `kvs` or `sled`
+    pub engine:String,
+    /// number of keys, including expired keys that are not removed yet
+    pub keys:u64,
+    /// bytes of the latest version of every key
+    pub live_bytes:u64,
+    /// bytes of overwritten or removed entries that a compaction can reclaim
+    pub stale_bytes:u64,
+    /// bytes used on disk
+    pub disk_bytes:u64,
+    /// number of log files
+    pub generations:u64,
+    /// number of compactions since the store was opened
+    pub compactions:u64,
+    /// total time spent in compactions in milliseconds
+    pub compaction_millis:u64,
+    /// duration of the latest compaction in milliseconds
+    pub last_compaction_millis:u64,
+}
 
 ///KVEngine is a abstract interface
 pub trait KVEngine:Clone+Send + 'static{
@@ -8,14 +34,53 @@ pub trait KVEngine:Clone+Send + 'static{
     ///get value string from kv engine
     fn get(&self, key: String) -> Result<Option<String>>;
 
-    fn scan(&self, start: String,end:String) -> Result<Vec<String>>;
+    ///scan all key value pairs that satisfy start <= key <= end, ordered by key
+    fn scan(&self, start: String,end:String) -> Result<Vec<(String,String)>>;
+
+    ///keys that satisfy start <= key <= end, ordered by key, without the first `skip`
+    ///keys and at most `limit` of them, the values are not read
+    fn scan_keys(&self, start: String,end:String,skip:usize,limit:usize) -> Result<Vec<String>>;
+
+    ///remaining seconds before key expires, 0 if it never expires, None if it does not exist or expired
+    fn ttl(&self, key: String) -> Result<Option<u32>>;
 
     ///remove key value string from kv engine
     fn remove(&self, key: String) -> Result<()>;
+
+    ///flush all buffered writes to disk
+    fn flush(&self) -> Result<()>;
+
+    ///statistics about the keys and disk usage of the engine
+    fn stats(&self) -> Result<EngineStats>;
+
+    ///number of keys in the engine, counted without scanning the keys
+    fn key_count(&self) -> Result<u64>;
+
+    ///changes of the keys starting with prefix, made after this call
+    fn watch(&self, prefix: &str) -> Receiver<ChangeEvent>;
+
+    ///mutations recorded after sequence number `after`, 0 reads all retained mutations
+    fn cdc(&self, after: u64) -> Result<Box<dyn CdcCursor>>;
+
+    ///sequence number of the latest mutation, `cdc(seq)` reads the mutations made after this call
+    fn cdc_seq(&self) -> Result<u64>;
+
+    ///writes a consistent copy of the store to dest, which must be missing or empty, while it keeps serving
+    fn backup(&self, dest: &Path) -> Result<BackupManifest>;
 }
 
+mod backup;
+mod cdc;
+mod changes;
+mod inspect;
 mod kvs;
+mod meta;
 mod sled;
 
-pub use self::kvs::KvStore;
+pub use self::backup::{restore,BackupFile,BackupManifest,BACKUP_MANIFEST};
+pub use self::cdc::{CdcCursor,CdcRecord};
+pub use self::changes::{ChangeEvent,ChangeFeed,ChangeOp};
+pub use self::inspect::{GenerationReport,LogError,LogReader,LogRecord,RepairReport};
+pub use self::kvs::{Compression,KvStore,KvStoreOptions};
+pub use self::meta::{StoreMeta,META_FILE,FORMAT_VERSION};
 pub use self::sled::SledStore;
\ No newline at end of file
@@ -1,25 +1,153 @@
-use super::KVEngine;
+use super::{backup,BackupManifest,CdcCursor,CdcRecord,ChangeEvent,ChangeFeed,ChangeOp,EngineStats,KVEngine,StoreMeta,META_FILE};
 use crate::{KvsError, Result};
-use sled::{self,Db};
-use std::path::PathBuf;
+use crossbeam::channel::Receiver;
+use sled::{self,Db,Transactional,Tree};
+use sled::transaction::{ConflictableTransactionError,TransactionError};
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::cell::Cell;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicU64,Ordering};
+
+// the tree of the mutations read by change data capture, keyed by big endian sequence numbers
+const JOURNAL_TREE: &str = "cdc";
+// number of latest mutations kept in the journal
+const JOURNAL_RETENTION: u64 = 100_000;
+// the journal is trimmed once it is this many mutations over the retention
+const JOURNAL_TRIM_BATCH: u64 = 1_000;
+// key of the sequence number the journal was trimmed up to, sequence numbers start at 1
+const TRIMMED_KEY: [u8; 8] = [0; 8];
 
 #[derive(Clone)]
 pub struct SledStore{
+    path: Arc<PathBuf>,
     t: Db,
+    journal: Tree,
+    // number of mutations in the journal, writers hold the lock so the
+    // sequence numbers are in the same order as the commits
+    journal_len: Arc<Mutex<u64>>,
+    // number of keys, counted once on open and kept up to date by commit
+    keys: Arc<AtomicU64>,
+    changes: ChangeFeed,
 }
 
 impl SledStore{
+    /// Opens a `SledStore` with the given path.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::EngineMismatch` if the directory belongs to another engine.
     pub fn open(path: impl Into<PathBuf>)->Result<Self>{
-        let db=sled::open(path.into())?;
-        Ok(Self{t:db})
+        let path=path.into();
+        fs::create_dir_all(&path)?;
+        StoreMeta::open(&path, "sled")?;
+        let db=sled::open(&path)?;
+        let journal=db.open_tree(JOURNAL_TREE)?;
+        let mut journal_len=journal.len() as u64;
+        if journal.contains_key(TRIMMED_KEY)?{
+            journal_len-=1;
+        }
+        let keys=Arc::new(AtomicU64::new(db.len() as u64));
+        Ok(Self{path:Arc::new(path),t:db,journal,journal_len:Arc::new(Mutex::new(journal_len)),keys,changes:ChangeFeed::default()})
+    }
+
+    // Applies a mutation and appends it to the journal in one transaction.
+    fn commit(&self, key: String, value: Option<String>) -> Result<()> {
+        let mut journal_len=self.journal_len.lock().unwrap();
+        let record=CdcRecord{
+            seq:self.t.generate_id()?+1,
+            op:if value.is_some() {ChangeOp::Set} else {ChangeOp::Remove},
+            key,
+            value,
+            ttl:0,
+        };
+        let entry=serde_json::to_vec(&record)?;
+        // whether the transaction added a new key, it is set again when the transaction is retried
+        let added=Cell::new(false);
+        let res=(&*self.t,&self.journal).transaction(|(data,journal)|{
+            match &record.value{
+                Some(value)=>{
+                    added.set(data.insert(record.key.as_bytes(),value.as_bytes())?.is_none());
+                },
+                None=>{
+                    if data.remove(record.key.as_bytes())?.is_none(){
+                        return Err(ConflictableTransactionError::Abort(()));
+                    }
+                },
+            }
+            journal.insert(&record.seq.to_be_bytes(),entry.as_slice())?;
+            Ok(())
+        });
+        match res{
+            Ok(())=>{},
+            Err(TransactionError::Abort(()))=>return Err(KvsError::KeyNotFound),
+            Err(TransactionError::Storage(e))=>return Err(e.into()),
+        }
+        match record.op{
+            ChangeOp::Set if added.get()=>{self.keys.fetch_add(1,Ordering::SeqCst);},
+            ChangeOp::Set=>{},
+            _=>{self.keys.fetch_sub(1,Ordering::SeqCst);},
+        }
+        *journal_len+=1;
+        if *journal_len>JOURNAL_RETENTION+JOURNAL_TRIM_BATCH{
+            self.trim_journal()?;
+            *journal_len-=JOURNAL_TRIM_BATCH;
+        }
+        self.t.flush()?;
+        self.changes.emit(&record.key,record.op,record.seq);
+        Ok(())
+    }
+
+    fn trim_journal(&self) -> Result<()> {
+        let mut trimmed=0;
+        for r in self.journal.range(1u64.to_be_bytes()..).take(JOURNAL_TRIM_BATCH as usize){
+            let (k,_)=r?;
+            self.journal.remove(&k)?;
+            trimmed=decode_seq(&k);
+        }
+        self.journal.insert(TRIMMED_KEY,&trimmed.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+fn decode_seq(bytes: &[u8]) -> u64 {
+    let mut buf=[0u8;8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+// the sequence number the journal was trimmed up to, 0 if it was never trimmed
+fn trimmed_seq(journal: &Tree) -> Result<u64> {
+    Ok(journal.get(TRIMMED_KEY)?.map(|v|decode_seq(&v)).unwrap_or(0))
+}
+
+/// Reads the journal of a `SledStore`.
+struct SledCursor{
+    journal: Tree,
+    // sequence number of the latest mutation read
+    last: u64,
+}
+
+impl CdcCursor for SledCursor{
+    fn next(&mut self) -> Result<Option<CdcRecord>> {
+        if trimmed_seq(&self.journal)?>self.last{
+            return Err(KvsError::CdcUnavailable(self.last));
+        }
+        match self.journal.range((self.last+1).to_be_bytes()..).next(){
+            Some(r)=>{
+                let (_,v)=r?;
+                let record:CdcRecord=serde_json::from_slice(&v)?;
+                self.last=record.seq;
+                Ok(Some(record))
+            },
+            None=>Ok(None),
+        }
     }
 }
 
 impl KVEngine for SledStore{
     fn set(&self, key: String, value: String,_:u32) -> Result<()> {
-        self.t.insert(key.as_bytes(),value.as_bytes())?;
-        self.t.flush()?;
-        Ok(())
+        self.commit(key,Some(value))
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
@@ -33,24 +161,100 @@ impl KVEngine for SledStore{
         }
     }
 
-    fn scan(&self, start: String,end:String) -> Result<Vec<String>>{
+    fn scan(&self, start: String,end:String) -> Result<Vec<(String,String)>>{
         let mut res=Vec::new();
         let start=start.as_bytes();
         let end=end.as_bytes();
-        for r in self.t.range(start..end){
-            let (_k,v)=r?;
-            let s=String::from_utf8(v.to_vec())?;
-            res.push(s);
+        for r in self.t.range(start..=end){
+            let (k,v)=r?;
+            let k=String::from_utf8(k.to_vec())?;
+            let v=String::from_utf8(v.to_vec())?;
+            res.push((k,v));
         }
         Ok(res)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let res=self.t.remove(key.as_bytes())?;
-        if res.is_none(){
-            return Err(KvsError::KeyNotFound);
+    fn scan_keys(&self, start: String,end:String,skip:usize,limit:usize) -> Result<Vec<String>>{
+        let mut res=Vec::new();
+        for k in self.t.range(start.as_bytes()..=end.as_bytes()).keys().skip(skip).take(limit){
+            res.push(String::from_utf8(k?.to_vec())?);
         }
+        Ok(res)
+    }
+
+    /// sled keeps no expiration times, keys never expire.
+    fn ttl(&self, key: String) -> Result<Option<u32>> {
+        Ok(self.t.contains_key(key.as_bytes())?.then_some(0))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.commit(key,None)
+    }
+
+    fn flush(&self) -> Result<()> {
         self.t.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// sled reclaims space by itself, so stale bytes, generations and compactions are not reported.
+    fn stats(&self) -> Result<EngineStats> {
+        let mut keys=0;
+        let mut live_bytes=0;
+        for r in self.t.iter(){
+            let (k,v)=r?;
+            keys+=1;
+            live_bytes+=(k.len()+v.len()) as u64;
+        }
+        Ok(EngineStats{
+            engine:"sled".to_string(),
+            keys,
+            live_bytes,
+            disk_bytes:self.t.size_on_disk()?,
+            ..Default::default()
+        })
+    }
+
+    fn key_count(&self) -> Result<u64> {
+        Ok(self.keys.load(Ordering::SeqCst))
+    }
+
+    fn watch(&self, prefix: &str) -> Receiver<ChangeEvent> {
+        self.changes.watch(prefix)
+    }
+
+    /// Reads the journal of mutations, it keeps the latest 100000 mutations.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CdcUnavailable` if the journal was trimmed past `after`.
+    fn cdc(&self, after: u64) -> Result<Box<dyn CdcCursor>> {
+        let trimmed=trimmed_seq(&self.journal)?;
+        if after!=0 && after<trimmed{
+            return Err(KvsError::CdcUnavailable(after));
+        }
+        Ok(Box::new(SledCursor{journal:self.journal.clone(),last:after.max(trimmed)}))
+    }
+
+    fn cdc_seq(&self) -> Result<u64> {
+        // the trimmed marker sorts first, so the last entry is the latest mutation if there is any
+        match self.journal.last()?{
+            Some((k,_)) if k.as_ref()!=TRIMMED_KEY=>Ok(decode_seq(&k)),
+            _=>trimmed_seq(&self.journal),
+        }
+    }
+
+    /// Exports every tree into a new database at dest, writes are blocked meanwhile
+    /// so the data and the journal are copied at the same mutation.
+    fn backup(&self, dest: &Path) -> Result<BackupManifest> {
+        backup::create_dir(dest)?;
+        fs::copy(self.path.join(META_FILE),dest.join(META_FILE))?;
+        let keys={
+            let _journal_len=self.journal_len.lock().unwrap();
+            let db=sled::open(dest)?;
+            db.import(self.t.export());
+            db.flush()?;
+            db.len() as u64
+        };
+        backup::finish(dest,"sled",keys)
+    }
+}
@@ -39,6 +39,50 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// The data directory belongs to another engine
+    #[fail(display = "data directory belongs to engine '{}', cannot open it with '{}'", found, expected)]
+    EngineMismatch {
+        /// the engine trying to open the directory
+        expected: String,
+        /// the engine recorded in the directory metadata
+        found: String,
+    },
+    /// The data directory was written by a newer, unsupported format version
+    #[fail(display = "unsupported store format version {}", _0)]
+    UnsupportedFormat(u32),
+    /// Invalid server configuration
+    #[fail(display = "invalid config: {}", _0)]
+    Config(String),
+    /// Unknown user or wrong password
+    #[fail(display = "Invalid username or password")]
+    AuthFailed,
+    /// The client is not authenticated or its user may not run the command
+    #[fail(display = "No permission: {}", _0)]
+    NoPermission(String),
+    /// The change log after the sequence number was compacted or trimmed away
+    #[fail(display = "changes after sequence {} are no longer available, resume from 0", _0)]
+    CdcUnavailable(u64),
+    /// Invalid TLS certificates or keys, or a failed handshake
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
+    /// The server is a replica and only the leader accepts writes
+    #[fail(display = "Read-only replica, write to the leader at {}", _0)]
+    ReadOnlyReplica(String),
+    /// The replica is in the middle of a full sync from the leader at the address and serves no reads
+    #[fail(display = "Replica is loading a full sync from {}, retry later", _0)]
+    ReplicaLoading(String),
+    /// The node is not the Raft leader, it holds the client address of the leader or `unknown`
+    #[fail(display = "Not the leader, the leader is {}", _0)]
+    NotLeader(String),
+    /// The key belongs to a hash slot another cluster node serves, it holds the slot and the node address
+    #[fail(display = "MOVED {} {}", _0, _1)]
+    Moved(u16, String),
+    /// The key of a migrating slot has moved, retry the command once on the node at the address
+    #[fail(display = "ASK {} {}", _0, _1)]
+    Ask(u16, String),
+    /// The cluster cannot serve the command, e.g. its keys are in different slots
+    #[fail(display = "{}", _0)]
+    Cluster(String),
     #[fail(display = "Invalid Command,must be [get <key>,scan <start> <end>,set <key> <value> <EX duration>,remove <key>]")]
     InvalidCommand,
 }
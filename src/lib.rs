@@ -2,18 +2,35 @@
 //! A simple key/value store.
 
 //pub use client::KvsClient;
-pub use engines::{KvStore,KVEngine,SledStore};
+pub use engines::{BackupManifest,CdcRecord,ChangeEvent,ChangeOp,Compression,KvStore,KvStoreOptions,KVEngine,EngineStats,SledStore,StoreMeta};
+pub use acl::{Acl,Category,User};
 pub use error::{KvsError, Result};
-pub use server::KvServer;
-pub use client::KvClient;
-pub use common::{Cmd,GetCmd,SetCmd,RemoveCmd,ScanCmd,parse_response,init_logger,validate_vector};
+pub use server::{KvServer,ReplicationInfo,ServerHandle,ServerOptions,ServerStats};
+pub use cluster::ClusterClient;
+pub use proxy::{Proxy,ProxyHandle,ProxyOptions};
+pub use client::{CdcStream,KvClient,Message,Monitor,ServerAddr,Subscription,Watch};
+pub use config::Config;
+pub use common::{Cmd,GetCmd,SetCmd,RemoveCmd,ScanCmd,ServerInfo,parse_response,init_logger,set_log_level,validate_vector};
+pub use slowlog::SlowlogEntry;
 pub use thread_pool::{ThreadPool,ShardThreadPool};
+pub mod acl;
 pub mod client;
+pub mod cluster;
 pub mod common;
+pub mod config;
+mod connection;
+pub mod dump;
 
 ///a module represent kv engine
 pub mod engines;
 ///a module about errors
 pub mod error;
+pub mod metrics;
+pub mod monitor;
+pub mod proxy;
+pub mod pubsub;
+pub mod raft;
 pub mod server;
+pub mod slowlog;
 pub mod thread_pool;
+pub mod tls;
@@ -0,0 +1,182 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use log::{debug, error, info};
+use crate::{EngineStats, Result, ServerStats};
+
+//命令耗时直方图的桶,单位秒
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+//有统计的命令名,原生命令和RESP命令共用,其他RESP命令记为other
+const COMMANDS: [&str; 46] = [
+    "auth", "backup", "cdc", "client", "clustermigrate", "clustersetslot", "clusterslots", "command",
+    "config", "configget", "configset", "dbsize", "del", "echo", "exists", "get", "hello", "info", "keys",
+    "mget", "monitor", "mset", "other", "psetex", "psubscribe", "publish", "punsubscribe", "quit",
+    "remove", "replicaof", "scan", "select", "set", "setex", "setnx", "slaveof", "slowlogget",
+    "slowlogreset", "subscribe", "sync", "type", "unlink", "unsubscribe", "vdel", "vget", "vset",
+];
+
+#[derive(Default)]
+struct Histogram {
+    // 每个桶的计数(非累积),渲染时再累加
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Per-command counters and latency histograms.
+///
+/// Every known command has its own atomic counters, recording a command takes
+/// no lock and allocates nothing.
+pub struct CommandMetrics {
+    // 按命令名排序
+    commands: Vec<(&'static str, Histogram)>,
+    // 未知命令记入的other的下标
+    other: usize,
+}
+
+impl Default for CommandMetrics {
+    fn default() -> Self {
+        let mut names = COMMANDS;
+        names.sort_unstable();
+        let other = names.iter().position(|name| *name == "other").unwrap_or_default();
+        CommandMetrics { commands: names.into_iter().map(|name| (name, Histogram::default())).collect(), other }
+    }
+}
+
+impl CommandMetrics {
+    fn histogram(&self, cmd: &str) -> &Histogram {
+        let i = self.commands.binary_search_by(|(name, _)| (*name).cmp(cmd)).unwrap_or(self.other);
+        &self.commands[i].1
+    }
+
+    /// Records one processed command.
+    pub fn observe(&self, cmd: &str, elapsed: Duration, ok: bool) {
+        let h = self.histogram(cmd);
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+            h.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        h.count.fetch_add(1, Ordering::Relaxed);
+        h.sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        if !ok {
+            h.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        // 只输出执行过的命令
+        let commands: Vec<_> = self
+            .commands
+            .iter()
+            .map(|(cmd, h)| (cmd, h, h.count.load(Ordering::Relaxed)))
+            .filter(|(_, _, count)| *count > 0)
+            .collect();
+        out.push_str("# HELP kvs_commands_total Commands processed by command type.\n");
+        out.push_str("# TYPE kvs_commands_total counter\n");
+        for (cmd, _, count) in &commands {
+            let _ = writeln!(out, "kvs_commands_total{{cmd=\"{}\"}} {}", cmd, count);
+        }
+        out.push_str("# HELP kvs_command_errors_total Commands that returned an error by command type.\n");
+        out.push_str("# TYPE kvs_command_errors_total counter\n");
+        for (cmd, h, _) in &commands {
+            let _ = writeln!(out, "kvs_command_errors_total{{cmd=\"{}\"}} {}", cmd, h.errors.load(Ordering::Relaxed));
+        }
+        out.push_str("# HELP kvs_command_duration_seconds Command latency by command type.\n");
+        out.push_str("# TYPE kvs_command_duration_seconds histogram\n");
+        for (cmd, h, count) in &commands {
+            let mut cumulative = 0;
+            for (bound, n) in BUCKETS.iter().zip(h.buckets.iter()) {
+                cumulative += n.load(Ordering::Relaxed);
+                let _ = writeln!(out, "kvs_command_duration_seconds_bucket{{cmd=\"{}\",le=\"{}\"}} {}", cmd, bound, cumulative);
+            }
+            let sum = h.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "kvs_command_duration_seconds_bucket{{cmd=\"{}\",le=\"+Inf\"}} {}", cmd, count);
+            let _ = writeln!(out, "kvs_command_duration_seconds_sum{{cmd=\"{}\"}} {}", cmd, sum);
+            let _ = writeln!(out, "kvs_command_duration_seconds_count{{cmd=\"{}\"}} {}", cmd, count);
+        }
+    }
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub fn render(commands: &CommandMetrics, conns: &ServerStats, queue_depth: usize, engine: &EngineStats) -> String {
+    let mut out = String::new();
+    commands.render(&mut out);
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+    };
+    metric("kvs_connections_active", "gauge", "Client connections currently served.", conns.active_connections.to_string());
+    metric("kvs_connections_total", "counter", "Client connections accepted.", conns.total_connections.to_string());
+    metric("kvs_connections_rejected_total", "counter", "Connections rejected by the connection limit.", conns.rejected_connections.to_string());
+    metric("kvs_connection_idle_timeouts_total", "counter", "Connections closed for being idle.", conns.idle_timeouts.to_string());
+    metric("kvs_connection_read_timeouts_total", "counter", "Connections closed by a read timeout.", conns.read_timeouts.to_string());
+    metric("kvs_connection_write_timeouts_total", "counter", "Connections closed by a write timeout.", conns.write_timeouts.to_string());
+    metric("kvs_thread_pool_queue_depth", "gauge", "Connections waiting for a worker thread.", queue_depth.to_string());
+    metric("kvs_engine_keys", "gauge", "Keys in the engine index.", engine.keys.to_string());
+    metric("kvs_engine_live_bytes", "gauge", "Bytes of the latest version of every key.", engine.live_bytes.to_string());
+    metric("kvs_engine_stale_bytes", "gauge", "Bytes a compaction can reclaim.", engine.stale_bytes.to_string());
+    metric("kvs_engine_disk_bytes", "gauge", "Bytes used on disk.", engine.disk_bytes.to_string());
+    metric("kvs_engine_log_generations", "gauge", "Log files of the kvs engine.", engine.generations.to_string());
+    metric("kvs_engine_compactions_total", "counter", "Compactions since the store was opened.", engine.compactions.to_string());
+    metric("kvs_engine_compaction_seconds_total", "counter", "Time spent in compactions.", (engine.compaction_millis as f64 / 1000.0).to_string());
+    metric("kvs_engine_last_compaction_seconds", "gauge", "Duration of the latest compaction.", (engine.last_compaction_millis as f64 / 1000.0).to_string());
+    out
+}
+
+/// Serves `GET /metrics` on `listener` in a background thread, `render` produces the body.
+pub fn serve<F>(listener: TcpListener, render: F) -> Result<()>
+where
+    F: Fn() -> Result<String> + Send + 'static,
+{
+    info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+    thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_request(stream, &render) {
+                        debug!("Metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => error!("Error accepting metrics connection: {}", e),
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn handle_request<F>(mut stream: TcpStream, render: &F) -> Result<()>
+where
+    F: Fn() -> Result<String>,
+{
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // 丢弃请求头
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match render() {
+            Ok(body) => ("200 OK", body),
+            Err(e) => ("500 Internal Server Error", format!("{}\n", e)),
+        },
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+
+//每个monitor客户端最多缓存的事件数,缓存满时丢弃新事件而不是阻塞处理命令的线程
+const MONITOR_BUFFER: usize = 1024;
+
+/// Broadcasts every processed command to the attached `MONITOR` clients.
+#[derive(Default)]
+pub struct MonitorHub {
+    active: AtomicUsize,
+    subscribers: Mutex<Vec<Sender<String>>>,
+}
+
+impl MonitorHub {
+    /// Returns whether any monitor is attached, it is cheap enough to check for every command.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst) > 0
+    }
+
+    /// Attaches a monitor, it is detached once the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (sender, receiver) = channel::bounded(MONITOR_BUFFER);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(sender);
+        self.active.store(subscribers.len(), Ordering::SeqCst);
+        receiver
+    }
+
+    /// Sends an event to all monitors without blocking, slow monitors miss events.
+    pub fn publish(&self, event: String) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| !matches!(s.try_send(event.clone()), Err(TrySendError::Disconnected(_))));
+        self.active.store(subscribers.len(), Ordering::SeqCst);
+    }
+}
+
+/// Formats a command as `<unix time> [<client>] "arg" "arg"...`.
+pub fn format_event(client: impl Display, args: &[String]) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let args: Vec<String> = args.iter().map(|a| format!("{:?}", a)).collect();
+    format!("{}.{:06} [{}] {}", now.as_secs(), now.subsec_micros(), client, args.join(" "))
+}
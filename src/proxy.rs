@@ -0,0 +1,501 @@
+//! A proxy sharding keys over unmodified servers by consistent hashing.
+//!
+//! The proxy speaks the native protocol. Commands on a single key go to the
+//! backend owning the key on a `HashRing`, scans are sent to every backend and
+//! merged in key order. Each backend has many virtual nodes on the ring, so
+//! adding or removing a backend only moves the keys it gains or loses, about
+//! `1/n` of them; keys are not copied between backends.
+//!
+//! Backends are pinged in the background. A backend failing `failure_limit`
+//! checks in a row is marked down: its keys fail fast, or with `eject` it leaves
+//! the ring until it answers again and its keys go to the next backend.
+//!
+//! Clients authenticate with the proxy's own users and every command is checked
+//! against them, the proxy forwards with its own backend user. Requests larger
+//! than 64MB are refused and close the connection.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}};
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use crate::acl::{Acl, Category};
+use crate::client::parse_pairs;
+use crate::common::DbSizeCmd;
+use crate::{Cmd, KvClient, KvsError, Result, ServerAddr};
+
+//监听线程检查关闭标志的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+//客户端请求帧的最大长度,在认证之前读取,不能按客户端给出的长度分配
+const MAX_FRAME: usize = 64 << 20;
+
+/// A consistent hash ring with virtual nodes.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    vnodes: usize,
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    /// Creates an empty ring placing `vnodes` points for every node.
+    pub fn new(vnodes: usize) -> HashRing {
+        HashRing { vnodes: vnodes.max(1), ring: BTreeMap::new() }
+    }
+
+    /// Adds `node`, it takes over the keys between its points and the points before them.
+    pub fn add(&mut self, node: &str) {
+        for i in 0..self.vnodes {
+            self.ring.insert(hash(format!("{}#{}", node, i).as_bytes()), node.to_string());
+        }
+    }
+
+    /// Removes `node`, its keys move to the nodes following its points.
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, n| n != node);
+    }
+
+    /// Returns the node owning `key`, `None` if the ring is empty.
+    pub fn node(&self, key: &str) -> Option<&str> {
+        let point = hash(key.as_bytes());
+        self.ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    /// Returns the nodes on the ring, in order.
+    pub fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self.ring.values().cloned().collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
+
+// FNV-1a加上murmur3的混合步骤,相近的字符串也能均匀分布
+fn hash(data: &[u8]) -> u64 {
+    let mut h = 0xcbf29ce484222325u64;
+    for &b in data {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+/// Settings of a `Proxy`.
+#[derive(Clone, Debug)]
+pub struct ProxyOptions {
+    pub backends: Vec<ServerAddr>,
+    /// Points of every backend on the ring
+    pub vnodes: usize,
+    /// Time between two health checks of a backend
+    pub health_interval: Duration,
+    /// Failed health checks in a row after which a backend is down
+    pub failure_limit: u32,
+    /// Whether down backends leave the ring instead of failing their keys
+    pub eject: bool,
+    /// Bounds connecting to a backend and waiting for its response
+    pub timeout: Duration,
+    /// The user the proxy authenticates as with the backends, requires `users`
+    pub auth: Option<(String, String)>,
+    /// The users clients authenticate as, authentication is disabled if there are none
+    pub users: Acl,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        ProxyOptions {
+            backends: Vec::new(),
+            vnodes: 160,
+            health_interval: Duration::from_secs(1),
+            failure_limit: 3,
+            eject: false,
+            timeout: Duration::from_secs(5),
+            auth: None,
+            users: Acl::default(),
+        }
+    }
+}
+
+struct Backend {
+    addr: ServerAddr,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+    //每次标记为不可用时加1,连接建立时的值不同说明连接已失效
+    downs: AtomicU64,
+}
+
+struct Shared {
+    options: ProxyOptions,
+    ring: RwLock<HashRing>,
+    backends: RwLock<BTreeMap<String, Arc<Backend>>>,
+    shut_down: Arc<AtomicBool>,
+}
+
+/// Changes the backends of a running proxy.
+#[derive(Clone)]
+pub struct ProxyHandle {
+    shared: Arc<Shared>,
+}
+
+impl ProxyHandle {
+    /// Returns the addresses of all backends.
+    pub fn backends(&self) -> Vec<String> {
+        self.shared.backends.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the addresses of the backends passing their health checks.
+    pub fn healthy(&self) -> Vec<String> {
+        let backends = self.shared.backends.read().unwrap();
+        backends.iter().filter(|(_, b)| b.healthy.load(Ordering::SeqCst)).map(|(addr, _)| addr.clone()).collect()
+    }
+
+    /// Adds a backend, it takes over about `1/n` of the keys from the others.
+    pub fn add_backend(&self, addr: &ServerAddr) {
+        self.shared.add_backend(addr);
+    }
+
+    /// Removes a backend, only its keys move to the other backends.
+    ///
+    /// The keys stored on the removed backend are not copied.
+    pub fn remove_backend(&self, addr: &ServerAddr) -> Result<()> {
+        self.shared.remove_backend(&addr.to_string())
+    }
+}
+
+/// A native protocol proxy in front of several servers, see the module documentation.
+pub struct Proxy {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl Proxy {
+    /// Listens on `addr`, the proxy serves clients once `run` is called.
+    pub async fn bind(addr: SocketAddr, options: ProxyOptions, shut_down: Arc<AtomicBool>) -> Result<Proxy> {
+        if options.backends.is_empty() {
+            return Err(KvsError::StringError("the proxy needs at least one backend".to_string()));
+        }
+        // 否则任何客户端都能以代理的后端用户执行命令
+        if options.auth.is_some() && !options.users.is_enabled() {
+            return Err(KvsError::StringError("the proxy needs users for its clients when it authenticates with the backends".to_string()));
+        }
+        let listener = TcpListener::bind(addr).await?;
+        let shared = Arc::new(Shared {
+            ring: RwLock::new(HashRing::new(options.vnodes)),
+            backends: RwLock::new(BTreeMap::new()),
+            options,
+            shut_down,
+        });
+        for backend in &shared.options.backends {
+            shared.add_backend(backend);
+        }
+        Ok(Proxy { listener, shared })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn handle(&self) -> ProxyHandle {
+        ProxyHandle { shared: self.shared.clone() }
+    }
+
+    /// Serves clients and checks the backends until the shutdown flag is set.
+    pub async fn run(self) -> Result<()> {
+        info!("Proxy listening on {} for {:?}", self.local_addr()?, self.handle().backends());
+        tokio::spawn(check_health(self.shared.clone()));
+        while !self.shared.shut_down.load(Ordering::SeqCst) {
+            let (stream, peer) = match time::timeout(POLL_INTERVAL, self.listener.accept()).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(e)) => {
+                    error!("Proxy accept error: {}", e);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            let shared = self.shared.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(stream, peer, &shared).await {
+                    debug!("Proxy client {} closed: {}", peer, e);
+                }
+                info!("Proxy client {} disconnected", peer);
+            });
+        }
+        info!("Proxy shut down");
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn add_backend(&self, addr: &ServerAddr) {
+        let name = addr.to_string();
+        let mut backends = self.backends.write().unwrap();
+        if backends.contains_key(&name) {
+            return;
+        }
+        backends.insert(name.clone(), Arc::new(Backend {
+            addr: addr.clone(),
+            healthy: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+            downs: AtomicU64::new(0),
+        }));
+        self.ring.write().unwrap().add(&name);
+        info!("Backend {} added", name);
+    }
+
+    fn remove_backend(&self, name: &str) -> Result<()> {
+        let mut backends = self.backends.write().unwrap();
+        if !backends.contains_key(name) {
+            return Err(KvsError::StringError(format!("unknown backend {}", name)));
+        }
+        if backends.len() == 1 {
+            return Err(KvsError::StringError("cannot remove the last backend".to_string()));
+        }
+        backends.remove(name);
+        self.ring.write().unwrap().remove(name);
+        info!("Backend {} removed", name);
+        Ok(())
+    }
+
+    // 摘除后的节点不在环上,key由环上的下一个节点处理
+    fn route(&self, key: &str) -> Result<Arc<Backend>> {
+        let ring = self.ring.read().unwrap();
+        let name = ring.node(key).ok_or_else(|| KvsError::StringError("no backend is available".to_string()))?;
+        let backend = self.backends.read().unwrap().get(name).cloned();
+        backend.ok_or_else(|| KvsError::StringError(format!("unknown backend {}", name)))
+    }
+
+    // 环上的全部节点
+    fn ring_backends(&self) -> Vec<Arc<Backend>> {
+        let nodes = self.ring.read().unwrap().nodes();
+        let backends = self.backends.read().unwrap();
+        nodes.iter().filter_map(|name| backends.get(name).cloned()).collect()
+    }
+
+    // 客户端以代理的用户认证,之后的命令按该用户的权限检查
+    fn authorize(&self, user: &mut Option<String>, cmd: &Cmd, peer: SocketAddr) -> Result<()> {
+        let acl = &self.options.users;
+        if let Cmd::Auth(c) = cmd {
+            if !acl.is_enabled() {
+                return Err(KvsError::StringError("AUTH failed, no users are configured".to_string()));
+            }
+            if let Err(e) = acl.authenticate(&c.user, &c.password) {
+                warn!("Proxy client {} failed to authenticate as '{}'", peer, c.user);
+                return Err(e);
+            }
+            info!("Proxy client {} authenticated as '{}'", peer, c.user);
+            *user = Some(c.user.clone());
+            return Ok(());
+        }
+        // 修改后端需要admin用户,没有用户时只能通过ProxyHandle修改
+        if !acl.is_enabled() {
+            return match cmd {
+                Cmd::ConfigSet(_) => Err(KvsError::NoPermission("CONFIG SET needs an admin user of the proxy".to_string())),
+                _ => Ok(()),
+            };
+        }
+        match user.as_deref().and_then(|name| acl.user(name)) {
+            Some(user) => user.check(cmd),
+            None if Category::of(cmd).is_some() => Err(KvsError::NoPermission("authentication required".to_string())),
+            None => Ok(()),
+        }
+    }
+
+    async fn execute(&self, cmd: Cmd, conns: &mut Conns) -> Result<String> {
+        match cmd {
+            //AUTH由authorize处理
+            Cmd::Auth(_) => Ok(String::new()),
+            Cmd::Ping(c) if c.message.is_empty() => Ok("PONG".to_string()),
+            Cmd::Ping(c) => Ok(c.message),
+            Cmd::Scan(c) => {
+                // 合并各节点的结果,按key排序
+                let mut pairs = BTreeMap::new();
+                for backend in self.ring_backends() {
+                    let res = self.call(&backend, conns, Cmd::Scan(c.clone())).await?;
+                    pairs.extend(parse_pairs(&res)?);
+                }
+                let pairs: Vec<(String, String)> = pairs.into_iter().collect();
+                Ok(serde_json::to_string(&pairs)?)
+            }
+            Cmd::DbSize(_) => {
+                let mut keys = 0;
+                for backend in self.ring_backends() {
+                    let res = self.call(&backend, conns, Cmd::DbSize(DbSizeCmd)).await?;
+                    keys += res.parse::<u64>().map_err(|_| KvsError::StringError(format!("malformed dbsize response: {}", res)))?;
+                }
+                Ok(keys.to_string())
+            }
+            Cmd::ConfigGet(c) => {
+                let backends = self.backends.read().unwrap();
+                let list = |healthy: bool| -> String {
+                    let names: Vec<&str> = backends
+                        .iter()
+                        .filter(|(_, b)| !healthy || b.healthy.load(Ordering::SeqCst))
+                        .map(|(name, _)| name.as_str())
+                        .collect();
+                    names.join(",")
+                };
+                let pairs = [("backends", list(false)), ("healthy", list(true)), ("vnodes", self.options.vnodes.to_string())];
+                let items: Vec<(&str, String)> = pairs
+                    .into_iter()
+                    .filter(|(name, _)| c.pattern == "*" || c.pattern == *name)
+                    .collect();
+                Ok(serde_json::to_string(&items)?)
+            }
+            // config set backends <addr>,<addr>.. 增加或移除后端
+            Cmd::ConfigSet(c) if c.key == "backends" => {
+                let wanted = c
+                    .value
+                    .split(',')
+                    .filter(|s| !s.trim().is_empty())
+                    .map(|s| s.trim().parse::<ServerAddr>().map_err(KvsError::StringError))
+                    .collect::<Result<Vec<_>>>()?;
+                if wanted.is_empty() {
+                    return Err(KvsError::StringError("the proxy needs at least one backend".to_string()));
+                }
+                let names: Vec<String> = wanted.iter().map(|a| a.to_string()).collect();
+                for addr in &wanted {
+                    self.add_backend(addr);
+                }
+                let current: Vec<String> = self.backends.read().unwrap().keys().cloned().collect();
+                for name in current.iter().filter(|n| !names.contains(n)) {
+                    self.remove_backend(name)?;
+                }
+                Ok(String::new())
+            }
+            Cmd::ConfigSet(c) => Err(KvsError::StringError(format!("Unknown config '{}', the proxy only supports backends", c.key))),
+            cmd if cmd.key().is_some() => {
+                let backend = self.route(cmd.key().unwrap_or_default())?;
+                self.call(&backend, conns, cmd).await
+            }
+            cmd => Err(KvsError::StringError(format!("{} is not supported by the proxy", cmd.to_string().to_uppercase()))),
+        }
+    }
+
+    // 通过该连接上到backend的连接转发cmd,连接出错或超时后丢弃连接
+    async fn call(&self, backend: &Backend, conns: &mut Conns, cmd: Cmd) -> Result<String> {
+        let name = backend.addr.to_string();
+        if !backend.healthy.load(Ordering::SeqCst) {
+            return Err(KvsError::StringError(format!("backend {} is unavailable", name)));
+        }
+        let downs = backend.downs.load(Ordering::SeqCst);
+        if conns.get(&name).is_none_or(|(d, _)| *d != downs) {
+            let client = time::timeout(self.options.timeout, connect(&backend.addr, &self.options.auth))
+                .await
+                .map_err(|_| KvsError::StringError(format!("backend {} timed out", name)))??;
+            conns.insert(name.clone(), (downs, client));
+        }
+        let (_, client) = conns.get_mut(&name).unwrap();
+        match time::timeout(self.options.timeout, client.send_request(cmd)).await {
+            Ok(Err(KvsError::Io(e))) => {
+                conns.remove(&name);
+                Err(KvsError::Io(e))
+            }
+            Ok(res) => res,
+            Err(_) => {
+                conns.remove(&name);
+                Err(KvsError::StringError(format!("backend {} timed out", name)))
+            }
+        }
+    }
+}
+
+//客户端连接使用的到各个后端的连接,以及建立连接时后端的downs
+type Conns = HashMap<String, (u64, KvClient)>;
+
+async fn connect(addr: &ServerAddr, auth: &Option<(String, String)>) -> Result<KvClient> {
+    let mut client = KvClient::connect(addr).await?;
+    if let Some((user, password)) = auth {
+        client.auth(user, password).await?;
+    }
+    Ok(client)
+}
+
+async fn handle_client(stream: TcpStream, peer: SocketAddr, shared: &Shared) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut conns = Conns::new();
+    let mut user = None;
+    loop {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes(len);
+        if len as usize > MAX_FRAME {
+            let e = KvsError::StringError(format!("request of {} bytes is too large", len));
+            writer.write_all(format!("Error{}\n", e).as_bytes()).await?;
+            return Err(e);
+        }
+        let mut buf = vec![0u8; len as usize];
+        reader.read_exact(&mut buf).await?;
+        let res = match Cmd::decode(len, &buf) {
+            Ok(cmd) => match shared.authorize(&mut user, &cmd, peer) {
+                Ok(()) => shared.execute(cmd, &mut conns).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        // 按原生协议的格式返回,错误信息去掉后端响应中的换行
+        let line = match res {
+            Ok(value) => format!("OK{}\n", value),
+            Err(e) => format!("Error{}\n", e.to_string().trim_end()),
+        };
+        writer.write_all(line.as_bytes()).await?;
+    }
+}
+
+// 定期ping每个后端,连续失败failure_limit次后标记为不可用,开启eject时从环上摘除
+async fn check_health(shared: Arc<Shared>) {
+    let mut conns: HashMap<String, KvClient> = HashMap::new();
+    let timeout = shared.options.timeout.min(shared.options.health_interval);
+    while !shared.shut_down.load(Ordering::SeqCst) {
+        time::sleep(shared.options.health_interval).await;
+        let backends: Vec<(String, Arc<Backend>)> =
+            shared.backends.read().unwrap().iter().map(|(name, b)| (name.clone(), b.clone())).collect();
+        conns.retain(|name, _| backends.iter().any(|(n, _)| n == name));
+        for (name, backend) in backends {
+            let res = time::timeout(timeout, async {
+                if !conns.contains_key(&name) {
+                    conns.insert(name.clone(), connect(&backend.addr, &shared.options.auth).await?);
+                }
+                conns.get_mut(&name).unwrap().ping(None).await
+            })
+            .await;
+            if let Ok(Ok(_)) = res {
+                backend.failures.store(0, Ordering::SeqCst);
+                if !backend.healthy.swap(true, Ordering::SeqCst) {
+                    info!("Backend {} is up again", name);
+                    if shared.options.eject && shared.backends.read().unwrap().contains_key(&name) {
+                        shared.ring.write().unwrap().add(&name);
+                    }
+                }
+                continue;
+            }
+            conns.remove(&name);
+            let failures = backend.failures.fetch_add(1, Ordering::SeqCst) + 1;
+            if failures >= shared.options.failure_limit && backend.healthy.swap(false, Ordering::SeqCst) {
+                backend.downs.fetch_add(1, Ordering::SeqCst);
+                warn!("Backend {} is down after {} failed health checks", name, failures);
+                if shared.options.eject {
+                    shared.ring.write().unwrap().remove(&name);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+//每个订阅连接最多缓存的消息数,缓存满时丢弃新消息而不是阻塞发布者
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// A frame pushed to a connection in subscriber mode, sent as a single line of JSON.
+///
+/// Every (un)subscribe command is confirmed by one frame carrying the number of
+/// channels and patterns the connection is still subscribed to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PubSubFrame {
+    Subscribe { channels: Vec<String>, count: usize },
+    Unsubscribe { channels: Vec<String>, count: usize },
+    Psubscribe { patterns: Vec<String>, count: usize },
+    Punsubscribe { patterns: Vec<String>, count: usize },
+    Message { channel: String, payload: String },
+    Pmessage { pattern: String, channel: String, payload: String },
+}
+
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<String, HashMap<u64, Sender<PubSubFrame>>>,
+    patterns: HashMap<String, HashMap<u64, Sender<PubSubFrame>>>,
+}
+
+/// The server-side registry of channel and pattern subscriptions.
+#[derive(Default)]
+pub struct PubSub {
+    registry: Mutex<Registry>,
+    next_id: AtomicU64,
+}
+
+impl PubSub {
+    /// Creates the subscription state of a connection entering subscriber mode.
+    pub fn subscriber(&self) -> Subscriber<'_> {
+        let (sender, receiver) = channel::bounded(SUBSCRIBER_BUFFER);
+        Subscriber {
+            pubsub: self,
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            sender,
+            receiver,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+        }
+    }
+
+    /// Sends `payload` to all subscribers of `channel`, returns the number of receivers.
+    pub fn publish(&self, channel: &str, payload: &str) -> usize {
+        let registry = self.registry.lock().unwrap();
+        let mut receivers = 0;
+        if let Some(subscribers) = registry.channels.get(channel) {
+            for sender in subscribers.values() {
+                let frame = PubSubFrame::Message {
+                    channel: channel.to_string(),
+                    payload: payload.to_string(),
+                };
+                receivers += deliver(sender, frame);
+            }
+        }
+        for (pattern, subscribers) in registry.patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for sender in subscribers.values() {
+                let frame = PubSubFrame::Pmessage {
+                    pattern: pattern.clone(),
+                    channel: channel.to_string(),
+                    payload: payload.to_string(),
+                };
+                receivers += deliver(sender, frame);
+            }
+        }
+        receivers
+    }
+}
+
+fn deliver(sender: &Sender<PubSubFrame>, frame: PubSubFrame) -> usize {
+    match sender.try_send(frame) {
+        Ok(()) => 1,
+        Err(TrySendError::Full(_)) => {
+            warn!("Subscriber is too slow, dropping message");
+            0
+        }
+        Err(TrySendError::Disconnected(_)) => 0,
+    }
+}
+
+/// The subscriptions of one connection, they are all removed when it is dropped.
+pub struct Subscriber<'a> {
+    pubsub: &'a PubSub,
+    id: u64,
+    sender: Sender<PubSubFrame>,
+    receiver: Receiver<PubSubFrame>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+impl Subscriber<'_> {
+    /// The messages published to the subscribed channels and patterns.
+    pub fn messages(&self) -> &Receiver<PubSubFrame> {
+        &self.receiver
+    }
+
+    /// Number of subscribed channels and patterns.
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    pub fn subscribe(&mut self, channels: Vec<String>) -> PubSubFrame {
+        let mut registry = self.pubsub.registry.lock().unwrap();
+        for channel in channels.iter() {
+            registry
+                .channels
+                .entry(channel.clone())
+                .or_default()
+                .insert(self.id, self.sender.clone());
+            self.channels.insert(channel.clone());
+        }
+        PubSubFrame::Subscribe { channels, count: self.count() }
+    }
+
+    pub fn psubscribe(&mut self, patterns: Vec<String>) -> PubSubFrame {
+        let mut registry = self.pubsub.registry.lock().unwrap();
+        for pattern in patterns.iter() {
+            registry
+                .patterns
+                .entry(pattern.clone())
+                .or_default()
+                .insert(self.id, self.sender.clone());
+            self.patterns.insert(pattern.clone());
+        }
+        PubSubFrame::Psubscribe { patterns, count: self.count() }
+    }
+
+    /// Unsubscribes from `channels`, or from all channels if it is empty.
+    pub fn unsubscribe(&mut self, channels: Vec<String>) -> PubSubFrame {
+        let channels = if channels.is_empty() {
+            self.channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+        let mut registry = self.pubsub.registry.lock().unwrap();
+        for channel in channels.iter() {
+            remove(&mut registry.channels, channel, self.id);
+            self.channels.remove(channel);
+        }
+        PubSubFrame::Unsubscribe { channels, count: self.count() }
+    }
+
+    /// Unsubscribes from `patterns`, or from all patterns if it is empty.
+    pub fn punsubscribe(&mut self, patterns: Vec<String>) -> PubSubFrame {
+        let patterns = if patterns.is_empty() {
+            self.patterns.iter().cloned().collect()
+        } else {
+            patterns
+        };
+        let mut registry = self.pubsub.registry.lock().unwrap();
+        for pattern in patterns.iter() {
+            remove(&mut registry.patterns, pattern, self.id);
+            self.patterns.remove(pattern);
+        }
+        PubSubFrame::Punsubscribe { patterns, count: self.count() }
+    }
+}
+
+impl Drop for Subscriber<'_> {
+    fn drop(&mut self) {
+        let mut registry = self.pubsub.registry.lock().unwrap();
+        for channel in self.channels.iter() {
+            remove(&mut registry.channels, channel, self.id);
+        }
+        for pattern in self.patterns.iter() {
+            remove(&mut registry.patterns, pattern, self.id);
+        }
+    }
+}
+
+fn remove(map: &mut HashMap<String, HashMap<u64, Sender<PubSubFrame>>>, name: &str, id: u64) {
+    if let Some(subscribers) = map.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+/// Matches `s` against a glob pattern.
+///
+/// `*` matches any sequence, `?` any single character, `[abc]`, `[a-z]` and
+/// `[^a]` match character classes and `\` escapes the next character.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    match_from(&p, &s)
+}
+
+fn match_from(p: &[char], s: &[char]) -> bool {
+    let (mut pi, mut si) = (0, 0);
+    // 最近一个*的位置和它当前匹配到的位置,用于回溯
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() {
+            match p[pi] {
+                '*' => {
+                    star = Some((pi, si));
+                    pi += 1;
+                    continue;
+                }
+                '?' => {
+                    pi += 1;
+                    si += 1;
+                    continue;
+                }
+                '[' => match match_class(p, pi, s[si]) {
+                    Some((true, next)) => {
+                        pi = next;
+                        si += 1;
+                        continue;
+                    }
+                    // 未闭合的[按普通字符处理
+                    None if s[si] == '[' => {
+                        pi += 1;
+                        si += 1;
+                        continue;
+                    }
+                    _ => {}
+                },
+                '\\' if pi + 1 < p.len() => {
+                    if p[pi + 1] == s[si] {
+                        pi += 2;
+                        si += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == s[si] {
+                        pi += 1;
+                        si += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        match star {
+            Some((spi, ssi)) => {
+                pi = spi + 1;
+                si = ssi + 1;
+                star = Some((spi, ssi + 1));
+            }
+            None => return false,
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+//匹配从p[start]='['开始的字符类,返回是否匹配以及字符类之后的位置,字符类未闭合时返回None
+fn match_class(p: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = i < p.len() && p[i] == '^';
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < p.len() && (p[i] != ']' || first) {
+        first = false;
+        let mut lo = p[i];
+        if lo == '\\' && i + 1 < p.len() {
+            i += 1;
+            lo = p[i];
+        }
+        if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            let hi = p[i + 2];
+            if lo <= c && c <= hi {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if lo == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    if i >= p.len() {
+        return None;
+    }
+    Some((matched != negate, i + 1))
+}
@@ -0,0 +1,1047 @@
+//! Raft consensus with any `KVEngine` as the state machine.
+//!
+//! `RaftNode` implements `KVEngine` itself: writes are appended to the replicated
+//! log and return once a majority stored them and the local engine applied them,
+//! reads confirm the leadership with a round of heartbeats first (read index), so
+//! both are linearizable. Followers answer with `KvsError::NotLeader`.
+//!
+//! The engine doubles as the snapshot: the log is flushed into the engine and
+//! compacted up to the applied index, and a follower that lags behind the compacted
+//! log receives every key of the leader's engine in chunks. The keys are read while
+//! the leader keeps applying entries, so the snapshot is taken at the index the first
+//! chunk was read and the entries after it are applied again on top of it. Membership
+//! changes add or remove one node at a time.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use log::{debug, error, info};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::engines::{BackupManifest, CdcCursor, ChangeEvent, EngineStats};
+use crate::{KVEngine, KvsError, Result};
+use self::storage::{RaftLog, SnapshotEntry, Staged};
+
+mod storage;
+mod transport;
+
+pub use self::transport::{MemNetwork, TcpTransport, Transport};
+
+/// Identifier of a node, unique within a cluster.
+pub type NodeId = u64;
+
+//选举超时检查的间隔
+const TICK: Duration = Duration::from_millis(10);
+//一次AppendEntries最多携带的日志条数
+const MAX_BATCH: usize = 256;
+//快照时作为上界的最大字符
+const KEY_MAX: char = char::MAX;
+//快照每块最多包含的key数
+const SNAPSHOT_CHUNK: usize = 1000;
+
+/// Addresses of a cluster member.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    /// address the node receives Raft messages on
+    pub addr: String,
+    /// address clients reach the node's `kvs-server` on, reported to redirect writes
+    #[serde(default)]
+    pub client_addr: String,
+}
+
+/// An entry of the replicated log.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub data: EntryData,
+}
+
+/// The command an entry applies to the state machine.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntryData {
+    /// appended by a new leader to commit the entries of earlier terms
+    Noop,
+    Set { key: String, value: String, ttl: u32 },
+    Remove { key: String },
+    /// the new membership, in effect as soon as it is appended
+    Config { members: BTreeMap<NodeId, Member> },
+}
+
+/// Messages exchanged between nodes, every request gets exactly one reply.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Vote { term: u64, candidate: NodeId, last_index: u64, last_term: u64 },
+    VoteReply { term: u64, granted: bool },
+    Append { term: u64, leader: NodeId, prev_index: u64, prev_term: u64, entries: Vec<Entry>, commit: u64 },
+    /// `last_index` is the last matching entry on success, a hint where to retry otherwise
+    AppendReply { term: u64, success: bool, last_index: u64 },
+    /// a chunk of the keys of the leader's engine with their remaining TTL, the snapshot
+    /// holds every entry up to `index` and is installed after the chunk marked `done`
+    Snapshot {
+        term: u64,
+        leader: NodeId,
+        index: u64,
+        snapshot_term: u64,
+        members: BTreeMap<NodeId, Member>,
+        chunk: u64,
+        data: Vec<(String, String, u32)>,
+        done: bool,
+    },
+    /// `next_chunk` is the chunk the follower expects next, `None` once it holds the snapshot
+    SnapshotReply { term: u64, next_chunk: Option<u64> },
+}
+
+/// The role of a node in its current term.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Settings of a `RaftNode`.
+#[derive(Clone, Debug)]
+pub struct RaftOptions {
+    /// id of this node
+    pub id: NodeId,
+    /// the initial membership, only used when the log directory is empty;
+    /// a node joining an existing cluster starts without members and waits to be added
+    pub members: BTreeMap<NodeId, Member>,
+    /// how often the leader sends heartbeats
+    pub heartbeat_interval: Duration,
+    /// a follower starts an election after hearing nothing from the leader for
+    /// a random duration between this and twice this
+    pub election_timeout: Duration,
+    /// how long reads and writes wait for a majority of the cluster
+    pub proposal_timeout: Duration,
+    /// number of applied entries that triggers a log compaction
+    pub snapshot_threshold: u64,
+    /// the secret peers prove on every connection to `serve`, `None` accepts any peer;
+    /// the senders need the same secret, see `TcpTransport::with_secret`
+    pub secret: Option<String>,
+}
+
+impl RaftOptions {
+    pub fn new(id: NodeId) -> RaftOptions {
+        RaftOptions {
+            id,
+            members: BTreeMap::new(),
+            heartbeat_interval: Duration::from_millis(50),
+            election_timeout: Duration::from_millis(300),
+            proposal_timeout: Duration::from_secs(5),
+            snapshot_threshold: 10000,
+            secret: None,
+        }
+    }
+}
+
+/// The state of a node reported by `RaftNode::info`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RaftInfo {
+    pub id: NodeId,
+    pub role: Role,
+    pub term: u64,
+    /// the leader of the current term, if known
+    pub leader: Option<NodeId>,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    pub last_index: u64,
+    /// entries up to this index are compacted into the engine
+    pub snapshot_index: u64,
+    pub members: BTreeMap<NodeId, Member>,
+}
+
+//leader记录的每个peer的复制进度
+struct Peer {
+    next: u64,
+    matched: u64,
+    //已经请求过投票的任期
+    vote_term: u64,
+    heartbeat_at: Instant,
+    //发送失败后等到该时间再重试
+    retry_at: Instant,
+    //收到回复的最新一轮读确认
+    ack_seq: u64,
+    //正在发送的快照
+    snapshot: Option<Sending>,
+}
+
+impl Peer {
+    fn new(next: u64) -> Peer {
+        let now = Instant::now();
+        Peer { next, matched: 0, vote_term: 0, heartbeat_at: now, retry_at: now, ack_seq: 0, snapshot: None }
+    }
+}
+
+//leader发送给peer的快照的进度
+struct Sending {
+    index: u64,
+    term: u64,
+    members: BTreeMap<NodeId, Member>,
+    //下一块的序号
+    chunk: u64,
+    //已经发送的最后一个key
+    after: Option<String>,
+}
+
+//发送出去的快照块,收到回复后更新发送进度
+struct SentChunk {
+    chunk: u64,
+    last: Option<String>,
+}
+
+struct State<E: KVEngine> {
+    //状态机,只在持有锁时修改
+    engine: E,
+    role: Role,
+    leader: Option<NodeId>,
+    log: RaftLog,
+    //日志中最新的成员配置,追加后立即生效
+    members: BTreeMap<NodeId, Member>,
+    commit: u64,
+    applied: u64,
+    election_deadline: Instant,
+    heard_leader_at: Option<Instant>,
+    votes: HashSet<NodeId>,
+    peers: HashMap<NodeId, Peer>,
+    //leader上等待提交的写入: 日志位置 -> (任期, 应用结果)
+    pending: HashMap<u64, (u64, Sender<Result<()>>)>,
+    //每次线性一致读加一,peer回复之后确认leader身份
+    read_seq: u64,
+}
+
+struct Inner<E: KVEngine> {
+    id: NodeId,
+    options: RaftOptions,
+    transport: Arc<dyn Transport>,
+    state: Mutex<State<E>>,
+    changed: Condvar,
+    shut_down: Arc<AtomicBool>,
+}
+
+/// A member of a Raft cluster replicating the writes to its engine.
+pub struct RaftNode<E: KVEngine> {
+    inner: Arc<Inner<E>>,
+}
+
+impl<E: KVEngine> Clone for RaftNode<E> {
+    fn clone(&self) -> Self {
+        RaftNode { inner: self.inner.clone() }
+    }
+}
+
+impl<E: KVEngine> RaftNode<E> {
+    /// Opens the Raft log in `dir` and starts the node with `engine` as its state machine.
+    ///
+    /// After a restart the entries after the latest compaction are applied to the
+    /// engine again once the leader reports them committed. Messages for the node
+    /// have to be passed to `handle`, e.g. with `serve` or `MemNetwork::register`.
+    pub fn open(engine: E, dir: impl AsRef<Path>, options: RaftOptions, transport: Arc<dyn Transport>) -> Result<RaftNode<E>> {
+        let mut log = RaftLog::open(dir.as_ref())?;
+        if log.last_index() == 0 && log.members_at(0).is_empty() && !options.members.is_empty() {
+            log.install(0, 0, options.members.clone())?;
+        }
+        let members = log.members_at(log.last_index());
+        let applied = log.snapshot_index();
+        let state = State {
+            engine,
+            role: Role::Follower,
+            leader: None,
+            log,
+            members,
+            commit: applied,
+            applied,
+            election_deadline: Instant::now() + random_timeout(options.election_timeout),
+            heard_leader_at: None,
+            votes: HashSet::new(),
+            peers: HashMap::new(),
+            pending: HashMap::new(),
+            read_seq: 0,
+        };
+        let node = RaftNode {
+            inner: Arc::new(Inner {
+                id: options.id,
+                options,
+                transport,
+                state: Mutex::new(state),
+                changed: Condvar::new(),
+                shut_down: Arc::new(AtomicBool::new(false)),
+            }),
+        };
+        {
+            let mut st = node.lock();
+            //上次运行时已经落盘但没有安装完成的快照
+            node.install_staged(&mut st)?;
+            node.sync_peers(&mut st);
+        }
+        let ticker = node.clone();
+        thread::Builder::new().name("raft-ticker".to_string()).spawn(move || ticker.run_ticker())?;
+        info!("Raft node {} started at index {}", node.inner.id, applied);
+        Ok(node)
+    }
+
+    /// Serves the messages of other nodes over TCP on `addr` until `shut_down`.
+    pub fn serve(&self, addr: SocketAddr) -> Result<SocketAddr> {
+        let node = self.clone();
+        let secret = self.inner.options.secret.clone();
+        transport::serve_tcp(addr, self.inner.shut_down.clone(), secret, move |msg| node.handle(msg))
+    }
+
+    /// Stops the node, it no longer answers messages or elects itself.
+    pub fn shut_down(&self) {
+        self.inner.shut_down.store(true, Ordering::SeqCst);
+        self.inner.changed.notify_all();
+    }
+
+    pub fn info(&self) -> RaftInfo {
+        let st = self.lock();
+        RaftInfo {
+            id: self.inner.id,
+            role: st.role,
+            term: st.log.term(),
+            leader: st.leader,
+            commit_index: st.commit,
+            applied_index: st.applied,
+            last_index: st.log.last_index(),
+            snapshot_index: st.log.snapshot_index(),
+            members: st.members.clone(),
+        }
+    }
+
+    /// Adds a node to the cluster, it must be started without members.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotLeader` on followers and fails while another
+    /// membership change is not committed yet.
+    pub fn add_node(&self, id: NodeId, member: Member) -> Result<()> {
+        self.propose_with(|st| {
+            let mut members = st.members.clone();
+            members.insert(id, member);
+            Ok(EntryData::Config { members })
+        })
+    }
+
+    /// Removes a node from the cluster, the leader steps down once it removed itself.
+    pub fn remove_node(&self, id: NodeId) -> Result<()> {
+        self.propose_with(|st| {
+            let mut members = st.members.clone();
+            if members.remove(&id).is_none() {
+                return Err(KvsError::StringError(format!("node {} is not a member", id)));
+            }
+            if members.is_empty() {
+                return Err(KvsError::StringError("cannot remove the last member".to_string()));
+            }
+            Ok(EntryData::Config { members })
+        })
+    }
+
+    /// Changes the membership to `members` one node at a time.
+    ///
+    /// New and changed members are added first so the cluster keeps its majority,
+    /// the leader removes itself last.
+    pub fn set_members(&self, members: &BTreeMap<NodeId, Member>) -> Result<()> {
+        let current = self.info().members;
+        for (&id, member) in members {
+            if current.get(&id) != Some(member) {
+                self.add_node(id, member.clone())?;
+            }
+        }
+        let mut removed: Vec<NodeId> = current.keys().copied().filter(|id| !members.contains_key(id)).collect();
+        removed.sort_by_key(|&id| id == self.inner.id);
+        for id in removed {
+            self.remove_node(id)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a message of another node and returns the reply.
+    pub fn handle(&self, msg: Message) -> Result<Message> {
+        if self.inner.shut_down.load(Ordering::SeqCst) {
+            return Err(KvsError::StringError(format!("node {} is shut down", self.inner.id)));
+        }
+        let mut st = self.lock();
+        let reply = match msg {
+            Message::Vote { term, candidate, last_index, last_term } => self.on_vote(&mut st, term, candidate, last_index, last_term),
+            Message::Append { term, leader, prev_index, prev_term, entries, commit } => {
+                self.on_append(&mut st, term, leader, prev_index, prev_term, entries, commit)
+            }
+            Message::Snapshot { term, leader, index, snapshot_term, members, chunk, data, done } => {
+                let staged = Staged { index, term: snapshot_term, members };
+                self.on_snapshot(&mut st, term, leader, staged, chunk, data, done)
+            }
+            msg => Err(KvsError::StringError(format!("unexpected raft message {:?}", msg))),
+        };
+        self.inner.changed.notify_all();
+        reply
+    }
+
+    fn engine(&self) -> E {
+        self.lock().engine.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<E>> {
+        self.inner.state.lock().unwrap()
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.inner.shut_down.load(Ordering::SeqCst)
+    }
+
+    //追加一条日志并等待应用到本地引擎
+    fn propose_with(&self, f: impl FnOnce(&State<E>) -> Result<EntryData>) -> Result<()> {
+        let rx: Receiver<Result<()>> = {
+            let mut st = self.lock();
+            if st.role != Role::Leader {
+                return Err(self.not_leader(&st));
+            }
+            let data = f(&st)?;
+            if let EntryData::Config { .. } = data {
+                //一次只允许一个成员变更,且新leader需要先提交本任期的日志
+                if st.log.config_index() > st.commit || st.log.term_at(st.commit) != Some(st.log.term()) {
+                    return Err(KvsError::StringError("a membership change is in progress, retry later".to_string()));
+                }
+            }
+            let (tx, rx) = channel::bounded(1);
+            let index = st.log.last_index() + 1;
+            let term = st.log.term();
+            st.pending.insert(index, (term, tx));
+            if let Err(e) = self.append_local(&mut st, data) {
+                st.pending.remove(&index);
+                return Err(e);
+            }
+            rx
+        };
+        match rx.recv_timeout(self.inner.options.proposal_timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(KvsError::StringError("raft write timed out, it may still be applied".to_string())),
+            Err(RecvTimeoutError::Disconnected) => Err(self.not_leader(&self.lock())),
+        }
+    }
+
+    //确认自己仍是leader且本地已应用到确认时的提交位置,之后从返回的引擎读取是线性一致的
+    fn read_barrier(&self) -> Result<E> {
+        let deadline = Instant::now() + self.inner.options.proposal_timeout;
+        let mut st = self.lock();
+        let term = st.log.term();
+        loop {
+            if st.role != Role::Leader || st.log.term() != term {
+                return Err(self.not_leader(&st));
+            }
+            //新leader提交本任期的第一条日志之前不知道最新的提交位置
+            if st.log.term_at(st.commit) == Some(term) {
+                break;
+            }
+            st = self.wait(st, deadline)?;
+        }
+        let index = st.commit;
+        st.read_seq += 1;
+        let seq = st.read_seq;
+        self.inner.changed.notify_all();
+        loop {
+            if st.role != Role::Leader || st.log.term() != term {
+                return Err(self.not_leader(&st));
+            }
+            let confirmed = self.has_quorum(&st, |id| id == self.inner.id || st.peers.get(&id).is_some_and(|p| p.ack_seq >= seq));
+            if confirmed && st.applied >= index {
+                return Ok(st.engine.clone());
+            }
+            st = self.wait(st, deadline)?;
+        }
+    }
+
+    fn wait<'a>(&self, st: MutexGuard<'a, State<E>>, deadline: Instant) -> Result<MutexGuard<'a, State<E>>> {
+        let now = Instant::now();
+        if now >= deadline || self.is_shut_down() {
+            return Err(KvsError::StringError("raft read timed out waiting for a majority".to_string()));
+        }
+        Ok(self.inner.changed.wait_timeout(st, deadline - now).unwrap().0)
+    }
+
+    fn not_leader(&self, st: &State<E>) -> KvsError {
+        let leader = match st.leader.filter(|&id| id != self.inner.id) {
+            Some(id) => match st.members.get(&id) {
+                Some(member) if !member.client_addr.is_empty() => member.client_addr.clone(),
+                _ => format!("node {}", id),
+            },
+            None => "unknown".to_string(),
+        };
+        KvsError::NotLeader(leader)
+    }
+
+    fn has_quorum(&self, st: &State<E>, pred: impl Fn(NodeId) -> bool) -> bool {
+        let count = st.members.keys().filter(|&&id| pred(id)).count();
+        count > st.members.len() / 2
+    }
+
+    //为新成员启动复制线程,线程发现自己被移除后退出
+    fn sync_peers(&self, st: &mut State<E>) {
+        let next = st.log.last_index() + 1;
+        let ids: Vec<NodeId> = st.members.keys().copied().filter(|&id| id != self.inner.id).collect();
+        for id in ids {
+            if st.peers.contains_key(&id) {
+                continue;
+            }
+            st.peers.insert(id, Peer::new(next));
+            let node = self.clone();
+            let res = thread::Builder::new().name(format!("raft-peer-{}", id)).spawn(move || node.run_peer(id));
+            if let Err(e) = res {
+                error!("Failed to start the replication thread of node {}: {}", id, e);
+                st.peers.remove(&id);
+            }
+        }
+    }
+
+    fn run_ticker(&self) {
+        while !self.is_shut_down() {
+            thread::sleep(TICK);
+            let mut st = self.lock();
+            if st.role != Role::Leader && st.members.contains_key(&self.inner.id) && Instant::now() >= st.election_deadline {
+                if let Err(e) = self.campaign(&mut st) {
+                    error!("Raft election failed: {}", e);
+                }
+                self.inner.changed.notify_all();
+            }
+        }
+        debug!("Raft node {} stopped", self.inner.id);
+    }
+
+    fn reset_deadline(&self, st: &mut State<E>) {
+        st.election_deadline = Instant::now() + random_timeout(self.inner.options.election_timeout);
+    }
+
+    fn campaign(&self, st: &mut State<E>) -> Result<()> {
+        let term = st.log.term() + 1;
+        st.log.set_term(term, Some(self.inner.id))?;
+        st.role = Role::Candidate;
+        st.leader = None;
+        st.votes = HashSet::from([self.inner.id]);
+        self.reset_deadline(st);
+        debug!("Node {} starts an election for term {}", self.inner.id, term);
+        if self.has_quorum(st, |id| st.votes.contains(&id)) {
+            self.become_leader(st)?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&self, st: &mut State<E>) -> Result<()> {
+        info!("Node {} is the leader of term {}", self.inner.id, st.log.term());
+        st.role = Role::Leader;
+        st.leader = Some(self.inner.id);
+        let next = st.log.last_index() + 1;
+        let now = Instant::now();
+        for peer in st.peers.values_mut() {
+            *peer = Peer { vote_term: peer.vote_term, ack_seq: peer.ack_seq, ..Peer::new(next) };
+            peer.heartbeat_at = now;
+        }
+        self.append_local(st, EntryData::Noop)?;
+        Ok(())
+    }
+
+    fn step_down(&self, st: &mut State<E>, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > st.log.term() {
+            st.log.set_term(term, None)?;
+        }
+        if st.role != Role::Follower {
+            debug!("Node {} becomes a follower in term {}", self.inner.id, term);
+        }
+        st.role = Role::Follower;
+        st.leader = leader;
+        st.votes.clear();
+        Ok(())
+    }
+
+    fn append_local(&self, st: &mut State<E>, data: EntryData) -> Result<u64> {
+        let index = st.log.last_index() + 1;
+        let is_config = matches!(data, EntryData::Config { .. });
+        st.log.append(&[Entry { index, term: st.log.term(), data }])?;
+        if is_config {
+            st.members = st.log.members_at(index);
+            self.sync_peers(st);
+        }
+        self.advance_commit(st)?;
+        self.inner.changed.notify_all();
+        Ok(index)
+    }
+
+    //多数节点复制了当前任期的日志后提交
+    fn advance_commit(&self, st: &mut State<E>) -> Result<()> {
+        let mut matched: Vec<u64> = st
+            .members
+            .keys()
+            .map(|&id| if id == self.inner.id { st.log.last_index() } else { st.peers.get(&id).map_or(0, |p| p.matched) })
+            .collect();
+        if matched.is_empty() {
+            return Ok(());
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[matched.len() / 2];
+        if index > st.commit && st.log.term_at(index) == Some(st.log.term()) {
+            st.commit = index;
+            self.apply(st)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, st: &mut State<E>) -> Result<()> {
+        let engine = st.engine.clone();
+        while st.applied < st.commit {
+            let index = st.applied + 1;
+            let Some(entry) = st.log.entry(index).cloned() else {
+                return Err(KvsError::StringError(format!("raft entry {} is missing", index)));
+            };
+            let res = match entry.data {
+                EntryData::Set { key, value, ttl } => engine.set(key, value, ttl),
+                EntryData::Remove { key } => engine.remove(key),
+                EntryData::Noop | EntryData::Config { .. } => Ok(()),
+            };
+            if let Err(e) = &res
+                && !matches!(e, KvsError::KeyNotFound)
+            {
+                error!("Failed to apply raft entry {}: {}", index, e);
+            }
+            st.applied = index;
+            if let Some((term, tx)) = st.pending.remove(&index) {
+                let res = if term == entry.term { res } else { Err(self.not_leader(st)) };
+                let _ = tx.send(res);
+            }
+        }
+        //移除自身的成员变更提交后leader退位
+        if st.role == Role::Leader && !st.members.contains_key(&self.inner.id) && st.log.config_index() <= st.commit {
+            info!("Node {} was removed from the cluster, stepping down", self.inner.id);
+            let term = st.log.term();
+            self.step_down(st, term, None)?;
+        }
+        if st.applied - st.log.snapshot_index() >= self.inner.options.snapshot_threshold {
+            //正在发送的快照之后的日志还要发给peer
+            let upto = st.peers.values().filter_map(|p| p.snapshot.as_ref().map(|s| s.index)).fold(st.applied, u64::min);
+            if upto > st.log.snapshot_index() {
+                //日志删除之前引擎中的写入必须落盘
+                st.engine.flush()?;
+                st.log.compact(upto)?;
+                debug!("Raft log of node {} compacted up to {}", self.inner.id, upto);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_vote(&self, st: &mut State<E>, term: u64, candidate: NodeId, last_index: u64, last_term: u64) -> Result<Message> {
+        if term > st.log.term() {
+            //最近收到过leader的消息时忽略投票请求,避免被移除的节点扰乱集群
+            let heard = st.heard_leader_at.is_some_and(|t| t.elapsed() < self.inner.options.election_timeout);
+            if st.role == Role::Leader || heard {
+                return Ok(Message::VoteReply { term: st.log.term(), granted: false });
+            }
+            self.step_down(st, term, None)?;
+        }
+        let up_to_date = (last_term, last_index) >= (st.log.last_term(), st.log.last_index());
+        let granted = term == st.log.term() && st.log.voted_for().is_none_or(|v| v == candidate) && up_to_date;
+        if granted {
+            st.log.set_term(term, Some(candidate))?;
+            self.reset_deadline(st);
+        }
+        Ok(Message::VoteReply { term: st.log.term(), granted })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn on_append(
+        &self,
+        st: &mut State<E>,
+        term: u64,
+        leader: NodeId,
+        mut prev_index: u64,
+        mut prev_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<Message> {
+        if term < st.log.term() {
+            return Ok(Message::AppendReply { term: st.log.term(), success: false, last_index: st.log.last_index() });
+        }
+        self.follow(st, term, leader)?;
+        //快照之前的日志都已提交,一定与leader一致
+        let snapshot_index = st.log.snapshot_index();
+        if prev_index < snapshot_index {
+            entries.retain(|e| e.index > snapshot_index);
+            prev_index = snapshot_index;
+            prev_term = st.log.snapshot_term();
+        }
+        if st.log.term_at(prev_index) != Some(prev_term) {
+            let hint = st.log.last_index().min(prev_index.saturating_sub(1));
+            return Ok(Message::AppendReply { term, success: false, last_index: hint });
+        }
+        let last_new = prev_index + entries.len() as u64;
+        let mut start = entries.len();
+        let mut truncated = false;
+        for (i, entry) in entries.iter().enumerate() {
+            match st.log.term_at(entry.index) {
+                Some(t) if t == entry.term => continue,
+                Some(_) => {
+                    st.log.truncate(entry.index)?;
+                    truncated = true;
+                }
+                None => (),
+            }
+            start = i;
+            break;
+        }
+        let new = &entries[start..];
+        if !new.is_empty() {
+            st.log.append(new)?;
+        }
+        if truncated || new.iter().any(|e| matches!(e.data, EntryData::Config { .. })) {
+            st.members = st.log.members_at(st.log.last_index());
+            self.sync_peers(st);
+        }
+        let commit = commit.min(last_new);
+        if commit > st.commit {
+            st.commit = commit;
+            self.apply(st)?;
+        }
+        Ok(Message::AppendReply { term, success: true, last_index: last_new })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn on_snapshot(
+        &self,
+        st: &mut State<E>,
+        term: u64,
+        leader: NodeId,
+        staged: Staged,
+        chunk: u64,
+        data: Vec<(String, String, u32)>,
+        done: bool,
+    ) -> Result<Message> {
+        if term < st.log.term() {
+            return Ok(Message::SnapshotReply { term: st.log.term(), next_chunk: Some(0) });
+        }
+        self.follow(st, term, leader)?;
+        if staged.index <= st.applied {
+            return Ok(Message::SnapshotReply { term, next_chunk: None });
+        }
+        //快照先写入单独的文件,全部接收并落盘后才修改引擎
+        if !st.log.stage(staged, chunk, &data)? {
+            return Ok(Message::SnapshotReply { term, next_chunk: Some(0) });
+        }
+        if !done {
+            return Ok(Message::SnapshotReply { term, next_chunk: Some(chunk + 1) });
+        }
+        st.log.seal()?;
+        self.install_staged(st)?;
+        Ok(Message::SnapshotReply { term, next_chunk: None })
+    }
+
+    //把落盘的快照安装到引擎: 先写入快照中的key,再删除快照中没有的key,引擎落盘后才替换日志,
+    //中途崩溃时重启后重新安装
+    fn install_staged(&self, st: &mut State<E>) -> Result<()> {
+        let Some(staged) = st.log.staged().cloned() else {
+            return Ok(());
+        };
+        let engine = st.engine.clone();
+        let mut keys = HashSet::new();
+        for entry in st.log.staged_entries()? {
+            let (key, value, ttl) = entry?;
+            engine.set(key.clone(), value, ttl)?;
+            keys.insert(key);
+        }
+        info!("Node {} installs a snapshot at index {} with {} keys", self.inner.id, staged.index, keys.len());
+        for key in engine.scan_keys(String::new(), KEY_MAX.to_string(), 0, usize::MAX)? {
+            if keys.contains(&key) {
+                continue;
+            }
+            match engine.remove(key) {
+                Ok(()) | Err(KvsError::KeyNotFound) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        engine.flush()?;
+        st.log.install(staged.index, staged.term, staged.members)?;
+        st.members = st.log.members_at(staged.index);
+        st.commit = st.commit.max(staged.index);
+        st.applied = staged.index;
+        self.sync_peers(st);
+        Ok(())
+    }
+
+    //收到当前任期leader的消息
+    fn follow(&self, st: &mut State<E>, term: u64, leader: NodeId) -> Result<()> {
+        if term > st.log.term() || st.role != Role::Follower {
+            self.step_down(st, term, Some(leader))?;
+        }
+        st.leader = Some(leader);
+        st.heard_leader_at = Some(Instant::now());
+        self.reset_deadline(st);
+        Ok(())
+    }
+
+    //每个peer一个线程: leader发送日志、快照和心跳,candidate请求投票
+    fn run_peer(&self, id: NodeId) {
+        let heartbeat = self.inner.options.heartbeat_interval;
+        loop {
+            let mut st = self.lock();
+            let (mut request, term, seq, addr) = loop {
+                if self.is_shut_down() {
+                    return;
+                }
+                let Some(member) = st.members.get(&id).cloned() else {
+                    st.peers.remove(&id);
+                    return;
+                };
+                match self.next_request(&mut st, id) {
+                    Ok(Some(request)) => break (request, st.log.term(), st.read_seq, member.addr),
+                    Ok(None) => (),
+                    Err(e) => error!("Failed to prepare a raft message for node {}: {}", id, e),
+                }
+                st = self.inner.changed.wait_timeout(st, heartbeat).unwrap().0;
+            };
+            let mut after = None;
+            if let Some(peer) = st.peers.get_mut(&id) {
+                peer.heartbeat_at = Instant::now() + heartbeat;
+                after = peer.snapshot.as_ref().and_then(|s| s.after.clone());
+            }
+            let engine = st.engine.clone();
+            drop(st);
+            //快照的键值在释放锁之后读取
+            let mut sent = None;
+            if let Message::Snapshot { chunk, data, done, .. } = &mut request {
+                match read_chunk(&engine, after) {
+                    Ok((chunk_data, last, last_chunk)) => {
+                        *data = chunk_data;
+                        *done = last_chunk;
+                        sent = Some(SentChunk { chunk: *chunk, last });
+                    }
+                    Err(e) => {
+                        error!("Failed to read a snapshot for node {}: {}", id, e);
+                        thread::sleep(heartbeat);
+                        continue;
+                    }
+                }
+            }
+            let reply = self.inner.transport.send(id, &addr, request);
+            let mut st = self.lock();
+            let res = match reply {
+                Ok(reply) => self.on_reply(&mut st, id, term, seq, sent, reply),
+                Err(e) => {
+                    debug!("Node {} is unreachable: {}", id, e);
+                    if let Some(peer) = st.peers.get_mut(&id) {
+                        peer.retry_at = Instant::now() + heartbeat;
+                    }
+                    Ok(())
+                }
+            };
+            if let Err(e) = res {
+                error!("Failed to handle the raft reply of node {}: {}", id, e);
+            }
+            self.inner.changed.notify_all();
+        }
+    }
+
+    fn next_request(&self, st: &mut State<E>, id: NodeId) -> Result<Option<Message>> {
+        let now = Instant::now();
+        let term = st.log.term();
+        let read_seq = st.read_seq;
+        let Some(peer) = st.peers.get_mut(&id) else { return Ok(None) };
+        if now < peer.retry_at {
+            return Ok(None);
+        }
+        match st.role {
+            Role::Leader => {
+                let due = now >= peer.heartbeat_at || peer.next <= st.log.last_index() || peer.ack_seq < read_seq || peer.snapshot.is_some();
+                if !due {
+                    return Ok(None);
+                }
+                if peer.snapshot.is_some() || peer.next <= st.log.snapshot_index() {
+                    //需要的日志已经压缩,分块发送引擎中的全部键值,快照位置是开始发送时已应用的日志
+                    let index = st.applied;
+                    let sending = peer.snapshot.get_or_insert_with(|| Sending {
+                        index,
+                        term: st.log.term_at(index).unwrap_or(term),
+                        members: st.log.members_at(index),
+                        chunk: 0,
+                        after: None,
+                    });
+                    return Ok(Some(Message::Snapshot {
+                        term,
+                        leader: self.inner.id,
+                        index: sending.index,
+                        snapshot_term: sending.term,
+                        members: sending.members.clone(),
+                        chunk: sending.chunk,
+                        data: Vec::new(),
+                        done: false,
+                    }));
+                }
+                let prev_index = peer.next - 1;
+                Ok(Some(Message::Append {
+                    term,
+                    leader: self.inner.id,
+                    prev_index,
+                    prev_term: st.log.term_at(prev_index).unwrap_or(0),
+                    entries: st.log.entries_from(peer.next, MAX_BATCH),
+                    commit: st.commit,
+                }))
+            }
+            Role::Candidate if peer.vote_term != term => {
+                peer.vote_term = term;
+                Ok(Some(Message::Vote { term, candidate: self.inner.id, last_index: st.log.last_index(), last_term: st.log.last_term() }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn on_reply(&self, st: &mut State<E>, id: NodeId, term: u64, seq: u64, sent: Option<SentChunk>, reply: Message) -> Result<()> {
+        let reply_term = match reply {
+            Message::VoteReply { term, .. } | Message::AppendReply { term, .. } | Message::SnapshotReply { term, .. } => term,
+            reply => return Err(KvsError::StringError(format!("unexpected raft reply {:?}", reply))),
+        };
+        if reply_term > st.log.term() {
+            self.step_down(st, reply_term, None)?;
+            self.reset_deadline(st);
+            return Ok(());
+        }
+        if st.log.term() != term {
+            return Ok(());
+        }
+        match (st.role, reply) {
+            (Role::Candidate, Message::VoteReply { granted: true, .. }) => {
+                st.votes.insert(id);
+                if self.has_quorum(st, |id| st.votes.contains(&id)) {
+                    self.become_leader(st)?;
+                }
+            }
+            (Role::Leader, Message::AppendReply { success, last_index, .. }) => {
+                let Some(peer) = st.peers.get_mut(&id) else { return Ok(()) };
+                peer.ack_seq = peer.ack_seq.max(seq);
+                if success {
+                    peer.matched = peer.matched.max(last_index);
+                    peer.next = peer.matched + 1;
+                    self.advance_commit(st)?;
+                } else {
+                    peer.next = (last_index + 1).min(peer.next - 1).max(1);
+                }
+            }
+            (Role::Leader, Message::SnapshotReply { next_chunk, .. }) => {
+                let Some(peer) = st.peers.get_mut(&id) else { return Ok(()) };
+                peer.ack_seq = peer.ack_seq.max(seq);
+                let (Some(sending), Some(sent)) = (peer.snapshot.as_mut(), sent) else {
+                    return Ok(());
+                };
+                match next_chunk {
+                    None => {
+                        peer.matched = peer.matched.max(sending.index);
+                        peer.next = peer.matched + 1;
+                        peer.snapshot = None;
+                    }
+                    Some(next) if sending.chunk == sent.chunk && next == sent.chunk + 1 => {
+                        sending.chunk = next;
+                        sending.after = sent.last.or(sending.after.take());
+                    }
+                    //follower没有收到之前的块,从头重新发送
+                    Some(_) => peer.snapshot = None,
+                }
+                self.advance_commit(st)?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl<E: KVEngine> KVEngine for RaftNode<E> {
+    fn set(&self, key: String, value: String, ttl: u32) -> Result<()> {
+        self.propose_with(|_| Ok(EntryData::Set { key, value, ttl }))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.read_barrier()?.get(key)
+    }
+
+    fn scan(&self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        self.read_barrier()?.scan(start, end)
+    }
+
+    fn scan_keys(&self, start: String, end: String, skip: usize, limit: usize) -> Result<Vec<String>> {
+        self.read_barrier()?.scan_keys(start, end, skip, limit)
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<u32>> {
+        self.read_barrier()?.ttl(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.propose_with(|_| Ok(EntryData::Remove { key }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine().flush()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.engine().stats()
+    }
+
+    fn key_count(&self) -> Result<u64> {
+        self.engine().key_count()
+    }
+
+    fn watch(&self, prefix: &str) -> crossbeam::channel::Receiver<ChangeEvent> {
+        self.engine().watch(prefix)
+    }
+
+    fn cdc(&self, after: u64) -> Result<Box<dyn CdcCursor>> {
+        self.engine().cdc(after)
+    }
+
+    fn cdc_seq(&self) -> Result<u64> {
+        self.engine().cdc_seq()
+    }
+
+    /// Backs up the local state machine, the Raft log is not part of the backup.
+    fn backup(&self, dest: &Path) -> Result<BackupManifest> {
+        self.engine().backup(dest)
+    }
+}
+
+//快照的一块: 键值和剩余TTL、读到的最后一个key以及是否已经读完
+type Chunk = (Vec<SnapshotEntry>, Option<String>, bool);
+
+//读取after之后的一块快照
+fn read_chunk<E: KVEngine>(engine: &E, after: Option<String>) -> Result<Chunk> {
+    //after之后最小的key
+    let start = after.map_or(String::new(), |k| format!("{}\0", k));
+    let keys = engine.scan_keys(start, KEY_MAX.to_string(), 0, SNAPSHOT_CHUNK)?;
+    let last = keys.last().cloned();
+    let done = keys.len() < SNAPSHOT_CHUNK;
+    let mut data = Vec::with_capacity(keys.len());
+    for key in keys {
+        //读取期间过期或者删除的key不再发送
+        let Some(ttl) = engine.ttl(key.clone())? else { continue };
+        if let Some(value) = engine.get(key.clone())? {
+            data.push((key, value, ttl));
+        }
+    }
+    Ok((data, last, done))
+}
+
+fn random_timeout(base: Duration) -> Duration {
+    base + base.mul_f64(rand::thread_rng().gen_range(0.0, 1.0))
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::{KvsError, Result};
+use super::{Entry, EntryData, Member, NodeId};
+
+const STATE_FILE: &str = "state.json";
+const LOG_FILE: &str = "log.jsonl";
+const SNAPSHOT_FILE: &str = "snapshot.jsonl";
+
+/// A key of a snapshot: key, value and the remaining TTL in seconds, 0 if it never expires.
+pub(super) type SnapshotEntry = (String, String, u32);
+
+/// State that has to survive a restart besides the log entries.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<NodeId>,
+    //状态机(存储引擎)至少包含到snapshot_index为止的全部日志
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_members: BTreeMap<NodeId, Member>,
+    //已经完整接收并落盘、但还没有安装到状态机的快照
+    #[serde(default)]
+    staged: Option<Staged>,
+}
+
+/// The position of a snapshot received from the leader.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(super) struct Staged {
+    pub index: u64,
+    pub term: u64,
+    pub members: BTreeMap<NodeId, Member>,
+}
+
+//正在接收的快照,写入临时文件
+struct Staging {
+    staged: Staged,
+    //下一个期望的分块序号
+    next_chunk: u64,
+    writer: BufWriter<File>,
+}
+
+/// The persistent Raft log of a node: term, vote and the entries after the latest snapshot.
+///
+/// The hard state is replaced atomically with a rename, entries are appended to a
+/// JSON lines file that is rewritten when it is truncated or compacted. A snapshot
+/// from the leader is written to its own file chunk by chunk and recorded in the
+/// hard state once complete, so it can be installed again after a crash.
+pub(super) struct RaftLog {
+    dir: PathBuf,
+    state: HardState,
+    entries: Vec<Entry>,
+    writer: BufWriter<File>,
+    staging: Option<Staging>,
+}
+
+impl RaftLog {
+    pub(super) fn open(dir: &Path) -> Result<RaftLog> {
+        fs::create_dir_all(dir)?;
+        let state: HardState = match fs::read(dir.join(STATE_FILE)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries: Vec<Entry> = Vec::new();
+        if let Ok(file) = File::open(dir.join(LOG_FILE)) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                //崩溃时最后一行可能不完整,之前的日志都已经落盘
+                let Ok(entry) = serde_json::from_str::<Entry>(&line) else { break };
+                //压缩时先保存快照位置再重写日志,忽略已经包含在快照中的日志
+                if entry.index <= state.snapshot_index {
+                    continue;
+                }
+                if entry.index != state.snapshot_index + entries.len() as u64 + 1 {
+                    break;
+                }
+                entries.push(entry);
+            }
+        }
+        let writer = rewrite(dir, &entries)?;
+        Ok(RaftLog { dir: dir.to_path_buf(), state, entries, writer, staging: None })
+    }
+
+    pub(super) fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub(super) fn voted_for(&self) -> Option<NodeId> {
+        self.state.voted_for
+    }
+
+    pub(super) fn set_term(&mut self, term: u64, voted_for: Option<NodeId>) -> Result<()> {
+        self.state.term = term;
+        self.state.voted_for = voted_for;
+        self.save_state()
+    }
+
+    pub(super) fn snapshot_index(&self) -> u64 {
+        self.state.snapshot_index
+    }
+
+    pub(super) fn snapshot_term(&self) -> u64 {
+        self.state.snapshot_term
+    }
+
+    pub(super) fn last_index(&self) -> u64 {
+        self.state.snapshot_index + self.entries.len() as u64
+    }
+
+    pub(super) fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.state.snapshot_term, |e| e.term)
+    }
+
+    /// Term of the entry at `index`, `None` if it is compacted or does not exist yet.
+    pub(super) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.state.snapshot_index {
+            return Some(self.state.snapshot_term);
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    pub(super) fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.state.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.state.snapshot_index - 1) as usize)
+    }
+
+    /// At most `max` entries starting at `from`, which must be after the snapshot.
+    pub(super) fn entries_from(&self, from: u64, max: usize) -> Vec<Entry> {
+        let start = (from - self.state.snapshot_index - 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    pub(super) fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.writer, entry)?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    /// Removes the entries from `index` on, they conflict with the leader's log.
+    pub(super) fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate((index - self.state.snapshot_index - 1) as usize);
+        self.writer = rewrite(&self.dir, &self.entries)?;
+        Ok(())
+    }
+
+    /// Drops the entries up to `index`, the state machine contains them.
+    pub(super) fn compact(&mut self, index: u64) -> Result<()> {
+        let term = self.term_at(index).unwrap_or(self.state.snapshot_term);
+        let members = self.members_at(index);
+        let drop = (index - self.state.snapshot_index) as usize;
+        self.state.snapshot_index = index;
+        self.state.snapshot_term = term;
+        self.state.snapshot_members = members;
+        self.save_state()?;
+        self.entries.drain(..drop);
+        self.writer = rewrite(&self.dir, &self.entries)?;
+        Ok(())
+    }
+
+    /// Replaces the whole log with a snapshot, the state machine contains it.
+    pub(super) fn install(&mut self, index: u64, term: u64, members: BTreeMap<NodeId, Member>) -> Result<()> {
+        self.state.snapshot_index = index;
+        self.state.snapshot_term = term;
+        self.state.snapshot_members = members;
+        self.state.staged = None;
+        self.save_state()?;
+        self.entries.clear();
+        self.writer = rewrite(&self.dir, &self.entries)?;
+        match fs::remove_file(self.dir.join(SNAPSHOT_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes a chunk of a snapshot from the leader, chunk 0 starts a new snapshot.
+    ///
+    /// Returns false if the chunk does not continue the snapshot being received.
+    pub(super) fn stage(&mut self, staged: Staged, chunk: u64, entries: &[SnapshotEntry]) -> Result<bool> {
+        if chunk == 0 {
+            let file = File::create(self.dir.join(format!("{}.tmp", SNAPSHOT_FILE)))?;
+            self.staging = Some(Staging { staged, next_chunk: 0, writer: BufWriter::new(file) });
+        } else if self.staging.as_ref().is_none_or(|s| s.staged != staged || s.next_chunk != chunk) {
+            return Ok(false);
+        }
+        let Some(staging) = self.staging.as_mut() else {
+            return Ok(false);
+        };
+        for entry in entries {
+            serde_json::to_writer(&mut staging.writer, entry)?;
+            staging.writer.write_all(b"\n")?;
+        }
+        staging.next_chunk += 1;
+        Ok(true)
+    }
+
+    /// Persists the snapshot received by `stage`, it is installed with `install` afterwards.
+    pub(super) fn seal(&mut self) -> Result<()> {
+        let Some(mut staging) = self.staging.take() else {
+            return Err(KvsError::StringError("no snapshot is being received".to_string()));
+        };
+        staging.writer.flush()?;
+        staging.writer.get_ref().sync_all()?;
+        fs::rename(self.dir.join(format!("{}.tmp", SNAPSHOT_FILE)), self.dir.join(SNAPSHOT_FILE))?;
+        self.state.staged = Some(staging.staged);
+        self.save_state()
+    }
+
+    /// The snapshot sealed but not yet installed.
+    pub(super) fn staged(&self) -> Option<&Staged> {
+        self.state.staged.as_ref()
+    }
+
+    /// Reads the keys of the staged snapshot.
+    pub(super) fn staged_entries(&self) -> Result<impl Iterator<Item = Result<SnapshotEntry>> + use<>> {
+        let file = File::open(self.dir.join(SNAPSHOT_FILE))?;
+        Ok(BufReader::new(file).lines().map(|line| Ok(serde_json::from_str(&line?)?)))
+    }
+
+    /// The membership in effect after the entry at `index`.
+    pub(super) fn members_at(&self, index: u64) -> BTreeMap<NodeId, Member> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| e.index <= index)
+            .find_map(|e| match &e.data {
+                EntryData::Config { members } => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.state.snapshot_members.clone())
+    }
+
+    /// Index of the latest membership change in the log, 0 if it is part of the snapshot.
+    pub(super) fn config_index(&self) -> u64 {
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| matches!(e.data, EntryData::Config { .. }))
+            .map_or(0, |e| e.index)
+    }
+
+    fn save_state(&self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &self.state)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(STATE_FILE))?;
+        Ok(())
+    }
+}
+
+//把日志写入临时文件后替换原文件,返回追加写入的writer
+fn rewrite(dir: &Path, entries: &[Entry]) -> Result<BufWriter<File>> {
+    let tmp = dir.join(format!("{}.tmp", LOG_FILE));
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp, dir.join(LOG_FILE))?;
+    let file = OpenOptions::new().append(true).open(dir.join(LOG_FILE))?;
+    Ok(BufWriter::new(file))
+}
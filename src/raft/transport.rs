@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::Duration;
+use log::{debug, error, info};
+use pbkdf2::hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::{KvsError, Result};
+use super::{Message, NodeId};
+
+//消息帧的最大长度,快照按块发送,一帧不会包含全部键值
+const MAX_FRAME: usize = 1 << 30;
+//监听线程检查关闭标志的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+//握手时双方各自生成的随机数长度
+const NONCE_LEN: usize = 32;
+//HMAC-SHA256的长度
+const TAG_LEN: usize = 32;
+//等待连接方完成握手的最长时间
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Delivers Raft messages between nodes.
+///
+/// `send` is a blocking request/response call, every peer is served by its own
+/// thread so a slow or dead peer only delays itself.
+pub trait Transport: Send + Sync + 'static {
+    /// Sends `msg` to node `to` listening at `addr` and returns its reply.
+    fn send(&self, to: NodeId, addr: &str, msg: Message) -> Result<Message>;
+}
+
+type Handler = Arc<dyn Fn(Message) -> Result<Message> + Send + Sync>;
+
+/// An in-process network for tests, nodes can be cut off and reconnected.
+#[derive(Clone, Default)]
+pub struct MemNetwork {
+    nodes: Arc<RwLock<HashMap<NodeId, Handler>>>,
+    isolated: Arc<RwLock<HashSet<NodeId>>>,
+}
+
+impl MemNetwork {
+    pub fn new() -> MemNetwork {
+        MemNetwork::default()
+    }
+
+    /// The transport node `id` sends its messages with.
+    pub fn transport(&self, id: NodeId) -> Arc<dyn Transport> {
+        Arc::new(MemTransport { id, network: self.clone() })
+    }
+
+    /// Delivers the messages sent to node `id` to `handler`, usually `RaftNode::handle`.
+    pub fn register<F>(&self, id: NodeId, handler: F)
+    where
+        F: Fn(Message) -> Result<Message> + Send + Sync + 'static,
+    {
+        self.nodes.write().unwrap().insert(id, Arc::new(handler));
+    }
+
+    /// Drops every message from and to node `id`.
+    pub fn isolate(&self, id: NodeId) {
+        self.isolated.write().unwrap().insert(id);
+    }
+
+    /// Reconnects a node cut off by `isolate`.
+    pub fn heal(&self, id: NodeId) {
+        self.isolated.write().unwrap().remove(&id);
+    }
+}
+
+struct MemTransport {
+    id: NodeId,
+    network: MemNetwork,
+}
+
+impl Transport for MemTransport {
+    fn send(&self, to: NodeId, _addr: &str, msg: Message) -> Result<Message> {
+        {
+            let isolated = self.network.isolated.read().unwrap();
+            if isolated.contains(&self.id) || isolated.contains(&to) {
+                return Err(KvsError::StringError(format!("node {} is unreachable", to)));
+            }
+        }
+        let handler = self.network.nodes.read().unwrap().get(&to).cloned();
+        match handler {
+            Some(handler) => handler(msg),
+            None => Err(KvsError::StringError(format!("node {} is unknown", to))),
+        }
+    }
+}
+
+/// Sends messages over TCP as `<len u32 BE><json>` frames, one cached connection per peer.
+///
+/// With a secret both ends of a new connection prove they know it before the
+/// first frame, see `RaftOptions::secret`.
+pub struct TcpTransport {
+    timeout: Duration,
+    secret: Option<String>,
+    conns: Mutex<HashMap<NodeId, TcpStream>>,
+}
+
+impl TcpTransport {
+    /// `timeout` bounds connecting to a peer and waiting for its reply.
+    pub fn new(timeout: Duration) -> TcpTransport {
+        TcpTransport { timeout, secret: None, conns: Mutex::new(HashMap::new()) }
+    }
+
+    /// Authenticates every connection with the secret the peers serve with.
+    pub fn with_secret(mut self, secret: impl Into<String>) -> TcpTransport {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    fn connect(&self, addr: &str) -> Result<TcpStream> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| KvsError::StringError(format!("cannot resolve {}", addr)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        if let Some(secret) = &self.secret {
+            authenticate_server(&mut stream, secret)?;
+        }
+        Ok(stream)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, to: NodeId, addr: &str, msg: Message) -> Result<Message> {
+        //每个peer只有一个发送线程,取出连接后不需要持有锁
+        let cached = self.conns.lock().unwrap().remove(&to);
+        let mut stream = match cached {
+            Some(stream) => stream,
+            None => self.connect(addr)?,
+        };
+        write_frame(&mut stream, &msg)?;
+        let reply = read_frame(&mut stream)?;
+        self.conns.lock().unwrap().insert(to, stream);
+        Ok(reply)
+    }
+}
+
+/// Serves Raft messages on `addr` until `shut_down` is set, returns the bound address.
+///
+/// With a `secret` a connection is closed unless the peer proves it knows it.
+pub(super) fn serve_tcp<F>(addr: SocketAddr, shut_down: Arc<AtomicBool>, secret: Option<String>, handler: F) -> Result<SocketAddr>
+where
+    F: Fn(Message) -> Result<Message> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let local = listener.local_addr()?;
+    let handler: Handler = Arc::new(handler);
+    let secret: Option<Arc<str>> = secret.map(Into::into);
+    info!("Raft listening on {}", local);
+    thread::Builder::new().name("raft-listener".to_string()).spawn(move || {
+        while !shut_down.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    let (handler, shut_down, secret) = (handler.clone(), shut_down.clone(), secret.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = serve_conn(stream, &handler, &shut_down, secret.as_deref()) {
+                            debug!("Raft connection from {} closed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => error!("Raft accept error: {}", e),
+            }
+        }
+    })?;
+    Ok(local)
+}
+
+fn serve_conn(stream: TcpStream, handler: &Handler, shut_down: &AtomicBool, secret: Option<&str>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    if let Some(secret) = secret {
+        authenticate_client(&mut reader, &mut writer, secret)?;
+    }
+    reader.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    loop {
+        //空闲时定期检查关闭标志,帧的其余部分阻塞读取
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len[..1]) {
+            Ok(()) => (),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if shut_down.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        reader.get_ref().set_read_timeout(None)?;
+        reader.read_exact(&mut len[1..])?;
+        let msg = read_body(&mut reader, u32::from_be_bytes(len) as usize)?;
+        reader.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+        let reply = handler(msg)?;
+        write_frame(&mut writer, &reply)?;
+        writer.flush()?;
+    }
+}
+
+// 双方用secret对两个随机数计算HMAC证明自己持有secret,secret本身不经过网络;
+// 标签区分两个方向,对方不能把收到的证明原样返回
+fn proof(secret: &str, side: &[u8], client: &[u8], server: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(side);
+    mac.update(client);
+    mac.update(server);
+    mac
+}
+
+fn unauthenticated() -> KvsError {
+    KvsError::StringError("raft peer failed to prove the shared secret".to_string())
+}
+
+// 连接方:发送随机数,验证对方的证明后发送自己的证明
+fn authenticate_server(stream: &mut TcpStream, secret: &str) -> Result<()> {
+    let client: [u8; NONCE_LEN] = rand::random();
+    stream.write_all(&client)?;
+    let mut reply = [0; NONCE_LEN + TAG_LEN];
+    stream.read_exact(&mut reply)?;
+    let (server, tag) = reply.split_at(NONCE_LEN);
+    proof(secret, b"server", &client, server).verify_slice(tag).map_err(|_| unauthenticated())?;
+    stream.write_all(&proof(secret, b"client", &client, server).finalize().into_bytes())?;
+    Ok(())
+}
+
+// 监听方:回复自己的随机数和证明,连接方的证明不对时关闭连接
+fn authenticate_client(reader: &mut impl Read, writer: &mut impl Write, secret: &str) -> Result<()> {
+    let mut client = [0; NONCE_LEN];
+    reader.read_exact(&mut client)?;
+    let server: [u8; NONCE_LEN] = rand::random();
+    writer.write_all(&server)?;
+    writer.write_all(&proof(secret, b"server", &client, &server).finalize().into_bytes())?;
+    writer.flush()?;
+    let mut tag = [0; TAG_LEN];
+    reader.read_exact(&mut tag)?;
+    proof(secret, b"client", &client, &server).verify_slice(&tag).map_err(|_| unauthenticated())
+}
+
+fn write_frame(writer: &mut impl Write, msg: &Message) -> Result<()> {
+    let body = serde_json::to_vec(msg)?;
+    writer.write_all(&(body.len() as u32).to_be_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<Message> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    read_body(reader, u32::from_be_bytes(len) as usize)
+}
+
+fn read_body(reader: &mut impl Read, len: usize) -> Result<Message> {
+    if len > MAX_FRAME {
+        return Err(KvsError::StringError(format!("raft frame of {} bytes is too large", len)));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(serde_json::from_slice(&body)?)
+}
@@ -1,15 +1,233 @@
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::{fs::{DirBuilderExt, FileTypeExt, PermissionsExt}, net::UnixListener};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::io::{self,BufReader, BufWriter, Write, Read};
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
-use log::{debug, error, info};
-use crate::{Cmd, KvsError, KVEngine,ThreadPool, Result};
+use std::sync::{Arc, RwLock, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use crate::{Acl, Category, Cmd, KvsError, KVEngine,ServerAddr,ServerInfo,ThreadPool, Result};
+use crate::cluster::{SlotRange,SlotState,SLOTS};
+use crate::connection::{Connection,DeadlineReader,Peer};
+use crate::metrics::{self,CommandMetrics};
+use crate::monitor::{self,MonitorHub};
+use crate::pubsub::{PubSub,PubSubFrame};
+use self::cluster::Cluster;
+use self::locks::KeyLocks;
+use self::parking::{Next,Parked,ParkingLot,Resume};
+use self::replication::Replication;
+use crate::slowlog::SlowLog;
+use crossbeam::channel::{Receiver,RecvTimeoutError};
+use rustls::ServerConfig;
 use std::cell::RefCell;
+use serde::{Deserialize, Serialize};
+
+mod cluster;
+mod http;
+mod locks;
+mod parking;
+mod replication;
+mod resp;
+
+pub use replication::ReplicationInfo;
+
+//空闲连接检查关闭标志的间隔
+const POLL_INTERVAL:Duration=Duration::from_millis(100);
+
+/// Connection limits, timeouts, slowlog settings and users of a `KvServer`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ServerOptions{
+    /// Maximum number of concurrent client connections, 0 means unlimited.
+    pub max_connections:usize,
+    /// Close a connection after it has been idle for this long.
+    pub idle_timeout:Option<Duration>,
+    /// Maximum time to receive the rest of a frame once it has started.
+    pub read_timeout:Option<Duration>,
+    /// Maximum time a single response write may block.
+    pub write_timeout:Option<Duration>,
+    /// Commands running at least this long are recorded in the slowlog.
+    pub slowlog_threshold:Option<Duration>,
+    /// Maximum number of entries kept in the slowlog.
+    pub slowlog_max_len:usize,
+    /// Users allowed to connect, clients have to `AUTH` unless it is empty.
+    pub acl:Acl,
+}
+
+impl Default for ServerOptions{
+    fn default()->Self{
+        ServerOptions{
+            max_connections:0,
+            idle_timeout:None,
+            read_timeout:None,
+            write_timeout:None,
+            slowlog_threshold:None,
+            slowlog_max_len:128,
+            acl:Acl::default(),
+        }
+    }
+}
+
+/// A snapshot of the connection counters of a `KvServer`.
+#[derive(Clone,Debug,Default,PartialEq,Eq,Serialize,Deserialize)]
+pub struct ServerStats{
+    pub active_connections:usize,
+    pub total_connections:u64,
+    pub rejected_connections:u64,
+    pub idle_timeouts:u64,
+    pub read_timeouts:u64,
+    pub write_timeouts:u64,
+}
+
+#[derive(Default)]
+struct Counters{
+    active_connections:AtomicUsize,
+    total_connections:AtomicU64,
+    rejected_connections:AtomicU64,
+    idle_timeouts:AtomicU64,
+    read_timeouts:AtomicU64,
+    write_timeouts:AtomicU64,
+    commands_processed:AtomicU64,
+    //线程池中等待处理的连接数,由accept循环定期刷新
+    pool_queue_depth:AtomicUsize,
+}
+
+/// State shared between the server and all client handlers.
+struct Shared{
+    shut_down:Arc<AtomicBool>,
+    options:RwLock<ServerOptions>,
+    counters:Counters,
+    started:Instant,
+    commands:CommandMetrics,
+    slowlog:SlowLog,
+    monitors:MonitorHub,
+    pubsub:PubSub,
+    replication:Replication,
+    cluster:Cluster,
+    keys:KeyLocks,
+    parking:ParkingLot,
+}
+
+impl Shared{
+    fn options(&self)->ServerOptions{
+        self.options.read().unwrap().clone()
+    }
+
+    fn stats(&self)->ServerStats{
+        let c=&self.counters;
+        ServerStats{
+            active_connections:c.active_connections.load(Ordering::SeqCst),
+            total_connections:c.total_connections.load(Ordering::SeqCst),
+            rejected_connections:c.rejected_connections.load(Ordering::SeqCst),
+            idle_timeouts:c.idle_timeouts.load(Ordering::SeqCst),
+            read_timeouts:c.read_timeouts.load(Ordering::SeqCst),
+            write_timeouts:c.write_timeouts.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// A handle to inspect and reconfigure a running `KvServer` from other threads.
+#[derive(Clone)]
+pub struct ServerHandle{
+    shared:Arc<Shared>,
+}
+
+impl ServerHandle{
+    pub fn options(&self)->ServerOptions{
+        self.shared.options()
+    }
+
+    /// Replaces the connection limits and timeouts.
+    ///
+    /// The new limit applies to new connections, the new timeouts apply to
+    /// existing connections from their next request on.
+    pub fn set_options(&self,options:ServerOptions){
+        info!("Server options changed to {:?}",options);
+        *self.shared.options.write().unwrap()=options;
+    }
+
+    pub fn stats(&self)->ServerStats{
+        self.shared.stats()
+    }
+}
 
 pub struct KvServer<E:KVEngine,P:ThreadPool>{
     engine:E,
-    listener:TcpListener,
-    shut_down:Arc<AtomicBool>,
+    listener:Option<TcpListener>,
+    //Redis协议的监听端口
+    resp:Option<TcpListener>,
+    //HTTP/JSON网关的监听端口
+    http:Option<TcpListener>,
+    #[cfg(unix)]
+    unix:Option<UnixSocket>,
+    //Unix socket客户端没有地址,按连接顺序编号
+    unix_clients:u64,
     pool:RefCell<P>,
+    shared:Arc<Shared>,
+    tls:Option<Arc<ServerConfig>>,
+}
+
+/// The protocol spoken on a listener.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Protocol{
+    Native,
+    Resp,
+    Http,
+}
+
+/// A listening Unix socket, the socket file is removed when it is dropped.
+#[cfg(unix)]
+struct UnixSocket{
+    listener:UnixListener,
+    path:PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket{
+    fn bind(path:&Path,mode:Option<u32>)->Result<UnixSocket>{
+        //上次运行遗留的socket文件无法再连接,可以删除,其他文件不能删除
+        if let Ok(meta)=std::fs::symlink_metadata(path){
+            if std::os::unix::net::UnixStream::connect(path).is_ok(){
+                return Err(KvsError::StringError(format!("unix socket {} is already in use",path.display())));
+            }
+            if !meta.file_type().is_socket(){
+                return Err(KvsError::StringError(format!("{} exists and is not a unix socket",path.display())));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener=match mode{
+            Some(mode)=>bind_private(path,mode)?,
+            None=>UnixListener::bind(path)?,
+        };
+        let socket=UnixSocket{listener,path:path.to_path_buf()};
+        socket.listener.set_nonblocking(true)?;
+        Ok(socket)
+    }
+}
+
+//在只有当前用户能访问的临时目录中绑定并设置权限,再移动到path,socket不会以默认权限出现在path
+#[cfg(unix)]
+fn bind_private(path:&Path,mode:u32)->Result<UnixListener>{
+    let name=path.file_name().ok_or_else(||KvsError::StringError(format!("{} is not a file path",path.display())))?;
+    let dir=path.with_file_name(format!(".{}.{}",name.to_string_lossy(),std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp=dir.join("socket");
+    let res=UnixListener::bind(&tmp).map_err(KvsError::from).and_then(|listener|{
+        std::fs::set_permissions(&tmp,std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&tmp,path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    res
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket{
+    fn drop(&mut self){
+        let _ = std::fs::remove_file(&self.path);
+    }
 }
 
 fn generate_response(success:bool,s:String)->String{
@@ -20,181 +238,729 @@ fn generate_response(success:bool,s:String)->String{
     }
 }
 
-fn handle_client<E:KVEngine>(stream:TcpStream,peer_addr:SocketAddr,shut_down:Arc<AtomicBool>,engine:E)->Result<()>{
-    let mut reader=BufReader::new(stream.try_clone()?);
-    let mut writer=BufWriter::new(stream);
-    
-    let shutdown=shut_down.clone();
-    loop {
-        if shutdown.load(Ordering::SeqCst) {
-            debug!("Shutting down client handler for {}", peer_addr);
-            break;
-        }
+fn is_timeout(e:&io::Error)->bool{
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
 
-        // 读取 4 字节长度
-        let mut len_buf = [0u8; 4];
-        match reader.read_exact(&mut len_buf) {
-            Ok(()) => (),
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                std::thread::sleep(std::time::Duration::from_millis(10));
-                continue;
+//读取一帧请求<len><cmd>,返回<cmd>部分
+fn read_frame(reader:&mut BufReader<DeadlineReader>)->io::Result<Vec<u8>>{
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    let mut command_buf = vec![0u8; len];
+    reader.read_exact(&mut command_buf)?;
+    Ok(command_buf)
+}
+
+/// Decrements the active connection counter when the client handler exits.
+struct ConnectionGuard(Arc<Shared>);
+
+impl Drop for ConnectionGuard{
+    fn drop(&mut self){
+        self.0.counters.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A native protocol connection, kept between requests while it is parked.
+struct Client<E:KVEngine>{
+    stream:Connection,
+    reader:BufReader<DeadlineReader>,
+    writer:BufWriter<Connection>,
+    peer_addr:Peer,
+    engine:E,
+    //AUTH成功后的用户名
+    user:Option<String>,
+    last_active:Instant,
+}
+
+fn handle_client<E:KVEngine>(stream:Connection,peer_addr:Peer,shared:&Arc<Shared>,engine:E)->Result<Next>{
+    let client=Client{
+        reader:BufReader::new(DeadlineReader::new(stream.try_clone()?)),
+        writer:BufWriter::new(stream.try_clone()?),
+        stream,
+        peer_addr,
+        engine,
+        user:None,
+        last_active:Instant::now(),
+    };
+    client.serve(shared)
+}
+
+impl<E:KVEngine> Client<E>{
+    //处理请求直到连接关闭、空闲或者进入流式响应模式
+    fn serve(mut self,shared:&Arc<Shared>)->Result<Next>{
+        let peer_addr=self.peer_addr;
+        loop {
+            if shared.shut_down.load(Ordering::SeqCst) {
+                debug!("Shutting down client handler for {}", peer_addr);
+                //通知客户端服务端正在关闭
+                let _ = self.writer.write_all(generate_response(false,"Server is shutting down\n".to_string()).as_bytes());
+                let _ = self.writer.flush();
+                break;
             }
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break, // 客户端关闭连接
-            Err(e) => return Err(e).map_err(KvsError::Io),
-        }
-        let len = u32::from_be_bytes(len_buf) as usize;
-
-        // 读取命令
-        let mut command_buf = vec![0u8; len];
-        reader.read_exact(&mut command_buf)?;
-        let cmd=Cmd::decode(len as u32,command_buf)?;
-        //info!("Received command: {:?}",cmd);
-        match cmd{
-            Cmd::Get(c)=>{
-                info!("receive get cmd {:?} from client",c);
-                let mut res=match engine.get(c.key){
-                    Ok(Some(v))=>{
-                        let res=generate_response(true, v);
-                        res
-                    },
-                    Ok(None)=>{
-                        let res=generate_response(false,"Key not found".to_string());
-                        res
-                    },
-                    Err(e)=>{
-                        let res=generate_response(false,format!("{}",e));
-                        res
-                    }
-                };
-                res.push('\n');
-                writer.write_all(res.as_bytes())?;
-            },
-            Cmd::VGet(c)=>{
-                info!("receive vget cmd {:?} from client",c);
-                let mut res=match engine.get(c.key){
-                    Ok(Some(v))=>{
-                        let res=generate_response(true, v);
-                        res
-                    },
-                    Ok(None)=>{
-                        let res=generate_response(false,"Key not found".to_string());
-                        res
-                    },
-                    Err(e)=>{
-                        let res=generate_response(false,format!("{}",e));
-                        res
-                    }
-                };
-                res.push('\n');
-                writer.write_all(res.as_bytes())?;
-            }
-            Cmd::Set(c)=>{
-                info!("receive set cmd {:?}  from client",c);
-                let mut res=match engine.set(c.key, c.value,c.expire){
-                    Ok(_)=>{
-                        let res=generate_response(true, "".to_string());
-                        res
-                    },
-                    Err(e)=>{
-                        let res=generate_response(false,format!("{}",e));
-                        res
-                    }
-                };
-                res.push('\n');
-                writer.write_all(res.as_bytes())?;
-            },
-            Cmd::VSet(c)=>{
-                info!("receive vset cmd {:?}  from client",c);
-                let mut res=match engine.set(c.key, c.value,c.expire){
-                    Ok(_)=>{
-                        let res=generate_response(true, "".to_string());
-                        res
-                    },
-                    Err(e)=>{
-                        let res=generate_response(false,format!("{}",e));
-                        res
-                    }
-                };
-                res.push('\n');
-                writer.write_all(res.as_bytes())?;
-            }
-            Cmd::Remove(c)=>{
-                info!("receive remove cmd {:?}  from client",c);
-                let mut res=match engine.remove(c.key){
-                    Ok(_)=>{
-                        let res=generate_response(true, "".to_string());
-                        res
-                    },
-                    Err(KvsError::KeyNotFound)=>{
-                        let res=generate_response(false,"Key not found".to_string());
-                        res
-                    }
-                    Err(e)=>{
-                        let res=generate_response(false,format!("{}",e));
-                        res
-                    }
-                };
-                res.push('\n');
-                writer.write_all(res.as_bytes())?;
-            },
-            Cmd::VDel(c)=>{
-                info!("receive vdel cmd {:?}  from client",c);
-                let mut res=match engine.remove(c.key){
-                    Ok(_)=>{
-                        let res=generate_response(true, "".to_string());
-                        res
-                    },
-                    Err(KvsError::KeyNotFound)=>{
-                        let res=generate_response(false,"Key not found".to_string());
-                        res
-                    }
-                    Err(e)=>{
-                        let res=generate_response(false,format!("{}",e));
-                        res
-                    }
-                };
-                res.push('\n');
-                writer.write_all(res.as_bytes())?;
-            }
-            Cmd::Scan(c)=>{
-                info!("receive scan cmd {:?}  from client",c);
-                let mut res=match engine.scan(c.start, c.end){
-                    Ok(v)=>{
-                        let mut res=String::from("OK");
-                        let mut i=0;
-                        let v_len=v.len();
-                        for s in v{
-                            res.push_str(&s);
-                            if i!=v_len-1{
-                                res.push(' ');
-                            }
-                            i+=1
+            let options=shared.options();
+
+            // 等待下一个请求,没有马上到达时把连接交给accept循环,不再占用工作线程
+            if self.reader.buffer().is_empty(){
+                self.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                match self.stream.peek_ready() {
+                    Ok(0) => break, // 客户端关闭连接
+                    Ok(_) => (),
+                    Err(e) if is_timeout(&e) => {
+                        if let Some(idle)=options.idle_timeout && self.last_active.elapsed()>=idle{
+                            info!("Client {} idle for {:?}, closing connection", peer_addr, idle);
+                            shared.counters.idle_timeouts.fetch_add(1, Ordering::SeqCst);
+                            let _ = self.writer.write_all(generate_response(false,"Idle timeout\n".to_string()).as_bytes());
+                            let _ = self.writer.flush();
+                            break;
                         }
-                        res
-                    },
-                    Err(e)=>{
-                        let res=generate_response(false,format!("{}",e));
-                        res
+                        let (stream,last_active)=(self.stream.try_clone()?,self.last_active);
+                        return Ok(Next::park(stream,last_active,move|shared|self.serve(shared)));
                     }
-                };
+                    Err(e) => return Err(KvsError::Io(e)),
+                }
+            }
+
+            // 请求已到达,在读超时内读取完整的一帧并处理,关闭期间也会处理完
+            self.reader.get_mut().set_deadline(options.read_timeout)?;
+            self.stream.set_write_timeout(options.write_timeout)?;
+            let command_buf=match read_frame(&mut self.reader) {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break, // 客户端关闭连接
+                Err(e) if is_timeout(&e) => {
+                    warn!("Read timeout from client {}, closing connection", peer_addr);
+                    shared.counters.read_timeouts.fetch_add(1, Ordering::SeqCst);
+                    break;
+                }
+                Err(e) => return Err(KvsError::Io(e)),
+            };
+            let cmd=Cmd::decode(command_buf.len() as u32,&command_buf)?;
+            //info!("Received command: {:?}",cmd);
+            shared.counters.commands_processed.fetch_add(1, Ordering::SeqCst);
+            self.last_active=Instant::now();
+            //重新读取用户配置,等待请求期间可能重新加载过
+            if let Some(mut res)=authorize(&shared.options().acl,&mut self.user,&cmd,peer_addr){
                 res.push('\n');
-                writer.write_all(res.as_bytes())?;
+                self.writer.write_all(res.as_bytes())?;
+                self.writer.flush()?;
+                continue;
+            }
+            //流式响应在单独的线程上运行,不占用线程池
+            if let Cmd::Monitor(_)=cmd{
+                info!("Client {} started monitoring", peer_addr);
+                let events=shared.monitors.subscribe();
+                return Ok(Next::stream(move|shared|{
+                    stream_events(&self.stream,self.writer,peer_addr,shared,events,Ok)?;
+                    Ok(Next::Close)
+                }));
+            }
+            if shared.monitors.is_active(){
+                shared.monitors.publish(monitor::format_event(peer_addr,&cmd.args()));
+            }
+            if let Cmd::Watch(c)=cmd{
+                info!("Client {} started watching prefix '{}'", peer_addr, c.prefix);
+                let events=self.engine.watch(&c.prefix);
+                return Ok(Next::stream(move|shared|{
+                    stream_events(&self.stream,self.writer,peer_addr,shared,events,|e|serde_json::to_string(&e))?;
+                    Ok(Next::Close)
+                }));
+            }
+            if let Cmd::Cdc(c)=cmd{
+                info!("Client {} started reading changes after {}", peer_addr, c.after);
+                return Ok(Next::stream(move|shared|{
+                    cdc_client(&self.stream,self.writer,peer_addr,shared,&self.engine,c.after)?;
+                    Ok(Next::Close)
+                }));
+            }
+            if let Cmd::Sync(c)=cmd{
+                return Ok(Next::stream(move|shared|{
+                    replication::sync_client(&self.stream,self.writer,peer_addr,shared,&self.engine,c)?;
+                    Ok(Next::Close)
+                }));
+            }
+            if let Cmd::Subscribe(_) | Cmd::PSubscribe(_)=cmd{
+                //全部取消订阅后在同一个线程上继续处理普通命令
+                return Ok(Next::stream(move|shared|{
+                    if !subscriber_client(&self.stream,&mut self.reader,&mut self.writer,peer_addr,shared,cmd)?{
+                        return Ok(Next::Close);
+                    }
+                    self.last_active=Instant::now();
+                    self.serve(shared)
+                }));
+            }
+            //迁移槽位要等待目标节点写入每个key,在单独的线程上运行,完成后继续处理这个连接
+            if let Cmd::ClusterMigrate(ref c)=cmd{
+                info!("receive cluster migrate cmd {:?} from client",c);
+                let slot=c.slot;
+                return Ok(Next::stream(move|shared|{
+                    let start=Instant::now();
+                    let res=shared.replication.check(&cmd).and_then(|()|shared.cluster.migrate(&self.engine,slot,shared.replication.auth()));
+                    let mut res=match res{
+                        Ok(moved)=>generate_response(true,moved.to_string()),
+                        Err(e)=>generate_response(false,format!("{}",e)),
+                    };
+                    shared.commands.observe("clustermigrate",start.elapsed(),res.starts_with("OK"));
+                    res.push('\n');
+                    self.writer.write_all(res.as_bytes())?;
+                    self.writer.flush()?;
+                    self.last_active=Instant::now();
+                    self.serve(shared)
+                }));
+            }
+            let name=cmd.name();
+            let start=Instant::now();
+            let mut res=execute(cmd,&self.engine,shared);
+            let elapsed=start.elapsed();
+            shared.commands.observe(name,elapsed,res.starts_with("OK"));
+            //命令已经被执行消耗,慢命令的参数从请求帧重新解码
+            if let Some(threshold)=options.slowlog_threshold && elapsed>=threshold
+                && let Ok(cmd)=Cmd::decode(command_buf.len() as u32,&command_buf){
+                shared.slowlog.record(peer_addr,cmd.args(),elapsed,options.slowlog_max_len);
+            }
+            res.push('\n');
+            self.writer.write_all(res.as_bytes())?;
+            
+            self.writer.flush()?;
+            self.last_active=Instant::now();
+        }
+
+        info!("Client {} disconnected", peer_addr);
+        Ok(Next::Close)
+    }
+}
+
+//在当前线程上处理连接,直到它关闭、被停放或者进入流式响应模式
+fn serve_client(mut guard:ConnectionGuard,peer:Peer,mut resume:Resume){
+    let shared=guard.0.clone();
+    loop {
+        let next=match resume(&shared){
+            Ok(next)=>next,
+            Err(KvsError::Io(e)) if is_timeout(&e)=>{
+                warn!("Write timeout to client {}, connection closed",peer);
+                shared.counters.write_timeouts.fetch_add(1, Ordering::SeqCst);
+                Next::Close
+            }
+            Err(e)=>{
+                error!("Error handling client {}: {}",peer,e);
+                Next::Close
+            }
+        };
+        match next{
+            Next::Close=>return,
+            Next::Park{stream,last_active,resume:next}=>{
+                match shared.parking.park(&shared,Parked{stream,last_active,peer,resume:next,guard}){
+                    Ok(())=>return,
+                    //服务端正在关闭,继续处理以通知客户端
+                    Err(parked)=>(resume,guard)=(parked.resume,parked.guard),
+                }
+            }
+            Next::Stream(next)=>{
+                std::thread::spawn(move||serve_client(guard,peer,next));
+                return;
+            }
+        }
+    }
+}
+
+//连接进入流式响应模式(monitor/watch),持续发送events中的事件,直到连接断开或服务端关闭
+fn stream_events<T,S,F>(stream:&Connection,mut writer:BufWriter<Connection>,peer_addr:Peer,shared:&Shared,events:Receiver<T>,format:F)->Result<()>
+where
+    S:Into<String>,
+    F:Fn(T)->std::result::Result<S,serde_json::Error>,
+{
+    writer.write_all(generate_response(true,"\n".to_string()).as_bytes())?;
+    writer.flush()?;
+    loop {
+        if shared.shut_down.load(Ordering::SeqCst) {
+            let _ = writer.write_all(generate_response(false,"Server is shutting down\n".to_string()).as_bytes());
+            let _ = writer.flush();
+            break;
+        }
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => {
+                let mut res=String::new();
+                for event in std::iter::once(event).chain(events.try_iter()){
+                    res.push_str(&generate_response(true, format(event)?.into()));
+                    res.push('\n');
+                }
+                if let Err(e)=writer.write_all(res.as_bytes()).and_then(|_|writer.flush()){
+                    debug!("Event stream to {} write failed: {}", peer_addr, e);
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // 没有事件时检查客户端是否已断开
+                if stream.poll_readable()?.is_none(){
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                //事件源断开说明客户端处理太慢,丢失了事件
+                let _ = writer.write_all(generate_response(false,"Event stream lagged behind, events were dropped\n".to_string()).as_bytes());
+                let _ = writer.flush();
+                break;
+            }
+        }
+    }
+    info!("Client {} stopped streaming events", peer_addr);
+    Ok(())
+}
+
+//处理AUTH命令并检查当前用户能否执行cmd,返回Some(响应)表示命令已处理或被拒绝
+//未配置用户时不需要认证,用户在配置重新加载后被删除的连接需要重新认证
+fn authorize(acl:&Acl,user:&mut Option<String>,cmd:&Cmd,peer_addr:Peer)->Option<String>{
+    if let Cmd::Auth(c)=cmd{
+        if !acl.is_enabled(){
+            return Some(generate_response(false,"AUTH failed, no users are configured".to_string()));
+        }
+        return Some(match acl.authenticate(&c.user,&c.password){
+            Ok(_)=>{
+                info!("Client {} authenticated as '{}'", peer_addr, c.user);
+                *user=Some(c.user.clone());
+                generate_response(true,"".to_string())
+            }
+            Err(e)=>{
+                warn!("Client {} failed to authenticate as '{}'", peer_addr, c.user);
+                generate_response(false,e.to_string())
+            }
+        });
+    }
+    if !acl.is_enabled(){
+        return None;
+    }
+    let res=match user.as_deref().and_then(|name|acl.user(name)){
+        Some(user)=>user.check(cmd),
+        None if Category::of(cmd).is_some()=>Err(KvsError::NoPermission("authentication required".to_string())),
+        None=>Ok(()),
+    };
+    res.err().map(|e|{
+        debug!("Client {} denied {}: {}", peer_addr, cmd.to_string(), e);
+        generate_response(false,e.to_string())
+    })
+}
+
+//CDC连接每次最多合并写入的变更数
+const CDC_BATCH:usize=256;
+
+//连接进入CDC模式,按顺序发送after之后的全部变更,直到连接断开或服务端关闭
+//变更从引擎的日志读取,客户端处理慢时阻塞在写入上而不会丢弃变更
+fn cdc_client<E:KVEngine>(stream:&Connection,mut writer:BufWriter<Connection>,peer_addr:Peer,shared:&Shared,engine:&E,after:u64)->Result<()>{
+    //先监听变更再打开游标,读完日志之后的变更都会唤醒等待
+    let mut wakeup=engine.watch("");
+    let mut cursor=match engine.cdc(after){
+        Ok(cursor)=>cursor,
+        Err(e)=>{
+            writer.write_all(generate_response(false,format!("{}\n",e)).as_bytes())?;
+            writer.flush()?;
+            return Ok(());
+        }
+    };
+    writer.write_all(generate_response(true,"\n".to_string()).as_bytes())?;
+    writer.flush()?;
+    loop {
+        if shared.shut_down.load(Ordering::SeqCst) {
+            let _ = writer.write_all(generate_response(false,"Server is shutting down\n".to_string()).as_bytes());
+            let _ = writer.flush();
+            break;
+        }
+        let mut res=String::new();
+        let mut failed=false;
+        for _ in 0..CDC_BATCH{
+            match cursor.next(){
+                Ok(Some(record))=>{
+                    res.push_str(&generate_response(true,serde_json::to_string(&record)?));
+                    res.push('\n');
+                }
+                Ok(None)=>break,
+                Err(e)=>{
+                    res.push_str(&generate_response(false,format!("{}\n",e)));
+                    failed=true;
+                    break;
+                }
+            }
+        }
+        if !res.is_empty(){
+            if let Err(e)=writer.write_all(res.as_bytes()).and_then(|_|writer.flush()){
+                debug!("Change stream to {} write failed: {}", peer_addr, e);
+                break;
+            }
+            if failed{
+                break;
             }
-            Cmd::Ping(c)=>{
-                info!("receive ping cmd {:?}  from client",c);
-                let mut res =generate_response(true, "PONG".to_string());
-                if !c.message.is_empty(){
-                    res=generate_response(true, c.message);
+            continue;
+        }
+        match wakeup.recv_timeout(POLL_INTERVAL) {
+            Ok(_) => wakeup.try_iter().for_each(drop),
+            Err(RecvTimeoutError::Timeout) => {
+                if stream.poll_readable()?.is_none(){
+                    break;
                 }
+            }
+            //唤醒通道积压时会被断开,变更仍在日志中,重新监听即可
+            Err(RecvTimeoutError::Disconnected) => wakeup=engine.watch(""),
+        }
+    }
+    info!("Client {} stopped reading changes", peer_addr);
+    Ok(())
+}
+
+//订阅模式下等待消息的间隔,同时决定了订阅模式下命令的响应延迟
+const SUBSCRIBER_POLL_INTERVAL:Duration=Duration::from_millis(10);
+
+//连接进入订阅模式,推送订阅的消息,只接受(取消)订阅命令
+//全部取消订阅后返回true回到普通模式,连接断开或服务端关闭时返回false
+fn subscriber_client(stream:&Connection,reader:&mut BufReader<DeadlineReader>,writer:&mut BufWriter<Connection>,peer_addr:Peer,shared:&Shared,cmd:Cmd)->Result<bool>{
+    info!("Client {} entered subscriber mode", peer_addr);
+    let mut subscriber=shared.pubsub.subscriber();
+    let mut cmd=Some(cmd);
+    loop {
+        if let Some(cmd)=cmd.take(){
+            let mut res=match cmd{
+                Cmd::Subscribe(c)=>push_frame(&subscriber.subscribe(c.channels)),
+                Cmd::Unsubscribe(c)=>push_frame(&subscriber.unsubscribe(c.channels)),
+                Cmd::PSubscribe(c)=>push_frame(&subscriber.psubscribe(c.patterns)),
+                Cmd::PUnsubscribe(c)=>push_frame(&subscriber.punsubscribe(c.patterns)),
+                other=>generate_response(false,format!("{} is not allowed in subscriber mode",other.to_string().to_uppercase())),
+            };
+            res.push('\n');
+            writer.write_all(res.as_bytes())?;
+            writer.flush()?;
+            if subscriber.count()==0{
+                info!("Client {} left subscriber mode", peer_addr);
+                return Ok(true);
+            }
+        }
+        if shared.shut_down.load(Ordering::SeqCst) {
+            let _ = writer.write_all(generate_response(false,"Server is shutting down\n".to_string()).as_bytes());
+            let _ = writer.flush();
+            return Ok(false);
+        }
+
+        // 推送订阅的消息
+        if let Ok(frame)=subscriber.messages().recv_timeout(SUBSCRIBER_POLL_INTERVAL){
+            let mut res=push_frame(&frame);
+            res.push('\n');
+            for frame in subscriber.messages().try_iter(){
+                res.push_str(&push_frame(&frame));
                 res.push('\n');
-                writer.write_all(res.as_bytes())?;
+            }
+            if let Err(e)=writer.write_all(res.as_bytes()).and_then(|_|writer.flush()){
+                debug!("Subscriber {} write failed: {}", peer_addr, e);
+                return Ok(false);
             }
         }
-        
-        writer.flush()?;
+
+        // 读取新的(取消)订阅命令
+        if reader.buffer().is_empty(){
+            match stream.poll_readable()?{
+                None=>return Ok(false),
+                Some(false)=>continue,
+                Some(true)=>(),
+            }
+        }
+        reader.get_mut().set_deadline(shared.options().read_timeout)?;
+        let command_buf=match read_frame(reader) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) if is_timeout(&e) => {
+                warn!("Read timeout from client {}, closing connection", peer_addr);
+                shared.counters.read_timeouts.fetch_add(1, Ordering::SeqCst);
+                return Ok(false);
+            }
+            Err(e) => return Err(KvsError::Io(e)),
+        };
+        let next=Cmd::decode(command_buf.len() as u32,&command_buf)?;
+        shared.counters.commands_processed.fetch_add(1, Ordering::SeqCst);
+        if shared.monitors.is_active(){
+            shared.monitors.publish(monitor::format_event(peer_addr,&next.args()));
+        }
+        cmd=Some(next);
+    }
+}
+
+fn push_frame(frame:&PubSubFrame)->String{
+    match serde_json::to_string(frame){
+        Ok(s)=>generate_response(true, s),
+        Err(e)=>generate_response(false,format!("{}",e)),
     }
+}
+
+//执行一条命令,返回不带换行符的响应
+fn server_info<E:KVEngine>(engine:&E,shared:&Shared)->Result<ServerInfo>{
+    Ok(ServerInfo{
+        version:env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs:shared.started.elapsed().as_secs(),
+        engine:engine.stats()?,
+        connections:shared.stats(),
+        commands_processed:shared.counters.commands_processed.load(Ordering::SeqCst),
+        replication:shared.replication.info(),
+    })
+}
 
-    info!("Client {} disconnected", peer_addr);
+fn execute<E:KVEngine>(cmd:Cmd,engine:&E,shared:&Shared)->String{
+    //副本只接受读请求,全量同步期间也不接受读请求
+    if let Err(e)=shared.replication.check(&cmd){
+        return generate_response(false,e.to_string());
+    }
+    //集群模式下只处理本节点负责的槽位中的key,执行期间持有guard
+    let _guard=match shared.cluster.check(std::slice::from_ref(&cmd),engine){
+        Ok(guard)=>guard,
+        Err(e)=>return generate_response(false,e.to_string()),
+    };
+    let _locks=shared.keys.lock(std::slice::from_ref(&cmd));
+    match cmd{
+        Cmd::Get(c)=>{
+            info!("receive get cmd {:?} from client",c);
+            match engine.get(c.key){
+                Ok(Some(v))=>{
+                    let res=generate_response(true, v);
+                    res
+                },
+                Ok(None)=>{
+                    let res=generate_response(false,"Key not found".to_string());
+                    res
+                },
+                Err(e)=>{
+                    let res=generate_response(false,format!("{}",e));
+                    res
+                }
+            }
+        },
+        Cmd::VGet(c)=>{
+            info!("receive vget cmd {:?} from client",c);
+            match engine.get(c.key){
+                Ok(Some(v))=>{
+                    let res=generate_response(true, v);
+                    res
+                },
+                Ok(None)=>{
+                    let res=generate_response(false,"Key not found".to_string());
+                    res
+                },
+                Err(e)=>{
+                    let res=generate_response(false,format!("{}",e));
+                    res
+                }
+            }
+        }
+        Cmd::Set(c)=>{
+            info!("receive set cmd {:?}  from client",c);
+            match engine.set(c.key, c.value,c.expire){
+                Ok(_)=>{
+                    let res=generate_response(true, "".to_string());
+                    res
+                },
+                Err(e)=>{
+                    let res=generate_response(false,format!("{}",e));
+                    res
+                }
+            }
+        },
+        Cmd::VSet(c)=>{
+            info!("receive vset cmd {:?}  from client",c);
+            match engine.set(c.key, c.value,c.expire){
+                Ok(_)=>{
+                    let res=generate_response(true, "".to_string());
+                    res
+                },
+                Err(e)=>{
+                    let res=generate_response(false,format!("{}",e));
+                    res
+                }
+            }
+        }
+        Cmd::Remove(c)=>{
+            info!("receive remove cmd {:?}  from client",c);
+            match engine.remove(c.key){
+                Ok(_)=>{
+                    let res=generate_response(true, "".to_string());
+                    res
+                },
+                Err(KvsError::KeyNotFound)=>{
+                    let res=generate_response(false,"Key not found".to_string());
+                    res
+                }
+                Err(e)=>{
+                    let res=generate_response(false,format!("{}",e));
+                    res
+                }
+            }
+        },
+        Cmd::VDel(c)=>{
+            info!("receive vdel cmd {:?}  from client",c);
+            match engine.remove(c.key){
+                Ok(_)=>{
+                    let res=generate_response(true, "".to_string());
+                    res
+                },
+                Err(KvsError::KeyNotFound)=>{
+                    let res=generate_response(false,"Key not found".to_string());
+                    res
+                }
+                Err(e)=>{
+                    let res=generate_response(false,format!("{}",e));
+                    res
+                }
+            }
+        }
+        Cmd::Scan(c)=>{
+            info!("receive scan cmd {:?}  from client",c);
+            //key和value中可能有空格,以JSON数组返回
+            match engine.scan(c.start, c.end){
+                Ok(v)=>match serde_json::to_string(&v){
+                    Ok(s)=>generate_response(true, s),
+                    Err(e)=>generate_response(false,format!("{}",e)),
+                },
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        Cmd::Ping(c)=>{
+            info!("receive ping cmd {:?}  from client",c);
+            let mut res =generate_response(true, "PONG".to_string());
+            if !c.message.is_empty(){
+                res=generate_response(true, c.message);
+            }
+            res
+        }
+        Cmd::Info(_)=>{
+            info!("receive info cmd from client");
+            match server_info(engine,shared){
+                Ok(info)=>match serde_json::to_string(&info){
+                    Ok(s)=>generate_response(true, s),
+                    Err(e)=>generate_response(false,format!("{}",e)),
+                },
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        Cmd::DbSize(_)=>{
+            info!("receive dbsize cmd from client");
+            match engine.key_count(){
+                Ok(keys)=>generate_response(true, keys.to_string()),
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        Cmd::ConfigGet(c)=>{
+            info!("receive config get cmd {:?} from client",c);
+            match serde_json::to_string(&config_get(shared,&c.pattern)){
+                Ok(pairs)=>generate_response(true, pairs),
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        Cmd::ConfigSet(c)=>{
+            info!("receive config set cmd {:?} from client",c);
+            match config_set(shared,&c.key,&c.value){
+                Ok(())=>generate_response(true, "".to_string()),
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        Cmd::SlowlogGet(c)=>{
+            info!("receive slowlog get cmd {:?} from client",c);
+            match serde_json::to_string(&shared.slowlog.get(c.count as usize)){
+                Ok(s)=>generate_response(true, s),
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        //monitor、watch、cdc和订阅会占用整个连接,由handle_client处理
+        Cmd::Monitor(_)=>generate_response(false,"MONITOR is not supported here".to_string()),
+        Cmd::Watch(_)=>generate_response(false,"WATCH is not supported here".to_string()),
+        Cmd::Cdc(_)=>generate_response(false,"CDC is not supported here".to_string()),
+        Cmd::Sync(_)=>generate_response(false,"SYNC is not supported here".to_string()),
+        Cmd::ReplicaOf(c)=>{
+            info!("receive replicaof cmd {:?} from client",c);
+            if c.leader.is_empty(){
+                shared.replication.promote();
+                return generate_response(true,"".to_string());
+            }
+            let res=c.leader.parse::<ServerAddr>().map_err(KvsError::StringError)
+                .and_then(|leader|shared.replication.follow(engine.clone(),leader,shared.shut_down.clone()));
+            match res{
+                Ok(())=>generate_response(true,"".to_string()),
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        Cmd::ClusterSlots(_)=>{
+            info!("receive cluster slots cmd from client");
+            match shared.cluster.slots().and_then(|map|Ok(serde_json::to_string(&map)?)){
+                Ok(s)=>generate_response(true, s),
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        Cmd::ClusterSetSlot(c)=>{
+            info!("receive cluster setslot cmd {:?} from client",c);
+            match SlotState::from_parts(&c.state,&c.addr).and_then(|state|shared.cluster.set_slot(c.start..=c.end,state)){
+                Ok(())=>generate_response(true,"".to_string()),
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        //CLUSTER MIGRATE由serve在单独的线程上处理
+        Cmd::ClusterMigrate(_)=>generate_response(false,"CLUSTER MIGRATE is not supported here".to_string()),
+        Cmd::Backup(c)=>{
+            info!("receive backup cmd {:?} from client",c);
+            match engine.backup(Path::new(&c.dir)).and_then(|manifest|Ok(serde_json::to_string(&manifest)?)){
+                Ok(s)=>generate_response(true, s),
+                Err(e)=>generate_response(false,format!("{}",e)),
+            }
+        }
+        //AUTH由authorize处理
+        Cmd::Auth(_)=>generate_response(false,"AUTH is not supported here".to_string()),
+        Cmd::Subscribe(_) | Cmd::PSubscribe(_)=>generate_response(false,"SUBSCRIBE is not supported here".to_string()),
+        Cmd::Unsubscribe(_) | Cmd::PUnsubscribe(_)=>generate_response(false,"Not in subscriber mode".to_string()),
+        Cmd::Publish(c)=>{
+            info!("receive publish cmd to channel {} from client",c.channel);
+            generate_response(true, shared.pubsub.publish(&c.channel,&c.message).to_string())
+        }
+        Cmd::SlowlogReset(_)=>{
+            info!("receive slowlog reset cmd from client");
+            shared.slowlog.reset();
+            generate_response(true, "".to_string())
+        }
+    }
+}
+
+//可以在运行时查看和修改的配置,超时的单位为秒,slowlog_threshold的单位为毫秒,0表示不限制或关闭
+const RUNTIME_CONFIG:[&str;7]=["max_connections","idle_timeout","read_timeout","write_timeout","slowlog_threshold","slowlog_max_len","log_level"];
+
+fn config_get(shared:&Shared,pattern:&str)->Vec<(String,String)>{
+    let options=shared.options();
+    let secs=|d:Option<Duration>|d.map_or(0,|d|d.as_secs()).to_string();
+    RUNTIME_CONFIG.iter()
+        .filter(|name|pattern=="*" || pattern==**name)
+        .map(|name|{
+            let value=match *name{
+                "max_connections"=>options.max_connections.to_string(),
+                "idle_timeout"=>secs(options.idle_timeout),
+                "read_timeout"=>secs(options.read_timeout),
+                "write_timeout"=>secs(options.write_timeout),
+                "slowlog_threshold"=>options.slowlog_threshold.map_or(0,|d|d.as_millis()).to_string(),
+                "slowlog_max_len"=>options.slowlog_max_len.to_string(),
+                _=>log::max_level().to_string().to_lowercase(),
+            };
+            (name.to_string(),value)
+        })
+        .collect()
+}
+
+fn config_set(shared:&Shared,key:&str,value:&str)->Result<()>{
+    let invalid=||KvsError::StringError(format!("Invalid value '{}' for config '{}'",value,key));
+    if key=="log_level"{
+        let level=value.parse::<log::LevelFilter>().map_err(|_|invalid())?;
+        crate::set_log_level(level);
+        info!("Log level changed to {}",level);
+        return Ok(());
+    }
+    let n=value.parse::<u64>().map_err(|_|invalid())?;
+    let secs=if n==0 { None } else { Some(Duration::from_secs(n)) };
+    let mut options=shared.options.write().unwrap();
+    match key{
+        "max_connections"=>options.max_connections=n as usize,
+        "idle_timeout"=>options.idle_timeout=secs,
+        "read_timeout"=>options.read_timeout=secs,
+        "write_timeout"=>options.write_timeout=secs,
+        "slowlog_threshold"=>options.slowlog_threshold=if n==0 { None } else { Some(Duration::from_millis(n)) },
+        "slowlog_max_len"=>options.slowlog_max_len=n as usize,
+        _=>return Err(KvsError::StringError(format!("Unknown config '{}', must be one of {}",key,RUNTIME_CONFIG.join(", ")))),
+    }
+    info!("Server options changed to {:?}",*options);
     Ok(())
 }
 
@@ -202,30 +968,182 @@ impl<E:KVEngine,P:ThreadPool> KvServer<E,P>{
     pub fn new(engine:E,addr:SocketAddr,shut_down:Arc<AtomicBool>,pool:P)->Result<Self>{
         let listener=TcpListener::bind(addr)?;
         listener.set_nonblocking(true)?;
-        Ok(KvServer{engine,listener,shut_down,pool:RefCell::new(pool)})
+        Self::with_listener(engine,Some(listener),shut_down,pool)
+    }
+
+    /// Creates a server listening only on the Unix socket at `path`.
+    ///
+    /// See `with_unix_socket` for `mode`.
+    #[cfg(unix)]
+    pub fn unix(engine:E,path:impl AsRef<Path>,mode:Option<u32>,shut_down:Arc<AtomicBool>,pool:P)->Result<Self>{
+        Self::with_listener(engine,None,shut_down,pool)?.with_unix_socket(path,mode)
+    }
+
+    fn with_listener(engine:E,listener:Option<TcpListener>,shut_down:Arc<AtomicBool>,pool:P)->Result<Self>{
+        let shared=Arc::new(Shared{
+            shut_down,
+            options:RwLock::new(ServerOptions::default()),
+            counters:Counters::default(),
+            started:Instant::now(),
+            commands:CommandMetrics::default(),
+            slowlog:SlowLog::default(),
+            monitors:MonitorHub::default(),
+            pubsub:PubSub::default(),
+            replication:Replication::default(),
+            cluster:Cluster::default(),
+            keys:KeyLocks::default(),
+            parking:ParkingLot::new()?,
+        });
+        Ok(KvServer{
+            engine,
+            listener,
+            resp:None,
+            http:None,
+            #[cfg(unix)]
+            unix:None,
+            unix_clients:0,
+            pool:RefCell::new(pool),
+            shared,
+            tls:None,
+        })
+    }
+
+    /// Also listens on the Unix socket at `path`, TLS only applies to TCP clients.
+    ///
+    /// `mode` sets the permissions of the socket file, e.g. `0o660`, before it appears
+    /// at `path`, otherwise they follow the umask. A stale socket file left by a
+    /// previous run is replaced and the file is removed when the server is dropped.
+    ///
+    /// # Errors
+    ///
+    /// It fails if another process is listening on `path`, `path` is not a socket or
+    /// the socket cannot be bound.
+    #[cfg(unix)]
+    pub fn with_unix_socket(mut self,path:impl AsRef<Path>,mode:Option<u32>)->Result<Self>{
+        self.unix=Some(UnixSocket::bind(path.as_ref(),mode)?);
+        Ok(self)
+    }
+
+    /// Sets the connection limits and timeouts, they apply to new requests immediately.
+    pub fn with_options(self,options:ServerOptions)->Self{
+        *self.shared.options.write().unwrap()=options;
+        self
+    }
+
+    /// Also serves the Redis protocol (RESP2 and RESP3) on `addr` with the same engine.
+    ///
+    /// Redis clients can run GET, SET, DEL, EXISTS, SCAN, PING, INFO and similar
+    /// commands; users, TLS and connection limits apply like to native clients.
+    pub fn with_resp(mut self,addr:SocketAddr)->Result<Self>{
+        let listener=TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        self.resp=Some(listener);
+        Ok(self)
+    }
+
+    /// Also serves an HTTP/1.1 JSON gateway on `addr` with the same engine.
+    ///
+    /// Keys are read and written with `GET`, `PUT` and `DELETE` on `/keys/{key}` and
+    /// `/vectors/{key}`, `GET /keys?start=&end=&limit=` scans a range. Users log in
+    /// with basic auth; TLS and connection limits apply like to native clients.
+    pub fn with_http(mut self,addr:SocketAddr)->Result<Self>{
+        let listener=TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        self.http=Some(listener);
+        Ok(self)
+    }
+
+    /// Makes the server a replica of `leader`.
+    ///
+    /// The replica replaces its keys with a snapshot of the leader, then applies the
+    /// changes of the leader as they happen and reconnects if the link breaks. Writes
+    /// fail with `KvsError::ReadOnlyReplica` until `ReplicaOf` promotes the server, reads
+    /// fail with `KvsError::ReplicaLoading` while a full sync is in progress.
+    pub fn with_replica_of(self,leader:ServerAddr)->Result<Self>{
+        self.shared.replication.follow(self.engine.clone(),leader,self.shared.shut_down.clone())?;
+        Ok(self)
+    }
+
+    /// Sets the user a replica authenticates as when it connects to its leader.
+    pub fn with_replication_auth(self,user:&str,password:&str)->Self{
+        self.shared.replication.set_auth(user.to_string(),password.to_string());
+        self
+    }
+
+    /// Enables cluster mode, this node only serves the keys of its hash slots.
+    ///
+    /// `myself` is the address of this node as it appears in `slots`, clients are
+    /// redirected to the owners of other slots with `KvsError::Moved`. Slots can be
+    /// moved at runtime with `ClusterClient::migrate_slot`, migrations connect to
+    /// other nodes as the user set by `with_replication_auth`.
+    ///
+    /// # Errors
+    ///
+    /// It fails if a slot is out of range or assigned to two nodes.
+    pub fn with_cluster(self,myself:&str,slots:&[SlotRange])->Result<Self>{
+        let mut assigned=vec![false;SLOTS as usize];
+        for range in slots{
+            if range.start>range.end || range.end>=SLOTS{
+                return Err(KvsError::Config(format!("slots {}-{} are out of range",range.start,range.end)));
+            }
+            for slot in range.start..=range.end{
+                if std::mem::replace(&mut assigned[slot as usize],true){
+                    return Err(KvsError::Config(format!("slot {} is assigned twice",slot)));
+                }
+            }
+        }
+        self.shared.cluster.enable(myself.to_string(),slots);
+        Ok(self)
+    }
+
+    /// Sets the Redis protocol address of the cluster node at `addr`.
+    ///
+    /// Redirects sent to Redis protocol clients name it instead of the native
+    /// protocol address used in `with_cluster`.
+    pub fn with_cluster_resp_addr(self,addr:&str,resp_addr:&str)->Self{
+        self.shared.cluster.set_resp_addr(addr.to_string(),resp_addr.to_string());
+        self
+    }
+
+    /// Serves clients over TLS, see `tls::server_config`.
+    pub fn with_tls(mut self,config:Arc<ServerConfig>)->Self{
+        self.tls=Some(config);
+        self
+    }
+
+    /// Serves Prometheus metrics on `http://<addr>/metrics` from a background thread.
+    pub fn serve_metrics(&self,addr:SocketAddr)->Result<()>{
+        let listener=TcpListener::bind(addr)?;
+        let shared=self.shared.clone();
+        let engine=self.engine.clone();
+        metrics::serve(listener, move||{
+            let stats=engine.stats()?;
+            let queue_depth=shared.counters.pool_queue_depth.load(Ordering::SeqCst);
+            Ok(metrics::render(&shared.commands,&shared.stats(),queue_depth,&stats))
+        })
     }
 
     pub fn run(&mut self)->Result<()>{
         loop {
-            if self.shut_down.load(Ordering::SeqCst) {
+            let queue_len=self.pool.get_mut().queue_len();
+            self.shared.counters.pool_queue_depth.store(queue_len, Ordering::SeqCst);
+            if self.shared.shut_down.load(Ordering::SeqCst) {
                 info!("Shutdown!Stopping accepting new connections...");
 
                 break;
             }
             
-            match self.listener.accept() {
-                Ok((stream, addr)) => {
-                    info!("accept connection:{:?}",addr);
-                    let store = self.engine.clone();
-                    let shutdown = self.shut_down.clone();
-                    
-                    self.pool.get_mut().spawn(move||{
-                        handle_client(stream,addr,shutdown,store).unwrap();
-                    });
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    std::thread::sleep(std::time::Duration::from_millis(100));
-                    continue;
+            match self.accept() {
+                Ok(Some((stream, peer, protocol))) => self.dispatch(stream, peer, protocol),
+                Ok(None) => {
+                    //没有新连接时等待监听socket和停放的连接可读
+                    match self.wait_parked() {
+                        Ok(ready) => ready.into_iter().for_each(|parked|self.resume(parked)),
+                        Err(e) => {
+                            error!("Error polling connections: {}", e);
+                            break;
+                        }
+                    }
                 }
                 Err(e) => {
                     error!("Error accepting connection: {}", e);
@@ -237,7 +1155,150 @@ impl<E:KVEngine,P:ThreadPool> KvServer<E,P>{
         Ok(())
     }
 
-    pub fn shut_down(&mut self){
-        self.pool.get_mut().stop().unwrap();
+    #[cfg(unix)]
+    fn wait_parked(&self)->io::Result<Vec<Parked>>{
+        use std::os::unix::io::AsRawFd;
+        let mut listeners:Vec<_>=[&self.listener,&self.resp,&self.http].into_iter().flatten().map(|l|l.as_raw_fd()).collect();
+        listeners.extend(self.unix.as_ref().map(|u|u.listener.as_raw_fd()));
+        self.shared.parking.wait(&listeners,POLL_INTERVAL,self.shared.options().idle_timeout)
+    }
+
+    #[cfg(not(unix))]
+    fn wait_parked(&self)->io::Result<Vec<Parked>>{
+        self.shared.parking.wait(POLL_INTERVAL)
+    }
+
+    //停放的连接可读后交回线程池继续处理
+    fn resume(&mut self,parked:Parked){
+        let Parked{peer,resume,guard,..}=parked;
+        self.pool.get_mut().spawn(move||serve_client(guard,peer,resume));
     }
-}
\ No newline at end of file
+
+    //从各个监听socket上接受一个新连接,都没有新连接时返回None
+    fn accept(&mut self)->io::Result<Option<(Connection,Peer,Protocol)>>{
+        for (listener,protocol) in [(&self.listener,Protocol::Native),(&self.resp,Protocol::Resp),(&self.http,Protocol::Http)]{
+            let Some(listener)=listener else {
+                continue;
+            };
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    //监听socket是非阻塞的,连接socket需要阻塞读写
+                    stream.set_nonblocking(false)?;
+                    //TLS握手在第一次读取时进行,不阻塞accept
+                    let stream=match &self.tls{
+                        Some(config)=>Connection::tls(stream,config.clone())?,
+                        None=>Connection::Tcp(stream),
+                    };
+                    return Ok(Some((stream,Peer::Tcp(addr),protocol)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+        #[cfg(unix)]
+        if let Some(unix)=&self.unix{
+            match unix.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    self.unix_clients+=1;
+                    return Ok(Some((Connection::Unix(stream),Peer::Unix(self.unix_clients),Protocol::Native)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    //检查连接数限制并把连接交给线程池处理
+    fn dispatch(&mut self,stream:Connection,peer:Peer,protocol:Protocol){
+        info!("accept {:?} connection:{}",protocol,peer);
+        let counters=&self.shared.counters;
+        let max=self.shared.options().max_connections;
+        if max>0 && counters.active_connections.load(Ordering::SeqCst)>=max{
+            warn!("Reject connection {}: max connections {} reached",peer,max);
+            counters.rejected_connections.fetch_add(1, Ordering::SeqCst);
+            reject(stream,protocol);
+            return;
+        }
+        counters.active_connections.fetch_add(1, Ordering::SeqCst);
+        counters.total_connections.fetch_add(1, Ordering::SeqCst);
+
+        let store = self.engine.clone();
+        let guard=ConnectionGuard(self.shared.clone());
+        let resume:Resume=match protocol{
+            Protocol::Native=>Box::new(move|shared|handle_client(stream,peer,shared,store)),
+            Protocol::Resp=>Box::new(move|shared|resp::handle_client(stream,peer,shared,store)),
+            Protocol::Http=>Box::new(move|shared|http::handle_client(stream,peer,shared,store)),
+        };
+        self.pool.get_mut().spawn(move||serve_client(guard,peer,resume));
+    }
+
+    /// Returns the number of client connections currently being served.
+    pub fn active_connections(&self)->usize{
+        self.shared.counters.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Returns a snapshot of the connection counters.
+    pub fn stats(&self)->ServerStats{
+        self.shared.stats()
+    }
+
+    /// Returns a handle that can reconfigure the server while it is running.
+    pub fn handle(&self)->ServerHandle{
+        ServerHandle{shared:self.shared.clone()}
+    }
+
+    /// Waits for in-flight requests to finish, then flushes the engine and stops the pool.
+    ///
+    /// Idle connections are notified and closed once the shutdown flag is set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if connections are still active after `timeout`, in that
+    /// case the worker threads are left running and the caller is expected to exit.
+    pub fn shut_down(&mut self,timeout:Duration)->Result<()>{
+        self.shared.shut_down.store(true, Ordering::SeqCst);
+        //停止复制,之后不会再写入引擎
+        self.shared.replication.promote();
+        #[cfg(unix)]
+        {
+            self.unix=None;
+        }
+        //停放的连接收到关闭通知后断开
+        for parked in self.shared.parking.drain(){
+            self.resume(parked);
+        }
+        let deadline=Instant::now()+timeout;
+        while self.active_connections()>0{
+            if Instant::now()>=deadline{
+                let active=self.active_connections();
+                warn!("Shutdown timed out with {} connections still active",active);
+                self.engine.flush()?;
+                return Err(KvsError::StringError(format!("shutdown timed out with {} connections still active",active)));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        info!("All connections closed, flushing engine");
+        info!("Server stats: {:?}",self.stats());
+        self.engine.flush()?;
+        self.pool.get_mut().stop()
+    }
+}
+
+//连接数超过上限时返回错误并关闭连接,TLS连接未握手无法发送错误,直接关闭
+fn reject(mut stream:Connection,protocol:Protocol){
+    if stream.is_plain(){
+        let _ = stream.set_write_timeout(Some(POLL_INTERVAL));
+        let res=match protocol{
+            Protocol::Native=>generate_response(false,"Too many connections\n".to_string()),
+            Protocol::Resp=>"-ERR max number of clients reached\r\n".to_string(),
+            Protocol::Http=>{
+                let body="{\"error\":\"Too many connections\",\"code\":\"too_many_connections\"}\n";
+                format!("HTTP/1.1 503 Service Unavailable\r\nContent-Type: application/json\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",body.len(),body)
+            }
+        };
+        let _ = stream.write_all(res.as_bytes());
+    }
+    let _ = stream.shutdown();
+}
@@ -0,0 +1,225 @@
+//! The hash slots a cluster node serves, see `crate::cluster`.
+//!
+//! Commands on keys of slots served by other nodes fail with `KvsError::Moved`.
+//! While a slot migrates away, keys still on this node are served here and the
+//! others fail with `KvsError::Ask`; `migrate` moves the keys one by one.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::sync::{RwLock, RwLockReadGuard};
+use std::time::Duration;
+use log::info;
+use crate::cluster::{key_slot, SlotMap, SlotRange, SlotState, SLOTS};
+use crate::{Cmd, KVEngine, KvClient, KvsError, Result, ServerAddr};
+
+//扫描全部key时作为上界的最大字符
+const KEY_MAX: char = char::MAX;
+
+/// The slot state of a server, cluster mode is disabled until `enable`.
+pub(super) struct Cluster {
+    state: RwLock<Option<State>>,
+    //每个槽位一把锁,命令执行期间持有key所在槽位的读锁,移动一个key时持有写锁,
+    //保证key不会在移动时被修改,其他槽位的命令不受影响
+    migration: Vec<RwLock<()>>,
+    //节点的原生协议地址对应的Redis协议地址
+    resp_addrs: RwLock<HashMap<String, String>>,
+}
+
+impl Default for Cluster {
+    fn default() -> Cluster {
+        Cluster {
+            state: RwLock::new(None),
+            migration: (0..SLOTS).map(|_| RwLock::new(())).collect(),
+            resp_addrs: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+struct State {
+    //本节点的地址,与槽位归属中的地址比较
+    myself: String,
+    //每个槽位的归属节点
+    owners: Vec<Option<String>>,
+    migrating: BTreeMap<u16, String>,
+    importing: BTreeMap<u16, String>,
+}
+
+impl Cluster {
+    /// Enables cluster mode, `myself` is the address of this node in `slots`.
+    pub(super) fn enable(&self, myself: String, slots: &[SlotRange]) {
+        let map = SlotMap { myself: myself.clone(), slots: slots.to_vec(), ..SlotMap::default() };
+        *self.state.write().unwrap() = Some(State {
+            myself,
+            owners: map.owners(),
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+        });
+    }
+
+    /// Sets the Redis protocol address of the node at `addr`.
+    pub(super) fn set_resp_addr(&self, addr: String, resp_addr: String) {
+        self.resp_addrs.write().unwrap().insert(addr, resp_addr);
+    }
+
+    /// Names the Redis protocol address of the target in a redirect for a Redis protocol client.
+    ///
+    /// Nodes without one are named by their native protocol address.
+    pub(super) fn resp_redirect(&self, e: KvsError) -> KvsError {
+        let addrs = self.resp_addrs.read().unwrap();
+        let resp = |addr: String| addrs.get(&addr).cloned().unwrap_or(addr);
+        match e {
+            KvsError::Moved(slot, addr) => KvsError::Moved(slot, resp(addr)),
+            KvsError::Ask(slot, addr) => KvsError::Ask(slot, resp(addr)),
+            e => e,
+        }
+    }
+
+    /// Returns the slot assignment as seen by this node.
+    pub(super) fn slots(&self) -> Result<SlotMap> {
+        let state = self.state.read().unwrap();
+        let state = state.as_ref().ok_or_else(disabled)?;
+        Ok(SlotMap {
+            myself: state.myself.clone(),
+            slots: SlotMap::ranges(&state.owners),
+            migrating: state.migrating.clone(),
+            importing: state.importing.clone(),
+        })
+    }
+
+    /// Changes the owner or the migration state of `slots`.
+    pub(super) fn set_slot(&self, slots: RangeInclusive<u16>, slot_state: SlotState) -> Result<()> {
+        if *slots.end() >= SLOTS || slots.is_empty() {
+            return Err(KvsError::StringError(format!("invalid slots {}-{}", slots.start(), slots.end())));
+        }
+        let mut state = self.state.write().unwrap();
+        let state = state.as_mut().ok_or_else(disabled)?;
+        for slot in slots.clone() {
+            let owner = state.owners[slot as usize].as_deref();
+            match &slot_state {
+                SlotState::Migrating(_) if owner != Some(state.myself.as_str()) => {
+                    return Err(KvsError::StringError(format!("slot {} is not served by this node", slot)));
+                }
+                SlotState::Importing(_) if owner == Some(state.myself.as_str()) => {
+                    return Err(KvsError::StringError(format!("slot {} is already served by this node", slot)));
+                }
+                _ => (),
+            }
+        }
+        for slot in slots.clone() {
+            match &slot_state {
+                SlotState::Node(addr) => {
+                    state.owners[slot as usize] = Some(addr.clone());
+                    state.migrating.remove(&slot);
+                    state.importing.remove(&slot);
+                }
+                SlotState::Migrating(addr) => {
+                    state.migrating.insert(slot, addr.clone());
+                }
+                SlotState::Importing(addr) => {
+                    state.importing.insert(slot, addr.clone());
+                }
+                SlotState::Stable => {
+                    state.migrating.remove(&slot);
+                    state.importing.remove(&slot);
+                }
+            }
+        }
+        info!("Slots {}-{} set to {}", slots.start(), slots.end(), slot_state);
+        Ok(())
+    }
+
+    /// Checks that this node serves the keys of `cmds`.
+    ///
+    /// The returned guard has to be held while the commands run so `migrate`
+    /// does not move their keys meanwhile. Commands without keys always pass.
+    pub(super) fn check<E: KVEngine>(&self, cmds: &[Cmd], engine: &E) -> Result<Option<RwLockReadGuard<'_, ()>>> {
+        let keys: Vec<&str> = cmds.iter().filter_map(Cmd::key).collect();
+        let Some(first) = keys.first() else {
+            return Ok(None);
+        };
+        let state = self.state.read().unwrap();
+        let Some(state) = state.as_ref() else {
+            return Ok(None);
+        };
+        let slot = key_slot(first);
+        if keys.iter().any(|k| key_slot(k) != slot) {
+            return Err(KvsError::Cluster("CROSSSLOT Keys in request don't hash to the same slot".to_string()));
+        }
+        let guard = self.migration[slot as usize].read().unwrap();
+        // 迁入中的槽位由源节点通过ASK重定向过来
+        if state.importing.contains_key(&slot) {
+            return Ok(Some(guard));
+        }
+        match state.owners[slot as usize].as_deref() {
+            None => return Err(KvsError::Cluster(format!("CLUSTERDOWN Hash slot {} is not served", slot))),
+            Some(owner) if owner != state.myself => return Err(KvsError::Moved(slot, owner.to_string())),
+            _ => (),
+        }
+        let Some(target) = state.migrating.get(&slot) else {
+            return Ok(Some(guard));
+        };
+        // 迁出中的槽位,key还在本节点时在本节点执行,已经移走时转到目标节点
+        let mut found = 0;
+        for key in &keys {
+            if engine.get(key.to_string())?.is_some() {
+                found += 1;
+            }
+        }
+        match found {
+            0 => Err(KvsError::Ask(slot, target.clone())),
+            n if n == keys.len() => Ok(Some(guard)),
+            _ => Err(KvsError::Cluster("TRYAGAIN Multiple keys request during rehashing of slot".to_string())),
+        }
+    }
+
+    /// Moves the keys of a migrating `slot` to its target, returns the number of keys moved.
+    ///
+    /// It connects to the target as `auth` if it is given, keys keep their remaining
+    /// TTL. Only commands on `slot` wait while a key moves, the caller runs the
+    /// migration on a thread of its own.
+    pub(super) fn migrate<E: KVEngine>(&self, engine: &E, slot: u16, auth: Option<(String, String)>) -> Result<u64> {
+        let target = {
+            let state = self.state.read().unwrap();
+            let state = state.as_ref().ok_or_else(disabled)?;
+            state.migrating.get(&slot).cloned().ok_or_else(|| KvsError::StringError(format!("slot {} is not migrating", slot)))?
+        };
+        let addr: ServerAddr = target.parse().map_err(KvsError::StringError)?;
+        let keys: Vec<String> = engine
+            .scan_keys(String::new(), KEY_MAX.to_string(), 0, usize::MAX)?
+            .into_iter()
+            .filter(|key| key_slot(key) == slot)
+            .collect();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let mut client = runtime.block_on(async {
+            let mut client = KvClient::connect(&addr).await?;
+            if let Some((user, password)) = &auth {
+                client.auth(user, password).await?;
+            }
+            Ok::<_, KvsError>(client)
+        })?;
+        let mut moved = 0;
+        for key in keys {
+            // 写锁期间这个槽位没有命令在执行,移走后的key由check重定向到目标节点
+            let _guard = self.migration[slot as usize].write().unwrap();
+            // 已经过期的key不再移动,剩余的TTL随value一起写入目标节点
+            let Some(ttl) = engine.ttl(key.clone())? else {
+                continue;
+            };
+            let Some(value) = engine.get(key.clone())? else {
+                continue;
+            };
+            let ttl = (ttl > 0).then(|| Duration::from_secs(ttl.into()));
+            runtime.block_on(client.set(&key, &value, ttl))?;
+            match engine.remove(key) {
+                Ok(()) | Err(KvsError::KeyNotFound) => moved += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        info!("Moved {} keys of slot {} to {}", moved, slot, target);
+        Ok(moved)
+    }
+}
+
+fn disabled() -> KvsError {
+    KvsError::Cluster("ERR This instance has cluster support disabled".to_string())
+}
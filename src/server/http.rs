@@ -0,0 +1,515 @@
+//! An HTTP/1.1 JSON gateway on a separate listener.
+//!
+//! Routes:
+//!
+//! - `GET /keys/{key}`, `PUT /keys/{key}` with the value as body, `DELETE /keys/{key}`
+//! - `GET /keys?start=&end=&limit=` scans the inclusive range `[start, end]`
+//! - `GET /vectors/{key}`, `PUT /vectors/{key}` with a body like `[1.0,2.5]`, `DELETE /vectors/{key}`
+//!
+//! `PUT` takes the time to live in seconds from the `ttl` query parameter or the
+//! `X-TTL` header. Errors are returned as `{"error": ..., "code": ...}`, writes to a
+//! replica fail with status 421 naming the leader. When users are configured,
+//! requests authenticate with HTTP basic auth.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use crate::common::{DelVector, GetCmd, GetVector, RemoveCmd, ScanCmd, SetCmd, SetVector, validate_vector};
+use crate::connection::{Connection, DeadlineReader, Peer};
+use crate::monitor;
+use crate::{Category, Cmd, KVEngine, KvsError, Result};
+use super::{is_timeout, Next, Shared, POLL_INTERVAL};
+
+//请求行和每个请求头的最大长度
+const MAX_LINE: u64 = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY: usize = 512 * 1024 * 1024;
+//scan未指定limit时最多返回的key数
+const DEFAULT_LIMIT: usize = 100;
+//scan时作为上界的最大字符
+const KEY_MAX: char = char::MAX;
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    //响应后关闭连接
+    close: bool,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// An error response, most of them are mapped from `KvsError`.
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError { status, code, message: message.into() }
+    }
+
+    fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(400, "bad_request", message)
+    }
+
+    fn not_found() -> ApiError {
+        ApiError::new(404, "not_found", "Not found")
+    }
+
+    fn body(&self) -> Value {
+        json!({ "error": self.message, "code": self.code })
+    }
+}
+
+impl From<KvsError> for ApiError {
+    fn from(e: KvsError) -> ApiError {
+        let (status, code) = match &e {
+            KvsError::KeyNotFound => (404, "key_not_found"),
+            KvsError::AuthFailed => (401, "auth_failed"),
+            KvsError::NoPermission(_) => (403, "no_permission"),
+            KvsError::ReadOnlyReplica(_) => (421, "read_only_replica"),
+            KvsError::ReplicaLoading(_) => (503, "replica_loading"),
+            KvsError::NotLeader(_) => (421, "not_leader"),
+            KvsError::Moved(..) => (421, "moved"),
+            KvsError::Ask(..) => (421, "ask"),
+            KvsError::Cluster(_) => (503, "cluster"),
+            KvsError::StringError(_) | KvsError::InvalidCommand | KvsError::Utf8(_) | KvsError::Serde(_) => (400, "bad_request"),
+            KvsError::Config(_) => (400, "invalid_config"),
+            _ => (500, "internal"),
+        };
+        ApiError::new(status, code, e.to_string())
+    }
+}
+
+type Response = std::result::Result<(u16, Option<Value>), ApiError>;
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        421 => "Misdirected Request",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn protocol_error(status: u16, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} {}", status, msg))
+}
+
+//读取一行,去掉结尾的\r\n,连接在行首关闭时返回None
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        if line.len() as u64 == MAX_LINE {
+            return Err(protocol_error(431, "request line or header too long"));
+        }
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| protocol_error(400, "invalid request encoding"))
+}
+
+//百分号编码解码,查询参数中的+表示空格
+fn percent_decode(s: &str, plus_as_space: bool) -> io::Result<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+                let b = hex.and_then(|h| u8::from_str_radix(h, 16).ok()).ok_or_else(|| protocol_error(400, "invalid percent encoding"))?;
+                out.push(b);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).map_err(|_| protocol_error(400, "invalid percent encoding"))
+}
+
+fn parse_query(query: &str) -> io::Result<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            Ok((percent_decode(k, true)?, percent_decode(v, true)?))
+        })
+        .collect()
+}
+
+//读取一个请求,连接在请求之间关闭时返回None
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let line = loop {
+        match read_line(reader)? {
+            None => return Ok(None),
+            //请求之前的空行会被忽略
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(protocol_error(400, "malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(protocol_error(400, "unsupported HTTP version"));
+    }
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(protocol_error(431, "too many headers"));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| protocol_error(400, "malformed header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: parse_query(query)?,
+        headers,
+        body: Vec::new(),
+        close: false,
+    };
+    //HTTP/1.1默认保持连接,HTTP/1.0默认关闭
+    request.close = match request.header("Connection") {
+        Some(c) if c.eq_ignore_ascii_case("close") => true,
+        Some(c) if c.eq_ignore_ascii_case("keep-alive") => false,
+        _ => version == "HTTP/1.0",
+    };
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(protocol_error(411, "chunked requests are not supported, send Content-Length"));
+    }
+    if let Some(len) = request.header("Content-Length") {
+        let len: usize = len.parse().map_err(|_| protocol_error(400, "invalid Content-Length"))?;
+        if len > MAX_BODY {
+            return Err(protocol_error(413, "request body too large"));
+        }
+        //按实际收到的字节分配,不信任客户端声明的长度
+        reader.by_ref().take(len as u64).read_to_end(&mut request.body)?;
+        if request.body.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(Some(request))
+}
+
+fn write_response(writer: &mut impl Write, status: u16, body: Option<&Value>, close: bool) -> io::Result<()> {
+    let body = body.map(|b| format!("{}\n", b)).unwrap_or_default();
+    write!(writer, "HTTP/1.1 {} {}\r\n", status, status_text(status))?;
+    if !body.is_empty() {
+        write!(writer, "Content-Type: application/json\r\n")?;
+    }
+    if status == 401 {
+        write!(writer, "WWW-Authenticate: Basic realm=\"kvs\"\r\n")?;
+    }
+    if close {
+        write!(writer, "Connection: close\r\n")?;
+    }
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// An HTTP connection, kept between requests while it is parked.
+struct Client<E: KVEngine> {
+    stream: Connection,
+    reader: BufReader<DeadlineReader>,
+    writer: BufWriter<Connection>,
+    peer_addr: Peer,
+    engine: E,
+    last_active: Instant,
+    //本连接上次验证通过的凭据
+    verified: Option<Verified>,
+}
+
+/// Basic auth credentials verified on a connection.
+struct Verified {
+    header: String,
+    user: String,
+    //验证时用户的密码哈希,密码修改后重新验证
+    password: String,
+}
+
+pub(super) fn handle_client<E: KVEngine>(stream: Connection, peer_addr: Peer, shared: &Arc<Shared>, engine: E) -> Result<Next> {
+    let client = Client {
+        reader: BufReader::new(DeadlineReader::new(stream.try_clone()?)),
+        writer: BufWriter::new(stream.try_clone()?),
+        stream,
+        peer_addr,
+        engine,
+        last_active: Instant::now(),
+        verified: None,
+    };
+    client.serve(shared)
+}
+
+impl<E: KVEngine> Client<E> {
+    //处理请求直到连接关闭或者空闲
+    fn serve(mut self, shared: &Arc<Shared>) -> Result<Next> {
+        let peer_addr = self.peer_addr;
+        loop {
+            if shared.shut_down.load(Ordering::SeqCst) {
+                debug!("Shutting down HTTP client handler for {}", peer_addr);
+                break;
+            }
+            let options = shared.options();
+
+            // 等待下一个请求,没有马上到达时把连接交给accept循环,不再占用工作线程
+            if self.reader.buffer().is_empty() {
+                self.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                match self.stream.peek_ready() {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(e) if is_timeout(&e) => {
+                        if let Some(idle) = options.idle_timeout && self.last_active.elapsed() >= idle {
+                            info!("Client {} idle for {:?}, closing connection", peer_addr, idle);
+                            shared.counters.idle_timeouts.fetch_add(1, Ordering::SeqCst);
+                            break;
+                        }
+                        let (stream, last_active) = (self.stream.try_clone()?, self.last_active);
+                        return Ok(Next::park(stream, last_active, move |shared| self.serve(shared)));
+                    }
+                    Err(e) => return Err(KvsError::Io(e)),
+                }
+            }
+
+            self.reader.get_mut().set_deadline(options.read_timeout)?;
+            self.stream.set_write_timeout(options.write_timeout)?;
+            let request = match read_request(&mut self.reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) if is_timeout(&e) => {
+                    warn!("Read timeout from client {}, closing connection", peer_addr);
+                    shared.counters.read_timeouts.fetch_add(1, Ordering::SeqCst);
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    warn!("Bad HTTP request from client {}: {}", peer_addr, e);
+                    //错误信息以状态码开头
+                    let msg = e.to_string();
+                    let (status, msg) = msg.split_once(' ').unwrap_or(("400", &msg));
+                    let error = ApiError::new(status.parse().unwrap_or(400), "bad_request", msg);
+                    let _ = write_response(&mut self.writer, error.status, Some(&error.body()), true);
+                    break;
+                }
+                Err(e) => return Err(KvsError::Io(e)),
+            };
+            shared.counters.commands_processed.fetch_add(1, Ordering::SeqCst);
+
+            let (status, body) = match serve(&request, &self.engine, shared, peer_addr, &mut self.verified) {
+                Ok((status, body)) => (status, body),
+                Err(e) => (e.status, Some(e.body())),
+            };
+            write_response(&mut self.writer, status, body.as_ref(), request.close)?;
+            if request.close {
+                break;
+            }
+            self.last_active = Instant::now();
+        }
+
+        info!("Client {} disconnected", peer_addr);
+        Ok(Next::Close)
+    }
+}
+
+//把请求映射为等价的原生命令,检查权限后执行,并记录monitor、slowlog和命令统计
+fn serve<E: KVEngine>(request: &Request, engine: &E, shared: &Shared, peer: Peer, verified: &mut Option<Verified>) -> Response {
+    let cmd = route(request)?;
+    let options = shared.options();
+    authorize(request, shared, &cmd, verified)?;
+    shared.replication.check(&cmd)?;
+    let _guard = shared.cluster.check(std::slice::from_ref(&cmd), engine)?;
+    let _locks = shared.keys.lock(std::slice::from_ref(&cmd));
+    if shared.monitors.is_active() {
+        shared.monitors.publish(monitor::format_event(peer, &cmd.args()));
+    }
+    let name = cmd.name();
+    let start = Instant::now();
+    let res = execute(cmd, request, engine);
+    let elapsed = start.elapsed();
+    shared.commands.observe(name, elapsed, res.is_ok());
+    //命令已经被执行消耗,慢命令的参数从请求重新映射
+    if let Some(threshold) = options.slowlog_threshold
+        && elapsed >= threshold
+        && let Ok(cmd) = route(request)
+    {
+        shared.slowlog.record(peer, cmd.args(), elapsed, options.slowlog_max_len);
+    }
+    res
+}
+
+fn route(request: &Request) -> std::result::Result<Cmd, ApiError> {
+    let method = request.method.as_str();
+    if request.path == "/keys" {
+        if method != "GET" {
+            return Err(method_not_allowed());
+        }
+        let start = request.param("start").unwrap_or("").to_string();
+        let end = request.param("end").map(str::to_string).unwrap_or_else(|| KEY_MAX.to_string());
+        return Ok(Cmd::Scan(ScanCmd { start, end }));
+    }
+    let (vector, key) = if let Some(key) = request.path.strip_prefix("/keys/") {
+        (false, key)
+    } else if let Some(key) = request.path.strip_prefix("/vectors/") {
+        (true, key)
+    } else {
+        return Err(ApiError::not_found());
+    };
+    let key = percent_decode(key, false).map_err(|_| ApiError::bad_request("invalid percent encoding in key"))?;
+    if key.is_empty() {
+        return Err(ApiError::not_found());
+    }
+    Ok(match (method, vector) {
+        ("GET", false) => Cmd::Get(GetCmd { key }),
+        ("GET", true) => Cmd::VGet(GetVector { key }),
+        ("DELETE", false) => Cmd::Remove(RemoveCmd { key }),
+        ("DELETE", true) => Cmd::VDel(DelVector { key }),
+        ("PUT", _) => {
+            let value = String::from_utf8(request.body.clone()).map_err(|_| ApiError::bad_request("value must be UTF-8"))?;
+            let expire = ttl(request)?;
+            if vector {
+                let value = validate_vector(&value)?;
+                Cmd::VSet(SetVector { key, value, expire })
+            } else {
+                Cmd::Set(SetCmd { key, value, expire })
+            }
+        }
+        _ => return Err(method_not_allowed()),
+    })
+}
+
+fn method_not_allowed() -> ApiError {
+    ApiError::new(405, "method_not_allowed", "Method not allowed")
+}
+
+//过期秒数,查询参数优先于请求头,0表示不过期
+fn ttl(request: &Request) -> std::result::Result<u32, ApiError> {
+    match request.param("ttl").or_else(|| request.header("X-TTL")) {
+        Some(ttl) => ttl.parse().map_err(|_| ApiError::bad_request(format!("invalid ttl '{}'", ttl))),
+        None => Ok(0),
+    }
+}
+
+//未配置用户时不需要认证,否则每个请求都要带basic auth
+fn authorize(request: &Request, shared: &Shared, cmd: &Cmd, verified: &mut Option<Verified>) -> std::result::Result<(), ApiError> {
+    let acl = shared.options().acl;
+    if !acl.is_enabled() {
+        return Ok(());
+    }
+    let Some(auth) = request.header("Authorization") else {
+        if Category::of(cmd).is_none() {
+            return Ok(());
+        }
+        return Err(ApiError::new(401, "auth_required", "Authentication required"));
+    };
+    //同一连接重复发送的凭据只计算一次密码哈希
+    if let Some(v) = verified.as_ref()
+        && v.header == auth
+        && let Some(user) = acl.user(&v.user)
+        && user.password == v.password
+    {
+        user.check(cmd)?;
+        return Ok(());
+    }
+    let (user, password) = auth
+        .strip_prefix("Basic ")
+        .and_then(|c| STANDARD.decode(c.trim()).ok())
+        .and_then(|c| String::from_utf8(c).ok())
+        .and_then(|c| c.split_once(':').map(|(u, p)| (u.to_string(), p.to_string())))
+        .ok_or_else(|| ApiError::new(401, "auth_failed", "Invalid Authorization header, expected basic auth"))?;
+    let user = acl.authenticate(&user, &password)?;
+    *verified = Some(Verified { header: auth.to_string(), user: user.name.clone(), password: user.password.clone() });
+    user.check(cmd)?;
+    Ok(())
+}
+
+fn execute<E: KVEngine>(cmd: Cmd, request: &Request, engine: &E) -> Response {
+    match cmd {
+        Cmd::Get(c) => match engine.get(c.key.clone())? {
+            Some(value) => Ok((200, Some(json!({ "key": c.key, "value": value })))),
+            None => Err(KvsError::KeyNotFound.into()),
+        },
+        Cmd::VGet(c) => match engine.get(c.key.clone())? {
+            Some(value) => {
+                let vector: Vec<f64> = serde_json::from_str(&value)
+                    .map_err(|_| ApiError::new(409, "not_a_vector", format!("value of '{}' is not a vector", c.key)))?;
+                Ok((200, Some(json!({ "key": c.key, "vector": vector }))))
+            }
+            None => Err(KvsError::KeyNotFound.into()),
+        },
+        Cmd::Set(c) => {
+            engine.set(c.key, c.value, c.expire)?;
+            Ok((204, None))
+        }
+        Cmd::VSet(c) => {
+            engine.set(c.key, c.value, c.expire)?;
+            Ok((204, None))
+        }
+        Cmd::Remove(RemoveCmd { key }) | Cmd::VDel(DelVector { key }) => {
+            engine.remove(key)?;
+            Ok((204, None))
+        }
+        Cmd::Scan(c) => {
+            let limit = match request.param("limit") {
+                Some(limit) => limit.parse().map_err(|_| ApiError::bad_request(format!("invalid limit '{}'", limit)))?,
+                None => DEFAULT_LIMIT,
+            };
+            let mut items = engine.scan(c.start, c.end)?;
+            //下一页从第一个没有返回的key开始
+            let next = items.get(limit).map(|(k, _)| k.clone());
+            items.truncate(limit);
+            let items: Vec<Value> = items.into_iter().map(|(k, v)| json!({ "key": k, "value": v })).collect();
+            Ok((200, Some(json!({ "items": items, "next": next }))))
+        }
+        _ => Err(ApiError::not_found()),
+    }
+}
@@ -0,0 +1,48 @@
+//! Serializes the writes to a key across client handlers.
+//!
+//! Conditional writes such as `SET key value NX` read the old value before
+//! writing. Every write holds the lock of its keys so no other write to the
+//! same key runs in between. Keys are hashed to a fixed number of stripes.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use crate::Cmd;
+
+const STRIPES: usize = 64;
+
+/// Striped locks over the keys of a server.
+pub(super) struct KeyLocks {
+    stripes: [Mutex<()>; STRIPES],
+}
+
+impl Default for KeyLocks {
+    fn default() -> KeyLocks {
+        KeyLocks { stripes: std::array::from_fn(|_| Mutex::new(())) }
+    }
+}
+
+impl KeyLocks {
+    /// Locks the keys written by `cmds`, reads take no lock.
+    pub(super) fn lock(&self, cmds: &[Cmd]) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = cmds
+            .iter()
+            .filter(|cmd| matches!(cmd, Cmd::Set(_) | Cmd::Remove(_) | Cmd::VSet(_) | Cmd::VDel(_)))
+            .filter_map(Cmd::key)
+            .map(stripe)
+            .collect();
+        // 按顺序加锁,多个key的写入之间不会死锁
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|i| self.stripes[i].lock().unwrap_or_else(|e| e.into_inner()))
+            .collect()
+    }
+}
+
+fn stripe(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % STRIPES
+}
@@ -0,0 +1,140 @@
+//! Idle connections wait for their next request here instead of holding a worker.
+//!
+//! A handler parks its connection when no request arrives within `POLL_INTERVAL`.
+//! The accept loop polls the parked connections together with the listeners and
+//! hands a connection back to the thread pool once it is readable, closed or idle
+//! for longer than the idle timeout.
+
+use std::io;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::connection::{Connection, Peer};
+use crate::Result;
+use super::{ConnectionGuard, Shared};
+
+/// Continues serving a connection on the thread it is handed to.
+pub(super) type Resume = Box<dyn FnOnce(&Arc<Shared>) -> Result<Next> + Send>;
+
+/// What happens to a connection after its handler returned.
+pub(super) enum Next {
+    Close,
+    /// Wait for the next request on `stream` without holding a thread.
+    Park { stream: Connection, last_active: Instant, resume: Resume },
+    /// Stream events on a thread of its own, the pool only runs requests.
+    Stream(Resume),
+}
+
+impl Next {
+    pub(super) fn park(stream: Connection, last_active: Instant, resume: impl FnOnce(&Arc<Shared>) -> Result<Next> + Send + 'static) -> Next {
+        Next::Park { stream, last_active, resume: Box::new(resume) }
+    }
+
+    pub(super) fn stream(resume: impl FnOnce(&Arc<Shared>) -> Result<Next> + Send + 'static) -> Next {
+        Next::Stream(Box::new(resume))
+    }
+}
+
+/// A connection waiting for its next request.
+pub(super) struct Parked {
+    pub stream: Connection,
+    pub last_active: Instant,
+    pub peer: Peer,
+    pub resume: Resume,
+    pub guard: ConnectionGuard,
+}
+
+/// The parked connections of a server.
+pub(super) struct ParkingLot {
+    parked: Mutex<Vec<Parked>>,
+    // 停放连接时写入一个字节唤醒accept循环的poll
+    #[cfg(unix)]
+    wake: (UnixStream, UnixStream),
+}
+
+impl ParkingLot {
+    pub(super) fn new() -> io::Result<ParkingLot> {
+        #[cfg(unix)]
+        let wake = {
+            let (reader, writer) = UnixStream::pair()?;
+            reader.set_nonblocking(true)?;
+            writer.set_nonblocking(true)?;
+            (reader, writer)
+        };
+        Ok(ParkingLot {
+            parked: Mutex::new(Vec::new()),
+            #[cfg(unix)]
+            wake,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Parked>> {
+        self.parked.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Parks a connection, after a shutdown started it is handed back to be served right away.
+    pub(super) fn park(&self, shared: &Shared, parked: Parked) -> std::result::Result<(), Parked> {
+        let mut list = self.lock();
+        // 关闭时停放的连接已经全部取出,之后停放的连接不会再被唤醒
+        if shared.shut_down.load(Ordering::SeqCst) {
+            return Err(parked);
+        }
+        list.push(parked);
+        // 唤醒字节已经积压时poll本来就会返回
+        #[cfg(unix)]
+        let _ = (&self.wake.1).write(&[1]);
+        Ok(())
+    }
+
+    /// Removes all parked connections.
+    pub(super) fn drain(&self) -> Vec<Parked> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    /// Waits up to `timeout` for one of `listeners` or a parked connection to become readable.
+    ///
+    /// Returns the parked connections that are readable, closed or idle for at
+    /// least `idle_timeout`, they are removed from the lot.
+    #[cfg(unix)]
+    pub(super) fn wait(&self, listeners: &[RawFd], timeout: Duration, idle_timeout: Option<Duration>) -> io::Result<Vec<Parked>> {
+        let pollfd = |fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        let mut fds: Vec<libc::pollfd> = std::iter::once(self.wake.0.as_raw_fd()).chain(listeners.iter().copied()).map(pollfd).collect();
+        let first = fds.len();
+        fds.extend(self.lock().iter().map(|p| pollfd(p.stream.as_raw_fd())));
+        let polled = fds.len() - first;
+
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout.as_millis() as libc::c_int) };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::Interrupted { Ok(Vec::new()) } else { Err(e) };
+        }
+        if fds[0].revents != 0 {
+            let mut buf = [0u8; 64];
+            while matches!((&self.wake.0).read(&mut buf), Ok(n) if n > 0) {}
+        }
+
+        // 只有accept循环会取出连接,poll期间新停放的连接排在polled之后
+        let mut list = self.lock();
+        let mut ready = Vec::new();
+        for i in (0..polled).rev() {
+            let idle = idle_timeout.is_some_and(|idle| list[i].last_active.elapsed() >= idle);
+            if fds[first + i].revents != 0 || idle {
+                ready.push(list.swap_remove(i));
+            }
+        }
+        Ok(ready)
+    }
+
+    /// Without `poll` every parked connection is handed back after `timeout` to check itself.
+    #[cfg(not(unix))]
+    pub(super) fn wait(&self, timeout: Duration) -> io::Result<Vec<Parked>> {
+        std::thread::sleep(timeout);
+        Ok(self.drain())
+    }
+}
@@ -0,0 +1,417 @@
+//! Asynchronous leader-follower replication.
+//!
+//! A replica sends `Sync` to its leader. The leader answers with a snapshot of every
+//! key, or resumes after the last change the replica applied if it synced from the
+//! same leader before, then streams the changes of its write log and a heartbeat
+//! whenever the replica has all of them. Replicas serve reads and reject writes
+//! until they are promoted, during a full sync they reject reads as well.
+
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam::channel::RecvTimeoutError;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use crate::common::{SyncCmd, SyncFrame};
+use crate::connection::{Connection, Peer};
+use crate::engines::{CdcCursor, CdcRecord, ChangeOp};
+use crate::{Cmd, KVEngine, KvClient, KvsError, Result, ServerAddr};
+use super::{generate_response, Shared, CDC_BATCH, POLL_INTERVAL};
+
+//主节点没有新变更时发送心跳的间隔
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+//副本超过该时间没有收到主节点的数据时重新连接
+const LINK_TIMEOUT: Duration = Duration::from_secs(5);
+//连接断开后重新连接的间隔
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+//快照时作为上界的最大字符
+const KEY_MAX: char = char::MAX;
+
+/// The replication state reported by `Info`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationInfo {
+    /// `leader` or `replica`
+    pub role: String,
+    /// address of the leader of a replica
+    pub leader: Option<String>,
+    /// whether a replica is connected to its leader
+    pub link_up: bool,
+    /// sequence number of the latest change of the leader a replica applied
+    pub applied_seq: u64,
+    /// seconds since a replica last had every change of its leader
+    pub lag_secs: Option<u64>,
+    /// number of full syncs a replica did since it started following its leader
+    pub full_syncs: u64,
+    /// number of replicas streaming from this server
+    pub replicas: u64,
+}
+
+/// The replication state of a server.
+pub(super) struct Replication {
+    //本节点的复制id,每次启动重新生成,副本据此判断能否从断点续传
+    id: String,
+    //副本连接主节点时认证的用户和口令
+    auth: Arc<RwLock<Option<(String, String)>>>,
+    //作为副本时与主节点的连接
+    link: RwLock<Option<Link>>,
+    replicas: AtomicU64,
+}
+
+struct Link {
+    leader: ServerAddr,
+    stop: Arc<AtomicBool>,
+    state: Arc<LinkState>,
+}
+
+struct LinkState {
+    connected: AtomicBool,
+    //已同步的主节点复制id和变更序号,全量同步完成前为空
+    position: Mutex<(String, u64)>,
+    //最近一次追上主节点的时间,还没有追上时为开始复制的时间
+    synced_at: Mutex<Instant>,
+    full_syncs: AtomicU64,
+    //全量同步开始后到快照的全部key应用之前为true,期间副本只有部分key
+    loading: AtomicBool,
+    //应用变更时持有,停止复制时等待正在应用的变更完成
+    apply: Mutex<()>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            auth: Arc::default(),
+            link: RwLock::new(None),
+            replicas: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Replication {
+    pub(super) fn set_auth(&self, user: String, password: String) {
+        *self.auth.write().unwrap() = Some((user, password));
+    }
+
+    /// The user to authenticate as with other servers.
+    pub(super) fn auth(&self) -> Option<(String, String)> {
+        self.auth.read().unwrap().clone()
+    }
+
+    /// Returns `KvsError::ReadOnlyReplica` if this server is a replica and `cmd` writes a key,
+    /// or `KvsError::ReplicaLoading` if `cmd` reads keys during a full sync.
+    pub(super) fn check(&self, cmd: &Cmd) -> Result<()> {
+        let writes = matches!(cmd, Cmd::Set(_) | Cmd::Remove(_) | Cmd::VSet(_) | Cmd::VDel(_));
+        let reads = matches!(cmd, Cmd::Get(_) | Cmd::Scan(_) | Cmd::VGet(_) | Cmd::DbSize(_));
+        if !writes && !reads {
+            return Ok(());
+        }
+        match &*self.link.read().unwrap() {
+            Some(link) if writes => Err(KvsError::ReadOnlyReplica(link.leader.to_string())),
+            Some(link) if link.state.loading.load(Ordering::SeqCst) => Err(KvsError::ReplicaLoading(link.leader.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts following `leader` from a background thread, replacing the current leader.
+    pub(super) fn follow<E: KVEngine>(&self, engine: E, leader: ServerAddr, shut_down: Arc<AtomicBool>) -> Result<()> {
+        let mut link = self.link.write().unwrap();
+        if let Some(old) = link.take() {
+            old.detach();
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let state = Arc::new(LinkState {
+            connected: AtomicBool::new(false),
+            position: Mutex::default(),
+            synced_at: Mutex::new(Instant::now()),
+            full_syncs: AtomicU64::new(0),
+            loading: AtomicBool::new(false),
+            apply: Mutex::new(()),
+        });
+        let (auth, thread_leader, thread_state, thread_stop) = (self.auth.clone(), leader.clone(), state.clone(), stop.clone());
+        thread::Builder::new().name("replication".to_string()).spawn(move || {
+            run_link(engine, thread_leader, auth, thread_state, thread_stop, shut_down)
+        })?;
+        info!("Replicating from {}", leader);
+        *link = Some(Link { leader, stop, state });
+        Ok(())
+    }
+
+    /// Stops following the leader, the server accepts writes afterwards.
+    ///
+    /// Returns false if the server is not a replica.
+    pub(super) fn promote(&self) -> bool {
+        match self.link.write().unwrap().take() {
+            Some(link) => {
+                link.detach();
+                info!("Promoted to leader, stopped replicating from {}", link.leader);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(super) fn info(&self) -> ReplicationInfo {
+        let replicas = self.replicas.load(Ordering::SeqCst);
+        match &*self.link.read().unwrap() {
+            Some(link) => ReplicationInfo {
+                role: "replica".to_string(),
+                leader: Some(link.leader.to_string()),
+                link_up: link.state.connected.load(Ordering::SeqCst),
+                applied_seq: link.state.position.lock().unwrap().1,
+                lag_secs: Some(link.state.synced_at.lock().unwrap().elapsed().as_secs()),
+                full_syncs: link.state.full_syncs.load(Ordering::SeqCst),
+                replicas,
+            },
+            None => ReplicationInfo { role: "leader".to_string(), replicas, ..ReplicationInfo::default() },
+        }
+    }
+}
+
+impl Link {
+    // 通知复制线程退出,返回时不会再应用主节点的变更
+    fn detach(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _apply = self.state.apply.lock().unwrap();
+    }
+}
+
+//复制线程:连接主节点并应用变更,连接断开后重新连接,直到停止复制或服务端关闭
+fn run_link<E: KVEngine>(
+    engine: E,
+    leader: ServerAddr,
+    auth: Arc<RwLock<Option<(String, String)>>>,
+    state: Arc<LinkState>,
+    stop: Arc<AtomicBool>,
+    shut_down: Arc<AtomicBool>,
+) {
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Cannot start replication from {}: {}", leader, e);
+            return;
+        }
+    };
+    let stopped = || stop.load(Ordering::SeqCst) || shut_down.load(Ordering::SeqCst);
+    runtime.block_on(async {
+        while !stopped() {
+            let auth = auth.read().unwrap().clone();
+            match sync(&engine, &leader, auth, &state, &stopped).await {
+                Ok(()) => info!("Replication link to {} closed", leader),
+                Err(e) => warn!("Replication from {} failed: {}", leader, e),
+            }
+            state.connected.store(false, Ordering::SeqCst);
+            let deadline = Instant::now() + RECONNECT_INTERVAL;
+            while !stopped() && Instant::now() < deadline {
+                time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    });
+    debug!("Replication thread for {} stopped", leader);
+}
+
+//同步一次,直到连接断开或停止复制
+async fn sync<E: KVEngine>(
+    engine: &E,
+    leader: &ServerAddr,
+    auth: Option<(String, String)>,
+    state: &LinkState,
+    stopped: &impl Fn() -> bool,
+) -> Result<()> {
+    let mut client = KvClient::connect(leader).await?;
+    if let Some((user, password)) = auth {
+        client.auth(&user, &password).await?;
+    }
+    let (id, after) = state.position.lock().unwrap().clone();
+    let mut stream = client.sync(&id, after).await?;
+    state.connected.store(true, Ordering::SeqCst);
+    //全量同步时快照的复制id、变更起点和剩余的key数
+    let mut snapshot: Option<(String, u64, u64)> = None;
+    loop {
+        let frame = match time::timeout(LINK_TIMEOUT, stream.next()).await {
+            Ok(frame) => frame?,
+            Err(_) => return Err(KvsError::StringError(format!("no data from the leader for {:?}", LINK_TIMEOUT))),
+        };
+        let Some(frame) = frame else {
+            return Ok(());
+        };
+        let _apply = state.apply.lock().unwrap();
+        if stopped() {
+            return Ok(());
+        }
+        match frame {
+            SyncFrame::Snapshot { id, seq, keys } => {
+                info!("Full sync from {}: {} keys", leader, keys);
+                *state.position.lock().unwrap() = (String::new(), 0);
+                state.full_syncs.fetch_add(1, Ordering::SeqCst);
+                //同步中断时保持loading,直到下一次全量同步完成
+                state.loading.store(true, Ordering::SeqCst);
+                clear(engine)?;
+                snapshot = Some((id, seq, keys));
+            }
+            SyncFrame::Resume { seq, .. } => info!("Resuming replication from {} after {}", leader, seq),
+            SyncFrame::Entry { key, value, ttl } => {
+                let Some((_, _, keys)) = snapshot.as_mut() else {
+                    return Err(KvsError::StringError("unexpected snapshot entry".to_string()));
+                };
+                engine.set(key, value, ttl)?;
+                *keys = keys.saturating_sub(1);
+            }
+            SyncFrame::Change(record) => {
+                if snapshot.is_some() {
+                    return Err(KvsError::StringError("change before the end of the snapshot".to_string()));
+                }
+                let seq = record.seq;
+                apply(engine, record)?;
+                state.position.lock().unwrap().1 = seq;
+            }
+            SyncFrame::Heartbeat => *state.synced_at.lock().unwrap() = Instant::now(),
+        }
+        //快照的全部key都应用后才能从快照之后的变更续传
+        if let Some((id, seq, 0)) = &snapshot {
+            *state.position.lock().unwrap() = (id.clone(), *seq);
+            state.loading.store(false, Ordering::SeqCst);
+            snapshot = None;
+        }
+    }
+}
+
+//全量同步前删除副本上的全部key
+fn clear<E: KVEngine>(engine: &E) -> Result<()> {
+    for key in engine.scan_keys(String::new(), KEY_MAX.to_string(), 0, usize::MAX)? {
+        match engine.remove(key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+//快照之后的变更可能已经包含在快照中,删除不存在的key会被忽略
+fn apply<E: KVEngine>(engine: &E, record: CdcRecord) -> Result<()> {
+    match record.op {
+        ChangeOp::Set => engine.set(record.key, record.value.unwrap_or_default(), record.ttl),
+        ChangeOp::Remove | ChangeOp::Expire => match engine.remove(record.key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+//同步开始时发送的帧、快照中的键值和剩余TTL,以及之后的变更游标
+type SyncStart = (SyncFrame, Vec<(String, String, u32)>, Box<dyn CdcCursor>);
+
+//打开变更游标,能续传时从cmd.after之后开始,否则先读取快照
+fn start<E: KVEngine>(engine: &E, id: &str, cmd: &SyncCmd) -> Result<SyncStart> {
+    if cmd.id == id && cmd.after > 0 {
+        match engine.cdc(cmd.after) {
+            Ok(cursor) => return Ok((SyncFrame::Resume { id: id.to_string(), seq: cmd.after }, Vec::new(), cursor)),
+            Err(KvsError::CdcUnavailable(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    //先打开游标再读取快照,快照期间的变更会在快照之后重新应用
+    let seq = engine.cdc_seq()?;
+    let cursor = engine.cdc(seq)?;
+    let mut entries = Vec::new();
+    for (key, value) in engine.scan(String::new(), KEY_MAX.to_string())? {
+        //读取快照期间过期的key不再发送
+        if let Some(ttl) = engine.ttl(key.clone())? {
+            entries.push((key, value, ttl));
+        }
+    }
+    let frame = SyncFrame::Snapshot { id: id.to_string(), seq, keys: entries.len() as u64 };
+    Ok((frame, entries, cursor))
+}
+
+struct ReplicaGuard<'a>(&'a AtomicU64);
+
+impl Drop for ReplicaGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn frame_line(frame: &SyncFrame) -> Result<String> {
+    let mut line = generate_response(true, serde_json::to_string(frame)?);
+    line.push('\n');
+    Ok(line)
+}
+
+//连接进入复制模式,发送快照或续传的起点,然后按顺序发送全部变更,没有新变更时定期发送心跳
+pub(super) fn sync_client<E: KVEngine>(stream: &Connection, mut writer: BufWriter<Connection>, peer_addr: Peer, shared: &Shared, engine: &E, cmd: SyncCmd) -> Result<()> {
+    let replication = &shared.replication;
+    //先监听变更再打开游标,读完日志之后的变更都会唤醒等待
+    let mut wakeup = engine.watch("");
+    let (first, entries, mut cursor) = match start(engine, &replication.id, &cmd) {
+        Ok(start) => start,
+        Err(e) => {
+            writer.write_all(generate_response(false, format!("{}\n", e)).as_bytes())?;
+            writer.flush()?;
+            return Ok(());
+        }
+    };
+    replication.replicas.fetch_add(1, Ordering::SeqCst);
+    let _guard = ReplicaGuard(&replication.replicas);
+    match &first {
+        SyncFrame::Snapshot { keys, .. } => info!("Replica {} started a full sync of {} keys", peer_addr, keys),
+        _ => info!("Replica {} resumed after {}", peer_addr, cmd.after),
+    }
+    writer.write_all(frame_line(&first)?.as_bytes())?;
+    for (key, value, ttl) in entries {
+        writer.write_all(frame_line(&SyncFrame::Entry { key, value, ttl })?.as_bytes())?;
+    }
+    writer.flush()?;
+
+    let mut last_heartbeat: Option<Instant> = None;
+    loop {
+        if shared.shut_down.load(Ordering::SeqCst) {
+            let _ = writer.write_all(generate_response(false, "Server is shutting down\n".to_string()).as_bytes());
+            let _ = writer.flush();
+            break;
+        }
+        let mut res = String::new();
+        let mut failed = false;
+        for _ in 0..CDC_BATCH {
+            match cursor.next() {
+                Ok(Some(record)) => res.push_str(&frame_line(&SyncFrame::Change(record))?),
+                Ok(None) => break,
+                Err(e) => {
+                    res.push_str(&generate_response(false, format!("{}\n", e)));
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        let caught_up = res.is_empty();
+        if caught_up && last_heartbeat.is_none_or(|t| t.elapsed() >= HEARTBEAT_INTERVAL) {
+            res.push_str(&frame_line(&SyncFrame::Heartbeat)?);
+            last_heartbeat = Some(Instant::now());
+        }
+        if !res.is_empty() {
+            if let Err(e) = writer.write_all(res.as_bytes()).and_then(|_| writer.flush()) {
+                debug!("Replication stream to {} write failed: {}", peer_addr, e);
+                break;
+            }
+            if failed {
+                break;
+            }
+            if !caught_up {
+                continue;
+            }
+        }
+        match wakeup.recv_timeout(POLL_INTERVAL) {
+            Ok(_) => wakeup.try_iter().for_each(drop),
+            Err(RecvTimeoutError::Timeout) => {
+                if stream.poll_readable()?.is_none() {
+                    break;
+                }
+            }
+            //唤醒通道积压时会被断开,变更仍在日志中,重新监听即可
+            Err(RecvTimeoutError::Disconnected) => wakeup = engine.watch(""),
+        }
+    }
+    info!("Replica {} disconnected", peer_addr);
+    Ok(())
+}
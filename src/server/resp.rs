@@ -0,0 +1,793 @@
+//! The Redis protocol (RESP2 and RESP3) on a separate listener.
+//!
+//! Commands run on the same engine as the native protocol and go through the
+//! same users, monitor, slowlog and metrics.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use log::{debug, info, warn};
+use crate::common::{ConfigGetCmd, ConfigSetCmd, DbSizeCmd, GetCmd, InfoCmd, PublishCmd, RemoveCmd, ReplicaOfCmd, ScanCmd, SetCmd};
+use crate::connection::{Connection, DeadlineReader, Peer};
+use crate::monitor;
+use crate::pubsub::glob_match;
+use crate::{Category, Cmd, KVEngine, KvsError, Result, ServerAddr};
+use super::{config_get, config_set, is_timeout, server_info, Next, Shared, POLL_INTERVAL};
+
+//与Redis默认的proto-max-bulk-len相同
+const MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+const MAX_ARGS: i64 = 1024 * 1024;
+//inline命令和协议头的最大长度
+const MAX_LINE: u64 = 64 * 1024;
+//SCAN未指定COUNT时每次返回的key数
+const DEFAULT_SCAN_COUNT: usize = 10;
+//scan时作为上界的最大字符
+const KEY_MAX: char = char::MAX;
+
+/// A RESP reply, encoded as RESP2 or RESP3 depending on the connection.
+#[derive(Debug, Clone, PartialEq)]
+enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Frame>),
+    // RESP2中编码为键值交替的数组
+    Map(Vec<(Frame, Frame)>),
+}
+
+impl Frame {
+    fn ok() -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+
+    fn error(msg: impl Into<String>) -> Frame {
+        Frame::Error(msg.into())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>, resp3: bool) {
+        match self {
+            Frame::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Frame::Error(s) => out.extend_from_slice(format!("-{}\r\n", s).as_bytes()),
+            Frame::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Frame::Bulk(s) => {
+                out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Null if resp3 => out.extend_from_slice(b"_\r\n"),
+            Frame::Null => out.extend_from_slice(b"$-1\r\n"),
+            Frame::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out, resp3);
+                }
+            }
+            Frame::Map(pairs) => {
+                if resp3 {
+                    out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (k, v) in pairs {
+                    k.encode(out, resp3);
+                    v.encode(out, resp3);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+//读取一行,去掉结尾的\r\n,连接在行首关闭时返回None
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        if line.len() as u64 == MAX_LINE {
+            return Err(protocol_error("too big inline request"));
+        }
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(line: &[u8], what: &str) -> io::Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error(format!("invalid {} length", what)))
+}
+
+//读取一条命令:批量字符串组成的数组,或按空白分隔的inline命令,连接关闭时返回None
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let Some(line) = read_line(reader)? else {
+            return Ok(None);
+        };
+        let Some(count) = line.strip_prefix(b"*") else {
+            let args: Vec<Vec<u8>> = line.split(|b| b.is_ascii_whitespace()).filter(|a| !a.is_empty()).map(<[u8]>::to_vec).collect();
+            //忽略空行
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        };
+        let count = parse_len(count, "multibulk")?;
+        if count > MAX_ARGS {
+            return Err(protocol_error("invalid multibulk length"));
+        }
+        if count <= 0 {
+            continue;
+        }
+        let mut args = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let line = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            let Some(len) = line.strip_prefix(b"$") else {
+                return Err(protocol_error(format!("expected '$', got '{}'", String::from_utf8_lossy(&line[..line.len().min(1)]))));
+            };
+            let len = parse_len(len, "bulk")?;
+            if len < 0 || len as u64 > MAX_BULK_LEN {
+                return Err(protocol_error("invalid bulk length"));
+            }
+            //按实际收到的数据增长缓冲区,不按客户端声明的长度预先分配
+            let mut arg = Vec::new();
+            reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+            if arg.len() as i64 != len + 2 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if !arg.ends_with(b"\r\n") {
+                return Err(protocol_error("bulk string is not terminated by CRLF"));
+            }
+            arg.truncate(len as usize);
+            args.push(arg);
+        }
+        return Ok(Some(args));
+    }
+}
+
+/// The state of a RESP connection.
+#[derive(Default)]
+struct Session {
+    resp3: bool,
+    //AUTH成功后的用户名
+    user: Option<String>,
+    name: Option<String>,
+    id: u64,
+    quit: bool,
+}
+
+impl Session {
+    fn authenticate(&mut self, shared: &Shared, user: &str, password: &str, peer: Peer) -> Option<Frame> {
+        let acl = shared.options().acl;
+        if !acl.is_enabled() {
+            return Some(Frame::error("ERR AUTH called without any users configured"));
+        }
+        match acl.authenticate(user, password) {
+            Ok(_) => {
+                info!("Client {} authenticated as '{}'", peer, user);
+                self.user = Some(user.to_string());
+                None
+            }
+            Err(_) => {
+                warn!("Client {} failed to authenticate as '{}'", peer, user);
+                Some(Frame::error("WRONGPASS invalid username-password pair or user is disabled."))
+            }
+        }
+    }
+
+    //检查当前用户能否执行等价的原生命令,配置重新加载后被删除的用户需要重新认证
+    fn check(&self, shared: &Shared, cmds: &[Cmd]) -> Option<Frame> {
+        let acl = shared.options().acl;
+        if !acl.is_enabled() {
+            return None;
+        }
+        let Some(user) = self.user.as_deref().and_then(|name| acl.user(name)) else {
+            return cmds
+                .iter()
+                .any(|cmd| Category::of(cmd).is_some())
+                .then(|| Frame::error("NOAUTH Authentication required."));
+        };
+        cmds.iter().find_map(|cmd| match user.check(cmd) {
+            Ok(()) => None,
+            Err(KvsError::NoPermission(reason)) => Some(Frame::error(format!("NOPERM {}", reason))),
+            Err(e) => Some(Frame::error(format!("ERR {}", e))),
+        })
+    }
+
+    //副本拒绝写命令,全量同步期间也拒绝读命令
+    fn check_replica(shared: &Shared, cmds: &[Cmd]) -> Option<Frame> {
+        cmds.iter().find_map(|cmd| shared.replication.check(cmd).err()).map(|e| match e {
+            KvsError::ReadOnlyReplica(leader) => Frame::error(format!("READONLY You can't write against a read only replica, the leader is {}", leader)),
+            KvsError::ReplicaLoading(leader) => Frame::error(format!("LOADING The replica is loading the dataset from its leader {}", leader)),
+            e => Frame::error(format!("ERR {}", e)),
+        })
+    }
+}
+
+/// A RESP connection, kept between requests while it is parked.
+struct Client<E: KVEngine> {
+    stream: Connection,
+    reader: BufReader<DeadlineReader>,
+    writer: BufWriter<Connection>,
+    peer_addr: Peer,
+    engine: E,
+    session: Session,
+    last_active: Instant,
+}
+
+pub(super) fn handle_client<E: KVEngine>(stream: Connection, peer_addr: Peer, shared: &Arc<Shared>, engine: E) -> Result<Next> {
+    let client = Client {
+        reader: BufReader::new(DeadlineReader::new(stream.try_clone()?)),
+        writer: BufWriter::new(stream.try_clone()?),
+        stream,
+        peer_addr,
+        engine,
+        session: Session {
+            id: shared.counters.total_connections.load(Ordering::SeqCst),
+            ..Session::default()
+        },
+        last_active: Instant::now(),
+    };
+    client.serve(shared)
+}
+
+impl<E: KVEngine> Client<E> {
+    //处理请求直到连接关闭或者空闲
+    fn serve(mut self, shared: &Arc<Shared>) -> Result<Next> {
+        let peer_addr = self.peer_addr;
+        let mut out = Vec::new();
+        loop {
+            if shared.shut_down.load(Ordering::SeqCst) {
+                debug!("Shutting down RESP client handler for {}", peer_addr);
+                break;
+            }
+            let options = shared.options();
+
+            // 等待下一个请求,没有马上到达时把连接交给accept循环,不再占用工作线程
+            if self.reader.buffer().is_empty() {
+                self.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                match self.stream.peek_ready() {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(e) if is_timeout(&e) => {
+                        if let Some(idle) = options.idle_timeout && self.last_active.elapsed() >= idle {
+                            info!("Client {} idle for {:?}, closing connection", peer_addr, idle);
+                            shared.counters.idle_timeouts.fetch_add(1, Ordering::SeqCst);
+                            break;
+                        }
+                        let (stream, last_active) = (self.stream.try_clone()?, self.last_active);
+                        return Ok(Next::park(stream, last_active, move |shared| self.serve(shared)));
+                    }
+                    Err(e) => return Err(KvsError::Io(e)),
+                }
+            }
+
+            self.reader.get_mut().set_deadline(options.read_timeout)?;
+            self.stream.set_write_timeout(options.write_timeout)?;
+            let args = match read_command(&mut self.reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) if is_timeout(&e) => {
+                    warn!("Read timeout from client {}, closing connection", peer_addr);
+                    shared.counters.read_timeouts.fetch_add(1, Ordering::SeqCst);
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    warn!("Protocol error from client {}: {}", peer_addr, e);
+                    out.clear();
+                    Frame::error(format!("ERR Protocol error: {}", e)).encode(&mut out, self.session.resp3);
+                    let _ = self.writer.write_all(&out).and_then(|_| self.writer.flush());
+                    break;
+                }
+                Err(e) => return Err(KvsError::Io(e)),
+            };
+            shared.counters.commands_processed.fetch_add(1, Ordering::SeqCst);
+
+            // 批量处理已经到达的全部命令(pipeline),一次写回
+            out.clear();
+            let mut next = Some(args);
+            while let Some(args) = next.take() {
+                let reply = match args.into_iter().map(String::from_utf8).collect::<std::result::Result<Vec<_>, _>>() {
+                    Ok(args) => run(args, &self.engine, shared, &mut self.session, peer_addr),
+                    Err(_) => Frame::error("ERR only UTF-8 arguments are supported"),
+                };
+                reply.encode(&mut out, self.session.resp3);
+                if self.session.quit || self.reader.buffer().is_empty() {
+                    break;
+                }
+                //缓冲区中的下一条命令可能不完整,剩余部分有自己的读超时
+                self.reader.get_mut().set_deadline(options.read_timeout)?;
+                next = match read_command(&mut self.reader) {
+                    Ok(Some(args)) => {
+                        shared.counters.commands_processed.fetch_add(1, Ordering::SeqCst);
+                        Some(args)
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        Frame::error(format!("ERR Protocol error: {}", e)).encode(&mut out, self.session.resp3);
+                        self.session.quit = true;
+                        None
+                    }
+                };
+            }
+            self.writer.write_all(&out)?;
+            self.writer.flush()?;
+            if self.session.quit {
+                break;
+            }
+            self.last_active = Instant::now();
+        }
+
+        info!("Client {} disconnected", peer_addr);
+        Ok(Next::Close)
+    }
+}
+
+//执行一条命令,并记录monitor、slowlog和命令统计
+fn run<E: KVEngine>(args: Vec<String>, engine: &E, shared: &Shared, session: &mut Session, peer: Peer) -> Frame {
+    let name = args[0].to_lowercase();
+    let options = shared.options();
+    //口令不出现在monitor和slowlog中
+    let logged = || {
+        let mut logged = args.clone();
+        if name == "auth"
+            && logged.len() > 1
+            && let Some(p) = logged.last_mut()
+        {
+            *p = "(redacted)".to_string();
+        } else if name == "hello"
+            && let Some(i) = logged.iter().position(|a| a.eq_ignore_ascii_case("auth"))
+            && let Some(p) = logged.get_mut(i + 2)
+        {
+            *p = "(redacted)".to_string();
+        }
+        logged
+    };
+    if shared.monitors.is_active() {
+        shared.monitors.publish(monitor::format_event(peer, &logged()));
+    }
+    let start = Instant::now();
+    let reply = execute(&name, &args[1..], engine, shared, session, peer);
+    let elapsed = start.elapsed();
+    shared.commands.observe(&name, elapsed, !matches!(reply, Frame::Error(_)));
+    if let Some(threshold) = options.slowlog_threshold && elapsed >= threshold {
+        shared.slowlog.record(peer, logged(), elapsed, options.slowlog_max_len);
+    }
+    reply
+}
+
+fn wrong_args(name: &str) -> Frame {
+    Frame::error(format!("ERR wrong number of arguments for '{}' command", name))
+}
+
+fn syntax_error() -> Frame {
+    Frame::error("ERR syntax error")
+}
+
+fn not_integer() -> Frame {
+    Frame::error("ERR value is not an integer or out of range")
+}
+
+fn engine_error(e: KvsError) -> Frame {
+    Frame::error(format!("ERR {}", e))
+}
+
+fn bulk_or_null(value: Option<String>) -> Frame {
+    value.map_or(Frame::Null, Frame::Bulk)
+}
+
+fn get_cmd(key: &str) -> Cmd {
+    Cmd::Get(GetCmd { key: key.to_string() })
+}
+
+fn set_cmd(key: &str, value: &str) -> Cmd {
+    Cmd::Set(SetCmd { key: key.to_string(), value: value.to_string(), expire: 0 })
+}
+
+fn remove_cmd(key: &str) -> Cmd {
+    Cmd::Remove(RemoveCmd { key: key.to_string() })
+}
+
+//key的范围[prefix, prefix+KEY_MAX],匹配所有以prefix开头的key
+fn prefix_range(prefix: &str) -> (String, String) {
+    (prefix.to_string(), format!("{}{}", prefix, KEY_MAX))
+}
+
+//glob模式中第一个通配符之前的部分
+fn literal_prefix(pattern: &str) -> &str {
+    let end = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+fn scan_cmd(pattern: &str) -> Cmd {
+    let prefix = literal_prefix(pattern);
+    Cmd::Scan(ScanCmd { start: prefix.to_string(), end: prefix.to_string() })
+}
+
+//过期时间转换为引擎使用的秒数,不足一秒的部分向上取整
+fn expire_secs(value: &str, millis: bool) -> std::result::Result<u32, Frame> {
+    let n: i64 = value.parse().map_err(|_| not_integer())?;
+    if n <= 0 {
+        return Err(Frame::error("ERR invalid expire time in 'set' command"));
+    }
+    let secs = if millis { (n as u64).div_ceil(1000) } else { n as u64 };
+    u32::try_from(secs).map_err(|_| Frame::error("ERR invalid expire time in 'set' command"))
+}
+
+fn execute<E: KVEngine>(name: &str, args: &[String], engine: &E, shared: &Shared, session: &mut Session, peer: Peer) -> Frame {
+    // 和原生协议等价的命令,用于检查用户权限
+    let acl_cmds: Vec<Cmd> = match name {
+        "get" | "exists" | "mget" | "type" => args.iter().map(|k| get_cmd(k)).collect(),
+        "set" | "setnx" => args.first().map(|k| set_cmd(k, "")).into_iter().collect(),
+        "setex" | "psetex" => args.first().map(|k| set_cmd(k, "")).into_iter().collect(),
+        "mset" => args.chunks(2).map(|kv| set_cmd(&kv[0], "")).collect(),
+        "del" | "unlink" => args.iter().map(|k| remove_cmd(k)).collect(),
+        "keys" => args.first().map(|p| scan_cmd(p)).into_iter().collect(),
+        "scan" => {
+            let pattern = args.iter().position(|a| a.eq_ignore_ascii_case("match")).and_then(|i| args.get(i + 1));
+            vec![scan_cmd(pattern.map_or("", String::as_str))]
+        }
+        "dbsize" => vec![Cmd::DbSize(DbSizeCmd)],
+        "info" => vec![Cmd::Info(InfoCmd)],
+        "config" if args.first().is_some_and(|a| a.eq_ignore_ascii_case("set")) => {
+            vec![Cmd::ConfigSet(ConfigSetCmd { key: String::new(), value: String::new() })]
+        }
+        "config" => vec![Cmd::ConfigGet(ConfigGetCmd { pattern: String::new() })],
+        "publish" => vec![Cmd::Publish(PublishCmd { channel: String::new(), message: String::new() })],
+        "replicaof" | "slaveof" => vec![Cmd::ReplicaOf(ReplicaOfCmd { leader: String::new() })],
+        _ => Vec::new(),
+    };
+    if let Some(denied) = session.check(shared, &acl_cmds) {
+        debug!("Client {} denied {}", peer, name);
+        return denied;
+    }
+    if let Some(denied) = Session::check_replica(shared, &acl_cmds) {
+        return denied;
+    }
+    //集群模式下重定向其他节点的key,重定向地址是目标节点的Redis协议地址
+    let _guard = match shared.cluster.check(&acl_cmds, engine) {
+        Ok(guard) => guard,
+        Err(e @ (KvsError::Moved(..) | KvsError::Ask(..) | KvsError::Cluster(_))) => {
+            return Frame::error(shared.cluster.resp_redirect(e).to_string());
+        }
+        Err(e) => return engine_error(e),
+    };
+    // 写入的key在执行期间加锁,NX/XX/GET读取旧值和写入之间没有其他写入
+    let _locks = shared.keys.lock(&acl_cmds);
+
+    match (name, args) {
+        ("ping", []) => Frame::Simple("PONG".to_string()),
+        ("ping", [message]) | ("echo", [message]) => Frame::Bulk(message.clone()),
+        ("quit", _) => {
+            session.quit = true;
+            Frame::ok()
+        }
+        ("select", [db]) => match db.parse::<i64>() {
+            Ok(0) => Frame::ok(),
+            Ok(_) => Frame::error("ERR DB index is out of range"),
+            Err(_) => not_integer(),
+        },
+        ("auth", [password]) => session.authenticate(shared, "default", password, peer).unwrap_or_else(Frame::ok),
+        ("auth", [user, password]) => session.authenticate(shared, user, password, peer).unwrap_or_else(Frame::ok),
+        ("hello", _) => hello(args, shared, session, peer),
+        ("client", [sub, rest @ ..]) => match (sub.to_lowercase().as_str(), rest) {
+            ("setname", [name]) => {
+                session.name = Some(name.clone());
+                Frame::ok()
+            }
+            ("getname", []) => bulk_or_null(session.name.clone()),
+            ("id", []) => Frame::Integer(session.id as i64),
+            ("setinfo", [_, _]) => Frame::ok(),
+            _ => Frame::error(format!("ERR unknown subcommand '{}'. Try CLIENT HELP.", sub)),
+        },
+        //redis-cli启动时查询命令文档,没有文档时返回空
+        ("command", _) => Frame::Array(Vec::new()),
+        ("get", [key]) => match engine.get(key.clone()) {
+            Ok(value) => bulk_or_null(value),
+            Err(e) => engine_error(e),
+        },
+        ("set", [key, value, options @ ..]) => set(engine, key, value, options),
+        ("setnx", [key, value]) => match engine.get(key.clone()) {
+            Ok(Some(_)) => Frame::Integer(0),
+            Ok(None) => match engine.set(key.clone(), value.clone(), 0) {
+                Ok(()) => Frame::Integer(1),
+                Err(e) => engine_error(e),
+            },
+            Err(e) => engine_error(e),
+        },
+        ("setex", [key, secs, value]) | ("psetex", [key, secs, value]) => match expire_secs(secs, name == "psetex") {
+            Ok(expire) => match engine.set(key.clone(), value.clone(), expire) {
+                Ok(()) => Frame::ok(),
+                Err(e) => engine_error(e),
+            },
+            Err(e) => e,
+        },
+        ("mget", keys) if !keys.is_empty() => {
+            let mut values = Vec::with_capacity(keys.len());
+            for key in keys {
+                match engine.get(key.clone()) {
+                    Ok(value) => values.push(bulk_or_null(value)),
+                    Err(e) => return engine_error(e),
+                }
+            }
+            Frame::Array(values)
+        }
+        ("mset", pairs) if !pairs.is_empty() && pairs.len().is_multiple_of(2) => {
+            for kv in pairs.chunks(2) {
+                if let Err(e) = engine.set(kv[0].clone(), kv[1].clone(), 0) {
+                    return engine_error(e);
+                }
+            }
+            Frame::ok()
+        }
+        ("del", keys) | ("unlink", keys) if !keys.is_empty() => {
+            let mut removed = 0;
+            for key in keys {
+                match engine.remove(key.clone()) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => (),
+                    Err(e) => return engine_error(e),
+                }
+            }
+            Frame::Integer(removed)
+        }
+        ("exists", keys) if !keys.is_empty() => {
+            let mut found = 0;
+            for key in keys {
+                match engine.get(key.clone()) {
+                    Ok(Some(_)) => found += 1,
+                    Ok(None) => (),
+                    Err(e) => return engine_error(e),
+                }
+            }
+            Frame::Integer(found)
+        }
+        ("type", [key]) => match engine.get(key.clone()) {
+            Ok(Some(_)) => Frame::Simple("string".to_string()),
+            Ok(None) => Frame::Simple("none".to_string()),
+            Err(e) => engine_error(e),
+        },
+        ("keys", [pattern]) => match matching_keys(engine, pattern) {
+            Ok(keys) => Frame::Array(keys.into_iter().map(Frame::Bulk).collect()),
+            Err(e) => engine_error(e),
+        },
+        ("scan", [cursor, options @ ..]) => scan(engine, cursor, options),
+        ("dbsize", []) => match engine.key_count() {
+            Ok(keys) => Frame::Integer(keys as i64),
+            Err(e) => engine_error(e),
+        },
+        ("info", [] | [_]) => match server_info(engine, shared) {
+            Ok(info) => Frame::Bulk(format_info(&info)),
+            Err(e) => engine_error(e),
+        },
+        ("config", [sub, rest @ ..]) => match (sub.to_lowercase().as_str(), rest) {
+            ("get", [pattern]) => Frame::Map(
+                config_get(shared, pattern)
+                    .into_iter()
+                    .map(|(k, v)| (Frame::Bulk(k), Frame::Bulk(v)))
+                    .collect(),
+            ),
+            ("set", [key, value]) => match config_set(shared, key, value) {
+                Ok(()) => Frame::ok(),
+                Err(e) => engine_error(e),
+            },
+            _ => Frame::error(format!("ERR unknown subcommand '{}'. Try CONFIG HELP.", sub)),
+        },
+        ("publish", [channel, message]) => Frame::Integer(shared.pubsub.publish(channel, message) as i64),
+        ("replicaof" | "slaveof", [host, port]) if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") => {
+            shared.replication.promote();
+            Frame::ok()
+        }
+        ("replicaof" | "slaveof", [host, port]) => match format!("{}:{}", host, port).parse::<ServerAddr>() {
+            Ok(leader) => match shared.replication.follow(engine.clone(), leader, shared.shut_down.clone()) {
+                Ok(()) => Frame::ok(),
+                Err(e) => engine_error(e),
+            },
+            Err(e) => Frame::error(format!("ERR {}", e)),
+        },
+        (
+            "ping" | "echo" | "select" | "auth" | "client" | "get" | "set" | "setnx" | "setex" | "psetex" | "mget" | "mset"
+            | "del" | "unlink" | "exists" | "type" | "keys" | "scan" | "dbsize" | "info" | "config" | "publish" | "replicaof"
+            | "slaveof",
+            _,
+        ) => wrong_args(name),
+        _ => {
+            let start: Vec<String> = args.iter().map(|a| format!("'{}'", a)).collect();
+            Frame::error(format!("ERR unknown command '{}', with args beginning with: {}", name, start.join(" ")))
+        }
+    }
+}
+
+// SET key value [NX|XX] [GET] [EX seconds|PX milliseconds]
+// NX/XX和GET先读取旧值,调用者持有key的锁
+fn set<E: KVEngine>(engine: &E, key: &str, value: &str, options: &[String]) -> Frame {
+    let (mut nx, mut xx, mut get) = (false, false, false);
+    let mut expire = None;
+    let mut i = 0;
+    while i < options.len() {
+        match options[i].to_lowercase().as_str() {
+            "nx" if !xx => nx = true,
+            "xx" if !nx => xx = true,
+            "get" => get = true,
+            unit @ ("ex" | "px") if expire.is_none() => {
+                let Some(n) = options.get(i + 1) else {
+                    return syntax_error();
+                };
+                match expire_secs(n, unit == "px") {
+                    Ok(secs) => expire = Some(secs),
+                    Err(e) => return e,
+                }
+                i += 1;
+            }
+            _ => return syntax_error(),
+        }
+        i += 1;
+    }
+    let old = if nx || xx || get {
+        match engine.get(key.to_string()) {
+            Ok(old) => old,
+            Err(e) => return engine_error(e),
+        }
+    } else {
+        None
+    };
+    if (nx && old.is_some()) || (xx && old.is_none()) {
+        return if get { bulk_or_null(old) } else { Frame::Null };
+    }
+    if let Err(e) = engine.set(key.to_string(), value.to_string(), expire.unwrap_or(0)) {
+        return engine_error(e);
+    }
+    if get { bulk_or_null(old) } else { Frame::ok() }
+}
+
+fn matching_keys<E: KVEngine>(engine: &E, pattern: &str) -> Result<Vec<String>> {
+    let (start, end) = prefix_range(literal_prefix(pattern));
+    Ok(engine
+        .scan_keys(start, end, 0, usize::MAX)?
+        .into_iter()
+        .filter(|k| glob_match(pattern, k))
+        .collect())
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+// 游标是按key排序后已经遍历的key数,期间写入的key可能被跳过或重复返回
+fn scan<E: KVEngine>(engine: &E, cursor: &str, options: &[String]) -> Frame {
+    let Ok(cursor) = cursor.parse::<usize>() else {
+        return Frame::error("ERR invalid cursor");
+    };
+    let mut pattern = "*";
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut strings = true;
+    for option in options.chunks(2) {
+        match (option[0].to_lowercase().as_str(), option.get(1)) {
+            ("match", Some(p)) => pattern = p,
+            ("count", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => count = n,
+                _ => return not_integer(),
+            },
+            ("type", Some(t)) => strings = t.eq_ignore_ascii_case("string"),
+            _ => return syntax_error(),
+        }
+    }
+    // 只读取这一页的key,多读一个判断是否还有下一页
+    let (start, end) = prefix_range(literal_prefix(pattern));
+    let mut page = match engine.scan_keys(start, end, cursor, count.saturating_add(1)) {
+        Ok(page) => page,
+        Err(e) => return engine_error(e),
+    };
+    let next = if page.len() > count { cursor + count } else { 0 };
+    page.truncate(count);
+    let keys: Vec<Frame> = page
+        .into_iter()
+        .filter(|k| strings && glob_match(pattern, k))
+        .map(Frame::Bulk)
+        .collect();
+    Frame::Array(vec![Frame::Bulk(next.to_string()), Frame::Array(keys)])
+}
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]
+fn hello(args: &[String], shared: &Shared, session: &mut Session, peer: Peer) -> Frame {
+    let mut resp3 = session.resp3;
+    let mut rest = args;
+    if let [version, tail @ ..] = args {
+        match version.as_str() {
+            "2" => resp3 = false,
+            "3" => resp3 = true,
+            _ => return Frame::error("NOPROTO unsupported protocol version"),
+        }
+        rest = tail;
+    }
+    let mut name = None;
+    while !rest.is_empty() {
+        match (rest[0].to_lowercase().as_str(), &rest[1..]) {
+            ("auth", [user, password, tail @ ..]) => {
+                if let Some(e) = session.authenticate(shared, user, password, peer) {
+                    return e;
+                }
+                rest = tail;
+            }
+            ("setname", [n, tail @ ..]) => {
+                name = Some(n.clone());
+                rest = tail;
+            }
+            _ => return syntax_error(),
+        }
+    }
+    if shared.options().acl.is_enabled() && session.user.is_none() {
+        return Frame::error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time");
+    }
+    if name.is_some() {
+        session.name = name;
+    }
+    session.resp3 = resp3;
+    let field = |k: &str, v: Frame| (Frame::Bulk(k.to_string()), v);
+    Frame::Map(vec![
+        field("server", Frame::Bulk("mini-kv".to_string())),
+        field("version", Frame::Bulk(env!("CARGO_PKG_VERSION").to_string())),
+        field("proto", Frame::Integer(if resp3 { 3 } else { 2 })),
+        field("id", Frame::Integer(session.id as i64)),
+        field("mode", Frame::Bulk("standalone".to_string())),
+        field("role", Frame::Bulk("master".to_string())),
+        field("modules", Frame::Array(Vec::new())),
+    ])
+}
+
+//INFO的文本格式,按Redis的分节和字段名输出
+fn format_info(info: &crate::ServerInfo) -> String {
+    let mut s = String::new();
+    let mut section = |name: &str, fields: Vec<(&str, String)>| {
+        s.push_str(&format!("# {}\r\n", name));
+        for (k, v) in fields {
+            s.push_str(&format!("{}:{}\r\n", k, v));
+        }
+        s.push_str("\r\n");
+    };
+    section("Server", vec![
+        ("mini_kv_version", info.version.clone()),
+        ("redis_mode", "standalone".to_string()),
+        ("uptime_in_seconds", info.uptime_secs.to_string()),
+        ("storage_engine", info.engine.engine.clone()),
+    ]);
+    section("Clients", vec![("connected_clients", info.connections.active_connections.to_string())]);
+    section("Stats", vec![
+        ("total_connections_received", info.connections.total_connections.to_string()),
+        ("total_commands_processed", info.commands_processed.to_string()),
+        ("rejected_connections", info.connections.rejected_connections.to_string()),
+    ]);
+    section("Persistence", vec![
+        ("disk_bytes", info.engine.disk_bytes.to_string()),
+        ("live_bytes", info.engine.live_bytes.to_string()),
+        ("stale_bytes", info.engine.stale_bytes.to_string()),
+        ("compactions", info.engine.compactions.to_string()),
+    ]);
+    let replication = &info.replication;
+    let mut fields = vec![
+        ("role", if replication.role == "replica" { "slave" } else { "master" }.to_string()),
+        ("connected_slaves", replication.replicas.to_string()),
+    ];
+    if let Some(leader) = &replication.leader {
+        let (host, port) = leader.rsplit_once(':').unwrap_or((leader.as_str(), ""));
+        fields.push(("master_host", host.to_string()));
+        fields.push(("master_port", port.to_string()));
+        fields.push(("master_link_status", if replication.link_up { "up" } else { "down" }.to_string()));
+        fields.push(("master_repl_offset", replication.applied_seq.to_string()));
+        fields.push(("master_lag_seconds", replication.lag_secs.unwrap_or(0).to_string()));
+    }
+    section("Replication", fields);
+    section("Keyspace", vec![("db0", format!("keys={},expires=0", info.engine.keys))]);
+    s.trim_end().to_string()
+}
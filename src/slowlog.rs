@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+//记录的命令参数最多保留的字节数,避免大value占用过多内存
+const MAX_ARG_LEN: usize = 128;
+
+/// A command that took longer than the slowlog threshold.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowlogEntry {
+    /// Increasing id of the entry, it is not reset by `SLOWLOG RESET`.
+    pub id: u64,
+    /// Unix timestamp in seconds when the command finished.
+    pub timestamp: u64,
+    pub duration_micros: u64,
+    pub client: String,
+    /// The command name followed by its arguments.
+    pub command: Vec<String>,
+}
+
+/// A bounded in-memory log of slow commands, the newest entry first.
+#[derive(Default)]
+pub struct SlowLog {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    entries: VecDeque<SlowlogEntry>,
+}
+
+impl SlowLog {
+    /// Records a command, dropping the oldest entries beyond `max_len`.
+    pub fn record(&self, client: impl Display, command: Vec<String>, elapsed: Duration, max_len: usize) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let command = command.into_iter().map(truncate).collect();
+        let mut inner = self.inner.lock().unwrap();
+        let entry = SlowlogEntry {
+            id: inner.next_id,
+            timestamp,
+            duration_micros: elapsed.as_micros() as u64,
+            client: client.to_string(),
+            command,
+        };
+        inner.next_id += 1;
+        inner.entries.push_front(entry);
+        inner.entries.truncate(max_len);
+    }
+
+    /// Returns up to `count` of the newest entries, all of them if `count` is 0.
+    pub fn get(&self, count: usize) -> Vec<SlowlogEntry> {
+        let inner = self.inner.lock().unwrap();
+        let count = if count == 0 { inner.entries.len() } else { count };
+        inner.entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+}
+
+fn truncate(mut arg: String) -> String {
+    if arg.len() > MAX_ARG_LEN {
+        let mut end = MAX_ARG_LEN;
+        while !arg.is_char_boundary(end) {
+            end -= 1;
+        }
+        let more = arg.len() - end;
+        arg.truncate(end);
+        arg.push_str(&format!("... ({} more bytes)", more));
+    }
+    arg
+}
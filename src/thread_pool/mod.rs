@@ -22,6 +22,11 @@ pub trait ThreadPool {
         F: FnOnce() + Send + 'static;
 
     fn stop(&mut self) -> Result<()>;
+
+    /// Returns the number of jobs waiting for a free thread.
+    fn queue_len(&self) -> usize {
+        0
+    }
 }
 
 pub use native::NativeThreadPool;
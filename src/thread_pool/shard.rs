@@ -83,6 +83,10 @@ impl ThreadPool for ShardThreadPool {
         self.sender.send(TaskMessage::NewTask(Box::new(job))).unwrap();
      }
 
+     fn queue_len(&self) -> usize {
+        self.sender.len()
+     }
+
      fn stop(&mut self) -> Result<()>{
         for _ in &self.workers {
             self.sender.send(TaskMessage::Terminate).unwrap();
@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use crate::{KvsError, Result};
+
+/// Builds the TLS config of the server listener from PEM files.
+///
+/// Clients have to present a certificate signed by `client_ca` if it is given.
+///
+/// # Errors
+///
+/// It returns `KvsError::Tls` if a file cannot be read or contains no usable
+/// certificate or key.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(ca)?), provider())
+                .build()
+                .map_err(tls_error)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+/// Builds the TLS config of `KvClient` trusting the certificates signed by `ca`.
+///
+/// `identity` is the certificate and key presented to servers requiring client certificates.
+///
+/// # Errors
+///
+/// It returns `KvsError::Tls` if a file cannot be read or contains no usable
+/// certificate or key.
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(root_store(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(tls_error)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn tls_error(e: impl std::fmt::Display) -> KvsError {
+    KvsError::Tls(e.to_string())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| KvsError::Tls(format!("cannot read {}: {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| KvsError::Tls(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!("{}: no certificate found", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| KvsError::Tls(format!("{}: {}", path.display(), e)))?
+        .ok_or_else(|| KvsError::Tls(format!("{}: no private key found", path.display())))
+}
+
+fn root_store(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| KvsError::Tls(format!("{}: {}", path.display(), e)))?;
+    }
+    Ok(roots)
+}
@@ -0,0 +1,144 @@
+use kvs::acl::{hash_password, verify_password};
+use kvs::common::{CdcCmd, GetCmd, InfoCmd, MonitorCmd, PingCmd, ScanCmd, SetCmd};
+use kvs::{Acl, Category, Cmd, Config, KvClient, KvServer, KvStore, KvsError, ServerOptions, ShardThreadPool, ThreadPool, User};
+use std::fs;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn user(name: &str, password: &str, commands: Vec<Category>, keys: Vec<&str>) -> User {
+    User {
+        name: name.to_string(),
+        password: hash_password(password),
+        commands,
+        keys: keys.into_iter().map(String::from).collect(),
+    }
+}
+
+#[test]
+fn password_hashes() {
+    let hash = hash_password("secret");
+    assert!(verify_password(&hash, "secret"));
+    assert!(!verify_password(&hash, "Secret"));
+    // the salt differs every time
+    assert_ne!(hash, hash_password("secret"));
+    assert!(!verify_password("secret", "secret"));
+}
+
+#[test]
+fn check_categories_and_prefixes() {
+    let app = user("app", "pw", vec![Category::Read, Category::Write], vec!["app:"]);
+    let get = |key: &str| Cmd::Get(GetCmd { key: key.to_string() });
+    let scan = |start: &str, end: &str| Cmd::Scan(ScanCmd { start: start.to_string(), end: end.to_string() });
+
+    assert!(app.check(&get("app:1")).is_ok());
+    assert!(app.check(&Cmd::Set(SetCmd { key: "app:1".to_string(), value: "v".to_string(), expire: 0 })).is_ok());
+    assert!(app.check(&scan("app:a", "app:z")).is_ok());
+    assert!(app.check(&Cmd::Ping(PingCmd { message: String::new() })).is_ok());
+    for denied in [get("other"), scan("app:a", "b"), Cmd::Info(InfoCmd), Cmd::Cdc(CdcCmd { after: 0 })] {
+        match app.check(&denied) {
+            Err(KvsError::NoPermission(_)) => (),
+            res => panic!("{:?} should be denied, got {:?}", denied, res.map_err(|e| e.to_string())),
+        }
+    }
+
+    let admin = user("admin", "pw", vec![Category::Admin], vec![]);
+    assert!(admin.check(&Cmd::Cdc(CdcCmd { after: 0 })).is_ok());
+    assert!(admin.check(&Cmd::Monitor(MonitorCmd)).is_ok());
+    assert!(admin.check(&get("any")).is_err());
+
+    // monitor shows the commands on every key
+    let ops = user("ops", "pw", vec![Category::Admin], vec!["app:"]);
+    assert!(matches!(ops.check(&Cmd::Monitor(MonitorCmd)), Err(KvsError::NoPermission(_))));
+}
+
+// an unknown user is hashed like a known one, the response time does not reveal user names
+#[test]
+fn unknown_users_take_as_long() {
+    let acl = Acl::new(vec![user("app", "pw", vec![Category::Read], vec![])]);
+    let time = |name: &str| {
+        let start = Instant::now();
+        assert!(matches!(acl.authenticate(name, "wrong"), Err(KvsError::AuthFailed)));
+        start.elapsed()
+    };
+    let known = (0..5).map(|_| time("app")).min().unwrap();
+    let unknown = (0..5).map(|_| time("nobody")).min().unwrap();
+    assert!(unknown * 2 > known, "unknown {:?}, known {:?}", unknown, known);
+}
+
+#[test]
+fn load_users_from_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.toml");
+    let hash = hash_password("secret");
+    fs::write(
+        &path,
+        format!("[[users]]\nname = \"app\"\npassword = \"{}\"\ncommands = [\"read\", \"vector\"]\nkeys = [\"app:\"]\n", hash),
+    )
+    .unwrap();
+    let config = Config::load(&path).unwrap();
+    let acl = config.server_options().acl;
+    assert!(acl.is_enabled());
+    let app = acl.authenticate("app", "secret").unwrap();
+    assert_eq!(app.commands, vec![Category::Read, Category::Vector]);
+    assert!(matches!(acl.authenticate("app", "wrong"), Err(KvsError::AuthFailed)));
+    assert!(matches!(acl.authenticate("nobody", "secret"), Err(KvsError::AuthFailed)));
+
+    let invalid = [
+        "[[users]]\nname = \"app\"\npassword = \"secret\"\n".to_string(),
+        "[[users]]\nname = \"app\"\npassword = \"x\"\ncommands = [\"delete\"]\n".to_string(),
+        format!("[[users]]\nname = \"app\"\npassword = \"{0}\"\n[[users]]\nname = \"app\"\npassword = \"{0}\"\n", hash),
+    ];
+    for content in invalid {
+        fs::write(&path, &content).unwrap();
+        assert!(matches!(Config::load(&path), Err(KvsError::Config(_))), "{} should be rejected", content);
+    }
+}
+
+#[tokio::test]
+async fn server_enforces_acl() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4601".parse().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(4).unwrap();
+    let options = ServerOptions {
+        acl: Acl::new(vec![
+            user("app", "app-pw", vec![Category::Read, Category::Write], vec!["app:"]),
+            user("admin", "admin-pw", vec![Category::Read, Category::Write, Category::Admin], vec![]),
+        ]),
+        ..ServerOptions::default()
+    };
+    let mut server = KvServer::new(store, addr, shutdown.clone(), pool).unwrap().with_options(options);
+    let handle = server.handle();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+        server.shut_down(Duration::from_secs(5)).unwrap();
+    });
+
+    let mut client = KvClient::new(addr).await.unwrap();
+    assert_eq!(client.ping(None).await.unwrap(), "PONG");
+    assert!(matches!(client.get("app:1").await, Err(KvsError::NoPermission(_))));
+    assert!(matches!(client.auth("app", "wrong").await, Err(KvsError::AuthFailed)));
+    client.auth("app", "app-pw").await.unwrap();
+    client.set("app:1", "v", None).await.unwrap();
+    assert_eq!(client.get("app:1").await.unwrap(), Some("v".to_string()));
+    assert!(matches!(client.set("other", "v", None).await, Err(KvsError::NoPermission(_))));
+    assert!(matches!(client.remove("other").await, Err(KvsError::NoPermission(_))));
+    assert!(matches!(client.dbsize().await, Err(KvsError::NoPermission(_))));
+
+    let mut admin = KvClient::new(addr).await.unwrap();
+    admin.auth("admin", "admin-pw").await.unwrap();
+    assert_eq!(admin.dbsize().await.unwrap(), 1);
+    admin.set("other", "v", None).await.unwrap();
+
+    // users removed by a reload have to authenticate again
+    let mut options = handle.options();
+    options.acl = Acl::new(vec![user("admin", "admin-pw", vec![Category::Admin], vec![])]);
+    handle.set_options(options);
+    assert!(matches!(client.get("app:1").await, Err(KvsError::NoPermission(_))));
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
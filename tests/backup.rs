@@ -0,0 +1,122 @@
+use kvs::engines::{restore, BackupManifest, BACKUP_MANIFEST};
+use kvs::{KVEngine, KvClient, KvServer, KvStore, KvStoreOptions, ShardThreadPool, SledStore, ThreadPool};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A backup taken while a writer keeps setting keys holds a prefix of the writes.
+fn backup_while_writing<E: KVEngine>(store: E, open: impl Fn(&Path) -> E) {
+    for i in 0..200 {
+        store.set(format!("key{:04}", i), "old".to_owned(), 0).unwrap();
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (store, stop) = (store.clone(), stop.clone());
+        thread::spawn(move || {
+            let mut i = 0;
+            while !stop.load(Ordering::SeqCst) {
+                store.set(format!("key{:04}", i % 400), format!("new{}", i), 0).unwrap();
+                i += 1;
+            }
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    let dir = TempDir::new().unwrap();
+    let manifest = store.backup(&dir.path().join("backup")).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+    assert!(manifest.keys >= 200);
+    assert_eq!(BackupManifest::load(&dir.path().join("backup")).unwrap(), manifest);
+
+    let restored = dir.path().join("restored");
+    assert_eq!(restore(&dir.path().join("backup"), &restored, false).unwrap(), manifest);
+    let copy = open(&restored);
+    let pairs = copy.scan(String::new(), "~".to_owned()).unwrap();
+    assert_eq!(pairs.len() as u64, manifest.keys);
+    // 新值的序号连续,后写入的key出现时先写入的key也已经是新值
+    let mut seqs: Vec<u64> = pairs.iter().filter_map(|(_, v)| v.strip_prefix("new")).map(|n| n.parse().unwrap()).collect();
+    seqs.sort_unstable();
+    assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1), "backup is not a prefix of the writes");
+    assert!(store.stats().unwrap().keys >= manifest.keys);
+}
+
+#[test]
+fn kvs_backup_while_writing() {
+    let temp_dir = TempDir::new().unwrap();
+    // 小的压缩阈值使备份期间发生压缩
+    let options = KvStoreOptions { compaction_threshold: 10_000, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    backup_while_writing(store, |path| KvStore::open(path).unwrap());
+}
+
+#[test]
+fn sled_backup_while_writing() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    backup_while_writing(store, |path| SledStore::open(path).unwrap());
+}
+
+#[test]
+fn restore_validates_backups() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("store")).unwrap();
+    store.set("a".to_owned(), "1".to_owned(), 0).unwrap();
+    let backup = temp_dir.path().join("backup");
+    store.backup(&backup).unwrap();
+    // 备份目录必须为空
+    assert!(store.backup(&backup).is_err());
+
+    // 已有的存储只在force时替换,并保留原来的目录
+    let dest = temp_dir.path().join("data").join("kvs");
+    KvStore::open(&dest).unwrap().set("b".to_owned(), "2".to_owned(), 0).unwrap();
+    assert!(restore(&backup, &dest, false).is_err());
+    restore(&backup, &dest, true).unwrap();
+    let restored = KvStore::open(&dest).unwrap();
+    assert_eq!(restored.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+    assert_eq!(restored.get("b".to_owned()).unwrap(), None);
+    let kept = fs::read_dir(temp_dir.path().join("data")).unwrap().filter(|e| {
+        e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".old")
+    });
+    assert_eq!(kept.count(), 1);
+
+    // 截断的文件和不完整的备份不会被恢复
+    let manifest = BackupManifest::load(&backup).unwrap();
+    let log = manifest.files.iter().find(|f| f.name.ends_with(".log")).unwrap();
+    fs::OpenOptions::new().write(true).open(backup.join(&log.name)).unwrap().set_len(log.len - 1).unwrap();
+    assert!(restore(&backup, &temp_dir.path().join("other"), false).is_err());
+    fs::remove_file(backup.join(BACKUP_MANIFEST)).unwrap();
+    assert!(restore(&backup, &temp_dir.path().join("other"), false).is_err());
+    assert!(!temp_dir.path().join("other").exists());
+}
+
+#[tokio::test]
+async fn backup_through_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("store")).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(4).unwrap();
+    let addr = "127.0.0.1:5501".parse().unwrap();
+    let mut server = KvServer::new(store, addr, shutdown.clone(), pool).unwrap();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+        server.shut_down(Duration::from_secs(5)).unwrap();
+    });
+
+    let mut client = KvClient::new(addr).await.unwrap();
+    client.set("a", "1", None).await.unwrap();
+    client.set("b", "2", None).await.unwrap();
+    let backup = temp_dir.path().join("backup");
+    let manifest = client.backup(backup.to_str().unwrap()).await.unwrap();
+    assert_eq!((manifest.engine.as_str(), manifest.keys), ("kvs", 2));
+    assert!(client.backup(backup.to_str().unwrap()).await.is_err());
+    shutdown.store(true, Ordering::SeqCst);
+    handle.join().unwrap();
+
+    restore(&backup, &temp_dir.path().join("restored"), false).unwrap();
+    let restored = KvStore::open(temp_dir.path().join("restored")).unwrap();
+    assert_eq!(restored.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+}
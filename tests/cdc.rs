@@ -0,0 +1,146 @@
+use kvs::{CdcRecord, ChangeOp, KVEngine, KvClient, KvServer, KvStore, KvStoreOptions, KvsError, ShardThreadPool, SledStore, ThreadPool};
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// Mutations are read in order, with the sequence numbers of the change events,
+// and a cursor resumes after any sequence number it returned.
+fn records_in_order<E: KVEngine>(store: E) {
+    let changes = store.watch("");
+    store.set("a".to_owned(), "1".to_owned(), 0).unwrap();
+    store.set("b".to_owned(), "2".to_owned(), 0).unwrap();
+    store.remove("a".to_owned()).unwrap();
+    assert!(store.remove("a".to_owned()).is_err());
+
+    let mut cursor = store.cdc(0).unwrap();
+    let records: Vec<CdcRecord> = (0..3).map(|_| cursor.next().unwrap().unwrap()).collect();
+    assert!(cursor.next().unwrap().is_none());
+    assert_eq!(
+        records.iter().map(|r| (r.op, r.key.as_str(), r.value.as_deref())).collect::<Vec<_>>(),
+        vec![(ChangeOp::Set, "a", Some("1")), (ChangeOp::Set, "b", Some("2")), (ChangeOp::Remove, "a", None)]
+    );
+    let versions: Vec<u64> = changes.try_iter().map(|e| e.version).collect();
+    assert_eq!(records.iter().map(|r| r.seq).collect::<Vec<_>>(), versions);
+
+    store.set("c".to_owned(), "3".to_owned(), 0).unwrap();
+    assert_eq!(cursor.next().unwrap().unwrap().key, "c");
+
+    let mut resumed = store.cdc(records[1].seq).unwrap();
+    let keys: Vec<String> = (0..2).map(|_| resumed.next().unwrap().unwrap().key).collect();
+    assert_eq!(keys, vec!["a", "c"]);
+    assert!(resumed.next().unwrap().is_none());
+}
+
+#[test]
+fn kvs_records_in_order() {
+    let temp_dir = TempDir::new().unwrap();
+    records_in_order(KvStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn sled_records_in_order() {
+    let temp_dir = TempDir::new().unwrap();
+    records_in_order(SledStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn sled_records_survive_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned(), 0).unwrap();
+    drop(store);
+
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    store.set("b".to_owned(), "2".to_owned(), 0).unwrap();
+    let mut cursor = store.cdc(0).unwrap();
+    let first = cursor.next().unwrap().unwrap();
+    let second = cursor.next().unwrap().unwrap();
+    assert_eq!((first.key.as_str(), second.key.as_str()), ("a", "b"));
+    assert!(first.seq < second.seq);
+}
+
+// A cursor keeping up with the writer follows it across compactions without
+// reading the compacted snapshots.
+#[test]
+fn kvs_records_across_compactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions { compaction_threshold: 1000, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let value = "v".repeat(100);
+    let mut cursor = store.cdc(0).unwrap();
+    let mut last = 0;
+    for i in 0..50 {
+        store.set("key".to_owned(), format!("{}{}", value, i), 0).unwrap();
+        let record = cursor.next().unwrap().unwrap();
+        assert_eq!(record.value, Some(format!("{}{}", value, i)));
+        assert!(record.seq > last);
+        last = record.seq;
+    }
+    assert!(cursor.next().unwrap().is_none());
+    assert!(store.stats().unwrap().compactions > 1);
+
+    // the early mutations are compacted away
+    match store.cdc(1 << 40) {
+        Err(KvsError::CdcUnavailable(seq)) => assert_eq!(seq, 1 << 40),
+        _ => panic!("expected CdcUnavailable"),
+    }
+
+    // a new cursor starts from the latest snapshot
+    store.set("other".to_owned(), "x".to_owned(), 0).unwrap();
+    let mut cursor = store.cdc(0).unwrap();
+    let mut keys = Vec::new();
+    while let Some(record) = cursor.next().unwrap() {
+        keys.push(record.key);
+    }
+    assert_eq!(keys.last().map(String::as_str), Some("other"));
+    assert!(keys.len() < 50);
+}
+
+// Reading through the server streams the mutations made by other clients.
+async fn cdc_through_server<E: KVEngine>(store: E, addr: SocketAddr) {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(4).unwrap();
+    let mut server = KvServer::new(store, addr, shutdown.clone(), pool).unwrap();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+        server.shut_down(Duration::from_secs(5)).unwrap();
+    });
+
+    let mut client = KvClient::new(addr).await.unwrap();
+    client.set("a", "1", None).await.unwrap();
+    let mut stream = KvClient::new(addr).await.unwrap().cdc(0).await.unwrap();
+    let first = stream.next().await.unwrap().unwrap();
+    assert_eq!((first.key.as_str(), first.value.as_deref()), ("a", Some("1")));
+
+    client.set("b", "2", None).await.unwrap();
+    client.remove("a").await.unwrap();
+    let set = tokio::time::timeout(TIMEOUT, stream.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!((set.key.as_str(), set.op), ("b", ChangeOp::Set));
+    let remove = stream.next().await.unwrap().unwrap();
+    assert_eq!((remove.key.as_str(), remove.op), ("a", ChangeOp::Remove));
+
+    let mut resumed = KvClient::new(addr).await.unwrap().cdc(first.seq).await.unwrap();
+    assert_eq!(resumed.next().await.unwrap().unwrap(), set);
+
+    shutdown.store(true, Ordering::SeqCst);
+    handle.join().unwrap();
+    assert!(stream.next().await.is_err());
+}
+
+#[tokio::test]
+async fn kvs_cdc_through_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    cdc_through_server(store, "127.0.0.1:4501".parse().unwrap()).await;
+}
+
+#[tokio::test]
+async fn sled_cdc_through_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    cdc_through_server(store, "127.0.0.1:4502".parse().unwrap()).await;
+}
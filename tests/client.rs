@@ -0,0 +1,90 @@
+mod common;
+
+use common::Server;
+use kvs::{KvClient, KvsError};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+#[tokio::test]
+async fn client_typed_methods() {
+    let addr: SocketAddr = "127.0.0.1:4101".parse().unwrap();
+    let server = Server::start(addr);
+    let mut client = KvClient::new(addr).await.unwrap();
+
+    assert_eq!(client.ping(None).await.unwrap(), "PONG");
+    assert_eq!(client.ping(Some("hello")).await.unwrap(), "hello");
+
+    assert_eq!(client.get("key1").await.unwrap(), None);
+    client.set("key1", "value1", None).await.unwrap();
+    client.set("key2", "value2", Some(Duration::from_secs(100))).await.unwrap();
+    client.set("key3", "value3", None).await.unwrap();
+    assert_eq!(client.get("key1").await.unwrap(), Some("value1".to_owned()));
+
+    let pairs = client.scan("key1"..="key2").await.unwrap();
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+
+    // key和value中的空格和换行原样返回
+    client.set("key1 b", "two words\nand a line", None).await.unwrap();
+    let pairs = client.scan("key1 "..="key1 z").await.unwrap();
+    assert_eq!(pairs, vec![("key1 b".to_owned(), "two words\nand a line".to_owned())]);
+    client.remove("key1 b").await.unwrap();
+
+    client.remove("key1").await.unwrap();
+    assert_eq!(client.get("key1").await.unwrap(), None);
+    assert!(matches!(client.remove("key1").await, Err(KvsError::KeyNotFound)));
+
+    client.vset("vec1", &[1.0, 2.5, -3.0], None).await.unwrap();
+    assert_eq!(client.vget("vec1").await.unwrap(), Some(vec![1.0, 2.5, -3.0]));
+    client.vdel("vec1").await.unwrap();
+    assert_eq!(client.vget("vec1").await.unwrap(), None);
+    assert!(client.vset("vec2", &[], None).await.is_err());
+
+    // 不足一秒的ttl向上取整,key仍会过期
+    client.set("short", "v", Some(Duration::from_millis(500))).await.unwrap();
+    thread::sleep(Duration::from_millis(2100));
+    assert_eq!(client.get("short").await.unwrap(), None);
+
+    server.stop();
+}
+
+#[tokio::test]
+async fn client_admin_commands() {
+    let addr: SocketAddr = "127.0.0.1:4102".parse().unwrap();
+    let server = Server::start(addr);
+    let mut client = KvClient::new(addr).await.unwrap();
+
+    assert_eq!(client.dbsize().await.unwrap(), 0);
+    client.set("key1", "value1", None).await.unwrap();
+    client.set("key2", "value2", None).await.unwrap();
+    client.set("key1", "value3", None).await.unwrap();
+    assert_eq!(client.dbsize().await.unwrap(), 2);
+
+    let info = client.info().await.unwrap();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.engine.engine, "kvs");
+    assert_eq!(info.engine.keys, 2);
+    assert!(info.engine.stale_bytes > 0);
+    assert_eq!(info.engine.disk_bytes, info.engine.live_bytes + info.engine.stale_bytes);
+    assert_eq!(info.connections.active_connections, 1);
+    assert_eq!(info.commands_processed, 6);
+
+    client.config_set("idle_timeout", "30").await.unwrap();
+    client.config_set("max_connections", "8").await.unwrap();
+    assert_eq!(
+        client.config_get("idle_timeout").await.unwrap(),
+        vec![("idle_timeout".to_owned(), "30".to_owned())]
+    );
+    assert_eq!(client.config_get("*").await.unwrap().len(), 7);
+    assert!(client.config_get("unknown").await.unwrap().is_empty());
+    assert!(client.config_set("unknown", "1").await.is_err());
+    assert!(client.config_set("read_timeout", "soon").await.is_err());
+
+    server.stop();
+}
@@ -0,0 +1,165 @@
+mod common;
+
+use common::{Server, TestServer};
+use kvs::cluster::{key_slot, parse_slots, SlotRange, SlotState};
+use kvs::{ClusterClient, KvClient, KvsError, ServerAddr};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+// 以`slots`为槽位表启动集群节点
+fn start_node(addr: &str, slots: &[SlotRange]) -> Server {
+    start_node_with(addr, slots, |server| server)
+}
+
+// `configure`添加测试需要的其他设置
+fn start_node_with(addr: &str, slots: &[SlotRange], configure: impl FnOnce(TestServer) -> TestServer) -> Server {
+    Server::start_with(addr.parse().unwrap(), |server| configure(server.with_cluster(addr, slots).unwrap()))
+}
+
+fn slots(ranges: &[(u16, u16, &str)]) -> Vec<SlotRange> {
+    ranges.iter().map(|&(start, end, addr)| SlotRange { start, end, addr: addr.to_string() }).collect()
+}
+
+async fn connect(addr: &str) -> KvClient {
+    KvClient::new(addr.parse::<SocketAddr>().unwrap()).await.unwrap()
+}
+
+#[test]
+fn key_slots() {
+    assert_eq!(key_slot("foo"), 12182);
+    assert_eq!(key_slot("bar"), 5061);
+    assert_eq!(key_slot("123456789"), 0x31C3);
+    assert_eq!(key_slot("{user1000}.following"), key_slot("{user1000}.followers"));
+    assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+    // 空的tag不生效
+    assert_eq!(key_slot("foo{}{bar}"), 8363);
+    assert_eq!(parse_slots("0-8191").unwrap(), 0..=8191);
+    assert_eq!(parse_slots("100").unwrap(), 100..=100);
+    assert!(parse_slots("8192-100").is_err());
+    assert!(parse_slots("0-16384").is_err());
+    assert!(SlotState::from_parts("node", "").is_err());
+    assert_eq!(SlotState::from_parts("Migrating", "127.0.0.1:1").unwrap(), SlotState::Migrating("127.0.0.1:1".to_string()));
+}
+
+#[tokio::test]
+async fn cluster_redirects_and_routes() {
+    let (first, second) = ("127.0.0.1:5301", "127.0.0.1:5302");
+    let map = slots(&[(0, 8191, first), (8192, 16383, second)]);
+    let servers = vec![
+        start_node_with(first, &map, |server| {
+            server.with_resp("127.0.0.1:5311".parse().unwrap()).unwrap().with_cluster_resp_addr(second, "127.0.0.1:5312")
+        }),
+        start_node(second, &map),
+    ];
+
+    // Redis协议的客户端重定向到目标节点的Redis协议地址
+    let mut resp = TcpStream::connect("127.0.0.1:5311").unwrap();
+    resp.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n").unwrap();
+    let mut line = String::new();
+    BufReader::new(resp).read_line(&mut line).unwrap();
+    assert_eq!(line, "-MOVED 12182 127.0.0.1:5312\r\n");
+
+    // 不属于本节点的key返回MOVED
+    let mut client = connect(first).await;
+    match client.set("foo", "1", None).await {
+        Err(KvsError::Moved(slot, addr)) => assert_eq!((slot, addr.as_str()), (12182, second)),
+        other => panic!("unexpected {:?}", other),
+    }
+    client.set("bar", "1", None).await.unwrap();
+    assert_eq!(client.cluster_slots().await.unwrap().slots, map);
+
+    let mut cluster = ClusterClient::connect(&[first.parse::<ServerAddr>().unwrap()], None).await.unwrap();
+    assert_eq!(cluster.nodes(), vec![first.to_string(), second.to_string()]);
+    for i in 0..100 {
+        cluster.set(&format!("k{:03}", i), &i.to_string(), None).await.unwrap();
+    }
+    assert_eq!(cluster.get("k042").await.unwrap(), Some("42".to_string()));
+    cluster.remove("k042").await.unwrap();
+    assert_eq!(cluster.get("k042").await.unwrap(), None);
+    assert!(matches!(cluster.remove("k042").await, Err(KvsError::KeyNotFound)));
+    cluster.vset("vec", &[1.0, 2.5], None).await.unwrap();
+    assert_eq!(cluster.vget("vec").await.unwrap(), Some(vec![1.0, 2.5]));
+
+    // 扫描合并全部节点的结果
+    let pairs = cluster.scan("k000"..="k999").await.unwrap();
+    assert_eq!(pairs.len(), 99);
+    assert!(pairs.windows(2).all(|p| p[0].0 < p[1].0));
+    let mut first_keys = client.scan("k000"..="k999").await.unwrap().len();
+    first_keys += connect(second).await.scan("k000"..="k999").await.unwrap().len();
+    assert_eq!(first_keys, 99);
+
+    for server in servers {
+        server.stop();
+    }
+}
+
+#[tokio::test]
+async fn cluster_migrates_slots_online() {
+    let (first, second, third) = ("127.0.0.1:5303", "127.0.0.1:5304", "127.0.0.1:5305");
+    let map = slots(&[(0, 8191, first), (8192, 16383, second)]);
+    let servers = vec![start_node(first, &map), start_node(second, &map), start_node(third, &map)];
+    let slot = key_slot("foo");
+    let mut cluster = ClusterClient::connect(&[second.parse::<ServerAddr>().unwrap()], None).await.unwrap();
+    let mut stale = ClusterClient::connect(&[first.parse::<ServerAddr>().unwrap()], None).await.unwrap();
+    for i in 0..10 {
+        cluster.set(&format!("{{foo}}{}", i), &i.to_string(), None).await.unwrap();
+    }
+    cluster.set("{foo}gone", "v", Some(Duration::from_secs(1))).await.unwrap();
+    thread::sleep(Duration::from_millis(2100));
+    cluster.set("{foo}ttl", "v", Some(Duration::from_secs(2))).await.unwrap();
+
+    // 迁移中key还在源节点时由源节点处理,已移走或新的key转到目标节点
+    let (mut source, mut target) = (connect(second).await, connect(third).await);
+    target.cluster_set_slot(slot..=slot, &SlotState::Importing(second.to_string())).await.unwrap();
+    source.cluster_set_slot(slot..=slot, &SlotState::Migrating(third.to_string())).await.unwrap();
+    assert_eq!(source.get("{foo}1").await.unwrap(), Some("1".to_string()));
+    // 过期的key视为已移走
+    assert!(matches!(source.get("{foo}gone").await, Err(KvsError::Ask(..))));
+    match source.set("{foo}new", "v", None).await {
+        Err(KvsError::Ask(s, addr)) => assert_eq!((s, addr.as_str()), (slot, third)),
+        other => panic!("unexpected {:?}", other),
+    }
+    cluster.set("{foo}new", "v", None).await.unwrap();
+    assert_eq!(target.get("{foo}new").await.unwrap(), Some("v".to_string()));
+    let map = source.cluster_slots().await.unwrap();
+    assert_eq!(map.migrating.get(&slot).map(String::as_str), Some(third));
+
+    assert_eq!(source.cluster_migrate(slot).await.unwrap(), 11);
+    assert!(matches!(source.get("{foo}1").await, Err(KvsError::Ask(..))));
+    assert_eq!(cluster.get("{foo}1").await.unwrap(), Some("1".to_string()));
+    // 移动的key保留剩余的TTL
+    assert_eq!(target.get("{foo}ttl").await.unwrap(), Some("v".to_string()));
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(target.get("{foo}ttl").await.unwrap(), None);
+    source.cluster_set_slot(slot..=slot, &SlotState::Stable).await.unwrap();
+    target.cluster_set_slot(slot..=slot, &SlotState::Stable).await.unwrap();
+
+    // migrate_slot完成全部步骤并通知所有节点
+    let other = key_slot("bar");
+    cluster.set("{bar}x", "y", None).await.unwrap();
+    assert_eq!(cluster.migrate_slot(other, third).await.unwrap(), 1);
+    assert_eq!(cluster.migrate_slot(slot, third).await.unwrap(), 0);
+    for addr in [first, second, third] {
+        let map = connect(addr).await.cluster_slots().await.unwrap();
+        assert_eq!(map.owner(slot), Some(third));
+        assert_eq!(map.owner(other), Some(third));
+        assert!(map.migrating.is_empty() && map.importing.is_empty());
+    }
+    match source.get("{foo}2").await {
+        Err(KvsError::Moved(s, addr)) => assert_eq!((s, addr.as_str()), (slot, third)),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(cluster.get("{bar}x").await.unwrap(), Some("y".to_string()));
+    assert_eq!(cluster.scan("{foo}0"..="{foo}9").await.unwrap().len(), 10);
+
+    // 缓存过期的客户端跟随MOVED
+    assert_eq!(stale.owner(slot), Some(second));
+    assert_eq!(stale.get("{foo}3").await.unwrap(), Some("3".to_string()));
+    assert_eq!(stale.owner(slot), Some(third));
+
+    for server in servers {
+        server.stop();
+    }
+}
@@ -0,0 +1,43 @@
+// 集成测试共用的服务端,每个测试文件只用到其中一部分
+#![allow(dead_code)]
+
+use kvs::{KvServer, KvStore, ShardThreadPool, ThreadPool};
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+pub type TestServer = KvServer<KvStore, ShardThreadPool>;
+
+// A kvs server on a `KvStore` in a temporary directory, run in a background thread.
+pub struct Server {
+    shutdown: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+    _dir: TempDir,
+}
+
+impl Server {
+    pub fn start(addr: SocketAddr) -> Server {
+        Server::start_with(addr, |server| server)
+    }
+
+    // `configure` applies the builders of a test before the server runs.
+    pub fn start_with(addr: SocketAddr, configure: impl FnOnce(TestServer) -> TestServer) -> Server {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let pool = ShardThreadPool::new(4).unwrap();
+        let mut server = configure(KvServer::new(store, addr, shutdown.clone(), pool).unwrap());
+        let handle = thread::spawn(move || {
+            server.run().unwrap();
+            server.shut_down(Duration::from_secs(5)).unwrap();
+        });
+        Server { shutdown, handle, _dir: dir }
+    }
+
+    pub fn stop(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.handle.join().unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+use kvs::{KVEngine, KvStore, KvStoreOptions};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn small_threshold() -> KvStoreOptions {
+    let mut options = KvStoreOptions::default();
+    options.compaction_threshold = 1000;
+    options
+}
+
+fn generations(dir: &Path) -> Vec<u64> {
+    let mut gens: Vec<u64> = fs::read_dir(dir)
+        .unwrap()
+        .filter_map(|entry| entry.unwrap().file_name().to_str()?.strip_suffix(".log")?.parse().ok())
+        .collect();
+    gens.sort_unstable();
+    gens
+}
+
+// 覆盖写入一个key触发压缩,其他key只在压缩生成的快照中
+fn churn(store: &KvStore, times: usize) {
+    for i in 0..times {
+        store.set("churn".to_owned(), format!("value{}", i), 0).unwrap();
+    }
+}
+
+#[test]
+fn compaction_keeps_latest_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open_with_options(temp_dir.path(), small_threshold()).unwrap();
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i), 0).unwrap();
+    }
+    store.remove("key9".to_owned()).unwrap();
+    churn(&store, 200);
+    assert!(generations(temp_dir.path())[0] > 1);
+    for i in 0..9 {
+        assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..9 {
+        assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key9".to_owned()).unwrap(), None);
+    assert_eq!(store.get("churn".to_owned()).unwrap(), Some("value199".to_owned()));
+}
+
+#[test]
+fn compaction_keeps_ttls() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open_with_options(temp_dir.path(), small_threshold()).unwrap();
+    store.set("short".to_owned(), "1".to_owned(), 1).unwrap();
+    store.set("long".to_owned(), "2".to_owned(), 100).unwrap();
+    churn(&store, 200);
+    assert!(generations(temp_dir.path())[0] > 1);
+    std::thread::sleep(std::time::Duration::from_millis(2100));
+    assert_eq!(store.get("short".to_owned()).unwrap(), None);
+    assert_eq!(store.key_count().unwrap(), 2);
+    assert_eq!(store.get("long".to_owned()).unwrap(), Some("2".to_owned()));
+}
+
+// 压缩丢弃过期的key,key_count不再计入它们
+#[test]
+fn compaction_drops_expired_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open_with_options(temp_dir.path(), small_threshold()).unwrap();
+    store.set("short".to_owned(), "1".to_owned(), 1).unwrap();
+    store.set("long".to_owned(), "2".to_owned(), 100).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(2100));
+    assert_eq!(store.key_count().unwrap(), 2);
+    churn(&store, 200);
+    assert!(generations(temp_dir.path())[0] > 1);
+    assert_eq!(store.key_count().unwrap(), 2);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.key_count().unwrap(), 2);
+    assert_eq!(store.get("short".to_owned()).unwrap(), None);
+    assert_eq!(store.get("long".to_owned()).unwrap(), Some("2".to_owned()));
+}
+
+// 默认阈值为1MB,少量过期数据不会触发压缩
+#[test]
+fn default_threshold() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let value = "v".repeat(200);
+    for i in 0..500 {
+        store.set("key".to_owned(), format!("{}{}", value, i), 0).unwrap();
+    }
+    assert_eq!(generations(temp_dir.path()), vec![1]);
+    for i in 0..5000 {
+        store.set("key".to_owned(), format!("{}{}", value, i), 0).unwrap();
+    }
+    assert!(generations(temp_dir.path())[0] > 1);
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some(format!("{}4999", value)));
+}
@@ -0,0 +1,116 @@
+use kvs::engines::LogReader;
+use kvs::{Compression, KVEngine, KvStore, KvStoreOptions};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn json(i: usize) -> String {
+    let items: Vec<String> = (0..50).map(|j| format!("{{\"id\":{},\"name\":\"item{}\",\"tags\":[\"a\",\"b\"]}}", j, i)).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn options(compression: Compression) -> KvStoreOptions {
+    KvStoreOptions { compression, compression_threshold: 100, ..Default::default() }
+}
+
+fn log_bytes(dir: &Path) -> u64 {
+    KvStore::inspect(dir).unwrap().iter().map(|r| r.bytes).sum()
+}
+
+// 压缩后日志变小,读取时透明解压,重新打开后仍然可读
+fn compresses_values(compression: Compression) {
+    let temp_dir = TempDir::new().unwrap();
+    let raw_dir = temp_dir.path().join("raw");
+    let dir = temp_dir.path().join("compressed");
+    let raw = KvStore::open_with_options(&raw_dir, options(Compression::None)).unwrap();
+    let store = KvStore::open_with_options(&dir, options(compression)).unwrap();
+    for i in 0..100 {
+        raw.set(format!("key{}", i), json(i), 0).unwrap();
+        store.set(format!("key{}", i), json(i), 0).unwrap();
+    }
+    store.set("small".to_owned(), "tiny".to_owned(), 0).unwrap();
+    assert!(log_bytes(&dir) * 3 < log_bytes(&raw_dir));
+    assert_eq!(store.get("key7".to_owned()).unwrap(), Some(json(7)));
+    assert_eq!(store.scan("key1".to_owned(), "key10".to_owned()).unwrap()[1], ("key10".to_owned(), json(10)));
+    drop(store);
+
+    // 其他压缩方式打开时旧记录保持原来的压缩方式
+    let store = KvStore::open(&dir).unwrap();
+    assert_eq!(store.get("key99".to_owned()).unwrap(), Some(json(99)));
+    store.set("plain".to_owned(), json(0), 0).unwrap();
+    let records: Vec<_> = LogReader::open(&dir.join("1.log")).unwrap().collect();
+    assert_eq!(records[0].compression, compression);
+    assert_eq!(records[0].value, Some(json(0)));
+    // 小于阈值的value不压缩
+    assert_eq!(records[100].compression, Compression::None);
+    assert_eq!(records[101].compression, Compression::None);
+}
+
+#[test]
+fn lz4_values() {
+    compresses_values(Compression::Lz4);
+}
+
+#[test]
+fn zstd_values() {
+    compresses_values(Compression::Zstd);
+}
+
+#[test]
+fn incompressible_values_are_stored_raw() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open_with_options(temp_dir.path(), options(Compression::Lz4)).unwrap();
+    // 没有重复的内容,lz4压缩后反而更长
+    let mut x: u32 = 2463534242;
+    let value: String = (0..500)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            char::from(b'!' + (x % 90) as u8)
+        })
+        .collect();
+    store.set("key".to_owned(), value.clone(), 0).unwrap();
+    let record = LogReader::open(&temp_dir.path().join("1.log")).unwrap().next().unwrap();
+    assert_eq!(record.compression, Compression::None);
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some(value));
+}
+
+#[test]
+fn compaction_keeps_compressed_records() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions { compaction_threshold: 10_000, ..options(Compression::Lz4) };
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let mut cursor = store.cdc(0).unwrap();
+    for round in 0..20 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("{}{}", json(i), round), 0).unwrap();
+        }
+    }
+    assert!(store.stats().unwrap().compactions > 0);
+    let first = cursor.next().unwrap().unwrap();
+    assert_eq!(first.value, Some(format!("{}0", json(0))));
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(format!("{}19", json(i))));
+    }
+    let snapshot = KvStore::inspect(temp_dir.path()).unwrap().into_iter().find(|r| r.is_compacted()).unwrap();
+    let path = temp_dir.path().join(format!("{}.log", snapshot.generation));
+    assert!(LogReader::open(&path).unwrap().all(|r| r.compression == Compression::Lz4));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key3".to_owned()).unwrap(), Some(format!("{}19", json(3))));
+    assert!(fs::metadata(&path).is_ok());
+}
+
+#[test]
+fn repair_keeps_compression() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open_with_options(temp_dir.path(), options(Compression::Zstd)).unwrap();
+    store.set("key".to_owned(), json(1), 0).unwrap();
+    drop(store);
+    let report = KvStore::repair(temp_dir.path(), false).unwrap();
+    let path = temp_dir.path().join(format!("{}.log", report.generation));
+    let record = LogReader::open(&path).unwrap().next().unwrap();
+    assert_eq!((record.compression, record.value), (Compression::Zstd, Some(json(1))));
+}
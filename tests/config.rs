@@ -0,0 +1,87 @@
+use kvs::{Acl, Config, KvsError, ServerOptions};
+use kvs::config::RaftMemberConfig;
+use std::fs;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn load(content: &str) -> kvs::Result<Config> {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(&path, content).unwrap();
+    Config::load(&path)
+}
+
+// Missing fields fall back to the defaults
+#[test]
+fn load_partial_config() {
+    let config = load(
+        r#"
+        [server]
+        addr = "127.0.0.1:5000"
+        idle_timeout = 60
+
+        [engine]
+        name = "sled"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.addr().unwrap().port(), 5000);
+    assert_eq!(config.server.threads, 4);
+    assert_eq!(config.engine.name.as_deref(), Some("sled"));
+    assert_eq!(config.log.level, "info");
+    assert_eq!(
+        config.server_options(),
+        ServerOptions {
+            max_connections: 1024,
+            idle_timeout: Some(Duration::from_secs(60)),
+            read_timeout: None,
+            write_timeout: None,
+            slowlog_threshold: Some(Duration::from_millis(10)),
+            slowlog_max_len: 128,
+            acl: Acl::default(),
+        }
+    );
+    assert_eq!(load("").unwrap(), Config::default());
+}
+
+#[test]
+fn reject_invalid_config() {
+    let invalid = [
+        "[server]\nport = 1",
+        "[server]\naddr = \"localhost\"",
+        "[server]\nthreads = 0",
+        "[engine]\nname = \"rocks\"",
+        "[engine]\ncompaction_threshold = 0",
+        "[engine]\ncompression = \"gzip\"",
+        "[log]\nlevel = \"verbose\"",
+        "[raft]\nid = 1",
+        "[raft]\nid = 1\naddr = \"127.0.0.1:7001\"",
+        "[raft]\nid = 1\naddr = \"127.0.0.1:7001\"\nsecret = \"s\"\n[replication]\nreplica_of = \"127.0.0.1:4002\"",
+        "[cluster]\nnodes = [{ addr = \"127.0.0.1:4001\", slots = [\"0-16384\"] }]",
+        "[cluster]\nnodes = [{ addr = \"127.0.0.1:4001\", slots = [\"0-100\"] }, { addr = \"127.0.0.1:4002\", slots = [\"100\"] }]",
+        "[server]\nresp_addr = \"127.0.0.1:6379\"\n[cluster]\nnodes = [{ addr = \"127.0.0.1:4001\", slots = [\"0-16383\"] }]",
+        "[server\n",
+    ];
+    for content in invalid {
+        match load(content) {
+            Err(KvsError::Config(_)) => (),
+            res => panic!("{:?} should be rejected, got {:?}", content, res.map_err(|e| e.to_string())),
+        }
+    }
+}
+
+// Only limits, timeouts and the log level may change without a restart
+#[test]
+fn restart_required_settings() {
+    let old = Config::default();
+    let mut new = old.clone();
+    new.server.max_connections = 1;
+    new.server.read_timeout = 5;
+    new.log.level = "debug".to_string();
+    new.raft.members = vec![RaftMemberConfig { id: 1, addr: "127.0.0.1:7001".to_string(), client_addr: String::new() }];
+    assert!(new.restart_required(&old).is_empty());
+
+    new.server.addr = "127.0.0.1:5000".to_string();
+    new.engine.compaction_threshold = 1;
+    assert_eq!(new.restart_required(&old), vec!["server.addr", "engine"]);
+}
@@ -0,0 +1,141 @@
+use kvs::dump::{dump, load, DumpFormat, DumpReader, DumpRecord};
+use kvs::{Compression, KVEngine, KvStore, KvStoreOptions, SledStore};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn fill<E: KVEngine>(store: &E) {
+    for i in 0..12_000 {
+        store.set(format!("key{:05}", i), format!("value{}", i), 0).unwrap();
+    }
+    store.set("vec".to_owned(), "[1,2.5,-3]".to_owned(), 0).unwrap();
+    store.set("unicode".to_owned(), "值 \"quoted\"\nline".to_owned(), 0).unwrap();
+}
+
+fn records(path: &Path) -> Vec<DumpRecord> {
+    DumpReader::open(path).unwrap().map(Result::unwrap).collect()
+}
+
+// sled导出后导入kvs,kvs再以另一种格式导出,内容不变
+fn round_trip(format: DumpFormat) {
+    let temp_dir = TempDir::new().unwrap();
+    let sled = SledStore::open(temp_dir.path().join("sled")).unwrap();
+    fill(&sled);
+    let first = temp_dir.path().join("first");
+    let mut reports = Vec::new();
+    assert_eq!(dump(&sled, &first, format, false, |n| reports.push(n)).unwrap(), 12_002);
+    assert_eq!(reports, vec![10_000, 12_002]);
+    assert_eq!(DumpReader::open(&first).unwrap().format(), format);
+
+    let kvs = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    assert_eq!(load(&kvs, &first, false, |_| ()).unwrap(), 12_002);
+    assert_eq!(kvs.get("vec".to_owned()).unwrap(), Some("[1,2.5,-3]".to_owned()));
+    assert_eq!(kvs.get("unicode".to_owned()).unwrap(), Some("值 \"quoted\"\nline".to_owned()));
+    let other = if format == DumpFormat::Json { DumpFormat::Binary } else { DumpFormat::Json };
+    let second = temp_dir.path().join("second");
+    dump(&kvs, &second, other, false, |_| ()).unwrap();
+    assert_eq!(records(&first), records(&second));
+}
+
+#[test]
+fn json_round_trip() {
+    round_trip(DumpFormat::Json);
+}
+
+#[test]
+fn binary_round_trip() {
+    round_trip(DumpFormat::Binary);
+}
+
+#[test]
+fn dump_keeps_ttls() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    store.set("a".to_owned(), "1".to_owned(), 100).unwrap();
+    store.set("b".to_owned(), "2".to_owned(), 0).unwrap();
+    let path = temp_dir.path().join("dump");
+    dump(&store, &path, DumpFormat::Binary, false, |_| ()).unwrap();
+    let dumped = records(&path);
+    assert!(dumped[0].ttl > 90 && dumped[0].ttl <= 100);
+    assert_eq!(dumped[1].ttl, 0);
+
+    let copy = KvStore::open(temp_dir.path().join("copy")).unwrap();
+    load(&copy, &path, false, |_| ()).unwrap();
+    assert!(copy.ttl("a".to_owned()).unwrap().unwrap() > 90);
+    assert_eq!(copy.ttl("b".to_owned()).unwrap(), Some(0));
+}
+
+// 过期时间写在日志中,重新打开后导出的TTL不变,压缩的value也一样
+#[test]
+fn dump_keeps_ttls_after_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions { compression: Compression::Zstd, compression_threshold: 100, ..Default::default() };
+    let store = KvStore::open_with_options(temp_dir.path().join("kvs"), options.clone()).unwrap();
+    store.set("a".to_owned(), "1".to_owned(), 100).unwrap();
+    store.set("b".to_owned(), "2".to_owned(), 0).unwrap();
+    store.set("c".to_owned(), "x".repeat(1000), 100).unwrap();
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path().join("kvs"), options).unwrap();
+    let path = temp_dir.path().join("dump");
+    dump(&store, &path, DumpFormat::Json, false, |_| ()).unwrap();
+    let dumped = records(&path);
+    assert!(dumped[0].ttl > 90 && dumped[0].ttl <= 100);
+    assert_eq!(dumped[1].ttl, 0);
+    assert!(dumped[2].ttl > 90 && dumped[2].ttl <= 100);
+    assert_eq!(dumped[2].value, "x".repeat(1000));
+}
+
+// 中断的导出截掉写了一半的记录后继续,结果与完整导出相同
+fn resume_dump(format: DumpFormat) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    fill(&store);
+    let full = temp_dir.path().join("full");
+    dump(&store, &full, format, false, |_| ()).unwrap();
+    let partial = temp_dir.path().join("partial");
+    fs::copy(&full, &partial).unwrap();
+    let len = fs::metadata(&full).unwrap().len();
+    OpenOptions::new().write(true).open(&partial).unwrap().set_len(len / 2 + 3).unwrap();
+    assert!(DumpReader::open(&partial).unwrap().any(|r| r.is_err()));
+
+    let other = if format == DumpFormat::Json { DumpFormat::Binary } else { DumpFormat::Json };
+    assert!(dump(&store, &partial, other, true, |_| ()).is_err());
+    assert_eq!(dump(&store, &partial, format, true, |_| ()).unwrap(), 12_002);
+    assert_eq!(fs::read(&full).unwrap(), fs::read(&partial).unwrap());
+}
+
+#[test]
+fn resume_json_dump() {
+    resume_dump(DumpFormat::Json);
+}
+
+#[test]
+fn resume_binary_dump() {
+    resume_dump(DumpFormat::Binary);
+}
+
+#[test]
+fn resume_load_from_checkpoint() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = SledStore::open(temp_dir.path().join("sled")).unwrap();
+    fill(&source);
+    let path = temp_dir.path().join("dump.json");
+    dump(&source, &path, DumpFormat::Json, false, |_| ()).unwrap();
+
+    // 检查点之前的记录不再写入
+    let checkpoint = temp_dir.path().join("dump.json.progress");
+    fs::write(&checkpoint, "10000").unwrap();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    let mut reports = Vec::new();
+    assert_eq!(load(&store, &path, true, |n| reports.push(n)).unwrap(), 12_002);
+    assert_eq!(reports, vec![12_002]);
+    assert_eq!(store.stats().unwrap().keys, 2_002);
+    assert_eq!(store.get("key00000".to_owned()).unwrap(), None);
+    assert!(!checkpoint.exists());
+
+    // 不续传时从头导入
+    fs::write(&checkpoint, "10000").unwrap();
+    load(&store, &path, false, |_| ()).unwrap();
+    assert_eq!(store.stats().unwrap().keys, 12_002);
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use kvs::acl::hash_password;
+use kvs::{Acl, Category, KvClient, ServerOptions, User};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+// a server with an HTTP listener on `http_addr`
+fn start_server(addr: SocketAddr, http_addr: SocketAddr, options: ServerOptions) -> common::Server {
+    common::Server::start_with(addr, |server| server.with_http(http_addr).unwrap().with_options(options))
+}
+
+// a keep-alive HTTP/1.1 connection
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Conn {
+    fn connect(addr: SocketAddr) -> Conn {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        Conn { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream }
+    }
+
+    fn send(&mut self, request: &str) -> (u16, Value) {
+        self.writer.write_all(request.as_bytes()).unwrap();
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut len = 0;
+        loop {
+            line.clear();
+            self.reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') && name.eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; len];
+        self.reader.read_exact(&mut body).unwrap();
+        let body = if body.is_empty() { Value::Null } else { serde_json::from_slice(&body).unwrap() };
+        (status, body)
+    }
+
+    fn request(&mut self, method: &str, target: &str, headers: &[&str], body: &str) -> (u16, Value) {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n", method, target, body.len());
+        for header in headers {
+            request.push_str(header);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        self.send(&request)
+    }
+
+    fn is_closed(&mut self) -> bool {
+        matches!(self.reader.read(&mut [0; 1]), Ok(0))
+    }
+}
+
+#[tokio::test]
+async fn http_keys_and_vectors() {
+    let addr: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+    let server = start_server(addr, "127.0.0.1:5011".parse().unwrap(), ServerOptions::default());
+    let mut conn = Conn::connect("127.0.0.1:5011".parse().unwrap());
+
+    assert_eq!(conn.request("PUT", "/keys/a", &[], "1"), (204, Value::Null));
+    assert_eq!(conn.request("GET", "/keys/a", &[], ""), (200, json!({"key": "a", "value": "1"})));
+    // keys are percent-decoded and may contain slashes
+    assert_eq!(conn.request("PUT", "/keys/dir/hello%20world", &[], "with space").0, 204);
+    assert_eq!(conn.request("GET", "/keys/dir%2Fhello%20world", &[], "").1["value"], "with space");
+    // the same key from the native protocol
+    let mut client = KvClient::new(addr).await.unwrap();
+    assert_eq!(client.get("dir/hello world").await.unwrap(), Some("with space".to_string()));
+
+    assert_eq!(conn.request("DELETE", "/keys/a", &[], ""), (204, Value::Null));
+    assert_eq!(conn.request("GET", "/keys/a", &[], ""), (404, json!({"error": "Key not found", "code": "key_not_found"})));
+    assert_eq!(conn.request("DELETE", "/keys/a", &[], "").0, 404);
+
+    for i in 0..5 {
+        conn.request("PUT", &format!("/keys/k{}", i), &[], &i.to_string());
+    }
+    let (status, body) = conn.request("GET", "/keys?start=k1&end=k3", &[], "");
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({"items": [{"key": "k1", "value": "1"}, {"key": "k2", "value": "2"}, {"key": "k3", "value": "3"}], "next": null}));
+    let (_, body) = conn.request("GET", "/keys?start=k&limit=2", &[], "");
+    assert_eq!(body["items"].as_array().unwrap().len(), 2);
+    assert_eq!(body["next"], "k2");
+
+    assert_eq!(conn.request("PUT", "/vectors/v", &[], "[1, 2.5,-3]").0, 204);
+    assert_eq!(conn.request("GET", "/vectors/v", &[], ""), (200, json!({"key": "v", "vector": [1.0, 2.5, -3.0]})));
+    let (status, body) = conn.request("PUT", "/vectors/v", &[], "[1,x]");
+    assert_eq!((status, body["code"].as_str()), (400, Some("bad_request")));
+    assert_eq!(conn.request("GET", "/vectors/k1", &[], "").1["code"], "not_a_vector");
+    assert_eq!(conn.request("DELETE", "/vectors/v", &[], "").0, 204);
+
+    assert_eq!(conn.request("GET", "/other", &[], "").0, 404);
+    assert_eq!(conn.request("POST", "/keys/a", &[], "").0, 405);
+    assert_eq!(conn.request("GET", "/keys?limit=x", &[], "").0, 400);
+    server.stop();
+}
+
+#[test]
+fn http_ttl() {
+    let server = start_server("127.0.0.1:5002".parse().unwrap(), "127.0.0.1:5012".parse().unwrap(), ServerOptions::default());
+    let mut conn = Conn::connect("127.0.0.1:5012".parse().unwrap());
+
+    assert_eq!(conn.request("PUT", "/keys/query?ttl=1", &[], "v").0, 204);
+    assert_eq!(conn.request("PUT", "/keys/header", &["X-TTL: 1"], "v").0, 204);
+    assert_eq!(conn.request("PUT", "/vectors/vector?ttl=1", &[], "[1]").0, 204);
+    assert_eq!(conn.request("PUT", "/keys/forever", &[], "v").0, 204);
+    assert_eq!(conn.request("PUT", "/keys/bad?ttl=-1", &[], "v").0, 400);
+    thread::sleep(Duration::from_millis(2100));
+    let not_found = (404, json!({"error": "Key not found", "code": "key_not_found"}));
+    for key in ["query", "header"] {
+        assert_eq!(conn.request("GET", &format!("/keys/{}", key), &[], ""), not_found, "{} should be expired", key);
+    }
+    assert_eq!(conn.request("GET", "/vectors/vector", &[], ""), not_found);
+    assert_eq!(conn.request("GET", "/keys/forever", &[], "").1["value"], "v");
+
+    // Connection: close is honoured
+    let (status, _) = conn.request("GET", "/keys/forever", &["Connection: close"], "");
+    assert_eq!(status, 200);
+    assert!(conn.is_closed());
+    server.stop();
+}
+
+#[test]
+fn http_auth_and_errors() {
+    let user = User {
+        name: "app".to_string(),
+        password: hash_password("secret"),
+        commands: vec![Category::Read, Category::Write],
+        keys: vec!["app:".to_string()],
+    };
+    let options = ServerOptions { acl: Acl::new(vec![user]), ..ServerOptions::default() };
+    let server = start_server("127.0.0.1:5003".parse().unwrap(), "127.0.0.1:5013".parse().unwrap(), options);
+    let mut conn = Conn::connect("127.0.0.1:5013".parse().unwrap());
+
+    // app:secret and app:wrong
+    let auth = "Authorization: Basic YXBwOnNlY3JldA==";
+    assert_eq!(conn.request("GET", "/keys/app:1", &[], "").1["code"], "auth_required");
+    let (status, body) = conn.request("GET", "/keys/app:1", &["Authorization: Basic YXBwOndyb25n"], "");
+    assert_eq!((status, body["code"].as_str()), (401, Some("auth_failed")));
+    assert_eq!(conn.request("PUT", "/keys/app:1", &[auth], "v").0, 204);
+    assert_eq!(conn.request("GET", "/keys/app:1", &[auth], "").1["value"], "v");
+    let (status, body) = conn.request("PUT", "/keys/other", &[auth], "v");
+    assert_eq!((status, body["code"].as_str()), (403, Some("no_permission")));
+    assert_eq!(conn.request("GET", "/keys", &[auth], "").0, 403);
+    assert_eq!(conn.request("GET", "/keys?start=app:&end=app:~", &[auth], "").0, 200);
+
+    // a malformed request closes the connection
+    let mut conn = Conn::connect("127.0.0.1:5013".parse().unwrap());
+    let (status, body) = conn.send("GET /keys/a HTTP/1.1\r\nbroken header\r\n\r\n");
+    assert_eq!((status, body["code"].as_str()), (400, Some("bad_request")));
+    assert!(conn.is_closed());
+    server.stop();
+}
+
+#[test]
+fn http_auth_cache_and_short_body() {
+    let user = |password: &str| User {
+        name: "app".to_string(),
+        password: hash_password(password),
+        commands: vec![Category::Read, Category::Write],
+        keys: Vec::new(),
+    };
+    let options = ServerOptions { acl: Acl::new(vec![user("secret")]), ..ServerOptions::default() };
+    let mut handle = None;
+    let server = common::Server::start_with("127.0.0.1:5004".parse().unwrap(), |server| {
+        handle = Some(server.handle());
+        server.with_http("127.0.0.1:5014".parse().unwrap()).unwrap().with_options(options.clone())
+    });
+    let handle = handle.unwrap();
+
+    // the credentials verified on a connection are checked again after the password changed
+    let auth = "Authorization: Basic YXBwOnNlY3JldA==";
+    let mut conn = Conn::connect("127.0.0.1:5014".parse().unwrap());
+    assert_eq!(conn.request("PUT", "/keys/a", &[auth], "v").0, 204);
+    assert_eq!(conn.request("GET", "/keys/a", &[auth], "").1["value"], "v");
+    handle.set_options(ServerOptions { acl: Acl::new(vec![user("changed")]), ..options });
+    let (status, body) = conn.request("GET", "/keys/a", &[auth], "");
+    assert_eq!((status, body["code"].as_str()), (401, Some("auth_failed")));
+
+    // a body shorter than its Content-Length closes the connection
+    let mut conn = Conn::connect("127.0.0.1:5014".parse().unwrap());
+    conn.writer.write_all(b"PUT /keys/a HTTP/1.1\r\nContent-Length: 500000000\r\n\r\nabc").unwrap();
+    conn.writer.shutdown(std::net::Shutdown::Write).unwrap();
+    assert!(conn.is_closed());
+
+    server.stop();
+}
@@ -0,0 +1,106 @@
+use kvs::engines::{ChangeOp, LogReader};
+use kvs::{KVEngine, KvStore};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn fill(dir: &Path) {
+    let store = KvStore::open(dir).unwrap();
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i), 0).unwrap();
+    }
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("new{}", i), 0).unwrap();
+    }
+    store.set("ttl".to_owned(), "1".to_owned(), 100).unwrap();
+    store.remove("key99".to_owned()).unwrap();
+}
+
+fn log(dir: &Path) -> std::path::PathBuf {
+    let r#gen = KvStore::inspect(dir).unwrap().last().unwrap().generation;
+    dir.join(format!("{}.log", r#gen))
+}
+
+#[test]
+fn decode_records() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let mut reader = LogReader::open(&log(temp_dir.path())).unwrap();
+    let records: Vec<_> = (&mut reader).collect();
+    assert!(reader.error().is_none());
+    assert_eq!(records.len(), 152);
+    assert_eq!(records[0].offset, 0);
+    assert_eq!(records[1].offset, records[0].len);
+    assert_eq!(records[150].ttl, 100);
+    assert_eq!(records[151].op, ChangeOp::Remove);
+    assert_eq!(records[151].key, "key99");
+    assert_eq!(reader.pos(), fs::metadata(log(temp_dir.path())).unwrap().len());
+}
+
+#[test]
+fn inspect_reports_stale_bytes() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let reports = KvStore::inspect(temp_dir.path()).unwrap();
+    assert_eq!(reports.len(), 1);
+    let report = &reports[0];
+    assert!(report.error.is_none());
+    assert_eq!((report.records, report.sets, report.removes), (152, 151, 1));
+    // 被覆盖的50条、被删除的key99和删除记录本身都是过期数据
+    assert!(report.live_bytes < report.bytes);
+    assert!(report.stale_ratio() > 0.25 && report.stale_ratio() < 0.5);
+}
+
+#[test]
+fn verify_finds_truncated_and_corrupted_logs() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let path = log(temp_dir.path());
+    let len = fs::metadata(&path).unwrap().len();
+    OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+    let error = KvStore::inspect(temp_dir.path()).unwrap()[0].error.clone().unwrap();
+    assert!(error.message.contains("truncated"));
+    assert!(error.offset < len - 3);
+
+    // 无法解码的命令类型
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.set_len(error.offset).unwrap();
+    file.write_all(&[7, 1, 2, 3]).unwrap();
+    let reports = KvStore::inspect(temp_dir.path()).unwrap();
+    assert_eq!(reports[0].error.as_ref().unwrap().offset, error.offset);
+    assert_eq!(reports[0].records, 151);
+}
+
+#[test]
+fn repair_salvages_readable_records() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let path = log(temp_dir.path());
+    let len = fs::metadata(&path).unwrap().len();
+    // 截掉最后的删除记录
+    OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+
+    let dry = KvStore::repair(temp_dir.path(), true).unwrap();
+    assert_eq!((dry.keys, dry.records), (101, 151));
+    assert!(dry.lost_bytes > 0);
+    assert!(dry.old_logs.is_none());
+    assert!(path.exists());
+
+    let report = KvStore::repair(temp_dir.path(), false).unwrap();
+    assert_eq!(report.keys, 101);
+    assert!(report.old_logs.unwrap().join(path.file_name().unwrap()).exists());
+    assert!(!path.exists());
+    let reports = KvStore::inspect(temp_dir.path()).unwrap();
+    assert_eq!(reports.iter().map(|r| r.generation).collect::<Vec<_>>(), vec![report.generation, report.generation + 1]);
+    assert!(reports.iter().all(|r| r.error.is_none() && r.stale_ratio() == 0.0));
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.stats().unwrap().keys, 101);
+    assert_eq!(store.get("key0".to_owned()).unwrap(), Some("new0".to_owned()));
+    assert_eq!(store.get("key99".to_owned()).unwrap(), Some("value99".to_owned()));
+    store.set("after".to_owned(), "repair".to_owned(), 0).unwrap();
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("after".to_owned()).unwrap(), Some("repair".to_owned()));
+}
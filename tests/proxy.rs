@@ -0,0 +1,186 @@
+mod common;
+
+use common::Server;
+use kvs::acl::{hash_password, Acl, Category, User};
+use kvs::proxy::HashRing;
+use kvs::{KvClient, KvsError, Proxy, ProxyHandle, ProxyOptions, ServerAddr};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn start_proxy(options: ProxyOptions) -> (KvClient, ProxyHandle, Arc<AtomicBool>, SocketAddr) {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let proxy = Proxy::bind("127.0.0.1:0".parse().unwrap(), options, shutdown.clone()).await.unwrap();
+    let (addr, handle) = (proxy.local_addr().unwrap(), proxy.handle());
+    tokio::spawn(proxy.run());
+    (KvClient::new(addr).await.unwrap(), handle, shutdown, addr)
+}
+
+fn addrs(addrs: &[&str]) -> Vec<ServerAddr> {
+    addrs.iter().map(|a| a.parse().unwrap()).collect()
+}
+
+#[test]
+fn hash_ring_moves_few_keys() {
+    let mut ring = HashRing::new(160);
+    for node in ["a", "b", "c", "d"] {
+        ring.add(node);
+    }
+    let keys: Vec<String> = (0..10000).map(|i| format!("key{}", i)).collect();
+    let owners: HashMap<&String, String> = keys.iter().map(|k| (k, ring.node(k).unwrap().to_string())).collect();
+    for node in ["a", "b", "c", "d"] {
+        let count = owners.values().filter(|n| *n == node).count();
+        assert!((1500..3500).contains(&count), "{} owns {} keys", node, count);
+    }
+
+    // 移除节点只移动该节点的key
+    ring.remove("b");
+    assert_eq!(ring.nodes(), vec!["a", "c", "d"]);
+    for key in &keys {
+        let node = ring.node(key).unwrap();
+        if owners[key] != "b" {
+            assert_eq!(node, owners[key]);
+        }
+        assert_ne!(node, "b");
+    }
+
+    // 增加节点只把key移到新节点
+    ring.add("b");
+    ring.add("e");
+    let mut moved = 0;
+    for key in &keys {
+        let node = ring.node(key).unwrap();
+        if node != owners[key] {
+            assert_eq!(node, "e");
+            moved += 1;
+        }
+    }
+    assert!((1000..3000).contains(&moved), "{} keys moved", moved);
+    assert_eq!(HashRing::new(10).node("key"), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_shards_keys() {
+    let backends = ["127.0.0.1:5401", "127.0.0.1:5402", "127.0.0.1:5403"];
+    let servers: Vec<Server> = backends.iter().map(|a| Server::start(a.parse().unwrap())).collect();
+    let user = |name: &str, commands: Vec<Category>| User {
+        name: name.to_string(),
+        password: hash_password("secret"),
+        commands,
+        keys: Vec::new(),
+    };
+    // 代理以自己的后端用户转发时必须配置客户端的用户
+    let auth = ProxyOptions { backends: addrs(&backends), auth: Some(("proxy".to_string(), "secret".to_string())), ..ProxyOptions::default() };
+    assert!(Proxy::bind("127.0.0.1:0".parse().unwrap(), auth, Arc::new(AtomicBool::new(false))).await.is_err());
+    let users = Acl::new(vec![
+        user("admin", vec![Category::Read, Category::Write, Category::Admin, Category::Vector]),
+        user("app", vec![Category::Read, Category::Write]),
+    ]);
+    let options = ProxyOptions { backends: addrs(&backends), users, ..ProxyOptions::default() };
+    let (mut client, handle, shutdown, addr) = start_proxy(options).await;
+
+    // 每个客户端连接都要以代理的用户认证,按该用户的权限检查命令
+    assert!(matches!(client.set("k", "v", None).await, Err(KvsError::NoPermission(_))));
+    assert!(matches!(client.auth("admin", "wrong").await, Err(KvsError::AuthFailed)));
+    assert_eq!(client.ping(None).await.unwrap(), "PONG");
+    let mut app = KvClient::new(addr).await.unwrap();
+    app.auth("app", "secret").await.unwrap();
+    app.set("k", "v", None).await.unwrap();
+    app.remove("k").await.unwrap();
+    assert!(matches!(app.config_set("backends", backends[0]).await, Err(KvsError::NoPermission(_))));
+    assert_eq!(handle.backends().len(), 3);
+    // 认证前不按客户端给出的长度分配内存,过长的请求被拒绝并断开
+    let mut raw = TcpStream::connect(addr).await.unwrap();
+    raw.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+    let mut reply = String::new();
+    raw.read_to_string(&mut reply).await.unwrap();
+    assert!(reply.starts_with("Error") && reply.contains("too large"), "{}", reply);
+    client.auth("admin", "secret").await.unwrap();
+
+    for i in 0..100 {
+        client.set(&format!("k{:03}", i), &i.to_string(), None).await.unwrap();
+    }
+    assert_eq!(client.get("k042").await.unwrap(), Some("42".to_string()));
+    client.remove("k042").await.unwrap();
+    assert_eq!(client.get("k042").await.unwrap(), None);
+    assert!(matches!(client.remove("k042").await, Err(KvsError::KeyNotFound)));
+    client.vset("vec", &[1.0, 2.5], None).await.unwrap();
+    assert_eq!(client.vget("vec").await.unwrap(), Some(vec![1.0, 2.5]));
+    assert_eq!(client.ping(None).await.unwrap(), "PONG");
+
+    // 扫描合并全部后端的结果并按key排序
+    let pairs = client.scan("k000"..="k999").await.unwrap();
+    assert_eq!(pairs.len(), 99);
+    assert!(pairs.windows(2).all(|p| p[0].0 < p[1].0));
+    assert_eq!(client.dbsize().await.unwrap(), 100);
+    let mut total = 0;
+    for addr in backends {
+        let keys = KvClient::new(addr.parse().unwrap()).await.unwrap().dbsize().await.unwrap();
+        assert!(keys > 0 && keys < 100);
+        total += keys;
+    }
+    assert_eq!(total, 100);
+    assert!(matches!(client.info().await, Err(KvsError::StringError(e)) if e.contains("not supported")));
+
+    // 移除后端后其余后端的key不受影响
+    let before = client.scan("k000"..="k999").await.unwrap();
+    client.config_set("backends", &format!("{},{}", backends[0], backends[1])).await.unwrap();
+    assert_eq!(handle.backends(), vec![backends[0].to_string(), backends[1].to_string()]);
+    let config = client.config_get("backends").await.unwrap();
+    assert_eq!(config, vec![("backends".to_string(), format!("{},{}", backends[0], backends[1]))]);
+    let after = client.scan("k000"..="k999").await.unwrap();
+    assert!(after.len() < before.len());
+    assert!(after.iter().all(|pair| before.contains(pair)));
+    assert!(handle.remove_backend(&backends[1].parse().unwrap()).is_ok());
+    assert!(handle.remove_backend(&backends[0].parse().unwrap()).is_err());
+
+    shutdown.store(true, Ordering::SeqCst);
+    for server in servers {
+        server.stop();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_marks_failed_backends() {
+    let backends = ["127.0.0.1:5404", "127.0.0.1:5405"];
+    let mut servers: Vec<Server> = backends.iter().map(|a| Server::start(a.parse().unwrap())).collect();
+    let options = ProxyOptions {
+        backends: addrs(&backends),
+        health_interval: Duration::from_millis(100),
+        failure_limit: 2,
+        timeout: Duration::from_secs(1),
+        ..ProxyOptions::default()
+    };
+    let (mut client, handle, shutdown, _) = start_proxy(options.clone()).await;
+    let mut ring = HashRing::new(options.vnodes);
+    backends.iter().for_each(|b| ring.add(b));
+    let key = |backend: &str| (0..).map(|i| format!("key{}", i)).find(|k| ring.node(k) == Some(backend)).unwrap();
+    let (alive, dead) = (key(backends[0]), key(backends[1]));
+    client.set(&dead, "v", None).await.unwrap();
+
+    // 后端停止后它的key快速失败,其余key正常处理
+    servers.pop().unwrap().stop();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(handle.healthy(), vec![backends[0].to_string()]);
+    match client.get(&dead).await {
+        Err(KvsError::StringError(e)) => assert!(e.contains("unavailable"), "{}", e),
+        other => panic!("unexpected {:?}", other),
+    }
+    client.set(&alive, "v", None).await.unwrap();
+    assert_eq!(client.get(&alive).await.unwrap(), Some("v".to_string()));
+
+    // 恢复后重新可用
+    let server = Server::start(backends[1].parse().unwrap());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(handle.healthy().len(), 2);
+    assert_eq!(client.get(&dead).await.unwrap(), None);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.stop();
+    for server in servers {
+        server.stop();
+    }
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use common::Server;
+use kvs::pubsub::glob_match;
+use kvs::{KvClient, KvsError};
+use std::net::SocketAddr;
+use std::thread;
+
+#[test]
+fn glob_patterns() {
+    assert!(glob_match("news.*", "news.tech"));
+    assert!(glob_match("news.*", "news."));
+    assert!(!glob_match("news.*", "news"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("h?llo", "hello"));
+    assert!(!glob_match("h?llo", "hllo"));
+    assert!(glob_match("h[ae]llo", "hallo"));
+    assert!(!glob_match("h[ae]llo", "hillo"));
+    assert!(glob_match("h[^e]llo", "hallo"));
+    assert!(!glob_match("h[^e]llo", "hello"));
+    assert!(glob_match("h[a-c]llo", "hbllo"));
+    assert!(glob_match("a*b*c", "axxbyyc"));
+    assert!(!glob_match("a*b*c", "axxbyy"));
+    assert!(glob_match(r"a\*", "a*"));
+    assert!(!glob_match(r"a\*", "ab"));
+    assert!(glob_match("a[", "a["));
+}
+
+#[tokio::test]
+async fn publish_and_subscribe() {
+    let addr: SocketAddr = "127.0.0.1:4301".parse().unwrap();
+    let server = Server::start(addr);
+
+    let mut publisher = KvClient::new(addr).await.unwrap();
+    assert_eq!(publisher.publish("news.tech", "nobody").await.unwrap(), 0);
+
+    let mut sub = KvClient::new(addr)
+        .await
+        .unwrap()
+        .subscribe(&["news.tech", "alerts"])
+        .await
+        .unwrap();
+    assert_eq!(sub.count(), 2);
+    let mut psub = KvClient::new(addr).await.unwrap().psubscribe(&["news.*"]).await.unwrap();
+
+    assert_eq!(publisher.publish("news.tech", "hello world").await.unwrap(), 2);
+    assert_eq!(publisher.publish("alerts", "line1\nline2").await.unwrap(), 1);
+    assert_eq!(publisher.publish("news.sport", "goal").await.unwrap(), 1);
+
+    let m = sub.next().await.unwrap().unwrap();
+    assert_eq!((m.channel.as_str(), m.pattern, m.payload.as_str()), ("news.tech", None, "hello world"));
+    let m = sub.next().await.unwrap().unwrap();
+    assert_eq!((m.channel.as_str(), m.payload.as_str()), ("alerts", "line1\nline2"));
+
+    let m = psub.next().await.unwrap().unwrap();
+    assert_eq!(m.pattern.as_deref(), Some("news.*"));
+    assert_eq!(m.channel, "news.tech");
+    let m = psub.next().await.unwrap().unwrap();
+    assert_eq!((m.channel.as_str(), m.payload.as_str()), ("news.sport", "goal"));
+
+    // subscriptions can change in subscriber mode
+    sub.unsubscribe(&["news.tech"]).await.unwrap();
+    assert_eq!(sub.count(), 1);
+    sub.psubscribe(&["a*"]).await.unwrap();
+    assert_eq!(sub.count(), 2);
+    assert_eq!(publisher.publish("news.tech", "again").await.unwrap(), 1);
+    assert_eq!(publisher.publish("alerts", "both").await.unwrap(), 2);
+    let m = sub.next().await.unwrap().unwrap();
+    assert_eq!((m.channel.as_str(), m.pattern), ("alerts", None));
+    let m = sub.next().await.unwrap().unwrap();
+    assert_eq!((m.channel.as_str(), m.pattern.as_deref()), ("alerts", Some("a*")));
+
+    // unsubscribing from everything returns the connection to normal mode
+    sub.unsubscribe(&[]).await.unwrap();
+    sub.punsubscribe(&[]).await.unwrap();
+    assert_eq!(sub.count(), 0);
+    assert!(sub.next().await.unwrap().is_none());
+    let mut client = sub.into_client().ok().unwrap();
+    client.set("key1", "value1", None).await.unwrap();
+    assert_eq!(client.get("key1").await.unwrap(), Some("value1".to_owned()));
+
+    // closed subscribers are removed from the registry
+    drop(psub);
+    thread::sleep(std::time::Duration::from_millis(100));
+    assert_eq!(publisher.publish("news.tech", "gone").await.unwrap(), 0);
+
+    // unsubscribe is rejected outside subscriber mode
+    let mut client = KvClient::new(addr).await.unwrap();
+    match client.send_request(kvs::Cmd::Unsubscribe(kvs::common::UnsubscribeCmd { channels: vec![] })).await {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("Not in subscriber mode")),
+        res => panic!("unexpected response {:?}", res.map_err(|e| e.to_string())),
+    }
+
+    server.stop();
+}
@@ -0,0 +1,269 @@
+use kvs::raft::{Member, MemNetwork, Message, RaftNode, RaftOptions, Role, TcpTransport, Transport};
+use kvs::{KVEngine, KvClient, KvServer, KvStore, KvsError, ShardThreadPool, ThreadPool};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+struct Node {
+    raft: RaftNode<KvStore>,
+    store: KvStore,
+    _dir: TempDir,
+}
+
+fn open(id: u64, members: &BTreeMap<u64, Member>, network: &MemNetwork, snapshot_threshold: u64) -> Node {
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path().join("kvs")).unwrap();
+    let options = RaftOptions {
+        members: members.clone(),
+        snapshot_threshold,
+        proposal_timeout: Duration::from_secs(3),
+        ..RaftOptions::new(id)
+    };
+    let raft = RaftNode::open(store.clone(), dir.path().join("raft"), options, network.transport(id)).unwrap();
+    let handler = raft.clone();
+    network.register(id, move |msg| handler.handle(msg));
+    Node { raft, store, _dir: dir }
+}
+
+fn members(ids: &[u64]) -> BTreeMap<u64, Member> {
+    ids.iter().map(|&id| (id, Member { addr: format!("node-{}", id), client_addr: String::new() })).collect()
+}
+
+// 等待在线节点中选出唯一的leader,返回其下标
+fn wait_leader(nodes: &[&RaftNode<KvStore>]) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let leaders: Vec<usize> = (0..nodes.len()).filter(|&i| nodes[i].info().role == Role::Leader).collect();
+        if let [leader] = leaders[..] {
+            return leader;
+        }
+        assert!(Instant::now() < deadline, "no leader elected");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn wait_applied(node: &RaftNode<KvStore>, index: u64) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while node.info().applied_index < index {
+        assert!(Instant::now() < deadline, "node {} did not apply {}: {:?}", node.info().id, index, node.info());
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn raft_failover() {
+    let network = MemNetwork::new();
+    let config = members(&[1, 2, 3]);
+    let nodes: Vec<Node> = (1..=3).map(|id| open(id, &config, &network, 10000)).collect();
+    let rafts: Vec<&RaftNode<KvStore>> = nodes.iter().map(|n| &n.raft).collect();
+
+    let leader = wait_leader(&rafts);
+    for i in 0..20 {
+        rafts[leader].set(format!("k{}", i), i.to_string(), 0).unwrap();
+    }
+    assert_eq!(rafts[leader].get("k7".to_string()).unwrap(), Some("7".to_string()));
+    assert!(matches!(rafts[leader].remove("missing".to_string()), Err(KvsError::KeyNotFound)));
+    let follower = (leader + 1) % 3;
+    assert!(matches!(rafts[follower].set("a".to_string(), "b".to_string(), 0), Err(KvsError::NotLeader(_))));
+    assert!(matches!(rafts[follower].get("k1".to_string()), Err(KvsError::NotLeader(_))));
+
+    // leader宕机后剩余两个节点选出新leader,数据不丢失
+    network.isolate(rafts[leader].info().id);
+    let alive: Vec<&RaftNode<KvStore>> = (0..3).filter(|&i| i != leader).map(|i| rafts[i]).collect();
+    let new_leader = alive[wait_leader(&alive)];
+    assert!(new_leader.info().term > rafts[leader].info().term);
+    assert_eq!(new_leader.get("k19".to_string()).unwrap(), Some("19".to_string()));
+    new_leader.set("k0".to_string(), "changed".to_string(), 0).unwrap();
+    new_leader.remove("k1".to_string()).unwrap();
+
+    // 旧leader无法确认多数节点,拒绝读取
+    assert!(rafts[leader].get("k0".to_string()).is_err());
+
+    // 恢复后旧leader退位并追上日志
+    network.heal(rafts[leader].info().id);
+    let index = new_leader.info().commit_index;
+    wait_applied(rafts[leader], index);
+    assert_eq!(rafts[leader].info().role, Role::Follower);
+    assert_eq!(new_leader.get("k0".to_string()).unwrap(), Some("changed".to_string()));
+    assert_eq!(new_leader.get("k1".to_string()).unwrap(), None);
+
+    for raft in rafts {
+        raft.shut_down();
+    }
+}
+
+#[test]
+fn raft_membership_and_snapshot() {
+    let network = MemNetwork::new();
+    let config = members(&[1, 2, 3]);
+    let nodes: Vec<Node> = (1..=3).map(|id| open(id, &config, &network, 10)).collect();
+    let rafts: Vec<&RaftNode<KvStore>> = nodes.iter().map(|n| &n.raft).collect();
+    let leader = rafts[wait_leader(&rafts)];
+    for i in 0..50 {
+        leader.set(format!("k{}", i), i.to_string(), 0).unwrap();
+    }
+    assert!(leader.info().snapshot_index > 0, "the log should be compacted");
+
+    // 新节点不带成员配置启动,加入后通过快照追上
+    let joined = open(4, &BTreeMap::new(), &network, 10);
+    leader.add_node(4, Member { addr: "node-4".to_string(), client_addr: String::new() }).unwrap();
+    wait_applied(&joined.raft, leader.info().commit_index);
+    assert_eq!(joined.raft.info().members.len(), 4);
+    leader.set("after-join".to_string(), "v".to_string(), 0).unwrap();
+    wait_applied(&joined.raft, leader.info().commit_index);
+
+    // 移除leader自身,剩余节点选出新leader
+    let leader_id = leader.info().id;
+    leader.remove_node(leader_id).unwrap();
+    let rest: Vec<&RaftNode<KvStore>> = rafts.iter().copied().filter(|r| r.info().id != leader_id).chain([&joined.raft]).collect();
+    let new_leader = rest[wait_leader(&rest)];
+    assert_eq!(new_leader.info().members.len(), 3);
+    assert_eq!(new_leader.get("k49".to_string()).unwrap(), Some("49".to_string()));
+    assert_eq!(new_leader.get("after-join".to_string()).unwrap(), Some("v".to_string()));
+    assert_ne!(leader.info().role, Role::Leader);
+
+    for raft in rafts {
+        raft.shut_down();
+    }
+    joined.raft.shut_down();
+}
+
+#[test]
+fn raft_set_members() {
+    let network = MemNetwork::new();
+    let config = members(&[1, 2, 3]);
+    let nodes: Vec<Node> = (1..=3).map(|id| open(id, &config, &network, 10000)).collect();
+    let rafts: Vec<&RaftNode<KvStore>> = nodes.iter().map(|n| &n.raft).collect();
+    let leader = rafts[wait_leader(&rafts)];
+    leader.set("k".to_string(), "v".to_string(), 0).unwrap();
+
+    // 一次变更中加入节点4并移除leader自身
+    let joined = open(4, &BTreeMap::new(), &network, 10000);
+    let leader_id = leader.info().id;
+    let new_config: BTreeMap<u64, Member> = members(&[1, 2, 3, 4]).into_iter().filter(|(id, _)| *id != leader_id).collect();
+    leader.set_members(&new_config).unwrap();
+    assert_eq!(leader.info().members, new_config);
+    let rest: Vec<&RaftNode<KvStore>> = rafts.iter().copied().filter(|r| r.info().id != leader_id).chain([&joined.raft]).collect();
+    let new_leader = rest[wait_leader(&rest)];
+    assert_eq!(new_leader.get("k".to_string()).unwrap(), Some("v".to_string()));
+
+    for raft in rafts {
+        raft.shut_down();
+    }
+    joined.raft.shut_down();
+}
+
+#[test]
+fn raft_snapshot_in_chunks_keeps_ttls() {
+    let network = MemNetwork::new();
+    let config = members(&[1, 2, 3]);
+    let nodes: Vec<Node> = (1..=3).map(|id| open(id, &config, &network, 100)).collect();
+    let rafts: Vec<&RaftNode<KvStore>> = nodes.iter().map(|n| &n.raft).collect();
+    let leader = wait_leader(&rafts);
+    let lagging = (leader + 1) % 3;
+    leader_set(rafts[leader], "stale", 0);
+
+    // 落后的节点错过压缩掉的日志,之后通过多块快照追上
+    network.isolate(rafts[lagging].info().id);
+    rafts[leader].remove("stale".to_string()).unwrap();
+    for i in 0..1500 {
+        leader_set(rafts[leader], &format!("k{:04}", i), 0);
+    }
+    leader_set(rafts[leader], "expiring", 100);
+    assert!(rafts[leader].info().snapshot_index > 0, "the log should be compacted");
+    network.heal(rafts[lagging].info().id);
+    wait_applied(rafts[lagging], rafts[leader].info().commit_index);
+
+    let store = &nodes[lagging].store;
+    assert_eq!(store.get("k1499".to_string()).unwrap(), Some("v".to_string()));
+    assert_eq!(store.get("stale".to_string()).unwrap(), None);
+    assert_eq!(store.key_count().unwrap(), 1501);
+    assert!(store.ttl("expiring".to_string()).unwrap().is_some_and(|ttl| ttl > 0 && ttl <= 100));
+    assert_eq!(store.ttl("k0000".to_string()).unwrap(), Some(0));
+
+    for raft in rafts {
+        raft.shut_down();
+    }
+}
+
+fn leader_set(leader: &RaftNode<KvStore>, key: &str, ttl: u32) {
+    leader.set(key.to_string(), "v".to_string(), ttl).unwrap();
+}
+
+struct ServerNode {
+    raft: RaftNode<KvStore>,
+    shutdown: Arc<AtomicBool>,
+    handle: thread::JoinHandle<()>,
+    _dir: TempDir,
+}
+
+#[tokio::test]
+async fn raft_over_tcp_with_servers() {
+    let config: BTreeMap<u64, Member> = (1..=3)
+        .map(|id| (id, Member { addr: format!("127.0.0.1:52{:02}", id), client_addr: format!("127.0.0.1:52{:02}", id + 10) }))
+        .collect();
+    let mut nodes = Vec::new();
+    for (&id, member) in &config {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path().join("kvs")).unwrap();
+        let options = RaftOptions { members: config.clone(), secret: Some("secret".to_string()), ..RaftOptions::new(id) };
+        let transport = Arc::new(TcpTransport::new(Duration::from_millis(500)).with_secret("secret"));
+        let raft = RaftNode::open(store, dir.path().join("raft"), options, transport).unwrap();
+        raft.serve(member.addr.parse().unwrap()).unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let addr: SocketAddr = member.client_addr.parse().unwrap();
+        let mut server = KvServer::new(raft.clone(), addr, shutdown.clone(), ShardThreadPool::new(2).unwrap()).unwrap();
+        let handle = thread::spawn(move || {
+            server.run().unwrap();
+            server.shut_down(Duration::from_secs(5)).unwrap();
+        });
+        nodes.push(ServerNode { raft, shutdown, handle, _dir: dir });
+    }
+    let rafts: Vec<&RaftNode<KvStore>> = nodes.iter().map(|n| &n.raft).collect();
+    let leader = wait_leader(&rafts);
+
+    // 不知道secret的连接在第一条消息之前被拒绝
+    let vote = || Message::Vote { term: 0, candidate: 9, last_index: 0, last_term: 0 };
+    let timeout = Duration::from_millis(500);
+    let addr = &config[&(leader as u64 + 1)].addr;
+    assert!(TcpTransport::new(timeout).send(1, addr, vote()).is_err());
+    assert!(TcpTransport::new(timeout).with_secret("guess").send(1, addr, vote()).is_err());
+    assert!(matches!(
+        TcpTransport::new(timeout).with_secret("secret").send(1, addr, vote()),
+        Ok(Message::VoteReply { granted: false, .. })
+    ));
+
+    // 写入follower时返回leader的客户端地址
+    let follower = (leader + 1) % 3;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while rafts[follower].info().leader.is_none() {
+        assert!(Instant::now() < deadline, "the follower did not learn the leader");
+        thread::sleep(Duration::from_millis(20));
+    }
+    let mut client = KvClient::new(config[&(follower as u64 + 1)].client_addr.parse::<SocketAddr>().unwrap()).await.unwrap();
+    let leader_addr = match client.set("key", "value", None).await {
+        Err(KvsError::NotLeader(addr)) => addr,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert_eq!(leader_addr, config[&(leader as u64 + 1)].client_addr);
+    let mut client = KvClient::new(leader_addr.parse::<SocketAddr>().unwrap()).await.unwrap();
+    client.set("key", "value", None).await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), Some("value".to_string()));
+
+    // 停掉一个follower后集群仍然可以读写
+    let stopped = nodes.remove(follower);
+    stopped.raft.shut_down();
+    stopped.shutdown.store(true, Ordering::SeqCst);
+    stopped.handle.join().unwrap();
+    client.set("after", "stop", None).await.unwrap();
+    assert_eq!(client.get("after").await.unwrap(), Some("stop".to_string()));
+
+    for node in nodes {
+        node.raft.shut_down();
+        node.shutdown.store(true, Ordering::SeqCst);
+        node.handle.join().unwrap();
+    }
+}
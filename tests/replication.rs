@@ -0,0 +1,152 @@
+mod common;
+
+use kvs::acl::hash_password;
+use kvs::{Acl, Category, KvClient, KvsError, ServerAddr, ServerOptions, User};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 给出`leader`时作为它的副本启动,`auth`是复制连接使用的用户
+fn start_server(addr: SocketAddr, leader: Option<SocketAddr>, options: ServerOptions, auth: Option<(&str, &str)>) -> common::Server {
+    common::Server::start_with(addr, |server| {
+        let mut server = server.with_options(options);
+        if let Some((user, password)) = auth {
+            server = server.with_replication_auth(user, password);
+        }
+        if let Some(leader) = leader {
+            server = server.with_replica_of(ServerAddr::Tcp(leader)).unwrap();
+        }
+        server
+    })
+}
+
+// 轮询直到副本上的值符合预期,全量同步期间副本拒绝读取
+async fn wait_for(client: &mut KvClient, key: &str, expected: Option<&str>) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let value = match client.get(key).await {
+            Ok(value) => Some(value),
+            Err(KvsError::ReplicaLoading(_)) => None,
+            Err(e) => panic!("get {} failed: {}", key, e),
+        };
+        if let Some(value) = &value && value.as_deref() == expected {
+            return;
+        }
+        assert!(Instant::now() < deadline, "{} is {:?}, expected {:?}", key, value, expected);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn replica_syncs_streams_and_promotes() {
+    let leader_addr: SocketAddr = "127.0.0.1:5101".parse().unwrap();
+    let replica_addr: SocketAddr = "127.0.0.1:5102".parse().unwrap();
+    let leader = start_server(leader_addr, None, ServerOptions::default(), None);
+    let mut client = KvClient::new(leader_addr).await.unwrap();
+    for i in 0..100 {
+        client.set(&format!("k{}", i), &i.to_string(), None).await.unwrap();
+    }
+
+    // 全量同步
+    let replica = start_server(replica_addr, Some(leader_addr), ServerOptions::default(), None);
+    let mut replica_client = KvClient::new(replica_addr).await.unwrap();
+    wait_for(&mut replica_client, "k99", Some("99")).await;
+    assert_eq!(replica_client.get("k0").await.unwrap(), Some("0".to_string()));
+
+    // 增量变更
+    client.set("k0", "changed", None).await.unwrap();
+    client.remove("k1").await.unwrap();
+    client.set("new", "value", None).await.unwrap();
+    wait_for(&mut replica_client, "new", Some("value")).await;
+    assert_eq!(replica_client.get("k0").await.unwrap(), Some("changed".to_string()));
+    assert_eq!(replica_client.get("k1").await.unwrap(), None);
+
+    // 副本拒绝写入并指向主节点
+    match replica_client.set("k2", "x", None).await {
+        Err(KvsError::ReadOnlyReplica(addr)) => assert_eq!(addr, leader_addr.to_string()),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(replica_client.remove("k2").await, Err(KvsError::ReadOnlyReplica(_))));
+
+    let info = replica_client.info().await.unwrap().replication;
+    assert_eq!(info.role, "replica");
+    assert_eq!(info.leader, Some(leader_addr.to_string()));
+    assert!(info.link_up);
+    assert_eq!(info.full_syncs, 1);
+    assert!(info.lag_secs.is_some_and(|lag| lag < 5), "{:?}", info.lag_secs);
+    let info = client.info().await.unwrap().replication;
+    assert_eq!((info.role.as_str(), info.replicas), ("leader", 1));
+
+    // 提升为主节点后可以写入,不再接收原主节点的变更
+    replica_client.replica_of(None).await.unwrap();
+    replica_client.set("k2", "x", None).await.unwrap();
+    client.set("after", "promote", None).await.unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(replica_client.get("after").await.unwrap(), None);
+    let info = replica_client.info().await.unwrap().replication;
+    assert_eq!((info.role.as_str(), info.leader), ("leader", None));
+
+    replica.stop();
+    leader.stop();
+}
+
+#[tokio::test]
+async fn replica_authenticates_and_switches_leader() {
+    let options = || {
+        let user = User {
+            name: "repl".to_string(),
+            password: hash_password("secret"),
+            commands: vec![Category::Read, Category::Write, Category::Admin],
+            keys: vec![],
+        };
+        ServerOptions { acl: Acl::new(vec![user]), ..ServerOptions::default() }
+    };
+    let first_addr: SocketAddr = "127.0.0.1:5103".parse().unwrap();
+    let second_addr: SocketAddr = "127.0.0.1:5104".parse().unwrap();
+    let replica_addr: SocketAddr = "127.0.0.1:5105".parse().unwrap();
+    let first = start_server(first_addr, None, options(), None);
+    let second = start_server(second_addr, None, options(), None);
+    let mut first_client = KvClient::new(first_addr).await.unwrap();
+    first_client.auth("repl", "secret").await.unwrap();
+    first_client.set("only-first", "1", None).await.unwrap();
+    let mut second_client = KvClient::new(second_addr).await.unwrap();
+    second_client.auth("repl", "secret").await.unwrap();
+    second_client.set("only-second", "2", None).await.unwrap();
+
+    let replica = start_server(replica_addr, Some(first_addr), ServerOptions::default(), Some(("repl", "secret")));
+    let mut replica_client = KvClient::new(replica_addr).await.unwrap();
+    wait_for(&mut replica_client, "only-first", Some("1")).await;
+
+    // 切换主节点时全量同步并清除旧数据
+    replica_client.replica_of(Some(&ServerAddr::Tcp(second_addr))).await.unwrap();
+    wait_for(&mut replica_client, "only-second", Some("2")).await;
+    assert_eq!(replica_client.get("only-first").await.unwrap(), None);
+    let info = replica_client.info().await.unwrap().replication;
+    assert_eq!(info.leader, Some(second_addr.to_string()));
+
+    replica.stop();
+    second.stop();
+    first.stop();
+}
+
+#[tokio::test]
+async fn full_sync_keeps_ttls() {
+    let leader_addr: SocketAddr = "127.0.0.1:5106".parse().unwrap();
+    let replica_addr: SocketAddr = "127.0.0.1:5107".parse().unwrap();
+    let leader = start_server(leader_addr, None, ServerOptions::default(), None);
+    let mut client = KvClient::new(leader_addr).await.unwrap();
+    client.set("short", "1", Some(Duration::from_secs(2))).await.unwrap();
+    client.set("long", "2", None).await.unwrap();
+
+    let replica = start_server(replica_addr, Some(leader_addr), ServerOptions::default(), None);
+    let mut replica_client = KvClient::new(replica_addr).await.unwrap();
+    wait_for(&mut replica_client, "long", Some("2")).await;
+    assert_eq!(replica_client.get("short").await.unwrap(), Some("1".to_string()));
+
+    // 主节点停止后副本收不到删除,key按快照中的TTL过期
+    leader.stop();
+    thread::sleep(Duration::from_secs(4));
+    assert_eq!(replica_client.get("short").await.unwrap(), None);
+    assert_eq!(replica_client.get("long").await.unwrap(), Some("2".to_string()));
+    replica.stop();
+}
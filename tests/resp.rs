@@ -0,0 +1,309 @@
+mod common;
+
+use kvs::acl::hash_password;
+use kvs::{Acl, Category, KvClient, ServerOptions, User};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+// a server with a RESP listener on `resp_addr`
+fn start_server(addr: SocketAddr, resp_addr: SocketAddr, options: ServerOptions) -> common::Server {
+    common::Server::start_with(addr, |server| server.with_resp(resp_addr).unwrap().with_options(options))
+}
+
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Conn {
+    fn connect(addr: SocketAddr) -> Conn {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        Conn { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream }
+    }
+
+    // sends a raw request and checks the raw reply byte by byte
+    fn expect(&mut self, request: &str, reply: &str) {
+        self.writer.write_all(request.as_bytes()).unwrap();
+        let mut buf = vec![0u8; reply.len()];
+        self.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), reply, "reply to {:?}", request);
+    }
+
+    // sends a command as an array of bulk strings
+    fn call(&mut self, args: &[&str]) -> Reply {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(request.as_bytes()).unwrap();
+        self.read_reply()
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "line {:?} does not end with CRLF", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    fn read_reply(&mut self) -> Reply {
+        let line = self.line();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Reply::Simple(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "_" => Reply::Null,
+            "$" if rest == "-1" => Reply::Null,
+            "$" => {
+                let mut buf = vec![0u8; rest.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut buf).unwrap();
+                buf.truncate(buf.len() - 2);
+                Reply::Bulk(String::from_utf8(buf).unwrap())
+            }
+            "*" => Reply::Array((0..rest.parse().unwrap()).map(|_| self.read_reply()).collect()),
+            "%" => Reply::Map((0..rest.parse().unwrap()).map(|_| (self.read_reply(), self.read_reply())).collect()),
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+
+    fn is_closed(&mut self) -> bool {
+        let mut buf = [0u8; 1];
+        matches!(self.reader.read(&mut buf), Ok(0))
+    }
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(s.to_string())
+}
+
+#[test]
+fn resp2_commands() {
+    let server = start_server("127.0.0.1:4901".parse().unwrap(), "127.0.0.1:4911".parse().unwrap(), ServerOptions::default());
+    let mut conn = Conn::connect("127.0.0.1:4911".parse().unwrap());
+
+    conn.expect("*1\r\n$4\r\nPING\r\n", "+PONG\r\n");
+    conn.expect("*2\r\n$4\r\nping\r\n$5\r\nhello\r\n", "$5\r\nhello\r\n");
+    conn.expect("*2\r\n$4\r\nECHO\r\n$3\r\na b\r\n", "$3\r\na b\r\n");
+    // inline commands as typed into telnet
+    conn.expect("PING\r\n", "+PONG\r\n");
+    conn.expect("set inline value\r\n", "+OK\r\n");
+    conn.expect("*2\r\n$3\r\nGET\r\n$6\r\ninline\r\n", "$5\r\nvalue\r\n");
+
+    conn.expect("*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n", "+OK\r\n");
+    conn.expect("*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n", "$5\r\nvalue\r\n");
+    conn.expect("*2\r\n$3\r\nGET\r\n$7\r\nmissing\r\n", "$-1\r\n");
+    // NX and XX
+    conn.expect("*4\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nother\r\n$2\r\nNX\r\n", "$-1\r\n");
+    conn.expect("*4\r\n$3\r\nSET\r\n$3\r\nnew\r\n$5\r\nother\r\n$2\r\nXX\r\n", "$-1\r\n");
+    conn.expect("*4\r\n$3\r\nSET\r\n$3\r\nnew\r\n$1\r\n1\r\n$2\r\nnx\r\n", "+OK\r\n");
+    conn.expect("*5\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n$2\r\nNX\r\n$2\r\nXX\r\n", "-ERR syntax error\r\n");
+    // GET returns the old value
+    conn.expect("*4\r\n$3\r\nSET\r\n$3\r\nkey\r\n$2\r\nv2\r\n$3\r\nGET\r\n", "$5\r\nvalue\r\n");
+    // EX and PX
+    conn.expect("*5\r\n$3\r\nSET\r\n$2\r\nex\r\n$1\r\nv\r\n$2\r\nEX\r\n$3\r\n100\r\n", "+OK\r\n");
+    conn.expect("*5\r\n$3\r\nSET\r\n$2\r\npx\r\n$1\r\nv\r\n$2\r\nPX\r\n$4\r\n1500\r\n", "+OK\r\n");
+    conn.expect("*5\r\n$3\r\nSET\r\n$2\r\nex\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\n0\r\n", "-ERR invalid expire time in 'set' command\r\n");
+    conn.expect("*5\r\n$3\r\nSET\r\n$2\r\nex\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\nx\r\n", "-ERR value is not an integer or out of range\r\n");
+    conn.expect("*4\r\n$3\r\nSET\r\n$2\r\nex\r\n$1\r\nv\r\n$2\r\nEX\r\n", "-ERR syntax error\r\n");
+
+    assert_eq!(conn.call(&["EXISTS", "key", "missing", "key"]), Reply::Integer(2));
+    assert_eq!(conn.call(&["DEL", "key", "missing", "new"]), Reply::Integer(2));
+    assert_eq!(conn.call(&["EXISTS", "key"]), Reply::Integer(0));
+    assert_eq!(conn.call(&["SETNX", "key", "1"]), Reply::Integer(1));
+    assert_eq!(conn.call(&["SETNX", "key", "2"]), Reply::Integer(0));
+    assert_eq!(conn.call(&["SETEX", "key", "10", "3"]), Reply::Simple("OK".to_string()));
+    assert_eq!(conn.call(&["MSET", "a", "1", "b", "2"]), Reply::Simple("OK".to_string()));
+    assert_eq!(conn.call(&["MGET", "a", "missing", "b"]), Reply::Array(vec![bulk("1"), Reply::Null, bulk("2")]));
+    assert_eq!(conn.call(&["TYPE", "a"]), Reply::Simple("string".to_string()));
+    assert_eq!(conn.call(&["TYPE", "missing"]), Reply::Simple("none".to_string()));
+    assert_eq!(conn.call(&["DBSIZE"]), Reply::Integer(6));
+    assert_eq!(conn.call(&["SELECT", "0"]), Reply::Simple("OK".to_string()));
+    assert!(matches!(conn.call(&["SELECT", "1"]), Reply::Error(_)));
+    match conn.call(&["INFO"]) {
+        Reply::Bulk(info) => {
+            assert!(info.contains("# Server\r\n"));
+            assert!(info.contains("db0:keys=6"));
+        }
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    }
+    assert_eq!(
+        conn.call(&["GET"]),
+        Reply::Error("ERR wrong number of arguments for 'get' command".to_string())
+    );
+    assert_eq!(
+        conn.call(&["FOO", "bar"]),
+        Reply::Error("ERR unknown command 'foo', with args beginning with: 'bar'".to_string())
+    );
+
+    // pipelined commands are answered in order
+    conn.expect(
+        "*3\r\n$3\r\nSET\r\n$1\r\np\r\n$1\r\n1\r\n*2\r\n$3\r\nGET\r\n$1\r\np\r\n*1\r\n$4\r\nPING\r\n",
+        "+OK\r\n$1\r\n1\r\n+PONG\r\n",
+    );
+    // a request split over several packets
+    conn.writer.write_all(b"*2\r\n$3\r\nGE").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    conn.expect("T\r\n$1\r\np\r\n", "$1\r\n1\r\n");
+
+    conn.expect("*1\r\n$4\r\nQUIT\r\n", "+OK\r\n");
+    assert!(conn.is_closed());
+    server.stop();
+}
+
+#[test]
+fn resp3_hello() {
+    let server = start_server("127.0.0.1:4902".parse().unwrap(), "127.0.0.1:4912".parse().unwrap(), ServerOptions::default());
+    let mut conn = Conn::connect("127.0.0.1:4912".parse().unwrap());
+
+    assert_eq!(conn.call(&["HELLO", "4"]), Reply::Error("NOPROTO unsupported protocol version".to_string()));
+    // RESP2 encodes the map as a flat array
+    match conn.call(&["HELLO"]) {
+        Reply::Array(fields) => assert_eq!(fields[..2], [bulk("server"), bulk("mini-kv")]),
+        reply => panic!("unexpected HELLO reply {:?}", reply),
+    }
+    match conn.call(&["HELLO", "3", "SETNAME", "app"]) {
+        Reply::Map(fields) => {
+            assert_eq!(fields[0], (bulk("server"), bulk("mini-kv")));
+            assert!(fields.contains(&(bulk("proto"), Reply::Integer(3))));
+        }
+        reply => panic!("unexpected HELLO reply {:?}", reply),
+    }
+    conn.expect("*2\r\n$3\r\nGET\r\n$7\r\nmissing\r\n", "_\r\n");
+    assert_eq!(conn.call(&["CLIENT", "GETNAME"]), bulk("app"));
+    match conn.call(&["CONFIG", "GET", "slowlog_max_len"]) {
+        Reply::Map(fields) => assert_eq!(fields, vec![(bulk("slowlog_max_len"), bulk("128"))]),
+        reply => panic!("unexpected CONFIG GET reply {:?}", reply),
+    }
+    server.stop();
+}
+
+#[tokio::test]
+async fn scan_and_shared_engine() {
+    let addr = "127.0.0.1:4903".parse().unwrap();
+    let server = start_server(addr, "127.0.0.1:4913".parse().unwrap(), ServerOptions::default());
+    let mut conn = Conn::connect("127.0.0.1:4913".parse().unwrap());
+    let mut client = KvClient::new(addr).await.unwrap();
+
+    for i in 0..25 {
+        client.set(&format!("user:{:02}", i), &i.to_string(), None).await.unwrap();
+    }
+    conn.call(&["SET", "other", "x"]);
+    assert_eq!(client.get("other").await.unwrap(), Some("x".to_string()));
+    assert_eq!(conn.call(&["GET", "user:07"]), bulk("7"));
+
+    let mut cursor = "0".to_string();
+    let mut keys = Vec::new();
+    let mut calls = 0;
+    loop {
+        let Reply::Array(reply) = conn.call(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "10"]) else {
+            panic!("SCAN should reply with an array");
+        };
+        let [Reply::Bulk(next), Reply::Array(batch)] = &reply[..] else {
+            panic!("unexpected SCAN reply {:?}", reply);
+        };
+        keys.extend(batch.iter().map(|k| match k {
+            Reply::Bulk(k) => k.clone(),
+            k => panic!("unexpected key {:?}", k),
+        }));
+        calls += 1;
+        cursor = next.clone();
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(calls, 3);
+    assert_eq!(keys, (0..25).map(|i| format!("user:{:02}", i)).collect::<Vec<_>>());
+
+    let Reply::Array(matched) = conn.call(&["KEYS", "user:1?"]) else {
+        panic!("KEYS should reply with an array");
+    };
+    assert_eq!(matched.len(), 10);
+    assert_eq!(conn.call(&["SCAN", "x"]), Reply::Error("ERR invalid cursor".to_string()));
+    server.stop();
+}
+
+#[test]
+fn resp_auth_and_errors() {
+    let user = User {
+        name: "app".to_string(),
+        password: hash_password("secret"),
+        commands: vec![Category::Read, Category::Write],
+        keys: vec!["app:".to_string()],
+    };
+    let options = ServerOptions { acl: Acl::new(vec![user]), ..ServerOptions::default() };
+    let server = start_server("127.0.0.1:4904".parse().unwrap(), "127.0.0.1:4914".parse().unwrap(), options);
+    let mut conn = Conn::connect("127.0.0.1:4914".parse().unwrap());
+
+    conn.expect("*1\r\n$4\r\nPING\r\n", "+PONG\r\n");
+    conn.expect("*2\r\n$3\r\nGET\r\n$5\r\napp:1\r\n", "-NOAUTH Authentication required.\r\n");
+    assert!(matches!(conn.call(&["AUTH", "app", "wrong"]), Reply::Error(e) if e.starts_with("WRONGPASS")));
+    assert!(matches!(conn.call(&["HELLO", "3"]), Reply::Error(e) if e.starts_with("NOAUTH")));
+    assert!(matches!(conn.call(&["HELLO", "3", "AUTH", "app", "secret"]), Reply::Map(_)));
+    assert_eq!(conn.call(&["SET", "app:1", "v"]), Reply::Simple("OK".to_string()));
+    assert!(matches!(conn.call(&["SET", "other", "v"]), Reply::Error(e) if e.starts_with("NOPERM")));
+    assert!(matches!(conn.call(&["DEL", "app:1", "other"]), Reply::Error(e) if e.starts_with("NOPERM")));
+    assert!(matches!(conn.call(&["SCAN", "0"]), Reply::Error(e) if e.starts_with("NOPERM")));
+    assert!(matches!(conn.call(&["SCAN", "0", "MATCH", "app:*"]), Reply::Array(_)));
+    assert!(matches!(conn.call(&["DBSIZE"]), Reply::Error(e) if e.starts_with("NOPERM")));
+
+    // a malformed frame closes the connection
+    let mut conn = Conn::connect("127.0.0.1:4914".parse().unwrap());
+    conn.expect("*1\r\n+PING\r\n", "-ERR Protocol error: expected '$', got '+'\r\n");
+    assert!(conn.is_closed());
+    server.stop();
+}
+
+#[test]
+fn conditional_set_is_atomic() {
+    let server = start_server("127.0.0.1:4905".parse().unwrap(), "127.0.0.1:4915".parse().unwrap(), ServerOptions::default());
+    for round in 0..20 {
+        let key = format!("lock:{}", round);
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let key = key.clone();
+                thread::spawn(move || {
+                    let mut conn = Conn::connect("127.0.0.1:4915".parse().unwrap());
+                    let nx = conn.call(&["SET", &key, &i.to_string(), "NX"]) == Reply::Simple("OK".to_string());
+                    let setnx = conn.call(&["SETNX", &format!("{}:n", key), &i.to_string()]) == Reply::Integer(1);
+                    (nx, setnx)
+                })
+            })
+            .collect();
+        let results: Vec<(bool, bool)> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results.iter().filter(|r| r.0).count(), 1);
+        assert_eq!(results.iter().filter(|r| r.1).count(), 1);
+    }
+    server.stop();
+}
+
+#[test]
+fn expired_keys_are_missing() {
+    let server = start_server("127.0.0.1:4906".parse().unwrap(), "127.0.0.1:4916".parse().unwrap(), ServerOptions::default());
+    let mut conn = Conn::connect("127.0.0.1:4916".parse().unwrap());
+    for key in ["a", "b", "c", "d"] {
+        assert_eq!(conn.call(&["SET", key, "v", "EX", "1"]), Reply::Simple("OK".to_string()));
+    }
+    thread::sleep(Duration::from_millis(2100));
+    assert_eq!(conn.call(&["GET", "a"]), Reply::Null);
+    assert_eq!(conn.call(&["EXISTS", "b"]), Reply::Integer(0));
+    assert_eq!(conn.call(&["TYPE", "c"]), Reply::Simple("none".to_string()));
+    assert_eq!(conn.call(&["SETNX", "d", "new"]), Reply::Integer(1));
+    assert_eq!(conn.call(&["GET", "d"]), bulk("new"));
+    server.stop();
+}
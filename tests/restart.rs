@@ -0,0 +1,68 @@
+use kvs::{KVEngine, KvStore, SledStore};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// 重新打开后继续写入同一代日志,新记录追加在原有记录之后
+#[test]
+fn writes_after_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned(), 0).unwrap();
+    store.set("b".to_owned(), "2".to_owned(), 0).unwrap();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("c".to_owned(), "3".to_owned(), 0).unwrap();
+    assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("c".to_owned()).unwrap(), Some("3".to_owned()));
+    store.remove("b".to_owned()).unwrap();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned()).unwrap(), None);
+    assert_eq!(store.get("c".to_owned()).unwrap(), Some("3".to_owned()));
+}
+
+fn count_keys<E: KVEngine>(open: impl Fn(&Path) -> E) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path());
+    assert_eq!(store.key_count().unwrap(), 0);
+    store.set("a".to_owned(), "1".to_owned(), 0).unwrap();
+    store.set("b".to_owned(), "2".to_owned(), 0).unwrap();
+    store.set("a".to_owned(), "3".to_owned(), 0).unwrap();
+    assert_eq!(store.key_count().unwrap(), 2);
+    store.remove("b".to_owned()).unwrap();
+    assert!(store.remove("b".to_owned()).is_err());
+    assert_eq!(store.key_count().unwrap(), 1);
+    drop(store);
+
+    let store = open(temp_dir.path());
+    assert_eq!(store.key_count().unwrap(), 1);
+    store.set("c".to_owned(), "4".to_owned(), 0).unwrap();
+    assert_eq!(store.key_count().unwrap(), 2);
+}
+
+// key_count不扫描全部key,重新打开后仍然准确
+#[test]
+fn key_count_after_reopen() {
+    count_keys(|path| KvStore::open(path).unwrap());
+}
+
+// sled的后台线程在drop之后仍会短暂持有目录锁,重新打开时重试
+fn open_sled(path: &Path) -> SledStore {
+    for _ in 0..50 {
+        if let Ok(store) = SledStore::open(path) {
+            return store;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    SledStore::open(path).unwrap()
+}
+
+#[test]
+fn sled_key_count_after_reopen() {
+    count_keys(open_sled);
+}
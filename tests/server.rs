@@ -0,0 +1,304 @@
+use kvs::{
+    KVEngine, KvClient, KvServer, KvStore, KvsError, ServerOptions, ServerStats, ShardThreadPool,
+    ThreadPool,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Runs a kvs server on `addr` until the shutdown flag is set, then shuts it down
+// with `timeout` and returns the shutdown result and the final stats.
+fn start_server(
+    addr: SocketAddr,
+    dir: &TempDir,
+    options: ServerOptions,
+    timeout: Duration,
+) -> (Arc<AtomicBool>, JoinHandle<(kvs::Result<()>, ServerStats)>) {
+    let store = KvStore::open(dir.path()).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(4).unwrap();
+    let mut server = KvServer::new(store, addr, shutdown.clone(), pool)
+        .unwrap()
+        .with_options(options);
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+        let res = server.shut_down(timeout);
+        (res, server.stats())
+    });
+    (shutdown, handle)
+}
+
+// Idle connections must not keep the server from shutting down.
+#[tokio::test]
+async fn shutdown_closes_idle_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4201".parse().unwrap();
+    let (shutdown, handle) =
+        start_server(addr, &temp_dir, ServerOptions::default(), Duration::from_secs(5));
+
+    let mut client = KvClient::new(addr).await.unwrap();
+    client.set("key1", "value1", None).await.unwrap();
+    let mut idle = KvClient::new(addr).await.unwrap();
+    idle.ping(None).await.unwrap();
+
+    let start = Instant::now();
+    shutdown.store(true, Ordering::SeqCst);
+    handle.join().unwrap().0.unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    // the connection was closed with a shutdown notice
+    match client.get("key1").await {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("shutting down")),
+        res => panic!("unexpected response {:?}", res.map_err(|e| e.to_string())),
+    }
+
+    // data written before shutdown is persisted
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+// Connections beyond the limit get an error, timed out connections are closed.
+#[tokio::test]
+async fn connection_limits_and_timeouts() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4202".parse().unwrap();
+    let options = ServerOptions {
+        max_connections: 2,
+        idle_timeout: Some(Duration::from_millis(500)),
+        read_timeout: Some(Duration::from_millis(300)),
+        write_timeout: Some(Duration::from_secs(1)),
+        ..ServerOptions::default()
+    };
+    let (shutdown, handle) = start_server(addr, &temp_dir, options, Duration::from_secs(5));
+
+    let mut first = KvClient::new(addr).await.unwrap();
+    first.ping(None).await.unwrap();
+    // a half-sent frame is dropped after the read timeout
+    let mut partial = TcpStream::connect(addr).unwrap();
+    partial.write_all(&[0, 0, 0, 9, 1]).unwrap();
+    let mut rejected = KvClient::new(addr).await.unwrap();
+    match rejected.ping(None).await {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("Too many connections")),
+        res => panic!("unexpected response {:?}", res.map_err(|e| e.to_string())),
+    }
+
+    // the idle connection is closed with a notice
+    thread::sleep(Duration::from_secs(1));
+    let mut line = String::new();
+    BufReader::new(partial).read_line(&mut line).unwrap();
+    assert!(line.is_empty());
+    match first.ping(None).await {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("Idle timeout")),
+        res => panic!("unexpected response {:?}", res.map_err(|e| e.to_string())),
+    }
+
+    shutdown.store(true, Ordering::SeqCst);
+    let (res, stats) = handle.join().unwrap();
+    res.unwrap();
+    assert_eq!(stats.active_connections, 0);
+    assert_eq!(stats.total_connections, 2);
+    assert_eq!(stats.rejected_connections, 1);
+    assert_eq!(stats.idle_timeouts, 1);
+    assert_eq!(stats.read_timeouts, 1);
+}
+
+// The metrics endpoint exposes command, connection and engine metrics.
+#[tokio::test]
+async fn metrics_endpoint() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4203".parse().unwrap();
+    let metrics_addr: SocketAddr = "127.0.0.1:4204".parse().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(4).unwrap();
+    let mut server = KvServer::new(store, addr, shutdown.clone(), pool).unwrap();
+    server.serve_metrics(metrics_addr).unwrap();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+        server.shut_down(Duration::from_secs(5))
+    });
+
+    let mut client = KvClient::new(addr).await.unwrap();
+    client.set("key1", "value1", None).await.unwrap();
+    client.set("key2", "value2", None).await.unwrap();
+    client.get("key1").await.unwrap();
+    assert!(client.remove("missing").await.is_err());
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut body = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut body).unwrap();
+    assert!(body.starts_with("HTTP/1.1 200 OK"));
+    assert!(body.contains("kvs_commands_total{cmd=\"set\"} 2"));
+    assert!(body.contains("kvs_command_errors_total{cmd=\"remove\"} 1"));
+    assert!(body.contains("kvs_command_duration_seconds_bucket{cmd=\"get\",le=\"+Inf\"} 1"));
+    assert!(body.contains("kvs_connections_active 1"));
+    assert!(body.contains("kvs_thread_pool_queue_depth"));
+    assert!(body.contains("kvs_engine_keys 2"));
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut body = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut body).unwrap();
+    assert!(body.starts_with("HTTP/1.1 404"));
+
+    drop(client);
+    shutdown.store(true, Ordering::SeqCst);
+    handle.join().unwrap().unwrap();
+}
+
+// Commands above the threshold are kept in a bounded slowlog, newest first.
+#[tokio::test]
+async fn slowlog_records_slow_commands() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4205".parse().unwrap();
+    let options = ServerOptions {
+        slowlog_threshold: Some(Duration::ZERO),
+        slowlog_max_len: 3,
+        ..ServerOptions::default()
+    };
+    let (shutdown, handle) = start_server(addr, &temp_dir, options, Duration::from_secs(5));
+
+    let mut client = KvClient::new(addr).await.unwrap();
+    let long = "v".repeat(1000);
+    client.set("key1", &long, Some(Duration::from_secs(60))).await.unwrap();
+    client.get("key1").await.unwrap();
+    client.remove("key1").await.unwrap();
+    client.dbsize().await.unwrap();
+
+    let entries = client.slowlog_get(0).await.unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].command, vec!["dbsize"]);
+    assert_eq!(entries[1].command, vec!["remove", "key1"]);
+    assert_eq!(entries[2].command, vec!["get", "key1"]);
+    assert!(entries[0].id > entries[1].id);
+    assert!(entries[0].client.starts_with("127.0.0.1:"));
+    assert!(entries[0].timestamp > 0);
+
+    // long arguments are truncated
+    client.config_set("slowlog_max_len", "10").await.unwrap();
+    client.set("key2", &long, None).await.unwrap();
+    let entries = client.slowlog_get(1).await.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].command[0], "set");
+    assert!(entries[0].command[2].len() < 200);
+    assert!(entries[0].command[2].ends_with("(872 more bytes)"));
+
+    client.config_set("slowlog_threshold", "0").await.unwrap();
+    client.slowlog_reset().await.unwrap();
+    client.ping(None).await.unwrap();
+    assert!(client.slowlog_get(0).await.unwrap().is_empty());
+
+    shutdown.store(true, Ordering::SeqCst);
+    handle.join().unwrap().0.unwrap();
+}
+
+// A monitor receives every command of the other clients until the server shuts down.
+#[tokio::test]
+async fn monitor_streams_commands() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4206".parse().unwrap();
+    let (shutdown, handle) =
+        start_server(addr, &temp_dir, ServerOptions::default(), Duration::from_secs(5));
+
+    let mut monitor = KvClient::new(addr).await.unwrap().monitor().await.unwrap();
+    let mut client = KvClient::new(addr).await.unwrap();
+    client.set("key1", "value 1", Some(Duration::from_secs(60))).await.unwrap();
+    client.get("key1").await.unwrap();
+    client.remove("missing").await.unwrap_err();
+
+    let event = monitor.next().await.unwrap().unwrap();
+    assert!(event.ends_with(r#""set" "key1" "value 1" "EX" "60""#), "{}", event);
+    assert!(event.contains("[127.0.0.1:"));
+    let event = monitor.next().await.unwrap().unwrap();
+    assert!(event.ends_with(r#""get" "key1""#), "{}", event);
+    let event = monitor.next().await.unwrap().unwrap();
+    assert!(event.ends_with(r#""remove" "missing""#), "{}", event);
+
+    // a dropped monitor does not keep receiving events or block the server
+    let dropped = KvClient::new(addr).await.unwrap().monitor().await.unwrap();
+    drop(dropped);
+    client.ping(None).await.unwrap();
+    let event = monitor.next().await.unwrap().unwrap();
+    assert!(event.ends_with(r#""ping""#), "{}", event);
+
+    let start = Instant::now();
+    shutdown.store(true, Ordering::SeqCst);
+    handle.join().unwrap().0.unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    match monitor.next().await {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("shutting down")),
+        res => panic!("unexpected event {:?}", res.map_err(|e| e.to_string())),
+    }
+}
+
+// The read timeout bounds a whole frame, a client sending it byte by byte is cut off.
+#[tokio::test]
+async fn read_timeout_is_per_frame() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4207".parse().unwrap();
+    let options = ServerOptions {
+        read_timeout: Some(Duration::from_millis(300)),
+        ..ServerOptions::default()
+    };
+    let (shutdown, handle) = start_server(addr, &temp_dir, options, Duration::from_secs(5));
+
+    let mut slow = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(slow.try_clone().unwrap());
+    let start = Instant::now();
+    slow.write_all(&[0, 0, 0, 20]).unwrap();
+    for _ in 0..20 {
+        thread::sleep(Duration::from_millis(100));
+        if slow.write_all(&[0]).is_err() {
+            break;
+        }
+    }
+    let mut line = String::new();
+    assert_eq!(reader.read_line(&mut line).unwrap_or(0), 0);
+    assert!(start.elapsed() < Duration::from_millis(1500), "{:?}", start.elapsed());
+
+    shutdown.store(true, Ordering::SeqCst);
+    let (res, stats) = handle.join().unwrap();
+    res.unwrap();
+    assert_eq!(stats.read_timeouts, 1);
+}
+
+// Idle and streaming connections do not hold the workers of the thread pool.
+#[tokio::test]
+async fn idle_connections_do_not_hold_workers() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4208".parse().unwrap();
+    let (shutdown, handle) =
+        start_server(addr, &temp_dir, ServerOptions::default(), Duration::from_secs(5));
+
+    // the pool has 4 threads
+    let mut idle = Vec::new();
+    for _ in 0..8 {
+        let mut client = KvClient::new(addr).await.unwrap();
+        client.ping(None).await.unwrap();
+        idle.push(client);
+    }
+    let mut monitors = Vec::new();
+    for _ in 0..4 {
+        monitors.push(KvClient::new(addr).await.unwrap().monitor().await.unwrap());
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvClient::new(addr).await.unwrap();
+    client.set("key1", "value1", None).await.unwrap();
+    assert_eq!(client.get("key1").await.unwrap(), Some("value1".to_owned()));
+    for client in &mut idle {
+        client.ping(None).await.unwrap();
+    }
+    let event = monitors[3].next().await.unwrap().unwrap();
+    assert!(event.ends_with(r#""set" "key1" "value1""#), "{}", event);
+
+    shutdown.store(true, Ordering::SeqCst);
+    handle.join().unwrap().0.unwrap();
+}
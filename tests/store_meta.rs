@@ -0,0 +1,99 @@
+use kvs::{KVEngine, KvStore, KvsError, SledStore, StoreMeta};
+use std::fs;
+use tempfile::TempDir;
+
+// Opening a store records its engine, reopening keeps the same metadata
+#[test]
+fn create_and_reuse_meta() {
+    let temp_dir = TempDir::new().unwrap();
+    assert_eq!(StoreMeta::load(temp_dir.path()).unwrap(), None);
+
+    drop(KvStore::open(temp_dir.path()).unwrap());
+    let meta = StoreMeta::load(temp_dir.path()).unwrap().unwrap();
+    assert_eq!(meta.engine, "kvs");
+    assert_eq!(meta.format_version, kvs::engines::FORMAT_VERSION);
+
+    drop(KvStore::open(temp_dir.path()).unwrap());
+    assert_eq!(StoreMeta::load(temp_dir.path()).unwrap(), Some(meta));
+}
+
+#[test]
+fn reject_engine_mismatch() {
+    let kvs_dir = TempDir::new().unwrap();
+    drop(KvStore::open(kvs_dir.path()).unwrap());
+    match SledStore::open(kvs_dir.path()) {
+        Err(KvsError::EngineMismatch { expected, found }) => {
+            assert_eq!(expected, "sled");
+            assert_eq!(found, "kvs");
+        }
+        _ => panic!("sled must not open a kvs directory"),
+    }
+
+    let sled_dir = TempDir::new().unwrap();
+    drop(SledStore::open(sled_dir.path()).unwrap());
+    assert!(matches!(
+        KvStore::open(sled_dir.path()),
+        Err(KvsError::EngineMismatch { .. })
+    ));
+}
+
+#[test]
+fn reject_newer_format() {
+    let temp_dir = TempDir::new().unwrap();
+    drop(KvStore::open(temp_dir.path()).unwrap());
+    let path = temp_dir.path().join(kvs::engines::META_FILE);
+    let mut meta = StoreMeta::load(temp_dir.path()).unwrap().unwrap();
+    meta.format_version = kvs::engines::FORMAT_VERSION + 1;
+    fs::write(&path, serde_json::to_vec(&meta).unwrap()).unwrap();
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedFormat(_))
+    ));
+}
+
+// Opening a store of an older format upgrades it, so older versions refuse it
+// instead of failing to decode the records they do not know
+#[test]
+fn upgrade_older_format() {
+    let temp_dir = TempDir::new().unwrap();
+    drop(KvStore::open(temp_dir.path()).unwrap());
+    let path = temp_dir.path().join(kvs::engines::META_FILE);
+    let mut meta = StoreMeta::load(temp_dir.path()).unwrap().unwrap();
+    meta.format_version = 1;
+    fs::write(&path, serde_json::to_vec(&meta).unwrap()).unwrap();
+
+    drop(KvStore::open(temp_dir.path()).unwrap());
+    let upgraded = StoreMeta::load(temp_dir.path()).unwrap().unwrap();
+    assert_eq!(upgraded.format_version, kvs::engines::FORMAT_VERSION);
+    assert_eq!(upgraded.store_id, meta.store_id);
+}
+
+// A directory from before the metadata file is only adopted by the engine that wrote it
+#[test]
+fn legacy_directory_keeps_its_engine() {
+    let kvs_dir = TempDir::new().unwrap();
+    let store = KvStore::open(kvs_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned(), 0).unwrap();
+    drop(store);
+    fs::remove_file(kvs_dir.path().join(kvs::engines::META_FILE)).unwrap();
+    assert!(matches!(
+        SledStore::open(kvs_dir.path()),
+        Err(KvsError::EngineMismatch { .. })
+    ));
+    assert_eq!(StoreMeta::load(kvs_dir.path()).unwrap(), None);
+    let store = KvStore::open(kvs_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(StoreMeta::load(kvs_dir.path()).unwrap().unwrap().engine, "kvs");
+
+    let sled_dir = TempDir::new().unwrap();
+    drop(SledStore::open(sled_dir.path()).unwrap());
+    fs::remove_file(sled_dir.path().join(kvs::engines::META_FILE)).unwrap();
+    match KvStore::open(sled_dir.path()) {
+        Err(KvsError::EngineMismatch { expected, found }) => {
+            assert_eq!(expected, "kvs");
+            assert_eq!(found, "sled");
+        }
+        _ => panic!("kvs must not open a legacy sled directory"),
+    }
+    drop(SledStore::open(sled_dir.path()).unwrap());
+}
@@ -0,0 +1,143 @@
+use kvs::{tls, Config, KvClient, KvServer, KvStore, KvsError, ShardThreadPool, ThreadPool};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    // a self-signed CA, its certificate is written to <dir>/<name>.pem
+    fn new(dir: &Path, name: &str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        Ca { cert, key }
+    }
+
+    // issues a certificate for localhost and 127.0.0.1, returns the paths of the certificate and key
+    fn issue(&self, dir: &Path, name: &str, usage: ExtendedKeyUsagePurpose) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()]).unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        let cert_path = dir.join(format!("{}.pem", name));
+        let key_path = dir.join(format!("{}-key.pem", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+}
+
+fn start_server(addr: SocketAddr, data: &Path, config: Arc<rustls::ServerConfig>) -> (Arc<AtomicBool>, JoinHandle<()>) {
+    let store = KvStore::open(data).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(4).unwrap();
+    let mut server = KvServer::new(store, addr, shutdown.clone(), pool).unwrap().with_tls(config);
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+        server.shut_down(Duration::from_secs(5)).unwrap();
+    });
+    (shutdown, handle)
+}
+
+#[tokio::test]
+async fn tls_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = Ca::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", ExtendedKeyUsagePurpose::ServerAuth);
+    let _other = Ca::new(dir, "other-ca");
+
+    let addr = "127.0.0.1:4701".parse().unwrap();
+    let data = dir.join("data");
+    let (shutdown, server) = start_server(addr, &data, tls::server_config(&cert, &key, None).unwrap());
+
+    let config = tls::client_config(&dir.join("ca.pem"), None).unwrap();
+    for name in ["localhost", "127.0.0.1"] {
+        let mut client = KvClient::new_tls(addr, name, config.clone()).await.unwrap();
+        client.set("key", name, None).await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), Some(name.to_string()));
+    }
+
+    // the server certificate is not signed by this CA
+    let untrusted = tls::client_config(&dir.join("other-ca.pem"), None).unwrap();
+    assert!(matches!(KvClient::new_tls(addr, "localhost", untrusted).await, Err(KvsError::Tls(_))));
+    // the name does not match the certificate
+    assert!(matches!(KvClient::new_tls(addr, "example.com", config).await, Err(KvsError::Tls(_))));
+    // plain text requests are not answered
+    let mut plain = KvClient::new(addr).await.unwrap();
+    assert!(plain.get("key").await.is_err());
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+#[tokio::test]
+async fn mutual_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = Ca::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", ExtendedKeyUsagePurpose::ServerAuth);
+    let client_identity = ca.issue(dir, "client", ExtendedKeyUsagePurpose::ClientAuth);
+    let other = Ca::new(dir, "other-ca");
+    let other_identity = other.issue(dir, "other", ExtendedKeyUsagePurpose::ClientAuth);
+
+    let addr = "127.0.0.1:4702".parse().unwrap();
+    let data = dir.join("data");
+    let ca_path = dir.join("ca.pem");
+    let (shutdown, server) = start_server(addr, &data, tls::server_config(&cert, &key, Some(&ca_path)).unwrap());
+
+    let identity = (client_identity.0.as_path(), client_identity.1.as_path());
+    let config = tls::client_config(&ca_path, Some(identity)).unwrap();
+    let mut client = KvClient::new_tls(addr, "localhost", config).await.unwrap();
+    client.set("key", "value", None).await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), Some("value".to_string()));
+
+    // with TLS 1.3 the server rejects the client certificate after the client finished
+    // the handshake, so the failure may only show up with the first request
+    let anonymous = tls::client_config(&ca_path, None).unwrap();
+    let identity = (other_identity.0.as_path(), other_identity.1.as_path());
+    let wrong_ca = tls::client_config(&ca_path, Some(identity)).unwrap();
+    for config in [anonymous, wrong_ca] {
+        match KvClient::new_tls(addr, "localhost", config).await {
+            Ok(mut client) => assert!(client.get("key").await.is_err()),
+            Err(e) => assert!(matches!(e, KvsError::Tls(_)), "unexpected error {}", e),
+        }
+    }
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+#[test]
+fn tls_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let ca = Ca::new(dir, "ca");
+    let (cert, key) = ca.issue(dir, "server", ExtendedKeyUsagePurpose::ServerAuth);
+    let path = dir.join("kvs.toml");
+
+    fs::write(&path, format!("[server]\ntls_cert = {:?}\ntls_key = {:?}\ntls_client_ca = {:?}\n", cert, key, dir.join("ca.pem"))).unwrap();
+    let config = Config::load(&path).unwrap();
+    assert!(config.tls().unwrap().is_some());
+    assert!(Config::default().tls().unwrap().is_none());
+
+    for content in ["[server]\ntls_cert = \"server.pem\"\n", "[server]\ntls_client_ca = \"ca.pem\"\n"] {
+        fs::write(&path, content).unwrap();
+        assert!(matches!(Config::load(&path), Err(KvsError::Config(_))), "{} should be rejected", content);
+    }
+
+    // the key is not a certificate
+    fs::write(&path, format!("[server]\ntls_cert = {:?}\ntls_key = {:?}\n", key, key)).unwrap();
+    assert!(matches!(Config::load(&path).unwrap().tls(), Err(KvsError::Tls(_))));
+}
@@ -0,0 +1,97 @@
+#![cfg(unix)]
+
+use kvs::{KvClient, KvServer, KvStore, ServerAddr, ShardThreadPool, ThreadPool};
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn parse_server_addr() {
+    assert_eq!("127.0.0.1:4001".parse::<ServerAddr>().unwrap(), ServerAddr::Tcp("127.0.0.1:4001".parse().unwrap()));
+    let unix: ServerAddr = "unix:///tmp/kvs.sock".parse().unwrap();
+    assert_eq!(unix, ServerAddr::Unix("/tmp/kvs.sock".into()));
+    assert_eq!(unix.to_string(), "unix:///tmp/kvs.sock");
+    assert!("unix://".parse::<ServerAddr>().is_err());
+    assert!("localhost".parse::<ServerAddr>().is_err());
+}
+
+#[tokio::test]
+async fn tcp_and_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let addr = "127.0.0.1:4801".parse().unwrap();
+    let store = KvStore::open(temp_dir.path().join("data")).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(4).unwrap();
+    let mut server = KvServer::new(store, addr, shutdown.clone(), pool)
+        .unwrap()
+        .with_unix_socket(&path, Some(0o600))
+        .unwrap();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+        server.shut_down(Duration::from_secs(5)).unwrap();
+    });
+
+    let meta = fs::metadata(&path).unwrap();
+    assert!(meta.file_type().is_socket());
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+    let unix_addr: ServerAddr = format!("unix://{}", path.display()).parse().unwrap();
+    let mut unix = KvClient::connect(&unix_addr).await.unwrap();
+    let mut tcp = KvClient::connect(&ServerAddr::Tcp(addr)).await.unwrap();
+    unix.set("key", "value", None).await.unwrap();
+    assert_eq!(tcp.get("key").await.unwrap(), Some("value".to_string()));
+    tcp.set("key", "other", None).await.unwrap();
+    assert_eq!(unix.get("key").await.unwrap(), Some("other".to_string()));
+
+    // the socket file is removed on shutdown
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn unix_socket_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    // a socket file left by a crashed server is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let store = KvStore::open(temp_dir.path().join("data")).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(2).unwrap();
+    let mut server = KvServer::unix(store, &path, None, shutdown.clone(), pool).unwrap();
+    // the socket is in use now
+    let store = KvStore::open(temp_dir.path().join("other")).unwrap();
+    assert!(KvServer::unix(store, &path, None, Arc::new(AtomicBool::new(false)), ShardThreadPool::new(1).unwrap()).is_err());
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+        server.shut_down(Duration::from_secs(5)).unwrap();
+    });
+
+    let mut client = KvClient::new_unix(&path).await.unwrap();
+    client.set("key", "value", None).await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), Some("value".to_string()));
+    let info = client.info().await.unwrap();
+    assert_eq!(info.connections.active_connections, 1);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+}
+
+#[test]
+fn keep_files_that_are_not_sockets() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    fs::write(&path, "not a socket").unwrap();
+    let store = KvStore::open(temp_dir.path().join("data")).unwrap();
+    let pool = ShardThreadPool::new(1).unwrap();
+    assert!(KvServer::unix(store, &path, Some(0o600), Arc::new(AtomicBool::new(false)), pool).is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+    // no temporary directory is left behind
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+}
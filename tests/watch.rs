@@ -0,0 +1,121 @@
+use kvs::{ChangeOp, KVEngine, KvClient, KvServer, KvStore, ShardThreadPool, SledStore, ThreadPool};
+use std::net::SocketAddr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// Changes of watched prefixes are emitted in order with increasing versions.
+fn emits_changes<E: KVEngine>(store: E) {
+    let users = store.watch("user:");
+    let all = store.watch("");
+    store.set("user:1".to_owned(), "a".to_owned(), 0).unwrap();
+    store.set("order:1".to_owned(), "b".to_owned(), 0).unwrap();
+    store.set("user:1".to_owned(), "c".to_owned(), 0).unwrap();
+    store.remove("user:1".to_owned()).unwrap();
+    assert!(store.remove("user:2".to_owned()).is_err());
+
+    let events: Vec<_> = (0..3).map(|_| users.recv_timeout(TIMEOUT).unwrap()).collect();
+    assert!(users.try_recv().is_err());
+    assert_eq!(
+        events.iter().map(|e| (e.key.as_str(), e.op)).collect::<Vec<_>>(),
+        vec![("user:1", ChangeOp::Set), ("user:1", ChangeOp::Set), ("user:1", ChangeOp::Remove)]
+    );
+    assert!(events.windows(2).all(|w| w[0].version < w[1].version));
+
+    let events: Vec<_> = all.try_iter().collect();
+    assert_eq!(events.len(), 4);
+    assert_eq!(events[1].key, "order:1");
+
+    // dropped watchers are not kept
+    drop(users);
+    store.set("user:3".to_owned(), "d".to_owned(), 0).unwrap();
+    assert_eq!(all.recv_timeout(TIMEOUT).unwrap().key, "user:3");
+}
+
+#[test]
+fn kvs_emits_changes() {
+    let temp_dir = TempDir::new().unwrap();
+    emits_changes(KvStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn sled_emits_changes() {
+    let temp_dir = TempDir::new().unwrap();
+    emits_changes(SledStore::open(temp_dir.path()).unwrap());
+}
+
+// Versions keep increasing after a restart and across compactions.
+#[test]
+fn kvs_versions_survive_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let changes = store.watch("");
+    store.set("key".to_owned(), "value".to_owned(), 0).unwrap();
+    let first = changes.recv_timeout(TIMEOUT).unwrap().version;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let changes = store.watch("");
+    store.set("key2".to_owned(), "value2".to_owned(), 0).unwrap();
+    assert!(changes.recv_timeout(TIMEOUT).unwrap().version > first);
+    // entries appended to the reused log are read back correctly
+    assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+    assert_eq!(store.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn kvs_emits_expire() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let changes = store.watch("session:");
+    store.set("session:1".to_owned(), "a".to_owned(), 1).unwrap();
+    assert_eq!(changes.recv_timeout(TIMEOUT).unwrap().op, ChangeOp::Set);
+    thread::sleep(Duration::from_millis(2100));
+    store.get("session:1".to_owned()).unwrap();
+    let event = changes.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!((event.key.as_str(), event.op), ("session:1", ChangeOp::Expire));
+}
+
+// Watching through the server streams the changes made by other clients.
+async fn watch_through_server<E: KVEngine>(store: E, addr: SocketAddr) {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pool = ShardThreadPool::new(4).unwrap();
+    let mut server = KvServer::new(store, addr, shutdown.clone(), pool).unwrap();
+    let handle = thread::spawn(move || {
+        server.run().unwrap();
+        server.shut_down(Duration::from_secs(5)).unwrap();
+    });
+
+    let mut watch = KvClient::new(addr).await.unwrap().watch("cache:").await.unwrap();
+    let mut client = KvClient::new(addr).await.unwrap();
+    client.set("cache:a", "1", None).await.unwrap();
+    client.set("other", "2", None).await.unwrap();
+    client.remove("cache:a").await.unwrap();
+
+    let set = watch.next().await.unwrap().unwrap();
+    assert_eq!((set.key.as_str(), set.op), ("cache:a", ChangeOp::Set));
+    let remove = watch.next().await.unwrap().unwrap();
+    assert_eq!((remove.key.as_str(), remove.op), ("cache:a", ChangeOp::Remove));
+    assert!(remove.version > set.version);
+
+    shutdown.store(true, Ordering::SeqCst);
+    handle.join().unwrap();
+    assert!(watch.next().await.is_err());
+}
+
+#[tokio::test]
+async fn kvs_watch_through_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    watch_through_server(store, "127.0.0.1:4401".parse().unwrap()).await;
+}
+
+#[tokio::test]
+async fn sled_watch_through_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    watch_through_server(store, "127.0.0.1:4402".parse().unwrap()).await;
+}